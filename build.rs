@@ -0,0 +1,50 @@
+use std::env;
+use std::process::Command;
+
+/// Provides information about the build to `mzr version`, via environment
+/// variables read with `env!`.
+fn main() {
+    let git_commit = command_output(Command::new("git").args(&["rev-parse", "--short=12", "HEAD"]))
+        .map(|commit| {
+            let dirty = command_output(Command::new("git").args(&["status", "--porcelain"]))
+                .map_or(false, |status| !status.is_empty());
+            if dirty {
+                format!("{}-dirty", commit)
+            } else {
+                commit
+            }
+        });
+    // SOURCE_DATE_EPOCH is respected so that builds can be reproducible.
+    let build_date = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => command_output(Command::new("date").args(&[
+            "-u",
+            "-d",
+            &format!("@{}", epoch),
+            "+%Y-%m-%d",
+        ])),
+        Err(_) => command_output(Command::new("date").args(&["-u", "+%Y-%m-%d"])),
+    };
+    println!(
+        "cargo:rustc-env=MZR_GIT_COMMIT={}",
+        git_commit.unwrap_or_else(|| "unknown".to_string())
+    );
+    println!(
+        "cargo:rustc-env=MZR_BUILD_DATE={}",
+        build_date.unwrap_or_else(|| "unknown".to_string())
+    );
+    println!(
+        "cargo:rustc-env=MZR_TARGET={}",
+        env::var("TARGET").unwrap_or_else(|_| "unknown".to_string())
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn command_output(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -0,0 +1,112 @@
+use crate::git;
+use crate::hg;
+use crate::jj;
+use crate::paths::{SnapName, UserWorkDir};
+use chrono::Local;
+use failure::Error;
+use std::env;
+
+/// Source of default snapshot names, based on how the work dir is versioned.
+/// The first provider which detects the work dir is used, see `detect`.
+pub trait BaselineProvider {
+    /// Whether the work dir is versioned by this provider.
+    fn detects(&self, work_dir: &UserWorkDir) -> bool;
+
+    /// Name for a snapshot of the work dir when none was specified.
+    fn default_snap_name(&self, work_dir: &UserWorkDir) -> Result<SnapName, Error>;
+
+    /// Describes what the default snapshot name is derived from, for
+    /// messages like "using the current git ref or sha".
+    fn describe_name(&self) -> &'static str;
+}
+
+/// Providers in the order they're probed. `Jj` comes before `Git`, since jj
+/// repositories are usually colocated with git. `Plain` comes last, since it
+/// applies to any work dir.
+fn providers() -> Vec<Box<dyn BaselineProvider>> {
+    vec![Box::new(Jj), Box::new(Git), Box::new(Hg), Box::new(Plain)]
+}
+
+/// Finds the provider for the work dir.
+pub fn detect(work_dir: &UserWorkDir) -> Box<dyn BaselineProvider> {
+    providers()
+        .into_iter()
+        .find(|provider| provider.detects(work_dir))
+        .unwrap_or_else(|| Box::new(Plain))
+}
+
+/// Names snapshots after the current git branch, or the commit's short sha
+/// when HEAD is detached.
+pub struct Git;
+
+impl BaselineProvider for Git {
+    /// `.git` may also be a file, for worktrees. `GIT_DIR` may point
+    /// elsewhere, see `git::warn_env`.
+    fn detects(&self, work_dir: &UserWorkDir) -> bool {
+        work_dir.join(".git").exists() || env::var_os("GIT_DIR").is_some()
+    }
+
+    fn default_snap_name(&self, work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+        git::warn_env();
+        git::default_snap_name(work_dir)
+    }
+
+    fn describe_name(&self) -> &'static str {
+        "the current git ref or sha"
+    }
+}
+
+/// Names snapshots after a bookmark on the working copy commit or its
+/// parent, or otherwise the working copy's change id.
+pub struct Jj;
+
+impl BaselineProvider for Jj {
+    fn detects(&self, work_dir: &UserWorkDir) -> bool {
+        work_dir.join(".jj").is_dir()
+    }
+
+    fn default_snap_name(&self, work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+        jj::default_snap_name(work_dir)
+    }
+
+    fn describe_name(&self) -> &'static str {
+        "the current jj bookmark or change id"
+    }
+}
+
+/// Names snapshots after the active bookmark, the named branch, or the hash
+/// of the working directory's parent.
+pub struct Hg;
+
+impl BaselineProvider for Hg {
+    fn detects(&self, work_dir: &UserWorkDir) -> bool {
+        work_dir.join(".hg").is_dir()
+    }
+
+    fn default_snap_name(&self, work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+        hg::default_snap_name(work_dir)
+    }
+
+    fn describe_name(&self) -> &'static str {
+        "the current hg bookmark, branch or hash"
+    }
+}
+
+/// Names snapshots after the date, for work dirs which aren't versioned by a
+/// known tool. Later snapshots on the same day get a sequence number
+/// appended, see `snapshot::next_versioned_name`.
+pub struct Plain;
+
+impl BaselineProvider for Plain {
+    fn detects(&self, _work_dir: &UserWorkDir) -> bool {
+        true
+    }
+
+    fn default_snap_name(&self, _work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+        SnapName::new(Local::now().format("%Y-%m-%d").to_string())
+    }
+
+    fn describe_name(&self) -> &'static str {
+        "today's date, since the work directory isn't versioned by a known tool"
+    }
+}
@@ -0,0 +1,345 @@
+use crate::colors::*;
+use crate::display::format_size;
+use failure::{Error, ResultExt};
+use libc::pid_t;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{create_dir, read_to_string, remove_dir, write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Period used for `cpu.max`, in microseconds.
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// Limits on the resources used by the processes in a cgroup.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub memory: Option<ByteSize>,
+    pub cpus: Option<f64>,
+    pub pids: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpus.is_none() && self.pids.is_none()
+    }
+
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.memory.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        if self.pids.is_some() {
+            controllers.push("pids");
+        }
+        controllers
+    }
+}
+
+/// Number of bytes, parsed from a number with an optional `K`, `M`, `G` or
+/// `T` suffix.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = Error;
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        let size = size.trim();
+        let (digits, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&size[..size.len() - 1], 1 << 10),
+            Some('M') => (&size[..size.len() - 1], 1 << 20),
+            Some('G') => (&size[..size.len() - 1], 1 << 30),
+            Some('T') => (&size[..size.len() - 1], 1 << 40),
+            _ => (size, 1),
+        };
+        match digits.parse::<u64>() {
+            Ok(count) => Ok(ByteSize(count * multiplier)),
+            Err(_) => bail!(
+                "Expected a number of bytes with an optional K, M, G, or T suffix, but got {:?}",
+                size
+            ),
+        }
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Peak resource usage of the processes in a cgroup. Fields are `None` when
+/// the kernel doesn't report them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub memory_peak: Option<u64>,
+    pub pids_peak: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        let mut parts = Vec::new();
+        if let Some(bytes) = self.memory_peak {
+            parts.push(format!("peak memory {}", format_size(bytes)));
+        }
+        if let Some(pids) = self.pids_peak {
+            parts.push(format!("peak process count {}", pids));
+        }
+        if let Some(usec) = self.cpu_usage_usec {
+            parts.push(format!(
+                "CPU time {}.{:03}s",
+                usec / 1_000_000,
+                (usec / 1000) % 1000
+            ));
+        }
+        if parts.is_empty() {
+            write!(f, "no usage reported")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+/// A cgroup created by mzr, which gets removed when dropped.
+pub struct Cgroup {
+    dir: PathBuf,
+    original_dir: PathBuf,
+}
+
+impl Cgroup {
+    /// Creates a cgroup with the limits, as a sibling of the current
+    /// process's cgroup. This requires cgroups v2, and that the parent cgroup
+    /// is delegated to the user, as systemd does for `user@UID.service`.
+    pub fn create(name: &str, limits: &Limits) -> Result<Cgroup, Error> {
+        let original_dir = cgroup_dir_of("self")?;
+        let parent_dir = parent_cgroup_dir(&original_dir)?;
+        let available = read_to_string(parent_dir.join("cgroup.controllers"))?;
+        let available: Vec<&str> = available.split_whitespace().collect();
+        let controllers = limits.controllers();
+        for controller in &controllers {
+            if !available.contains(controller) {
+                bail!(
+                    "The {} cgroup controller isn't available in {:?}, \
+                     so resource limits can't be applied.",
+                    controller,
+                    parent_dir
+                );
+            }
+        }
+        let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+        write(parent_dir.join("cgroup.subtree_control"), enable.join(" ")).context(format_err!(
+            "Failed to enable cgroup controllers in {:?}. Is it delegated to your user?",
+            parent_dir
+        ))?;
+        let dir = parent_dir.join(name);
+        create_dir(&dir).context(format_err!("Failed to create cgroup {:?}", dir))?;
+        let cgroup = Cgroup { dir, original_dir };
+        if let Some(memory) = limits.memory {
+            cgroup.write_file("memory.max", &memory.to_string())?;
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = (cpus * CPU_PERIOD_USEC as f64).round() as u64;
+            cgroup.write_file(
+                "cpu.max",
+                &format!("{} {}", quota.max(1000), CPU_PERIOD_USEC),
+            )?;
+        }
+        if let Some(pids) = limits.pids {
+            cgroup.write_file("pids.max", &pids.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    /// Moves the current process into the cgroup, so that processes it
+    /// spawns are also within it.
+    pub fn enter(&self) -> Result<(), Error> {
+        self.write_file("cgroup.procs", &process::id().to_string())
+    }
+
+    /// Moves the current process back to the cgroup it was in before
+    /// `enter`.
+    pub fn leave(&self) -> Result<(), Error> {
+        let procs_file = self.original_dir.join("cgroup.procs");
+        write(&procs_file, process::id().to_string())
+            .context(format_err!("Failed to write {:?}", procs_file))?;
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Result<Usage, Error> {
+        let cpu_usage_usec = self.read_file("cpu.stat")?.and_then(|stat| {
+            stat.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some("usage_usec"), Some(usec)) => usec.parse().ok(),
+                    _ => None,
+                }
+            })
+        });
+        Ok(Usage {
+            memory_peak: self.read_number("memory.peak")?,
+            pids_peak: self.read_number("pids.peak")?,
+            cpu_usage_usec,
+        })
+    }
+
+    fn write_file(&self, name: &str, contents: &str) -> Result<(), Error> {
+        let path = self.dir.join(name);
+        write(&path, contents).context(format_err!(
+            "Failed to write {:?} to {:?}",
+            contents,
+            path
+        ))?;
+        Ok(())
+    }
+
+    /// Reads a file of the cgroup, yielding `None` if the kernel doesn't
+    /// provide it.
+    fn read_file(&self, name: &str) -> Result<Option<String>, Error> {
+        let path = self.dir.join(name);
+        if path.exists() {
+            Ok(Some(read_to_string(&path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_number(&self, name: &str) -> Result<Option<u64>, Error> {
+        Ok(self
+            .read_file(name)?
+            .and_then(|contents| contents.trim().parse().ok()))
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(err) = remove_dir(&self.dir) {
+            println!(
+                "{} failed to remove cgroup {:?}: {}",
+                color_warn(&"Warning:"),
+                self.dir,
+                err
+            );
+        }
+    }
+}
+
+/// Processes frozen via the cgroup v2 freezer, by moving them into a cgroup
+/// created for the purpose. Unlike `Cgroup`, this isn't removed when dropped,
+/// since the processes stay frozen until explicitly thawed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrozenCgroup {
+    dir: PathBuf,
+    /// Cgroups which the processes were in before being frozen, so that they
+    /// can be moved back when thawed.
+    original_dirs: Vec<(pid_t, PathBuf)>,
+    /// Cgroup to move other processes to when thawed, such as ones started
+    /// while the processes were being moved.
+    default_dir: PathBuf,
+}
+
+impl FrozenCgroup {
+    /// Freezes the processes, creating a cgroup for them alongside the cgroup
+    /// of `reference_pid`. If this fails, any processes which were already
+    /// moved are moved back.
+    pub fn freeze(name: &str, reference_pid: Pid, pids: &[Pid]) -> Result<FrozenCgroup, Error> {
+        let default_dir = cgroup_dir_of(&reference_pid.to_string())?;
+        let dir = parent_cgroup_dir(&default_dir)?.join(name);
+        create_dir(&dir).context(format_err!("Failed to create cgroup {:?}", dir))?;
+        let mut frozen = FrozenCgroup {
+            dir,
+            original_dirs: Vec::new(),
+            default_dir,
+        };
+        let result: Result<(), Error> = try {
+            for pid in pids {
+                // Processes which have exited since being listed are skipped.
+                let original_dir = match cgroup_dir_of(&pid.to_string()) {
+                    Ok(original_dir) => original_dir,
+                    Err(_) => continue,
+                };
+                write_procs(&frozen.dir, pid_t::from(*pid))?;
+                frozen.original_dirs.push((pid_t::from(*pid), original_dir));
+            }
+            let freeze_file = frozen.dir.join("cgroup.freeze");
+            write(&freeze_file, "1").context(format_err!("Failed to write {:?}", freeze_file))?;
+        };
+        if let Err(err) = result {
+            if let Err(thaw_err) = frozen.thaw() {
+                println!(
+                    "{} failed to undo partial freeze: {}",
+                    color_warn(&"Warning:"),
+                    thaw_err
+                );
+            }
+            return Err(err);
+        }
+        Ok(frozen)
+    }
+
+    /// Unfreezes the processes, moves them back to their original cgroups,
+    /// and removes the cgroup created for freezing them.
+    pub fn thaw(&self) -> Result<(), Error> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+        let freeze_file = self.dir.join("cgroup.freeze");
+        write(&freeze_file, "0").context(format_err!("Failed to write {:?}", freeze_file))?;
+        let procs = read_to_string(self.dir.join("cgroup.procs"))?;
+        for pid in procs
+            .lines()
+            .filter_map(|line| line.trim().parse::<pid_t>().ok())
+        {
+            let original_dir = self
+                .original_dirs
+                .iter()
+                .find(|(original_pid, _)| *original_pid == pid)
+                .map_or(&self.default_dir, |(_, original_dir)| original_dir);
+            // The original cgroup may have been removed in the meantime.
+            if write_procs(original_dir, pid).is_err() {
+                write_procs(&self.default_dir, pid)?;
+            }
+        }
+        remove_dir(&self.dir).context(format_err!("Failed to remove cgroup {:?}", self.dir))?;
+        Ok(())
+    }
+}
+
+fn write_procs(dir: &Path, pid: pid_t) -> Result<(), Error> {
+    let procs_file = dir.join("cgroup.procs");
+    write(&procs_file, pid.to_string()).context(format_err!(
+        "Failed to move process {} to {:?}",
+        pid,
+        procs_file
+    ))?;
+    Ok(())
+}
+
+/// Parent of a cgroup, in which sibling cgroups can be created.
+fn parent_cgroup_dir(dir: &Path) -> Result<PathBuf, Error> {
+    match dir.parent() {
+        Some(parent_dir) if parent_dir.starts_with(CGROUP_ROOT) => Ok(parent_dir.to_path_buf()),
+        _ => bail!("Can't create a cgroup alongside the root cgroup."),
+    }
+}
+
+/// Directory of a process's cgroup, found via `/proc/PROCESS/cgroup`, where
+/// `PROCESS` is a pid or `self`.
+fn cgroup_dir_of(process: &str) -> Result<PathBuf, Error> {
+    let contents = read_to_string(format!("/proc/{}/cgroup", process))?;
+    // With cgroups v2, the entry for the unified hierarchy is "0::PATH".
+    match contents.lines().find(|line| line.starts_with("0::")) {
+        Some(line) => {
+            let path = Path::new(&line[3..]);
+            Ok(Path::new(CGROUP_ROOT).join(path.strip_prefix("/").unwrap_or(path)))
+        }
+        None => bail!("Resource limits require cgroups v2, which doesn't seem to be in use."),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::copier::{self, Copier};
+use crate::merge;
+use crate::paths::*;
+use crate::zone::Zone;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use failure::{Error, ResultExt};
+use nix::sys::stat::{mknod, Mode, SFlag};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, rename, symlink_metadata};
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Format of checkpoint names, which are the time they were made.
+const NAME_FORMAT: &str = "%Y-%m-%d-%H%M%S";
+
+/// Prefix of checkpoints which are still being copied.
+const PARTIAL_PREFIX: &str = ".partial-";
+
+/// How many checkpoints are kept for each zone, with older ones removed
+/// when new ones are made.
+pub const KEPT_CHECKPOINTS: usize = 24;
+
+/// A copy of a zone's changes dir, which the zone can be restored to.
+pub struct Checkpoint {
+    pub name: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Makes a checkpoint of the zone's changes, yielding its name. Contents are
+/// reflinked when the filesystem supports it, so this is cheap. The zone may
+/// be mounted, but its processes should be frozen for a consistent copy.
+pub fn create(zone: &Zone) -> Result<String, Error> {
+    compaction::check_not_compacted(zone)?;
+    let checkpoints_dir = ZoneCheckpointsDir::new(&zone.zone_dir);
+    let now = Utc::now();
+    let name = now.format(NAME_FORMAT).to_string();
+    let checkpoint_dir = checkpoints_dir.join(&name);
+    if symlink_metadata(&checkpoint_dir).is_ok() {
+        bail!(
+            "Zone {} already has a checkpoint named {}.",
+            zone.name,
+            name
+        );
+    }
+    // Copied to a partial dir first, so that interrupted copies aren't
+    // mistaken for checkpoints.
+    let partial_dir = checkpoints_dir.join(format!("{}{}", PARTIAL_PREFIX, name));
+    if symlink_metadata(&partial_dir).is_ok() {
+        remove_dir_all(&partial_dir)?;
+    }
+    create_dir_all(&checkpoints_dir)?;
+    // Metadata-only copy-ups become full copies, since their data is found
+    // via the lower dirs.
+    let mut copier = Copier::for_layers(&zone.ovfs_changes_dir, zone.lower_dirs()?)
+        .with_owner_of(&zone.ovfs_changes_dir)?;
+    copy_layer(&mut copier, &zone.ovfs_changes_dir, &partial_dir).context(format_err!(
+        "Failed to make checkpoint {} of zone {}",
+        name,
+        zone.name
+    ))?;
+    rename(&partial_dir, &checkpoint_dir)?;
+    Ok(name)
+}
+
+/// Lists the zone's checkpoints, oldest first.
+pub fn list(zone: &Zone) -> Result<Vec<Checkpoint>, Error> {
+    let checkpoints_dir = ZoneCheckpointsDir::new(&zone.zone_dir);
+    if !checkpoints_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut checkpoints = Vec::new();
+    for entry in read_dir(&checkpoints_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Ok(time) = NaiveDateTime::parse_from_str(&name, NAME_FORMAT) {
+            checkpoints.push(Checkpoint {
+                name,
+                time: DateTime::from_utc(time, Utc),
+            });
+        }
+    }
+    checkpoints.sort_by_key(|checkpoint| checkpoint.time);
+    Ok(checkpoints)
+}
+
+/// Removes all but the newest `keep` checkpoints of the zone, yielding the
+/// names of those removed.
+pub fn prune(zone: &Zone, keep: usize) -> Result<Vec<String>, Error> {
+    let checkpoints = list(zone)?;
+    let excess = checkpoints.len().saturating_sub(keep);
+    let checkpoints_dir = ZoneCheckpointsDir::new(&zone.zone_dir);
+    let mut removed = Vec::new();
+    for checkpoint in checkpoints.into_iter().take(excess) {
+        remove_dir_all(checkpoints_dir.join(&checkpoint.name))?;
+        removed.push(checkpoint.name);
+    }
+    Ok(removed)
+}
+
+/// Replaces the zone's changes with those of the checkpoint. A checkpoint of
+/// the changes being replaced is made first, so that restoring can be
+/// undone, and its name is yielded.
+///
+/// Overlayfs doesn't support modifying the changes dir while it's mounted,
+/// so the zone must not be mounted.
+pub fn restore(zone: &Zone, name: &str) -> Result<String, Error> {
+    compaction::check_not_compacted(zone)?;
+    let checkpoints_dir = ZoneCheckpointsDir::new(&zone.zone_dir);
+    let checkpoint_dir = checkpoints_dir.join(name);
+    if NaiveDateTime::parse_from_str(name, NAME_FORMAT).is_err() || !checkpoint_dir.is_dir() {
+        bail!(
+            "Zone {} doesn't have a checkpoint named {}. Use {} to list them.",
+            zone.name,
+            name,
+            color_cmd(&format!("mzr zone checkpoints {}", zone.name))
+        );
+    }
+    let previous = create(zone)?;
+    let restoring_dir = checkpoints_dir.join(format!("{}restore-{}", PARTIAL_PREFIX, name));
+    if symlink_metadata(&restoring_dir).is_ok() {
+        remove_dir_all(&restoring_dir)?;
+    }
+    let mut copier = Copier::new().with_owner_of(&zone.ovfs_changes_dir)?;
+    copy_layer(&mut copier, &checkpoint_dir, &restoring_dir)?;
+    remove_dir_all(&zone.ovfs_changes_dir)?;
+    rename(&restoring_dir, &zone.ovfs_changes_dir)?;
+    Ok(previous)
+}
+
+/// Copies an overlay layer, including the whiteouts and attributes which
+/// overlayfs uses to represent deletions, so that the copy can be used as
+/// the layer.
+fn copy_layer(copier: &mut Copier, source_dir: &Path, target_dir: &Path) -> Result<(), Error> {
+    for entry in WalkDir::new(source_dir).same_file_system(true) {
+        let entry = entry?;
+        let source = entry.path();
+        let target = target_dir.join(source.strip_prefix(source_dir)?);
+        let metadata = entry.metadata()?;
+        if merge::is_whiteout(&metadata) {
+            mknod(&target, SFlag::S_IFCHR, Mode::empty(), 0)
+                .context(format_err!("Failed to create whiteout {:?}", target))?;
+        } else {
+            copier.copy(source, &target)?;
+        }
+        if metadata.is_dir() {
+            copier::copy_overlay_xattrs(source, &target)?;
+        }
+    }
+    Ok(())
+}
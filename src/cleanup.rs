@@ -0,0 +1,174 @@
+use crate::colors::*;
+use failure::Error;
+use libc::pid_t;
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+use std::os::unix::thread::JoinHandleExt;
+use std::process::exit;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Signals which cause registered cleanup to happen.
+const SIGNALS: &[Signal] = &[Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// Signal used to stop the handler thread.
+const STOP_SIGNAL: Signal = Signal::SIGUSR1;
+
+/// Pid of the helper process being waited for via `as_helper`, or 0. When a
+/// signal is received, it's forwarded to the helper, which is then waited
+/// for before cleanup actions run, so that they don't race with it. For
+/// example, the `cp` taking a snapshot would otherwise keep writing into it
+/// while it's being removed.
+static HELPER_PID: AtomicIsize = AtomicIsize::new(0);
+
+/// Handles termination signals for commands which would otherwise leave
+/// partial state behind when interrupted. When a signal is received, it gets
+/// forwarded to registered child processes, then registered cleanup actions
+/// run in reverse order of registration, and then mzr exits with the
+/// conventional code of 128 plus the signal number.
+pub struct Interrupts {
+    state: Arc<Mutex<State>>,
+    handler: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    entries: Vec<(usize, Entry)>,
+}
+
+enum Entry {
+    Action(String, Box<dyn FnMut() -> Result<(), Error> + Send>),
+    Child(Pid),
+}
+
+/// Registration of a cleanup action or child process, which is unregistered
+/// when dropped.
+pub struct Registration {
+    id: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl Interrupts {
+    /// Blocks the signals in the current thread and spawns a thread to handle
+    /// them. This should be called before spawning other threads, since they
+    /// inherit the signal mask. Child processes spawned via
+    /// `std::process::Command` don't, since it resets the mask.
+    pub fn install() -> Result<Interrupts, Error> {
+        let mut signals = SigSet::empty();
+        for signal in SIGNALS {
+            signals.add(*signal);
+        }
+        signals.add(STOP_SIGNAL);
+        signals.thread_block()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler_state = state.clone();
+        let handler = thread::spawn(move || match signals.wait() {
+            Ok(STOP_SIGNAL) => {}
+            Ok(signal) => handle_signal(&handler_state, signal),
+            Err(err) => println!(
+                "{} failed to wait for signals: {}",
+                color_warn(&"Warning:"),
+                err
+            ),
+        });
+        Ok(Interrupts { state, handler })
+    }
+
+    /// Stops the handler thread, which is needed before entering a user
+    /// namespace, since that requires the process to be single threaded.
+    /// The signals stay blocked, so any received in the meantime get handled
+    /// once handling is installed again.
+    pub fn uninstall(self) -> Result<(), Error> {
+        let result =
+            unsafe { libc::pthread_kill(self.handler.as_pthread_t(), STOP_SIGNAL as libc::c_int) };
+        if result != 0 {
+            bail!(
+                "Failed to stop signal handling thread (error code {}).",
+                result
+            );
+        }
+        self.handler
+            .join()
+            .map_err(|_| format_err!("Signal handling thread panicked."))?;
+        Ok(())
+    }
+
+    /// Registers an action to run if interrupted, until the returned
+    /// registration is dropped.
+    pub fn on_interrupt<F>(&self, description: &str, action: F) -> Registration
+    where
+        F: FnMut() -> Result<(), Error> + Send + 'static,
+    {
+        self.register(Entry::Action(description.to_string(), Box::new(action)))
+    }
+
+    /// Registers a child process to forward signals to, until the returned
+    /// registration is dropped.
+    pub fn forward_to(&self, child: u32) -> Registration {
+        self.register(Entry::Child(Pid::from_raw(child as pid_t)))
+    }
+
+    fn register(&self, entry: Entry) -> Registration {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.entries.push((id, entry));
+        Registration {
+            id,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.entries.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// Runs the action, which waits for the helper process, registering it to be
+/// killed and waited for if a signal is received meanwhile.
+pub fn as_helper<T, F: FnOnce() -> T>(pid: u32, action: F) -> T {
+    HELPER_PID.store(pid as isize, Ordering::SeqCst);
+    let result = action();
+    HELPER_PID.store(0, Ordering::SeqCst);
+    result
+}
+
+fn handle_signal(state: &Mutex<State>, signal: Signal) {
+    println!();
+    println!("Received {:?}, cleaning up.", signal);
+    // The lock is held until exit, so that the main thread can't drop
+    // registrations while cleanup is happening.
+    let mut state = match state.lock() {
+        Ok(state) => state,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for (_, entry) in state.entries.iter_mut() {
+        if let Entry::Child(pid) = entry {
+            let _ = kill(*pid, signal);
+        }
+    }
+    let helper_pid = HELPER_PID.load(Ordering::SeqCst);
+    if helper_pid != 0 {
+        let helper_pid = Pid::from_raw(helper_pid as pid_t);
+        let _ = kill(helper_pid, signal);
+        // Fails if the main thread already waited for it, in which case it
+        // has exited.
+        let _ = waitpid(helper_pid, None);
+    }
+    for (_, entry) in state.entries.iter_mut().rev() {
+        if let Entry::Action(description, action) = entry {
+            println!("Cleanup: {}", description);
+            if let Err(err) = action() {
+                println!("{} cleanup failed: {}", color_err(&"Error:"), err);
+            }
+        }
+    }
+    exit(128 + signal as i32);
+}
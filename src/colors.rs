@@ -1,5 +1,26 @@
+use nix::unistd::isatty;
+use std::env;
 use yansi::Paint;
 
+/// Environment variable holding the `--color` choice, so that it also
+/// applies to mzr processes spawned by this one.
+pub const COLOR_VAR: &str = "MZR_COLOR";
+
+/// Enables or disables colored output according to `COLOR_VAR`, which is
+/// one of `auto`, `always`, or `never`. With `auto`, colors are only used
+/// when stdout is a terminal and `NO_COLOR` isn't set.
+pub fn configure() {
+    let choice = env::var(COLOR_VAR).unwrap_or_else(|_| "auto".to_string());
+    let enabled = match choice.as_str() {
+        "always" => true,
+        "never" => false,
+        _ => isatty(1).unwrap_or(false) && env::var_os("NO_COLOR").is_none(),
+    };
+    if !enabled {
+        Paint::disable();
+    }
+}
+
 pub fn color_dir<T>(x: &T) -> Paint<&T> {
     Paint::blue(x).bold()
 }
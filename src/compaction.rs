@@ -0,0 +1,279 @@
+use crate::colors::*;
+use crate::daemon;
+use crate::json;
+use crate::paths::*;
+use crate::utils::run_process;
+use crate::zone::Zone;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, remove_dir_all, remove_file, symlink_metadata, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Which files in the changes directory get compacted.
+pub struct Criteria {
+    /// Files smaller than this many bytes are left alone.
+    pub min_size: u64,
+    /// Files accessed more recently than this are left alone.
+    pub min_idle: Duration,
+}
+
+/// Records which files in the changes directory have been replaced by empty
+/// stubs, and which archives store their contents.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompactionManifest {
+    pub archives: Vec<CompactedArchive>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactedArchive {
+    /// File name of the archive, within the `CompactedDir`.
+    pub archive: String,
+    /// Paths of the compacted files, relative to the changes directory.
+    pub files: Vec<PathBuf>,
+    pub original_size: u64,
+    pub compaction_time: DateTime<Utc>,
+    /// Modification times of the stubs, in the same order as `files`, so
+    /// that files which were modified after being compacted can be
+    /// detected.
+    #[serde(default)]
+    pub stub_times: Vec<DateTime<Utc>>,
+}
+
+/// Compresses files in the zone's changes directory which match the
+/// criteria into a new archive, and truncates them to empty stubs. Yields
+/// `None` if no files matched.
+///
+/// The stubs are left in the changes directory so that they continue to hide
+/// the corresponding snapshot files. Overlayfs doesn't expect its upper
+/// directory to be modified while it is mounted, so this fails if the zone is
+/// in use, and otherwise has the daemon unmount it first. It also fails if
+/// zones are layered on the zone, since they would see the stubs.
+pub fn compact(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    criteria: &Criteria,
+) -> Result<Option<CompactedArchive>, Error> {
+    if !Zone::list_layered_on(mzr_dir, &zone.name)?.is_empty() {
+        bail!(
+            "Zone {} has zones layered on it, which would see its compacted files as empty, so \
+             it can't be compacted.",
+            zone.name
+        );
+    }
+    if daemon::socket_exists(mzr_dir) {
+        if !daemon::zone_usage(mzr_dir, &zone.name)?.is_empty() {
+            bail!(
+                "Zone {} is in use, so it can't be compacted. See {} for what's using it.",
+                zone.name,
+                color_cmd(&format!("mzr zone ps {}", zone.name))
+            );
+        }
+        daemon::stop_zone(mzr_dir, &zone.name)?;
+    }
+    let changes_dir = &zone.ovfs_changes_dir;
+    let now = SystemTime::now();
+    let mut files = Vec::new();
+    let mut original_size = 0;
+    for entry in WalkDir::new(changes_dir).same_file_system(true) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || metadata.len() < criteria.min_size.max(1) {
+            continue;
+        }
+        let idle = now
+            .duration_since(metadata.accessed()?)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        if idle < criteria.min_idle {
+            continue;
+        }
+        files.push(PathBuf::from(entry.path().strip_prefix(changes_dir)?));
+        original_size += metadata.len();
+    }
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let compacted_dir = CompactedDir::new(&zone.zone_dir);
+    create_dir_all(&compacted_dir).context(format_err!(
+        "Unexpected error while creating compacted directory {}",
+        compacted_dir
+    ))?;
+    let mut manifest = read_manifest(&compacted_dir)?;
+    let archive_name = format!("{}.tar.gz", manifest.archives.len());
+    let archive_path = compacted_dir.join(&archive_name);
+    // The list of files is passed to tar via a NUL separated file, so that
+    // there is no limit on the number of files, and any file name works.
+    let list_path = compacted_dir.join("files.list");
+    {
+        let mut list_file = File::create(&list_path)?;
+        for file in files.iter() {
+            list_file.write_all(file.as_os_str().as_bytes())?;
+            list_file.write_all(b"\0")?;
+        }
+    }
+    run_process(
+        Command::new("tar")
+            .stdin(Stdio::null())
+            .arg("--create")
+            .arg("--gzip")
+            // Overlayfs records metacopy, redirects and opaque directories
+            // in xattrs.
+            .arg("--xattrs")
+            .arg("--xattrs-include=trusted.overlay.*")
+            .arg("--xattrs-include=user.overlay.*")
+            .arg("--file")
+            .arg(&archive_path)
+            .arg("--directory")
+            .arg(changes_dir.as_os_str())
+            .arg("--null")
+            .arg("--files-from")
+            .arg(&list_path),
+    )?;
+    remove_file(&list_path)?;
+    // Record the archive before truncating, so that a failure part way
+    // through truncation doesn't lose track of any contents.
+    let archive = CompactedArchive {
+        archive: archive_name,
+        files,
+        original_size,
+        compaction_time: Utc::now(),
+        stub_times: Vec::new(),
+    };
+    manifest.archives.push(archive);
+    let manifest_file = CompactionManifestFile::new(&compacted_dir);
+    json::write(&manifest_file, &manifest)?;
+    let mut archive = manifest
+        .archives
+        .pop()
+        .ok_or_else(|| format_err!("Unexpected error: compaction manifest has no archives."))?;
+    for file in archive.files.iter() {
+        let stub = OpenOptions::new()
+            .write(true)
+            .open(changes_dir.join(file))?;
+        stub.set_len(0)?;
+        archive
+            .stub_times
+            .push(DateTime::from(stub.metadata()?.modified()?));
+    }
+    manifest.archives.push(archive.clone());
+    json::write(&manifest_file, &manifest)?;
+    Ok(Some(archive))
+}
+
+/// Restores all compacted files of the zone, replacing their stubs. This is
+/// a no-op if the zone hasn't been compacted. Since this modifies the changes
+/// directory, it must happen before the zone's overlay gets mounted.
+///
+/// Fails without restoring anything if any stubs were modified after being
+/// compacted, since restoring would overwrite the modifications.
+pub fn restore(zone: &Zone) -> Result<(), Error> {
+    if !is_compacted(zone) {
+        return Ok(());
+    }
+    let compacted_dir = CompactedDir::new(&zone.zone_dir);
+    let manifest = read_manifest(&compacted_dir)?;
+    let modified = modified_stubs(zone, &manifest)?;
+    if !modified.is_empty() {
+        let listing: Vec<String> = modified
+            .iter()
+            .map(|path| format!("  {}", path.display()))
+            .collect();
+        bail!(
+            "{} file(s) of zone {} were modified after being compacted, so restoring them would \
+             overwrite the modifications:\n{}\nTheir compacted contents are in {}. Remove the \
+             modified files from {} to restore them, or remove {} to keep the modified files.",
+            modified.len(),
+            zone.name,
+            listing.join("\n"),
+            compacted_dir,
+            zone.ovfs_changes_dir,
+            compacted_dir
+        );
+    }
+    println!(
+        "Restoring {} compacted archive(s) of zone {}",
+        manifest.archives.len(),
+        zone.name
+    );
+    for archive in manifest.archives.iter() {
+        run_process(
+            Command::new("tar")
+                .stdin(Stdio::null())
+                .arg("--extract")
+                .arg("--preserve-permissions")
+                .arg("--gzip")
+                .arg("--xattrs")
+                .arg("--xattrs-include=trusted.overlay.*")
+                .arg("--xattrs-include=user.overlay.*")
+                .arg("--file")
+                .arg(compacted_dir.join(&archive.archive))
+                .arg("--directory")
+                .arg(zone.ovfs_changes_dir.as_os_str()),
+        )?;
+    }
+    remove_dir_all(&compacted_dir).context(format_err!(
+        "Failed to remove compacted directory {} after restoring its contents",
+        compacted_dir
+    ))?;
+    Ok(())
+}
+
+/// Whether the zone has compacted files, whose stubs are empty in the changes
+/// directory.
+pub fn is_compacted(zone: &Zone) -> bool {
+    CompactionManifestFile::new(&CompactedDir::new(&zone.zone_dir)).exists()
+}
+
+/// Fails if the zone has compacted files. Code outside the daemon which reads
+/// the contents of the changes directory calls this first, since it would
+/// otherwise read the empty stubs as the files' contents. Within the daemon,
+/// `restore` is used instead.
+pub fn check_not_compacted(zone: &Zone) -> Result<(), Error> {
+    if is_compacted(zone) {
+        bail!(
+            "Zone {} has compacted files, which are restored when the zone is next used, such \
+             as by {}. Until then, its changes can't be read.",
+            zone.name,
+            color_cmd(&format!("mzr exec {} true", zone.name))
+        );
+    }
+    Ok(())
+}
+
+/// Compacted files, relative to the changes dir, whose stubs were replaced
+/// or modified. Files which were removed count as unmodified, so that they
+/// get restored.
+fn modified_stubs(zone: &Zone, manifest: &CompactionManifest) -> Result<Vec<PathBuf>, Error> {
+    let mut result = Vec::new();
+    for archive in manifest.archives.iter() {
+        for (ix, file) in archive.files.iter().enumerate() {
+            let metadata = match symlink_metadata(zone.ovfs_changes_dir.join(file)) {
+                Ok(metadata) => metadata,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => Err(err)?,
+            };
+            let time_matches = match archive.stub_times.get(ix) {
+                Some(stub_time) => DateTime::<Utc>::from(metadata.modified()?) == *stub_time,
+                None => true,
+            };
+            if !metadata.is_file() || metadata.len() != 0 || !time_matches {
+                result.push(file.clone());
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn read_manifest(compacted_dir: &CompactedDir) -> Result<CompactionManifest, Error> {
+    let manifest_file = CompactionManifestFile::new(compacted_dir);
+    if manifest_file.exists() {
+        Ok(json::read(&manifest_file)?.contents)
+    } else {
+        Ok(CompactionManifest::default())
+    }
+}
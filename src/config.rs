@@ -0,0 +1,95 @@
+use crate::json;
+use crate::overlay::OverlayOptions;
+use crate::paths::*;
+use crate::template::Template;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Settings for the mzr directory.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Whether the git hooks installed by `mzr git install-hooks` take
+    /// snapshots.
+    #[serde(default)]
+    pub auto_snapshot: bool,
+    /// Paths which get a tmpfs mounted over them in every zone, in addition
+    /// to the zone's own scratch dirs. See `ZoneInfo::scratch_dirs`.
+    #[serde(default)]
+    pub scratch_dirs: Vec<PathBuf>,
+    /// Commands to run for each hook, keyed by hook name, such as
+    /// `pre-merge`. They run after the hook's script in the hooks dir, if
+    /// any. See `hooks::Hook`.
+    #[serde(default)]
+    pub hooks: BTreeMap<String, Vec<String>>,
+    /// Files and commands applied to each new zone, in addition to the
+    /// contents of the template dir. See `template::Template`.
+    #[serde(default)]
+    pub template: Template,
+    /// Globs of paths which are never merged, in addition to those in the
+    /// work dir's `.mzrignore-merge`. See `merge::MergeIgnore`.
+    #[serde(default)]
+    pub merge_ignore: Vec<String>,
+    /// Environment variables set when entering any zone, which a zone's own
+    /// `ZoneInfo::env` takes precedence over. See `Zone::env_vars`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directories within the user's home directory, such as `.cache`, which
+    /// every zone gets its own version of, in addition to the zone's own
+    /// isolated home dirs. See `ZoneInfo::isolated_home_dirs`.
+    #[serde(default)]
+    pub isolated_home_dirs: Vec<PathBuf>,
+    /// Directories which are shared by every zone, such as a dependency
+    /// registry or compilation cache, so that builds in zones don't
+    /// redownload or rebuild what other zones already have. Relative paths
+    /// are relative to the work dir, and absolute paths are useful for
+    /// sharing directories within isolated home dirs. Writes to them don't
+    /// go to the changes dir. See `Zone::bind_shared_cache_dirs`.
+    #[serde(default)]
+    pub shared_cache_dirs: Vec<PathBuf>,
+    /// Overlayfs features to enable when mounting zones, in the `[overlay]`
+    /// section. See `overlay::OverlayOptions`.
+    #[serde(default)]
+    pub overlay: OverlayOptions,
+    /// Whether to use the work dir's path as it was found, when it was
+    /// reached through a symlink, rather than its canonical path. Either
+    /// way, the mzr dir is found beside the path it was reached by.
+    #[serde(default)]
+    pub keep_symlinked_work_dir: bool,
+    /// Entries of the work dir which are versioned together as a project
+    /// set, set by `mzr init --member`. When non-empty, snapshots only
+    /// include these. See `project_set::ProjectSet`.
+    #[serde(default)]
+    pub project_set: Vec<PathBuf>,
+    /// Whether to set the immutable attribute on the files of new
+    /// snapshots, so that they can't be modified by accident. This requires
+    /// privileges which mzr usually doesn't have, in which case it's
+    /// skipped with a warning. See `snapshot::make_immutable`.
+    #[serde(default)]
+    pub immutable_snapshots: bool,
+    /// Capabilities, such as `CAP_NET_BIND_SERVICE`, which commands run in
+    /// zones keep within the zone's user namespace. All others are dropped
+    /// once the zone is entered. Running mzr commands which enter zones,
+    /// such as `mzr run`, from within a zone requires keeping `sys_admin`
+    /// and `sys_chroot`. See `privileges::restrict`.
+    #[serde(default)]
+    pub zone_capabilities: Vec<String>,
+}
+
+impl Config {
+    /// Loads the configuration, which has default settings if it hasn't been
+    /// written.
+    pub fn load(mzr_dir: &MzrDir) -> Result<Config, Error> {
+        let config_file = ConfigFile::new(mzr_dir);
+        if config_file.exists() {
+            Ok(json::read(&config_file)?.contents)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    pub fn write(&self, mzr_dir: &MzrDir) -> Result<(), Error> {
+        json::write(&ConfigFile::new(mzr_dir), self)
+    }
+}
@@ -0,0 +1,94 @@
+use crate::journal;
+use crate::paths::*;
+use crate::zone::Zone;
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use walkdir::WalkDir;
+
+/// A path which has been changed within more than one zone of the same
+/// snapshot, so merging one of the zones will make the others' changes to it
+/// conflict.
+pub struct Overlap {
+    pub snapshot: SnapName,
+    pub rel_path: PathBuf,
+    pub zones: Vec<ZoneName>,
+}
+
+/// Compares the changes of zones which share a snapshot, and finds the paths
+/// changed within more than one of them. Directories are ignored, since
+/// files within them are compared, as are paths within `excluded_dirs`. Overlaps are ordered by
+/// snapshot and then by path.
+pub fn find_overlaps(zones: &[Zone], excluded_dirs: &[PathBuf]) -> Result<Vec<Overlap>, Error> {
+    let mut snapshots: BTreeMap<&str, Vec<&Zone>> = BTreeMap::new();
+    for zone in zones {
+        snapshots
+            .entry(zone.info.snapshot.as_str())
+            .or_insert_with(Vec::new)
+            .push(zone);
+    }
+    let mut overlaps = Vec::new();
+    for (_, zones) in snapshots {
+        if zones.len() < 2 {
+            continue;
+        }
+        let mut changes: BTreeMap<PathBuf, Vec<ZoneName>> = BTreeMap::new();
+        for zone in zones.iter() {
+            for rel_path in changed_paths(zone)? {
+                if excluded_dirs.iter().any(|dir| rel_path.starts_with(dir)) {
+                    continue;
+                }
+                changes
+                    .entry(rel_path)
+                    .or_insert_with(Vec::new)
+                    .push(zone.name.clone());
+            }
+        }
+        for (rel_path, zone_names) in changes {
+            if zone_names.len() > 1 {
+                overlaps.push(Overlap {
+                    snapshot: zones[0].info.snapshot.clone(),
+                    rel_path,
+                    zones: zone_names,
+                });
+            }
+        }
+    }
+    Ok(overlaps)
+}
+
+/// Paths within the zone's changes dir, other than directories, relative to
+/// the changes dir. Deletions are included, since overlayfs represents them
+/// as whiteout files. Like merge planning, this uses the zone's journal when
+/// there is one, rather than walking the whole changes dir.
+pub fn changed_paths(zone: &Zone) -> Result<BTreeSet<PathBuf>, Error> {
+    let changes_dir = &zone.ovfs_changes_dir;
+    let mut paths = BTreeSet::new();
+    if let Some(rel_paths) = journal::read(zone)? {
+        for rel_path in rel_paths {
+            match fs::symlink_metadata(changes_dir.join(&rel_path)) {
+                Ok(ref metadata) if metadata.is_dir() => {}
+                Ok(_) => {
+                    paths.insert(rel_path);
+                }
+                // Paths which have since been removed from the changes dir
+                // are no longer changes.
+                Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        return Ok(paths);
+    }
+    if !changes_dir.is_dir() {
+        return Ok(paths);
+    }
+    for entry in WalkDir::new(changes_dir).same_file_system(true) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            paths.insert(PathBuf::from(entry.path().strip_prefix(changes_dir)?));
+        }
+    }
+    Ok(paths)
+}
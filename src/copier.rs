@@ -0,0 +1,473 @@
+use crate::overlay;
+use failure::{Error, ResultExt};
+use libc::{c_char, c_ulong, c_void};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{
+    create_dir, hard_link, read_link, remove_file, set_permissions, symlink_metadata, File,
+    Metadata, OpenOptions, Permissions,
+};
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// `FICLONE` ioctl, which makes the target file share the source file's
+/// extents. Supported by filesystems like Btrfs and XFS.
+const FICLONE: c_ulong = 0x4004_9409;
+
+/// Prefixes of the extended attributes which overlayfs uses for its own
+/// bookkeeping, such as marking opaque directories. These aren't part of the
+/// file, so aren't copied. The `user.` prefix is used by overlays mounted
+/// with the `userxattr` option.
+const OVERLAY_XATTR_PREFIXES: &[&[u8]] = &[b"trusted.overlay.", b"user.overlay."];
+
+/// Copies files within the current process, preserving their permissions,
+/// timestamps, extended attributes, and when permitted their ownership.
+/// File contents are reflinked when the filesystem supports it.
+///
+/// When copying into a changes dir or the work dir, ownership is instead
+/// normalized via `with_owner`, since files written as root within a zone's
+/// user namespace, or copied from other users' snapshots, would otherwise
+/// keep owners which look arbitrary from the host.
+///
+/// Files which have multiple links are tracked, so that when another link to
+/// an already copied file is copied, it becomes a hardlink to the copy. This
+/// is why one `Copier` should be used for all of the files being copied
+/// together.
+#[derive(Default)]
+pub struct Copier {
+    /// Copies of files with multiple links, keyed by the device and inode of
+    /// the source.
+    copies_of_links: HashMap<(u64, u64), PathBuf>,
+    /// Changes dir and lower dirs of the overlay which files are copied out
+    /// of, for finding the data of metadata-only copy-ups.
+    layers: Option<(PathBuf, Vec<PathBuf>)>,
+    /// User and group to give copies, rather than those of the source.
+    owner: Option<(u32, u32)>,
+}
+
+impl Copier {
+    pub fn new() -> Copier {
+        Copier::default()
+    }
+
+    /// Copier for files within an overlay's changes dir, which may be
+    /// metadata-only copy-ups when the overlay has `metacopy` enabled, with
+    /// their data copied from the lower dirs instead.
+    pub fn for_layers(changes_dir: &Path, lower_dirs: Vec<PathBuf>) -> Copier {
+        Copier {
+            copies_of_links: HashMap::new(),
+            layers: Some((changes_dir.to_path_buf(), lower_dirs)),
+            owner: None,
+        }
+    }
+
+    /// Gives copies the user and group which own `dir`, which is typically
+    /// the directory being copied into. Within the daemon's user namespace,
+    /// this is the mapped root, which is the project's user from the host.
+    pub fn with_owner_of(mut self, dir: &Path) -> Result<Copier, Error> {
+        let metadata = symlink_metadata(dir)?;
+        self.owner = Some((metadata.uid(), metadata.gid()));
+        Ok(self)
+    }
+
+    /// Copies the source to the target, replacing the target if it's not a
+    /// directory. Directories aren't copied recursively, only created.
+    pub fn copy(&mut self, source: &Path, target: &Path) -> Result<(), Error> {
+        let metadata = symlink_metadata(source)?;
+        let file_type = metadata.file_type();
+        if !file_type.is_dir() {
+            remove_if_exists(target)?;
+        }
+        let inode = (metadata.dev(), metadata.ino());
+        if metadata.nlink() > 1 && !file_type.is_dir() {
+            if let Some(copy) = self.copies_of_links.get(&inode) {
+                // The earlier copy may have since been moved or removed, in
+                // which case this falls back on copying.
+                if hard_link(copy, target).is_ok() {
+                    return Ok(());
+                }
+            }
+        }
+        if file_type.is_symlink() {
+            symlink(read_link(source)?, target)?;
+        } else if file_type.is_file() {
+            copy_contents(&self.data_path(source)?, target)?;
+        } else if file_type.is_dir() {
+            match create_dir(target) {
+                Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {}
+                result => result?,
+            }
+        } else {
+            bail!(
+                "{:?} can't be copied, since it isn't a regular file, symlink, or directory.",
+                source
+            );
+        }
+        copy_metadata(source, target, &metadata, self.owner).context(format_err!(
+            "Failed to copy metadata of {:?} to {:?}",
+            source,
+            target
+        ))?;
+        if metadata.nlink() > 1 && !file_type.is_dir() {
+            self.copies_of_links.insert(inode, target.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Where the contents of the regular file are, which is in a lower dir
+    /// when it's a metadata-only copy-up.
+    fn data_path(&self, source: &Path) -> Result<PathBuf, Error> {
+        if !overlay::is_metacopy(source) {
+            return Ok(source.to_path_buf());
+        }
+        let (changes_dir, lower_dirs) = match &self.layers {
+            Some((changes_dir, lower_dirs)) => (changes_dir, lower_dirs),
+            None => bail!(
+                "{:?} is a metadata-only copy-up, but its overlay's lower dirs are unknown.",
+                source
+            ),
+        };
+        let rel_path = source.strip_prefix(changes_dir).map_err(|_| {
+            format_err!(
+                "{:?} is a metadata-only copy-up, but isn't within the changes dir {:?}.",
+                source,
+                changes_dir
+            )
+        })?;
+        match overlay::metacopy_data_path(changes_dir, lower_dirs, rel_path) {
+            Some(data_path) => Ok(data_path),
+            None => bail!(
+                "{:?} is a metadata-only copy-up, but its data wasn't found in the lower dirs.",
+                source
+            ),
+        }
+    }
+}
+
+fn copy_contents(source: &Path, target: &Path) -> Result<(), Error> {
+    let mut source_file = File::open(source)?;
+    // Only readable by the user until permissions are copied.
+    let mut target_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(target)?;
+    let cloned = unsafe {
+        libc::ioctl(
+            target_file.as_raw_fd(),
+            FICLONE,
+            source_file.as_raw_fd() as c_ulong,
+        )
+    };
+    if cloned != 0 {
+        io::copy(&mut source_file, &mut target_file)?;
+    }
+    Ok(())
+}
+
+/// Makes the target share the source's extents, for files known to have
+/// identical contents. Unlike copying, the target keeps its own inode, and so
+/// its metadata, with its timestamps restored from `metadata` afterwards.
+/// Yields `false` if the filesystem doesn't support reflinks.
+pub fn reflink_over(source: &Path, target: &Path, metadata: &Metadata) -> Result<bool, Error> {
+    let source_file = File::open(source)?;
+    let target_file = OpenOptions::new().write(true).open(target)?;
+    let cloned = unsafe {
+        libc::ioctl(
+            target_file.as_raw_fd(),
+            FICLONE,
+            source_file.as_raw_fd() as c_ulong,
+        )
+    };
+    if cloned != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) => Ok(false),
+            _ => Err(err.into()),
+        };
+    }
+    set_times(&path_cstring(target)?, metadata)?;
+    Ok(true)
+}
+
+/// Copies ownership, extended attributes, permissions, and then timestamps,
+/// since changing the others may affect timestamps. Failing to change
+/// ownership or to copy extended attributes is ignored when the user isn't
+/// permitted to, or the target filesystem doesn't support it.
+fn copy_metadata(
+    source: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    owner: Option<(u32, u32)>,
+) -> Result<(), Error> {
+    let source_cstring = path_cstring(source)?;
+    let target_cstring = path_cstring(target)?;
+    let ((uid, gid), mode) = owner_and_mode(
+        (metadata.uid(), metadata.gid()),
+        metadata.mode(),
+        metadata.is_dir(),
+        owner,
+    );
+    let chowned = unsafe { libc::lchown(target_cstring.as_ptr(), uid, gid) };
+    if chowned != 0 {
+        ignore_unpermitted(io::Error::last_os_error())?;
+    }
+    for name in list_xattrs(&source_cstring)? {
+        if OVERLAY_XATTR_PREFIXES
+            .iter()
+            .any(|prefix| name.to_bytes().starts_with(prefix))
+        {
+            continue;
+        }
+        let value = match get_xattr(&source_cstring, &name) {
+            Ok(value) => value,
+            // The attribute may have been removed since it was listed.
+            Err(ref err) if err.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let set = unsafe {
+            libc::lsetxattr(
+                target_cstring.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if set != 0 {
+            ignore_unpermitted(io::Error::last_os_error())?;
+        }
+    }
+    if !metadata.file_type().is_symlink() {
+        set_permissions(target, Permissions::from_mode(mode))?;
+    }
+    set_times(&target_cstring, metadata)
+}
+
+/// Yields the user and group to give a copy, along with its permission bits.
+/// These are the source's, unless the copy is given a different owner, in
+/// which case the setuid and setgid bits of files are cleared, as `chown(2)`
+/// does, since they'd otherwise grant the new owner's privileges.
+fn owner_and_mode(
+    source_owner: (u32, u32),
+    source_mode: u32,
+    is_dir: bool,
+    owner: Option<(u32, u32)>,
+) -> ((u32, u32), u32) {
+    let owner = owner.unwrap_or(source_owner);
+    let mut mode = source_mode & 0o7777;
+    if owner != source_owner && !is_dir {
+        mode &= !((libc::S_ISUID | libc::S_ISGID) as u32);
+    }
+    (owner, mode)
+}
+
+/// Copies the overlayfs bookkeeping attributes which `Copier` leaves out,
+/// such as the one marking opaque directories. This is for copying a layer
+/// of an overlay such that the copy can be used as a layer.
+pub fn copy_overlay_xattrs(source: &Path, target: &Path) -> Result<(), Error> {
+    let source_cstring = path_cstring(source)?;
+    let target_cstring = path_cstring(target)?;
+    for name in list_xattrs(&source_cstring)? {
+        if !OVERLAY_XATTR_PREFIXES
+            .iter()
+            .any(|prefix| name.to_bytes().starts_with(prefix))
+        {
+            continue;
+        }
+        let value = get_xattr(&source_cstring, &name)?;
+        let set = unsafe {
+            libc::lsetxattr(
+                target_cstring.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if set != 0 {
+            Err(io::Error::last_os_error())?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the access and modification times of the path to those in the
+/// metadata, without following symlinks.
+fn set_times(target_cstring: &CStr, metadata: &Metadata) -> Result<(), Error> {
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            target_cstring.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        Err(io::Error::last_os_error())?;
+    }
+    Ok(())
+}
+
+/// Ownership and extended attributes can't always be preserved, such as
+/// when the file's owner isn't mapped in the current user namespace.
+fn ignore_unpermitted(err: io::Error) -> Result<(), Error> {
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EINVAL) | Some(libc::ENOTSUP) => Ok(()),
+        _ => Err(err.into()),
+    }
+}
+
+fn list_xattrs(path: &CStr) -> Result<Vec<CString>, Error> {
+    let mut buffer = vec![0u8; 1024];
+    loop {
+        let size = unsafe {
+            libc::llistxattr(
+                path.as_ptr(),
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len(),
+            )
+        };
+        if size >= 0 {
+            buffer.truncate(size as usize);
+            break;
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ERANGE) => {
+                let len = buffer.len();
+                buffer.resize(len * 2, 0);
+            }
+            Some(libc::ENOTSUP) => return Ok(Vec::new()),
+            _ => return Err(err.into()),
+        }
+    }
+    Ok(buffer
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| CString::new(name).ok())
+        .collect())
+}
+
+/// Reads an extended attribute, without following symlinks.
+pub fn read_xattr(path: &Path, name: &str) -> Result<Vec<u8>, Error> {
+    Ok(get_xattr(&path_cstring(path)?, &CString::new(name)?)?)
+}
+
+fn get_xattr(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+    let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The size is checked again, in case the value has grown in between.
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut c_void,
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    Ok(value)
+}
+
+fn path_cstring(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match remove_file(path) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::process;
+
+    const USER: (u32, u32) = (1000, 1000);
+    const OTHER_USER: (u32, u32) = (1001, 1000);
+    const OTHER_GROUP: (u32, u32) = (1000, 1001);
+    const MAPPED_ROOT: (u32, u32) = (0, 0);
+
+    #[test]
+    fn owner_and_mode_keeps_source_owner_by_default() {
+        for &source in &[USER, OTHER_USER, MAPPED_ROOT] {
+            for &is_dir in &[false, true] {
+                assert_eq!(
+                    owner_and_mode(source, 0o104755, is_dir, None),
+                    (source, 0o4755)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn owner_and_mode_clears_setid_bits_of_files_given_other_owners() {
+        for &source in &[OTHER_USER, OTHER_GROUP, MAPPED_ROOT] {
+            assert_eq!(
+                owner_and_mode(source, 0o106755, false, Some(USER)),
+                (USER, 0o755)
+            );
+            // Setgid on directories makes new entries inherit the group, so
+            // it's kept.
+            assert_eq!(
+                owner_and_mode(source, 0o042775, true, Some(USER)),
+                (USER, 0o2775)
+            );
+        }
+    }
+
+    #[test]
+    fn owner_and_mode_keeps_setid_bits_when_owner_is_unchanged() {
+        assert_eq!(
+            owner_and_mode(USER, 0o106755, false, Some(USER)),
+            (USER, 0o6755)
+        );
+    }
+
+    #[test]
+    fn copier_gives_copies_the_owner_of_the_target_dir() {
+        let dir = env::temp_dir().join(format!("mzr-copier-test-{}", process::id()));
+        let target_dir = dir.join("target");
+        create_dir_all(&target_dir).unwrap();
+        let source = dir.join("source");
+        write(&source, "contents").unwrap();
+        set_permissions(&source, Permissions::from_mode(0o640)).unwrap();
+        let target = target_dir.join("copy");
+        Copier::new()
+            .with_owner_of(&target_dir)
+            .unwrap()
+            .copy(&source, &target)
+            .unwrap();
+        let target_dir_metadata = symlink_metadata(&target_dir).unwrap();
+        let target_metadata = symlink_metadata(&target).unwrap();
+        assert_eq!(target_metadata.uid(), target_dir_metadata.uid());
+        assert_eq!(target_metadata.gid(), target_dir_metadata.gid());
+        assert_eq!(target_metadata.mode() & 0o7777, 0o640);
+        remove_dir_all(&dir).unwrap();
+    }
+}
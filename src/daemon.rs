@@ -1,21 +1,65 @@
+use crate::checkpoints;
 use crate::colors::*;
-use crate::git::{get_git_dir, symlink_git_repo};
+use crate::compaction;
+use crate::config::Config;
+use crate::conflicts;
+use crate::errors::{kind_error, ErrorKind};
+use crate::events::{self, EventKind};
+use crate::freezer;
+use crate::git::{self, add_worktree, find_git_dirs, symlink_git_repo};
+use crate::hg;
+use crate::hooks::{self, Hook};
+use crate::ipc::{self, Channel, Message, Timeouts};
+use crate::jj;
+use crate::journal::{self, JournalSync};
+use crate::json;
+use crate::merge::{self, PathFilter, Plan, PlanSummary};
+use crate::merge_txn;
+use crate::metrics::{self, MetricsReport, SharedMetrics};
+use crate::mount;
 use crate::namespaces;
 use crate::paths::*;
+use crate::privileges;
+use crate::remote_daemon;
+use crate::retention::{self, Removal, RetentionPolicy};
+use crate::rpc::{self, RpcError, RpcRequest, RpcResponse};
+use crate::run_info::RunInfo;
+use crate::shared;
+use crate::snapshot::{self, SnapInfo};
+use crate::snapshot_manifest;
+use crate::subscriptions::{Notification, Subscribers};
 use crate::top_dirs::TopDirs;
-use crate::zone::Zone;
+use crate::utils::parse_pid_file;
+use crate::version;
+use crate::zone::{Zone, ZoneInfo};
+use crate::zone_copy;
+use crate::zone_stats::ZoneStats;
+use chrono::{DateTime, Utc};
 use daemonize::Daemonize;
 use failure::{Error, ResultExt};
-use libc::pid_t;
-use libmount::BindMount;
-use nix::unistd::{Gid, Pid, Uid};
+use libc::{pid_t, uid_t};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::mount::{umount, umount2, MntFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, getpid, ForkResult, Gid, Pid, Uid};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
-use std::fs::{create_dir_all, read_dir, remove_file, File};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, write, File};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
 use std::thread;
 use std::time;
 use yansi::Paint;
@@ -42,48 +86,213 @@ impl Display for ZonePid {
     }
 }
 
-type ProcessMap = HashMap<ZoneName, ZonePid>;
+/// A zone process, as recorded in the daemon's processes file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ZoneProcess {
+    pid: ZonePid,
+    /// Start time of the process, in clock ticks after boot, as listed in
+    /// `/proc/PID/stat`. Used to recognize when the pid has been reused by a
+    /// different process.
+    start_time: u64,
+    /// Whether the process was started by a previous daemon, in which case
+    /// it isn't a child of this one, so it can't be waited for.
+    #[serde(skip)]
+    adopted: bool,
+}
+
+impl ZoneProcess {
+    /// Whether the process has exited, reaping it if it's a child of the
+    /// daemon.
+    fn has_exited(&self) -> Result<bool, Error> {
+        if self.adopted {
+            Ok(process_start_time(self.pid.to_pid())? != Some(self.start_time))
+        } else {
+            // Zone processes are cloned without a termination signal, so
+            // __WALL is needed to wait for them.
+            let wait_flags = WaitPidFlag::__WALL | WaitPidFlag::WNOHANG;
+            Ok(waitpid(self.pid.to_pid(), Some(wait_flags))? != WaitStatus::StillAlive)
+        }
+    }
+}
+
+type ProcessMap = HashMap<ZoneName, ZoneProcess>;
+
+/// A shell within a zone, registered by `mzr shell`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShellInfo {
+    /// Pid of the mzr process which waits for the shell, which is the shell's
+    /// parent.
+    pub pid: pid_t,
+    pub zone_name: ZoneName,
+    pub registered: DateTime<Utc>,
+    /// Start time of the process, used to recognize when it has exited and
+    /// its pid has been reused, see `process_start_time`.
+    start_time: u64,
+}
+
+/// Mutable state of the daemon, tracking which zones have had their
+/// overlayfs mounted, which zone processes have been created, and which
+/// zones have been bound to other directories via `mzr mount`.
+#[derive(Default)]
+struct DaemonState {
+    mounted_zones: HashSet<ZoneName>,
+    processes: ProcessMap,
+    workspaces: HashMap<PathBuf, ZoneName>,
+    /// Connection to the process which exposes zones in the mount namespace
+    /// that the daemon was started from, if enabled.
+    exposer: Option<UnixStream>,
+    /// Metrics, shared with the thread serving them over HTTP.
+    metrics: SharedMetrics,
+    /// The user's git directories which have been bind-mounted so that zones
+    /// can share them, keyed by their path relative to the work dir.
+    bound_git_repos: HashMap<PathBuf, BoundGitRepoDir>,
+    /// Likewise for the user's jj and Mercurial directories.
+    bound_jj_repos: HashMap<PathBuf, BoundJjRepoDir>,
+    bound_hg_repos: HashMap<PathBuf, BoundHgRepoDir>,
+    /// Channels to the threads journaling changes to mounted zones.
+    journals: HashMap<ZoneName, JournalSync>,
+    /// Shells within zones, keyed by the pid of the mzr process which waits
+    /// for the shell to exit.
+    shells: HashMap<pid_t, ShellInfo>,
+    /// When the daemon started, for reporting its uptime.
+    started: Option<time::Instant>,
+    /// When the last request was handled, not counting idleness checks.
+    last_activity: Option<time::Instant>,
+    /// Set once the daemon has decided to exit due to being idle.
+    shutting_down: bool,
+    /// Clients which have subscribed to notifications via JSON-RPC.
+    subscribers: Subscribers,
+}
+
+/// Environment variables set by systemd for socket activation, see
+/// `sd_listen_fds(3)`.
+const LISTEN_PID_VAR: &str = "LISTEN_PID";
+const LISTEN_FDS_VAR: &str = "LISTEN_FDS";
+
+/// First file descriptor passed by socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Set within the daemon process, which can't make requests to itself.
+static IS_DAEMON: AtomicBool = AtomicBool::new(false);
+
+/// Longest time between checks of whether the daemon is idle.
+const MAX_IDLE_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60);
 
-pub fn run(top_dirs: &TopDirs) -> Result<(), Error> {
+pub fn run(
+    top_dirs: &TopDirs,
+    expose_zones: bool,
+    metrics_addr: Option<SocketAddr>,
+    auto_gc_interval: Option<time::Duration>,
+    idle_timeout: Option<time::Duration>,
+    checkpoint_interval: Option<time::Duration>,
+    remote: Option<(SocketAddr, Option<remote_daemon::TlsFiles>)>,
+) -> Result<(), Error> {
     let user = Uid::current();
     let group = Gid::current();
-    let _pid = namespaces::with_unshared_user_and_mount(
+    // Checked before cloning, since the variables refer to this process.
+    let mut activated_listener = socket_activation_listener()?;
+    let socket_activated = activated_listener.is_some();
+    // Fds inherited from whatever started the daemon shouldn't be passed on
+    // to hooks, or to the zone processes it forks.
+    privileges::cloexec_inherited_fds(&[])?;
+    // Forked before unsharing, so that it stays in the original namespaces.
+    let exposer = if expose_zones {
+        Some(spawn_exposer()?)
+    } else {
+        None
+    };
+    let pid = namespaces::with_unshared_user_and_mount(
         |child_process| namespaces::map_user_to_root(child_process, user, group),
         || {
             let daemon_dir = DaemonDir::new(&top_dirs.mzr_dir);
-            create_dir_all(&daemon_dir)?;
-            let git_info = bind_git_repo(top_dirs)?;
+            shared::create_user_dir(&top_dirs.mzr_dir, &daemon_dir)?;
             // TODO(cleanup): Don't truncate old daemon logs?
             let log_stdout_file = File::create(DaemonLogStdoutFile::new(&daemon_dir))?;
             let log_stderr_file = File::create(DaemonLogStderrFile::new(&daemon_dir))?;
-            Daemonize::new()
-                .pid_file(DaemonPidFile::new(&daemon_dir))
-                // TODO(friendliness): Would be nice to merge
-                // these. Is stderr ever even used?
-                .stdout(log_stdout_file)
-                .stderr(log_stderr_file)
-                .start()?;
+            // Bound before daemonizing, so that failure to bind is reported.
+            let metrics_listener = match metrics_addr {
+                Some(addr) => Some(TcpListener::bind(addr).context(format_err!(
+                    "Failed to listen for metrics requests on {}",
+                    addr
+                ))?),
+                None => None,
+            };
+            let remote_listener = match &remote {
+                Some((addr, tls_files)) => Some((
+                    remote_daemon::bind(*addr, tls_files.as_ref())?,
+                    remote_daemon::load_or_create_token(&daemon_dir)?,
+                )),
+                None => None,
+            };
+            let pid_file = DaemonPidFile::new(&daemon_dir);
+            if socket_activated {
+                // The service manager keeps track of the process and its
+                // output, so it isn't daemonized.
+                write(&pid_file, process::id().to_string())?;
+            } else {
+                Daemonize::new()
+                    .pid_file(&pid_file)
+                    // TODO(friendliness): Would be nice to merge
+                    // these. Is stderr ever even used?
+                    .stdout(log_stdout_file)
+                    .stderr(log_stderr_file)
+                    .start()?;
+            }
             // Disable ANSI codes in output, since it's sent to a log
             // rather than terminal.
             Paint::disable();
             // Listen for client connections.
             let socket_path = DaemonSocketFile::new(&daemon_dir);
-            if socket_path.exists() {
+            if !socket_activated && socket_path.exists() {
                 remove_file(&socket_path).context(format_err!(
                     "Failed to remove daemon socket file {}",
                     socket_path
                 ))?;
             }
-            // Mutable hashmap to track which child processes have been
-            // created.
-            let mut processes = HashMap::new();
+            let mut state = DaemonState::default();
+            state.started = Some(time::Instant::now());
+            state.last_activity = state.started;
+            restore_processes(&top_dirs.mzr_dir, &mut state)?;
+            state.exposer = match &exposer {
+                Some(stream) => Some(stream.try_clone()?),
+                None => None,
+            };
+            if let Some(listener) = metrics_listener {
+                let mzr_dir = top_dirs.mzr_dir.clone();
+                let metrics = state.metrics.clone();
+                thread::spawn(move || metrics::serve_http(listener, mzr_dir, metrics));
+            }
+            if let Some(interval) = auto_gc_interval {
+                let mzr_dir = top_dirs.mzr_dir.clone();
+                thread::spawn(move || run_auto_gc_timer(&mzr_dir, interval));
+            }
+            if let Some(timeout) = idle_timeout {
+                let mzr_dir = top_dirs.mzr_dir.clone();
+                thread::spawn(move || run_idle_timer(&mzr_dir, timeout));
+            }
+            if let Some(interval) = checkpoint_interval {
+                let mzr_dir = top_dirs.mzr_dir.clone();
+                thread::spawn(move || run_checkpoint_timer(&mzr_dir, interval));
+            }
             // Listen for client connections. In the future, perhaps tokio
             // or mio will be used, but for now using the lower level APIs
             // because they are simpler and have better documentation.
-            let listener = UnixListener::bind(socket_path)?;
+            let listener = match activated_listener.take() {
+                Some(listener) => listener,
+                None => UnixListener::bind(&socket_path)?,
+            };
+            // Started once the socket is bound, since it forwards to it.
+            if let Some((listener, token)) = remote_listener {
+                let socket_path = socket_path.clone();
+                thread::spawn(move || {
+                    remote_daemon::serve(listener, socket_path, token, reject_remote_request)
+                });
+            }
+            events::record(&top_dirs.mzr_dir, EventKind::DaemonStarted);
+            IS_DAEMON.store(true, Ordering::SeqCst);
             for stream_or_err in listener.incoming() {
                 let stream = stream_or_err?;
-                match handle_client(&top_dirs, &git_info, user, group, stream, &mut processes) {
+                match handle_client(&top_dirs, user, group, stream, &mut state) {
                     Ok(()) => (),
                     Err(err) => {
                         println!("");
@@ -94,44 +303,138 @@ pub fn run(top_dirs: &TopDirs) -> Result<(), Error> {
                         println!("");
                     }
                 }
+                if state.shutting_down {
+                    break;
+                }
+            }
+            drop(listener);
+            // When socket activated, the socket belongs to the service
+            // manager, which listens on it again once the daemon exits.
+            if !socket_activated {
+                remove_file(&socket_path)?;
             }
+            remove_file(&pid_file)?;
+            events::record(&top_dirs.mzr_dir, EventKind::DaemonStopped);
+            println!("Daemon exiting due to being idle.");
             Ok(())
         },
     )?;
+    if socket_activated {
+        // The service manager considers the service stopped once this
+        // process exits, so wait for the daemon. It's cloned without a
+        // termination signal, so __WALL is needed to wait for it.
+        match waitpid(pid, Some(WaitPidFlag::__WALL))? {
+            WaitStatus::Exited(_, 0) => {}
+            status => bail!("mzr daemon exited unexpectedly: {:?}", status),
+        }
+    }
     // TODO(friendliness): Include this output, but only do it when
     // the daemon has actually started. Currently if you start the
     // daemon while another is running, and this line is uncommented,
     // it outputs.
     //
-    // println!("Started {} with PID {}", color_cmd(&String::from("mzr daemon")), color_cmd(&pid));
+    // println!("Started {} with PID {}", color_cmd(&"mzr daemon"), color_cmd(&pid));
     Ok(())
 }
 
-// If there is a top level git repository, bind mount it, so that the
-// repo can be shared by the zones.
-//
-// TODO(correctness): This is gnarly. Instead, git repos should be
-// supported after the daemon has already started. Should also support
-// multiple git repos.
-fn bind_git_repo(
+/// Yields the socket passed via systemd socket activation, if the daemon was
+/// started that way. The environment variables are removed, so that they
+/// aren't inherited by child processes.
+fn socket_activation_listener() -> Result<Option<UnixListener>, Error> {
+    let listen_pid = env::var(LISTEN_PID_VAR).ok();
+    let listen_fds = env::var(LISTEN_FDS_VAR).ok();
+    env::remove_var(LISTEN_PID_VAR);
+    env::remove_var(LISTEN_FDS_VAR);
+    env::remove_var("LISTEN_FDNAMES");
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(None),
+    };
+    if listen_pid.parse::<u32>().ok() != Some(process::id()) {
+        return Ok(None);
+    }
+    match listen_fds.parse::<u32>() {
+        Ok(1) => {}
+        Ok(count) => bail!(
+            "mzr daemon expects to be passed exactly one socket, but {} were passed.",
+            count
+        ),
+        Err(_) => bail!("Invalid value for {}: {:?}", LISTEN_FDS_VAR, listen_fds),
+    }
+    fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    println!("Using socket passed via socket activation.");
+    Ok(Some(unsafe {
+        UnixListener::from_raw_fd(SD_LISTEN_FDS_START)
+    }))
+}
+
+/// Bind mounts the user's git directory, so that the repo can be shared by
+/// the zones. This is done lazily, so that repositories created after the
+/// daemon started are supported. Yields `None` if the git directory no longer
+/// exists in the user's work dir.
+fn ensure_git_repo_bound(
     top_dirs: &TopDirs,
-) -> Result<Option<(BoundGitRepoDir, RelativeGitRepoDir)>, Error> {
-    Ok(match get_git_dir(&top_dirs.user_work_dir) {
-        Err(_) => None,
-        Ok(rel_git_dir) => {
-            let src_git_dir = top_dirs.user_work_dir.join(&rel_git_dir);
-            if src_git_dir.is_dir() {
-                let bound_git_repo_dir = BoundGitRepoDir::new(&top_dirs.mzr_dir);
-                create_dir_all(&bound_git_repo_dir)?;
-                BindMount::new(&src_git_dir, &bound_git_repo_dir)
-                    .mount()
-                    .map_err(|e| format_err!("{}", e))?;
-                Some((bound_git_repo_dir, rel_git_dir))
-            } else {
-                None
-            }
-        }
-    })
+    state: &mut DaemonState,
+    rel_git_dir: &RelativeGitRepoDir,
+) -> Result<Option<BoundGitRepoDir>, Error> {
+    if let Some(bound_git_repo_dir) = state.bound_git_repos.get(rel_git_dir.as_path()) {
+        return Ok(Some(bound_git_repo_dir.clone()));
+    }
+    let src_git_dir = top_dirs.user_work_dir.join(rel_git_dir);
+    if !src_git_dir.is_dir() {
+        return Ok(None);
+    }
+    let bound_git_repo_dir = BoundGitRepoDir::new(&top_dirs.mzr_dir, rel_git_dir);
+    create_dir_all(&bound_git_repo_dir)?;
+    mount::bind(&src_git_dir, &bound_git_repo_dir)?;
+    state
+        .bound_git_repos
+        .insert(rel_git_dir.to_path_buf(), bound_git_repo_dir.clone());
+    Ok(Some(bound_git_repo_dir))
+}
+
+/// Like `ensure_git_repo_bound`, but for a jj directory.
+fn ensure_jj_repo_bound(
+    top_dirs: &TopDirs,
+    state: &mut DaemonState,
+    rel_jj_dir: &Path,
+) -> Result<Option<BoundJjRepoDir>, Error> {
+    if let Some(bound_jj_repo_dir) = state.bound_jj_repos.get(rel_jj_dir) {
+        return Ok(Some(bound_jj_repo_dir.clone()));
+    }
+    let src_jj_dir = top_dirs.user_work_dir.join(rel_jj_dir);
+    if !src_jj_dir.is_dir() {
+        return Ok(None);
+    }
+    let bound_jj_repo_dir = BoundJjRepoDir::new(&top_dirs.mzr_dir, rel_jj_dir);
+    create_dir_all(&bound_jj_repo_dir)?;
+    mount::bind(&src_jj_dir, &bound_jj_repo_dir)?;
+    state
+        .bound_jj_repos
+        .insert(rel_jj_dir.to_path_buf(), bound_jj_repo_dir.clone());
+    Ok(Some(bound_jj_repo_dir))
+}
+
+/// Like `ensure_git_repo_bound`, but for a Mercurial directory.
+fn ensure_hg_repo_bound(
+    top_dirs: &TopDirs,
+    state: &mut DaemonState,
+    rel_hg_dir: &Path,
+) -> Result<Option<BoundHgRepoDir>, Error> {
+    if let Some(bound_hg_repo_dir) = state.bound_hg_repos.get(rel_hg_dir) {
+        return Ok(Some(bound_hg_repo_dir.clone()));
+    }
+    let src_hg_dir = top_dirs.user_work_dir.join(rel_hg_dir);
+    if !src_hg_dir.is_dir() {
+        return Ok(None);
+    }
+    let bound_hg_repo_dir = BoundHgRepoDir::new(&top_dirs.mzr_dir, rel_hg_dir);
+    create_dir_all(&bound_hg_repo_dir)?;
+    mount::bind(&src_hg_dir, &bound_hg_repo_dir)?;
+    state
+        .bound_hg_repos
+        .insert(rel_hg_dir.to_path_buf(), bound_hg_repo_dir.clone());
+    Ok(Some(bound_hg_repo_dir))
 }
 
 /*
@@ -143,202 +446,2325 @@ fn bind_git_repo(
 #[derive(Debug, Serialize, Deserialize)]
 enum Request {
     ZoneProcess(ZoneName),
+    /// Binds the zone's overlay to the target directory. The bind is made
+    /// in the daemon's mount namespace, so it's only visible to zone
+    /// processes started afterwards, which inherit it, and not to processes
+    /// outside of zones.
+    BindZone(ZoneName, PathBuf),
+    UnbindZone(PathBuf),
+    Metrics,
+    /// Sent by clients after merging a zone's changes, so that the daemon
+    /// can count merges.
+    RecordMerge(ZoneName, PlanSummary),
+    /// Removes snapshots and zones according to the retention policy, or
+    /// only lists what would be removed if the flag is set. This is handled by
+    /// the daemon so that it can avoid removing zones that are in use.
+    ApplyRetention(RetentionPolicy, bool),
+    /// Responds once all changes to the zone made before the request have
+    /// been written to its change journal.
+    SyncJournal(ZoneName),
+    /// Stops the zone's process, unmounts it, and removes it.
+    RemoveZone(ZoneName),
+    /// Stops the zone's process and unmounts it.
+    StopZone(ZoneName),
+    /// Stops the zone's process, unmounts it, repairs it via `Zone::repair`,
+    /// and then mounts it again.
+    RepairZone(ZoneName),
+    /// Discards the zone's changes to the paths, or all of its changes if
+    /// none are given. The zone, along with any zones layered on it, is
+    /// stopped and unmounted first, and gets remounted when next used.
+    RevertZone(ZoneName, Vec<PathBuf>),
+    /// Copies the paths as they appear within the source zone into the
+    /// destination zone, via `zone_copy::copy`. Like `RevertZone`, the
+    /// destination is stopped and unmounted first.
+    CopyIntoZone {
+        source: ZoneName,
+        dest: ZoneName,
+        rel_paths: Vec<PathBuf>,
+    },
+    /// Makes a checkpoint of each mounted zone, via `checkpoints::create`.
+    CheckpointZones,
+    /// Restores the zone to the named checkpoint. Like `RevertZone`, the zone
+    /// is stopped and unmounted first.
+    RestoreCheckpoint(ZoneName, String),
+    /// Makes the daemon exit if it has no zone processes or zones mounted
+    /// via `mzr mount`, and hasn't handled any other requests for the given
+    /// number of seconds.
+    ShutdownIfIdle(u64),
+    /// Checks that the daemon is responsive.
+    Ping,
+    /// Records that the client is waiting for a shell within the zone, until
+    /// it sends `DeregisterShell` or exits.
+    RegisterShell(ZoneName),
+    DeregisterShell,
+    ListShells,
+    /// Finds the zone of the innermost registered shell which the client
+    /// process is running within, if any.
+    ZoneOfClient,
+    /// Pauses the processes running within the zone.
+    FreezeZone(ZoneName),
+    /// Resumes the processes of a zone paused by `FreezeZone`.
+    ThawZone(ZoneName),
+    /// Lists the zones along with their status.
+    ListZones,
+    /// Lists the snapshots along with the zones which use them.
+    ListSnapshots,
+    /// Reports the daemon's health along with its mounts and zone
+    /// processes.
+    DaemonInfo,
+    /// Reports what's using the zone, so that removing it can be refused
+    /// rather than breaking them.
+    ZoneUsage(ZoneName),
+    /// Merges the zone's changes into the target dir, defaulting to the work
+    /// dir, like `mzr merge`. The paths are relative to the work dir.
+    Merge {
+        zone_name: ZoneName,
+        target_dir: Option<PathBuf>,
+        paths: Vec<PathBuf>,
+        excludes: Vec<String>,
+        dry_run: bool,
+        /// Whether to merge even if paths with uncommitted changes in the
+        /// target would be modified.
+        #[serde(default)]
+        force: bool,
+    },
+}
+
+impl Request {
+    /// How long clients wait for the daemon to respond. Requests which stop,
+    /// copy, or merge zones can take a while for large zones, so get longer.
+    fn timeout(&self) -> time::Duration {
+        if let Some(secs) = env::var(TIMEOUT_VAR).ok().and_then(|s| s.parse().ok()) {
+            return time::Duration::from_secs(secs);
+        }
+        match self {
+            Request::ZoneProcess(_)
+            | Request::BindZone(_, _)
+            | Request::ApplyRetention(_, _)
+            | Request::SyncJournal(_)
+            | Request::RemoveZone(_)
+            | Request::StopZone(_)
+            | Request::RepairZone(_)
+            | Request::RevertZone(_, _)
+            | Request::CopyIntoZone { .. }
+            | Request::CheckpointZones
+            | Request::RestoreCheckpoint(_, _)
+            | Request::FreezeZone(_)
+            | Request::ThawZone(_)
+            | Request::ListZones
+            | Request::ListSnapshots
+            | Request::Merge { .. } => SLOW_REQUEST_TIMEOUT,
+            _ => REQUEST_TIMEOUT,
+        }
+    }
+}
+
+/// Status of the daemon, reported in response to `Request::Ping`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonHealth {
+    pub version: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub zone_processes: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Response {
     ZoneProcess(ZonePid),
+    Metrics(MetricsReport),
+    Removals(Vec<Removal>),
+    Health(DaemonHealth),
+    Shells(Vec<ShellInfo>),
+    Zone(Option<ZoneName>),
+    Paths(Vec<PathBuf>),
+    /// Descriptions of the repairs made by `Request::RepairZone`.
+    Repairs(Vec<String>),
+    /// Number of entries copied by `Request::CopyIntoZone`.
+    Copied(usize),
+    /// Name of the checkpoint made of a zone's changes before restoring it
+    /// to another checkpoint.
+    Checkpoint(String),
+    Zones(Vec<ZoneStatus>),
+    Snapshots(Vec<SnapshotStatus>),
+    Info(DaemonInfo),
+    Usage(ZoneUsage),
+    /// Result of `Request::Merge`. The report is a `merge::PlanReport`,
+    /// which is only serializable, so it's sent as JSON.
+    Merged {
+        summary: PlanSummary,
+        report: Value,
+    },
+    Success,
     Error(String),
 }
 
+/// A zone along with its status, reported in response to
+/// `Request::ListZones`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneStatus {
+    pub name: ZoneName,
+    #[serde(flatten)]
+    pub info: ZoneInfo,
+    /// Number of paths changed within the zone, if they could be listed.
+    pub changed_paths: Option<usize>,
+    /// Whether the zone's process is running.
+    pub running: bool,
+    pub frozen: bool,
+    /// Directories which the zone is mounted at via `Request::BindZone`.
+    pub mounted_at: Vec<PathBuf>,
+    /// Pids of the shells registered within the zone.
+    pub shells: Vec<pid_t>,
+    /// Statistics about the zone's changes, if they could be computed.
+    #[serde(default)]
+    pub stats: Option<ZoneStats>,
+}
+
+/// A snapshot along with its status, reported in response to
+/// `Request::ListSnapshots`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotStatus {
+    pub name: SnapName,
+    #[serde(flatten)]
+    pub info: SnapInfo,
+    /// Zones which are of the snapshot.
+    pub zones: Vec<ZoneName>,
+    /// Whether any of the zones which are of the snapshot are mounted.
+    pub mounted: bool,
+}
+
+/// State of the daemon, reported in response to `Request::DaemonInfo`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonInfo {
+    #[serde(flatten)]
+    pub health: DaemonHealth,
+    pub mzr_dir: PathBuf,
+    pub work_dir: PathBuf,
+    /// Zones whose overlays are mounted.
+    pub mounted_zones: Vec<ZoneName>,
+    /// Zones which have a running process.
+    pub running_zones: Vec<ZoneName>,
+    /// Directories which zones are mounted at via `Request::BindZone`, along
+    /// with the zone mounted at each.
+    pub workspaces: Vec<(PathBuf, ZoneName)>,
+    /// Number of shells registered within zones.
+    pub shells: usize,
+}
+
+/// What's using a zone, reported in response to `Request::ZoneUsage`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneUsage {
+    /// Pids of the shells registered within the zone.
+    pub shells: Vec<pid_t>,
+    /// The zone's process, if it's running. Processes within the zone,
+    /// including those started by `mzr exec`, are its members.
+    pub process: Option<ZonePid>,
+    /// Directories which the zone is mounted at via `Request::BindZone`.
+    pub mounted_at: Vec<PathBuf>,
+}
+
+impl ZoneUsage {
+    pub fn is_empty(&self) -> bool {
+        self.shells.is_empty() && self.process.is_none() && self.mounted_at.is_empty()
+    }
+}
+
 /*
  * Handler for a client connection
  */
 
 fn handle_client(
     top_dirs: &TopDirs,
-    git_info: &Option<(BoundGitRepoDir, RelativeGitRepoDir)>,
     user: Uid,
     group: Gid,
     stream: UnixStream,
-    processes: &mut ProcessMap,
+    state: &mut DaemonState,
 ) -> Result<(), Error> {
+    // Each user of a shared mzr dir has their own daemon, which only serves
+    // them. Their uid is root within the daemon's user namespace.
+    if SharedFile::new(&top_dirs.mzr_dir).exists() {
+        let peer = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+        if peer.uid() != uid_t::from(Uid::current()) {
+            // Sent unframed, since the request hasn't been read. Clients
+            // read unframed responses until the connection is closed.
+            return send_response(
+                &stream,
+                &Response::Error(format!(
+                    "This mzr daemon belongs to user {}, so it doesn't serve other users.",
+                    shared::current_user()
+                )),
+                None,
+            );
+        }
+    }
+    let message = Channel::new(&stream).recv_message()?;
+    // JSON-RPC requests are lines, while mzr's own requests are framed,
+    // except when sent by an mzr which predates framing.
+    let is_rpc = match &message {
+        Message::Line(line) => rpc::is_rpc_request(line),
+        Message::Frame { .. } => false,
+    };
+    if is_rpc {
+        let (response, subscription) =
+            handle_rpc(top_dirs, user, group, &stream, state, message.payload());
+        send_rpc_response(&stream, &response)?;
+        // The connection is kept open to send notifications, which only
+        // start once the client has the response.
+        if let Some(params) = subscription {
+            state
+                .subscribers
+                .add(stream, params.topics, params.change_threshold)?;
+        }
+        return Ok(());
+    }
+    let response = match parse_request(&message) {
+        Ok(request) => handle_request(top_dirs, user, group, &stream, state, request)?,
+        Err(e) => Response::Error(format!("Unexpected error: {}", e)),
+    };
+    send_response(&stream, &response, message.response_version())
+}
+
+fn handle_request(
+    top_dirs: &TopDirs,
+    user: Uid,
+    group: Gid,
+    stream: &UnixStream,
+    state: &mut DaemonState,
+    request: Request,
+) -> Result<Response, Error> {
     let result: Result<Response, Error> = try {
-        match recv_request(&stream)? {
-            Request::ZoneProcess(zone_name) => match processes.get(&zone_name) {
+        match request {
+            // Health checks and requests from the daemon's own timers aren't
+            // activity.
+            Request::ShutdownIfIdle(_) | Request::Ping | Request::CheckpointZones => {}
+            _ => state.last_activity = Some(time::Instant::now()),
+        }
+        match request {
+            Request::ZoneProcess(zone_name) => match state.processes.get(&zone_name) {
                 None => match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
                     None => Response::Error(String::from("Zone does not exist")),
                     Some(zone) => {
-                        match git_info {
-                            None => {}
-                            Some((source_git_dir, rel_git_dir)) => {
-                                let target_git_dir = zone.ovfs_changes_dir.join(rel_git_dir);
-                                symlink_git_repo(&source_git_dir, &target_git_dir)?;
-                            }
-                        }
-                        // Mount the zone's overlayfs in the daemon's namespace.
-                        //
-                        // TODO: Looks like this does not yet
-                        // propagate to the mount namespaces of the
-                        // existing zone processes, but it needs to.
-                        zone.mount()?;
+                        ensure_zone_mounted(top_dirs, state, &zone)?;
                         // Fork a zone process which bind-mounts the
                         // zone to the user's working directory.
-                        let pid = fork_zone_process(&top_dirs.user_work_dir, user, group, &zone)?;
-                        processes.insert(zone_name, pid.clone());
+                        let config = Config::load(&top_dirs.mzr_dir)?;
+                        let mut scratch_dirs = config.scratch_dirs;
+                        scratch_dirs.extend(zone.info.scratch_dirs.iter().cloned());
+                        let mut home_dirs = config.isolated_home_dirs;
+                        home_dirs.extend(zone.info.isolated_home_dirs.iter().cloned());
+                        let pid = fork_zone_process(
+                            &top_dirs.user_work_dir,
+                            user,
+                            group,
+                            &zone,
+                            &scratch_dirs,
+                            &home_dirs,
+                            &config.shared_cache_dirs,
+                        )?;
+                        let start_time = match process_start_time(pid.to_pid())? {
+                            Some(start_time) => start_time,
+                            None => Err(format_err!("Zone process {} exited immediately", pid))?,
+                        };
+                        let process = ZoneProcess {
+                            pid: pid.clone(),
+                            start_time,
+                            adopted: false,
+                        };
+                        state.processes.insert(zone_name, process);
+                        processes_changed(&top_dirs.mzr_dir, state)?;
                         Response::ZoneProcess(pid)
                     }
                 },
-                Some(pid) => Response::ZoneProcess(pid.clone()),
+                Some(process) => Response::ZoneProcess(process.pid.clone()),
+            },
+            Request::BindZone(zone_name, target) => {
+                match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                    None => Response::Error(String::from("Zone does not exist")),
+                    Some(zone) => match (state.workspaces.get(&target), &state.exposer) {
+                        (Some(existing), _) => Response::Error(format!(
+                            "Zone {} is already mounted at {:?}",
+                            existing, target
+                        )),
+                        // Mounts made in the daemon's own mount namespace
+                        // wouldn't be visible outside of zones.
+                        (None, None) => Response::Error(format!(
+                            "Zones can only be mounted outside of mzr when the daemon is \
+                             started by root with {}",
+                            color_cmd(&"mzr daemon --expose-zones")
+                        )),
+                        (None, Some(exposer)) => {
+                            let exposer = exposer.try_clone()?;
+                            ensure_zone_mounted(top_dirs, state, &zone)?;
+                            bind_outside(&exposer, &zone, &target, false)?;
+                            state.workspaces.insert(target, zone_name);
+                            Response::Success
+                        }
+                    },
+                }
+            }
+            Request::UnbindZone(target) => match (state.workspaces.get(&target), &state.exposer) {
+                (Some(_), Some(exposer)) => {
+                    unbind_outside(exposer, &target)
+                        .context(format_err!("Failed to unmount {:?}", target))?;
+                    state.workspaces.remove(&target);
+                    Response::Success
+                }
+                _ => Response::Error(format!("No zone is mounted at {:?}", target)),
+            },
+            Request::Metrics => {
+                let daemon_metrics = update_metrics(state, |metrics| metrics.clone())?;
+                Response::Metrics(MetricsReport::gather(&top_dirs.mzr_dir, daemon_metrics)?)
+            }
+            Request::RecordMerge(zone_name, summary) => {
+                println!(
+                    "Merged zone {}: {} update(s), {} conflict(s), {} skip(s)",
+                    zone_name, summary.updates, summary.conflicts, summary.skips
+                );
+                update_metrics(state, |metrics| metrics.merge_operations += 1)?;
+                state.subscribers.notify(&Notification::Merged {
+                    zone: zone_name,
+                    summary,
+                });
+                Response::Success
+            }
+            Request::ApplyRetention(policy, dry_run) => {
+                let mzr_dir = &top_dirs.mzr_dir;
+                let in_use = zones_in_use(mzr_dir, state)?;
+                let removals = retention::plan(mzr_dir, &policy, &in_use)?;
+                if !dry_run {
+                    for removal in removals.iter() {
+                        println!("Removing {} due to retention policy", removal);
+                        if let Removal::Zone(zone_name) = removal {
+                            release_zone(mzr_dir, state, zone_name)?;
+                        }
+                    }
+                    retention::apply(top_dirs, &removals)?;
+                }
+                Response::Removals(removals)
+            }
+            Request::RemoveZone(zone_name) => {
+                let workspace = state
+                    .workspaces
+                    .iter()
+                    .find(|(_, name)| **name == zone_name)
+                    .map(|(target, _)| target.clone());
+                match (
+                    Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)?,
+                    workspace,
+                ) {
+                    (None, _) => Response::Error(String::from("Zone does not exist")),
+                    (Some(_), Some(target)) => Response::Error(format!(
+                        "Zone {} is mounted at {:?}, so it can't be removed until it's unmounted",
+                        zone_name, target
+                    )),
+                    (Some(zone), None) => {
+                        release_zone(&top_dirs.mzr_dir, state, &zone_name)?;
+                        zone.remove(&top_dirs.user_work_dir)?;
+                        state.shells.retain(|_, shell| shell.zone_name != zone_name);
+                        Response::Success
+                    }
+                }
+            }
+            Request::StopZone(zone_name) => {
+                release_zone(&top_dirs.mzr_dir, state, &zone_name)?;
+                Response::Success
+            }
+            Request::RevertZone(zone_name, rel_paths) => {
+                match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                    None => Response::Error(String::from("Zone does not exist")),
+                    Some(zone) => {
+                        match release_zone_for_changes(
+                            &top_dirs.mzr_dir,
+                            state,
+                            &zone_name,
+                            "reverted",
+                        )? {
+                            Some(message) => Response::Error(message),
+                            None => {
+                                compaction::restore(&zone)?;
+                                Response::Paths(zone.revert(&rel_paths)?)
+                            }
+                        }
+                    }
+                }
+            }
+            Request::CopyIntoZone {
+                source,
+                dest,
+                rel_paths,
+            } => {
+                let mzr_dir = &top_dirs.mzr_dir;
+                match (
+                    Zone::load_if_exists(mzr_dir, &source)?,
+                    Zone::load_if_exists(mzr_dir, &dest)?,
+                ) {
+                    (Some(source_zone), Some(dest_zone)) => {
+                        match release_zone_for_changes(mzr_dir, state, &dest, "copied into")? {
+                            Some(message) => Response::Error(message),
+                            None => {
+                                let result: Result<usize, Error> = try {
+                                    compaction::restore(&source_zone)?;
+                                    compaction::restore(&dest_zone)?;
+                                    let mut count = 0;
+                                    for rel_path in rel_paths.iter() {
+                                        count +=
+                                            zone_copy::copy(&source_zone, &dest_zone, rel_path)?;
+                                    }
+                                    count
+                                };
+                                match result {
+                                    Ok(count) => Response::Copied(count),
+                                    Err(err) => Response::Error(err.to_string()),
+                                }
+                            }
+                        }
+                    }
+                    _ => Response::Error(String::from("Zone does not exist")),
+                }
+            }
+            Request::CheckpointZones => {
+                let mut zone_names: Vec<ZoneName> = state.mounted_zones.iter().cloned().collect();
+                zone_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                for zone_name in zone_names {
+                    let result: Result<(), Error> = try {
+                        let zone = Zone::load(&top_dirs.mzr_dir, &zone_name)?;
+                        checkpoint_zone(&top_dirs.mzr_dir, state, &zone)?
+                    };
+                    if let Err(err) = result {
+                        println!("Failed to checkpoint zone {}: {}", zone_name, err);
+                    }
+                }
+                Response::Success
+            }
+            Request::RestoreCheckpoint(zone_name, checkpoint) => {
+                match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                    None => Response::Error(String::from("Zone does not exist")),
+                    Some(zone) => {
+                        match release_zone_for_changes(
+                            &top_dirs.mzr_dir,
+                            state,
+                            &zone_name,
+                            "restored",
+                        )? {
+                            Some(message) => Response::Error(message),
+                            None => match compaction::restore(&zone)
+                                .and_then(|()| checkpoints::restore(&zone, &checkpoint))
+                            {
+                                Ok(previous) => Response::Checkpoint(previous),
+                                Err(err) => Response::Error(err.to_string()),
+                            },
+                        }
+                    }
+                }
+            }
+            Request::RepairZone(zone_name) => {
+                match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                    None => Response::Error(String::from("Zone does not exist")),
+                    Some(zone) => {
+                        let workspace = state
+                            .workspaces
+                            .iter()
+                            .find(|(_, name)| **name == zone_name)
+                            .map(|(target, _)| target.clone());
+                        match workspace {
+                            Some(target) => Response::Error(format!(
+                                "Zone {} is mounted at {:?}, so it can't be repaired until it's \
+                                 unmounted",
+                                zone_name, target
+                            )),
+                            None => {
+                                release_zone(&top_dirs.mzr_dir, state, &zone_name)?;
+                                match zone.repair() {
+                                    Err(err) => Response::Error(err.to_string()),
+                                    Ok(repairs) => {
+                                        match ensure_zone_mounted(top_dirs, state, &zone) {
+                                            Ok(()) => Response::Repairs(repairs),
+                                            Err(err) => Response::Error(format!(
+                                            "Zone {} was repaired, but still failed to mount: {}",
+                                            zone_name, err
+                                        )),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Request::RegisterShell(zone_name) => {
+                let pid = client_pid(stream)?;
+                match process_start_time(pid)? {
+                    None => Response::Error(String::from("Client process isn't running")),
+                    Some(start_time) => {
+                        let shell = ShellInfo {
+                            pid: pid_t::from(pid),
+                            zone_name,
+                            registered: Utc::now(),
+                            start_time,
+                        };
+                        state.shells.insert(shell.pid, shell);
+                        Response::Success
+                    }
+                }
+            }
+            Request::DeregisterShell => {
+                state.shells.remove(&pid_t::from(client_pid(stream)?));
+                Response::Success
+            }
+            Request::ListShells => {
+                remove_exited_shells(state)?;
+                let mut shells: Vec<ShellInfo> = state.shells.values().cloned().collect();
+                shells.sort_by_key(|shell| (shell.zone_name.to_string(), shell.registered));
+                Response::Shells(shells)
+            }
+            Request::ZoneOfClient => {
+                remove_exited_shells(state)?;
+                let mut ancestor = Some(client_pid(stream)?);
+                let mut zone_name = None;
+                while let Some(pid) = ancestor {
+                    if let Some(shell) = state.shells.get(&pid_t::from(pid)) {
+                        zone_name = Some(shell.zone_name.clone());
+                        break;
+                    }
+                    ancestor = match process_parent(pid)? {
+                        Some(parent) if pid_t::from(parent) > 0 => Some(parent),
+                        _ => None,
+                    };
+                }
+                Response::Zone(zone_name)
+            }
+            Request::FreezeZone(zone_name) => match state.processes.get(&zone_name) {
+                None => Response::Error(format!("Zone {} has no running processes", zone_name)),
+                Some(_) if freezer::is_frozen(&top_dirs.mzr_dir, &zone_name) => {
+                    Response::Error(format!("Zone {} is already frozen", zone_name))
+                }
+                Some(process) => {
+                    let zone_pid = process.pid.to_pid();
+                    let pids = namespaces::pid_namespace_members(zone_pid)?;
+                    if pids.is_empty() {
+                        Response::Error(format!("Zone {} has no running processes", zone_name))
+                    } else {
+                        let frozen =
+                            freezer::freeze(&top_dirs.mzr_dir, &zone_name, zone_pid, &pids)?;
+                        println!("Froze zone {} {}", zone_name, frozen);
+                        Response::Success
+                    }
+                }
+            },
+            Request::ThawZone(zone_name) => match freezer::thaw(&top_dirs.mzr_dir, &zone_name)? {
+                None => Response::Error(format!("Zone {} isn't frozen", zone_name)),
+                Some(frozen) => {
+                    println!("Thawed zone {}, which was frozen {}", zone_name, frozen);
+                    Response::Success
+                }
+            },
+            Request::ShutdownIfIdle(timeout_secs) => {
+                let idle_time = state
+                    .last_activity
+                    .map_or(time::Duration::from_secs(0), |instant| instant.elapsed());
+                if state.processes.is_empty()
+                    && state.workspaces.is_empty()
+                    && idle_time >= time::Duration::from_secs(timeout_secs)
+                {
+                    println!(
+                        "No zone processes or mounts, and idle for {} seconds, so exiting.",
+                        idle_time.as_secs()
+                    );
+                    state.shutting_down = true;
+                }
+                Response::Success
+            }
+            Request::Ping => Response::Health(daemon_health(state)),
+            Request::DaemonInfo => {
+                remove_exited_shells(state)?;
+                let mut mounted_zones: Vec<ZoneName> =
+                    state.mounted_zones.iter().cloned().collect();
+                mounted_zones.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                let mut running_zones: Vec<ZoneName> = state.processes.keys().cloned().collect();
+                running_zones.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                let mut workspaces: Vec<(PathBuf, ZoneName)> = state
+                    .workspaces
+                    .iter()
+                    .map(|(target, zone_name)| (target.clone(), zone_name.clone()))
+                    .collect();
+                workspaces.sort_by(|a, b| a.0.cmp(&b.0));
+                Response::Info(DaemonInfo {
+                    health: daemon_health(state),
+                    mzr_dir: top_dirs.mzr_dir.to_path_buf(),
+                    work_dir: top_dirs.user_work_dir.to_path_buf(),
+                    mounted_zones,
+                    running_zones,
+                    workspaces,
+                    shells: state.shells.len(),
+                })
+            }
+            Request::ListZones => {
+                remove_exited_shells(state)?;
+                let mzr_dir = &top_dirs.mzr_dir;
+                let mut zone_names = Zone::list_names(mzr_dir)?;
+                zone_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                let mut zones = Vec::new();
+                for zone_name in zone_names {
+                    let zone = Zone::load(mzr_dir, &zone_name)?;
+                    zones.push(ZoneStatus {
+                        changed_paths: conflicts::changed_paths(&zone)
+                            .ok()
+                            .map(|paths| paths.len()),
+                        stats: ZoneStats::get(&zone).ok(),
+                        running: state.processes.contains_key(&zone_name),
+                        frozen: freezer::is_frozen(mzr_dir, &zone_name),
+                        mounted_at: state
+                            .workspaces
+                            .iter()
+                            .filter(|(_, name)| **name == zone_name)
+                            .map(|(target, _)| target.clone())
+                            .collect(),
+                        shells: state
+                            .shells
+                            .values()
+                            .filter(|shell| shell.zone_name == zone_name)
+                            .map(|shell| shell.pid)
+                            .collect(),
+                        info: zone.info,
+                        name: zone_name,
+                    });
+                }
+                Response::Zones(zones)
+            }
+            Request::ListSnapshots => {
+                let mzr_dir = &top_dirs.mzr_dir;
+                let mut zones_of_snapshots: HashMap<String, Vec<ZoneName>> = HashMap::new();
+                for zone_name in Zone::list_names(mzr_dir)? {
+                    let zone = Zone::load(mzr_dir, &zone_name)?;
+                    zones_of_snapshots
+                        .entry(zone.info.snapshot.as_str().to_string())
+                        .or_insert_with(Vec::new)
+                        .push(zone_name);
+                }
+                let mut snap_names = snapshot::list_names(mzr_dir)?;
+                snap_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                let mut snapshots = Vec::new();
+                for snap_name in snap_names {
+                    let mut zones = zones_of_snapshots
+                        .remove(snap_name.as_str())
+                        .unwrap_or_else(Vec::new);
+                    zones.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                    snapshots.push(SnapshotStatus {
+                        info: SnapInfo::load(mzr_dir, &snap_name)?,
+                        mounted: zones
+                            .iter()
+                            .any(|zone_name| state.mounted_zones.contains(zone_name)),
+                        zones,
+                        name: snap_name,
+                    });
+                }
+                Response::Snapshots(snapshots)
+            }
+            Request::ZoneUsage(zone_name) => {
+                remove_exited_shells(state)?;
+                Response::Usage(ZoneUsage {
+                    shells: state
+                        .shells
+                        .values()
+                        .filter(|shell| shell.zone_name == zone_name)
+                        .map(|shell| shell.pid)
+                        .collect(),
+                    process: state
+                        .processes
+                        .get(&zone_name)
+                        .map(|process| process.pid.clone()),
+                    mounted_at: state
+                        .workspaces
+                        .iter()
+                        .filter(|(_, name)| **name == zone_name)
+                        .map(|(target, _)| target.clone())
+                        .collect(),
+                })
+            }
+            Request::Merge {
+                zone_name,
+                target_dir,
+                paths,
+                excludes,
+                dry_run,
+                force,
+            } => match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                None => Response::Error(String::from("Zone does not exist")),
+                Some(zone) => {
+                    let plan =
+                        merge_zone(top_dirs, &zone, target_dir, paths, excludes, dry_run, force)?;
+                    if !dry_run {
+                        update_metrics(state, |metrics| metrics.merge_operations += 1)?;
+                        state.subscribers.notify(&Notification::Merged {
+                            zone: zone_name,
+                            summary: plan.summary(),
+                        });
+                    }
+                    Response::Merged {
+                        summary: plan.summary(),
+                        report: serde_json::to_value(plan.report(&zone.ovfs_changes_dir))?,
+                    }
+                }
+            },
+            Request::SyncJournal(zone_name) => match state.journals.get(&zone_name) {
+                None => Response::Error(format!("Zone {} has no change journal", zone_name)),
+                Some(journal_sync) => {
+                    let (reply_sender, reply_receiver) = channel();
+                    let synced = journal_sync.send(reply_sender).is_ok()
+                        && reply_receiver
+                            .recv_timeout(time::Duration::from_secs(5))
+                            .is_ok();
+                    if synced {
+                        Response::Success
+                    } else {
+                        // The journaling thread has stopped.
+                        state.journals.remove(&zone_name);
+                        Response::Error(format!("Zone {} has no change journal", zone_name))
+                    }
+                }
             },
         }
     };
-    send_response(
-        &stream,
-        &match result {
-            Ok(x) => x,
-            Err(e) => Response::Error(format!("Unexpected error: {}", e)),
-        },
-    )
+    let response = match result {
+        Ok(x) => x,
+        Err(e) => Response::Error(format!("Unexpected error: {}", e)),
+    };
+    let is_error = match response {
+        Response::Error(_) => true,
+        _ => false,
+    };
+    update_metrics(state, |metrics| {
+        metrics.requests_served += 1;
+        if is_error {
+            metrics.errors += 1;
+        }
+    })?;
+    Ok(response)
 }
 
-const READY_MSG: &[u8; 6] = b"ready\n";
-
-fn fork_zone_process(
-    work_dir: &UserWorkDir,
+/// Handles a JSON-RPC request by translating it to the corresponding
+/// `Request`, see `rpc::RpcRequest`. Also yields the params of `subscribe`,
+/// since the subscription can only be added once the response is sent.
+fn handle_rpc(
+    top_dirs: &TopDirs,
     user: Uid,
     group: Gid,
-    zone: &Zone,
-) -> Result<ZonePid, Error> {
-    // TODO(cleanup): mzr now has a few different takes on IPC, should
-    // use a consistent style.
-    let (server_stream, mut client_stream) = UnixStream::pair()?;
-    let pid = namespaces::with_unshared_user_and_mount(
-        |child_process| namespaces::map_root_to_user(child_process, user, group),
-        || {
-            // TODO(cleanup): When the parent process exits, it should
-            // close the pipe, which should cause the read to
-            // exit. However, for some reason that didn't work. Setting
-            // PDEATHSIG seems to work, though. It would be nicer to avoid
-            // this, though.
-            unsafe {
-                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) != 0 {
-                    bail!("Failed to set PDEATHSIG");
-                }
-            }
-            // Bind mount zone over the user's work-dir.
-            zone.bind_to(work_dir)?;
-            // Indicate to parent process that the zone is ready.
-            client_stream.write_all(READY_MSG)?;
-            let mut data = Vec::new();
-            // This should just block forever, since server_stream never
-            // gets written to.
-            let result = client_stream.read_to_end(&mut data);
-            println!(
-                "mzr zone process unexpectedly done blocking, result was {:?}",
-                result
-            );
-            Ok(())
-        },
-    )?;
-    let mut data = Vec::new();
-    let mut reader = BufReader::new(server_stream);
-    reader.read_until(b'\n', &mut data)?;
-    if data != READY_MSG {
-        Err(format_err!(
-            "Didn't receive expected message from child process. Instead got {:?}",
-            data
-        ))
+    stream: &UnixStream,
+    state: &mut DaemonState,
+    line: &[u8],
+) -> (RpcResponse, Option<rpc::SubscribeParams>) {
+    let rpc_request: RpcRequest = match serde_json::from_slice(line) {
+        Ok(rpc_request) => rpc_request,
+        Err(err) => {
+            let error = RpcError::new(rpc::INVALID_REQUEST, format!("Invalid request: {}", err));
+            return (RpcResponse::new(Value::Null, Err(error)), None);
+        }
+    };
+    println!("==> {:?}", rpc_request);
+    let mut subscription = None;
+    let result = if rpc_request.jsonrpc == "2.0" && rpc_request.method == "subscribe" {
+        rpc::parse_params(&rpc_request.params).map(|params| {
+            subscription = Some(params);
+            Value::Null
+        })
     } else {
-        println!("Zone process forked for zone named \"{}\"", zone.name);
-        Ok(ZonePid::from_pid(pid))
-    }
+        match rpc_method_request(&rpc_request) {
+            Err(error) => Err(error),
+            Ok(request) => match handle_request(top_dirs, user, group, stream, state, request) {
+                Ok(response) => rpc_result(response),
+                Err(err) => Err(RpcError::new(rpc::FAILED, err.to_string())),
+            },
+        }
+    };
+    (RpcResponse::new(rpc_request.id, result), subscription)
 }
 
-/*
- * Functions for daemon receiving requests and sending responses.
- */
-
-fn recv_request(stream: &UnixStream) -> Result<Request, Error> {
-    let mut data = Vec::new();
-    let mut reader = BufReader::new(stream);
-    reader.read_until(b'\n', &mut data)?;
-    let request: Request = serde_json::from_slice(&data)?;
-    println!("==> {:?}", request);
+fn rpc_method_request(rpc_request: &RpcRequest) -> Result<Request, RpcError> {
+    if rpc_request.jsonrpc != "2.0" {
+        return Err(RpcError::new(
+            rpc::INVALID_REQUEST,
+            String::from("Only JSON-RPC 2.0 is supported"),
+        ));
+    }
+    let params = &rpc_request.params;
+    let request = match rpc_request.method.as_str() {
+        "ping" => Request::Ping,
+        "zones.list" => Request::ListZones,
+        "snapshots.list" => Request::ListSnapshots,
+        "daemon.info" => Request::DaemonInfo,
+        "zones.mount" => {
+            let params: rpc::MountParams = rpc::parse_params(params)?;
+            require_absolute(&params.target)?;
+            Request::BindZone(params.zone, params.target)
+        }
+        "zones.unmount" => {
+            let params: rpc::UnmountParams = rpc::parse_params(params)?;
+            require_absolute(&params.target)?;
+            Request::UnbindZone(params.target)
+        }
+        "zones.stop" => Request::StopZone(rpc::parse_params::<rpc::ZoneParams>(params)?.zone),
+        "zones.remove" => Request::RemoveZone(rpc::parse_params::<rpc::ZoneParams>(params)?.zone),
+        "zones.merge" => {
+            let params: rpc::MergeParams = rpc::parse_params(params)?;
+            if let Some(target_dir) = &params.target_dir {
+                require_absolute(target_dir)?;
+            }
+            Request::Merge {
+                zone_name: params.zone,
+                target_dir: params.target_dir,
+                paths: params.paths,
+                excludes: params.excludes,
+                dry_run: params.dry_run,
+                force: params.force,
+            }
+        }
+        "shells.list" => Request::ListShells,
+        "metrics" => Request::Metrics,
+        method => {
+            return Err(RpcError::new(
+                rpc::METHOD_NOT_FOUND,
+                format!("Method not found: {}", method),
+            ))
+        }
+    };
     Ok(request)
 }
 
-fn send_response(stream: &UnixStream, response: &Response) -> Result<(), Error> {
-    serde_json::to_writer(stream, &response)?;
-    println!("<== {:?}", response);
-    Ok(())
+/// Paths are resolved by the daemon, so relative ones would be relative to
+/// its working directory rather than the client's.
+fn require_absolute(path: &PathBuf) -> Result<(), RpcError> {
+    if path.is_absolute() {
+        Ok(())
+    } else {
+        Err(RpcError::new(
+            rpc::INVALID_PARAMS,
+            format!("Expected an absolute path, but got {:?}", path),
+        ))
+    }
 }
 
-/*
- * Functions for client sending requests and receiving responses.
- */
-
-fn send_request(mut stream: &UnixStream, request: &Request) -> Result<(), Error> {
-    serde_json::to_writer(stream, request)?;
-    stream.write_all(b"\n")?;
-    Ok(())
+fn rpc_result(response: Response) -> Result<Value, RpcError> {
+    let result = match response {
+        Response::Success => Ok(Value::Null),
+        Response::Error(message) => return Err(RpcError::new(rpc::FAILED, message)),
+        Response::Health(health) => serde_json::to_value(health),
+        Response::Zones(zones) => serde_json::to_value(zones),
+        Response::Snapshots(snapshots) => serde_json::to_value(snapshots),
+        Response::Info(info) => serde_json::to_value(info),
+        Response::Merged { summary, report } => serde_json::to_value(summary).map(|summary| {
+            let mut result = serde_json::Map::new();
+            result.insert(String::from("summary"), summary);
+            result.insert(String::from("report"), report);
+            Value::Object(result)
+        }),
+        Response::Shells(shells) => serde_json::to_value(shells),
+        Response::Metrics(report) => serde_json::to_value(report),
+        other => {
+            return Err(RpcError::new(
+                rpc::FAILED,
+                format!("Unexpected response: {:?}", other),
+            ))
+        }
+    };
+    result.map_err(|err| RpcError::new(rpc::FAILED, err.to_string()))
 }
 
-fn recv_response(stream: &UnixStream) -> Result<Response, Error> {
-    Ok(serde_json::from_reader(stream)?)
+/// Merges a zone on behalf of a client, running the merge hooks and
+/// recording the event like `mzr merge` does. The zone's git directory is
+/// left out, since it's shared with the work dir. Like `mzr merge`, this
+/// refuses to modify paths with uncommitted changes unless forced.
+fn merge_zone(
+    top_dirs: &TopDirs,
+    zone: &Zone,
+    target_dir: Option<PathBuf>,
+    paths: Vec<PathBuf>,
+    excludes: Vec<String>,
+    dry_run: bool,
+    force: bool,
+) -> Result<Plan, Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    let target_dir = target_dir.unwrap_or_else(|| top_dirs.user_work_dir.to_path_buf());
+    let excluded_dirs: Vec<PathBuf> = git::get_git_dir(&top_dirs.user_work_dir)
+        .into_iter()
+        .map(|rel_git_dir| rel_git_dir.to_path_buf())
+        .collect();
+    let filter = PathFilter {
+        prefixes: paths,
+        excludes,
+    };
+    let target_dir_string = target_dir.to_string_lossy().into_owned();
+    let hook_vars = [
+        ("MZR_HOOK_ZONE", zone.name.as_str()),
+        ("MZR_HOOK_TARGET_DIR", target_dir_string.as_str()),
+    ];
+    if !dry_run {
+        hooks::run(mzr_dir, Hook::PreMerge, &hook_vars)?;
+    }
+    // Compacted zones aren't mounted, so their files can be restored here.
+    compaction::restore(zone)?;
+    let plan = merge::plan(zone, &target_dir, &excluded_dirs, &filter)?;
+    let warning = merge::check_uncommitted(
+        &target_dir,
+        &excluded_dirs,
+        &plan,
+        force || dry_run,
+        "set the force parameter",
+    )?;
+    if let Some(message) = warning {
+        println!("Warning: {}", message);
+    }
+    if !dry_run {
+        merge_txn::apply(mzr_dir, zone, &plan, &target_dir)?;
+        events::record(
+            mzr_dir,
+            EventKind::Merged {
+                zone: zone.name.clone(),
+                target_dir: target_dir.clone(),
+                summary: plan.summary(),
+            },
+        );
+        hooks::run_or_warn(mzr_dir, Hook::PostMerge, &hook_vars);
+    }
+    Ok(plan)
 }
 
-fn connect_to_daemon(mzr_dir: &MzrDir) -> Result<UnixStream, Error> {
-    let daemon_dir = DaemonDir::new(&mzr_dir);
-    let socket_path = DaemonSocketFile::new(&daemon_dir);
-    if !socket_path.exists() {
-        bail!(
-            "Failed to connect to {}, because {} does not exist.",
-            color_cmd(&String::from("mzr daemon")),
-            socket_path
-        );
+/// Zones which shouldn't be removed. The zones of finished `mzr run`
+/// invocations stay mounted, but aren't considered to be in use, since they
+/// aren't entered after the run.
+fn zones_in_use(mzr_dir: &MzrDir, state: &DaemonState) -> Result<HashSet<ZoneName>, Error> {
+    let mut zones: HashSet<ZoneName> = state.workspaces.values().cloned().collect();
+    zones.extend(state.shells.values().map(|shell| shell.zone_name.clone()));
+    for zone_name in state.mounted_zones.iter().chain(state.processes.keys()) {
+        if RunInfo::load(mzr_dir, zone_name)?.is_none() {
+            zones.insert(zone_name.clone());
+        }
     }
-    Ok(UnixStream::connect(socket_path).context(format_err!(
-        "Failed to connect to {}. Is it running?",
-        color_cmd(&String::from("mzr daemon"))
-    ))?)
+    Ok(zones)
 }
 
-fn run_daemon_command(mzr_dir: &MzrDir, request: &Request) -> Result<Response, Error> {
-    let stream = connect_to_daemon(mzr_dir)?;
-    send_request(&stream, request)?;
-    recv_response(&stream)
+/// Stops the zone's process and unmounts its overlayfs, so that the zone can
+/// be removed.
+fn release_zone(
+    mzr_dir: &MzrDir,
+    state: &mut DaemonState,
+    zone_name: &ZoneName,
+) -> Result<(), Error> {
+    // Frozen processes wouldn't respond to being asked to terminate.
+    if let Some(frozen) = freezer::thaw(mzr_dir, zone_name)? {
+        println!("Thawed zone {}, which was frozen {}", zone_name, frozen);
+    }
+    if let Some(process) = state.processes.remove(zone_name) {
+        stop_zone_process(&process)?;
+        processes_changed(mzr_dir, state)?;
+    }
+    // Dropping the channel stops the journaling thread.
+    state.journals.remove(zone_name);
+    if state.mounted_zones.remove(zone_name) {
+        let zone = Zone::load(mzr_dir, zone_name)?;
+        if let Some(exposer) = &state.exposer {
+            let exposed_dir = ExposedZoneDir::new(mzr_dir, zone_name);
+            unbind_outside(exposer, &exposed_dir)
+                .context(format_err!("Failed to unexpose zone {}", zone_name))?;
+        }
+        umount(zone.ovfs_mount_dir.as_path())
+            .context(format_err!("Failed to unmount zone {}", zone_name))?;
+        state.subscribers.notify(&Notification::ZoneUnmounted {
+            zone: zone_name.clone(),
+        });
+    }
+    Ok(())
 }
 
-pub fn get_zone_process(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<ZonePid, Error> {
-    let request = Request::ZoneProcess(zone_name.clone());
-    // TODO(hack): Sending the request twice is an ugly hack. For some
-    // reason, on initial forking of the daemon's zone process, the
-    // response never makes it back to the client. I suspect this is
-    // related to the client process getting control of the the
-    // stream, but it seems like FD_CLOEXEC is being set in the
-    // code.
-    //
-    // The workaround here is to ask twice, and use the response from
-    // the 2nd request, since that will just be a lookup in the
-    // daemon's cache.
-    let stream = connect_to_daemon(mzr_dir)?;
-    send_request(&stream, &request)?;
-    // Make the request again to actually get the process.
-    match run_daemon_command(mzr_dir, &request)? {
-        Response::ZoneProcess(p) => Ok(p),
-        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+/// Stops and unmounts the zone along with the zones layered on it, which
+/// have its changes dir as a lower dir, so that its changes dir can be
+/// modified. Yields an error message instead if any of them is mounted via
+/// `Request::BindZone`, since unmounting it would break whatever is using it.
+fn release_zone_for_changes(
+    mzr_dir: &MzrDir,
+    state: &mut DaemonState,
+    zone_name: &ZoneName,
+    action: &str,
+) -> Result<Option<String>, Error> {
+    let mut affected = vec![zone_name.clone()];
+    let mut ix = 0;
+    while ix < affected.len() {
+        let layered = Zone::list_layered_on(mzr_dir, &affected[ix])?;
+        affected.extend(layered);
+        ix += 1;
     }
+    let workspace = state
+        .workspaces
+        .iter()
+        .find(|(_, name)| affected.contains(name))
+        .map(|(target, name)| (target.clone(), name.clone()));
+    if let Some((target, name)) = workspace {
+        return Ok(Some(format!(
+            "Zone {} is mounted at {:?}, so zone {} can't be {} until it's unmounted",
+            name, target, zone_name, action
+        )));
+    }
+    for name in affected.iter() {
+        release_zone(mzr_dir, state, name)?;
+    }
+    Ok(None)
 }
 
-/*
- * Functions for entering zone process namespaces.
- */
+/// How long to wait for a zone process to exit after asking it to terminate,
+/// which includes the time it gives processes within the zone to exit.
+const ZONE_PROCESS_STOP_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 
-pub fn enter_zone_process_mount(zone_pid: &ZonePid) -> Result<(), Error> {
-    namespaces::enter_mount(zone_pid.to_pid())
+/// Stops a zone process. It forwards the termination signal to the processes
+/// within the zone, and when it exits, any which remain are killed, since it
+/// is the init of their PID namespace.
+fn stop_zone_process(process: &ZoneProcess) -> Result<(), Error> {
+    let pid = process.pid.to_pid();
+    kill(pid, Signal::SIGTERM)?;
+    let deadline = time::Instant::now() + ZONE_PROCESS_STOP_TIMEOUT;
+    while time::Instant::now() < deadline {
+        if process.has_exited()? {
+            return Ok(());
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    println!(
+        "Zone process {} didn't exit after being asked to, so killing it.",
+        process.pid
+    );
+    kill(pid, Signal::SIGKILL)?;
+    if !process.adopted {
+        waitpid(pid, Some(WaitPidFlag::__WALL))?;
+    }
+    Ok(())
 }
 
-pub fn enter_zone_process_user_and_mount(zone_pid: &ZonePid) -> Result<(), Error> {
+/// Yields an error response for requests which can't be made via the remote
+/// listener, since they rely on the client being a local process, and the
+/// daemon sees forwarded requests as coming from itself.
+fn reject_remote_request(message: &Message) -> Option<Vec<u8>> {
+    if let Message::Line(line) = message {
+        if rpc::is_rpc_request(line) {
+            return None;
+        }
+    }
+    match parse_request(message) {
+        Ok(Request::ZoneProcess(_))
+        | Ok(Request::RegisterShell(_))
+        | Ok(Request::DeregisterShell)
+        | Ok(Request::ZoneOfClient) => {
+            let response = Response::Error(String::from(
+                "This request can only be made by clients on the same machine as the daemon.",
+            ));
+            match message.response_version() {
+                Some(version) => ipc::encode_version(version, &response).ok(),
+                None => serde_json::to_vec(&response).ok(),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Pid of the process on the other end of a client connection, as seen from
+/// the daemon's PID namespace.
+fn client_pid(stream: &UnixStream) -> Result<Pid, Error> {
+    let credentials = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+    Ok(Pid::from_raw(credentials.pid()))
+}
+
+fn daemon_health(state: &DaemonState) -> DaemonHealth {
+    DaemonHealth {
+        version: version::VERSION.to_string(),
+        pid: process::id(),
+        uptime_secs: state
+            .started
+            .map_or(0, |instant| instant.elapsed().as_secs()),
+        zone_processes: state.processes.len(),
+    }
+}
+
+/// Forgets shells whose mzr process has exited without deregistering.
+fn remove_exited_shells(state: &mut DaemonState) -> Result<(), Error> {
+    let mut exited = Vec::new();
+    for shell in state.shells.values() {
+        if process_start_time(Pid::from_raw(shell.pid))? != Some(shell.start_time) {
+            exited.push(shell.pid);
+        }
+    }
+    for pid in exited {
+        state.shells.remove(&pid);
+    }
+    Ok(())
+}
+
+/// Records the zone processes, and updates the metric for their count. Called
+/// whenever they change.
+fn processes_changed(mzr_dir: &MzrDir, state: &DaemonState) -> Result<(), Error> {
+    json::write(
+        &DaemonProcessesFile::new(&DaemonDir::new(mzr_dir)),
+        &state.processes,
+    )?;
+    let process_count = state.processes.len() as u64;
+    update_metrics(state, |metrics| {
+        metrics.active_zone_processes = process_count
+    })
+}
+
+/// Handles the zone processes recorded by a previous daemon, which keep
+/// running if it exits unexpectedly. Processes for zones which still exist
+/// are adopted, so that they continue to be used for entering the zone,
+/// rather than a duplicate being started. The zone's overlayfs remains
+/// mounted within the zone process's mount namespace. Processes for zones
+/// which have since been removed are killed.
+fn restore_processes(mzr_dir: &MzrDir, state: &mut DaemonState) -> Result<(), Error> {
+    let processes_file = DaemonProcessesFile::new(&DaemonDir::new(mzr_dir));
+    if !processes_file.exists() {
+        return Ok(());
+    }
+    let recorded: ProcessMap = match json::read(&processes_file) {
+        Ok(file) => file.contents,
+        Err(err) => {
+            println!(
+                "Warning: ignoring unreadable zone process records in {}: {}",
+                processes_file, err
+            );
+            ProcessMap::new()
+        }
+    };
+    for (zone_name, mut process) in recorded {
+        // If the start time differs, the pid has been reused.
+        if process_start_time(process.pid.to_pid())? != Some(process.start_time) {
+            continue;
+        }
+        process.adopted = true;
+        if Zone::load_if_exists(mzr_dir, &zone_name)?.is_some() {
+            println!(
+                "Adopting zone process {} for zone {}, which was started by a previous daemon.",
+                process.pid, zone_name
+            );
+            state.processes.insert(zone_name, process);
+        } else {
+            println!(
+                "Stopping stray zone process {} for zone {}, which no longer exists.",
+                process.pid, zone_name
+            );
+            stop_zone_process(&process)?;
+        }
+    }
+    processes_changed(mzr_dir, state)
+}
+
+/// Start time of a process, in clock ticks after boot, or `None` if it isn't
+/// running. Zombie processes aren't considered to be running.
+fn process_start_time(pid: Pid) -> Result<Option<u64>, Error> {
+    // The start time is field 22.
+    proc_stat_field(pid, 22)
+}
+
+/// Parent of a process, or `None` if it isn't running.
+fn process_parent(pid: Pid) -> Result<Option<Pid>, Error> {
+    // The parent's pid is field 4.
+    Ok(proc_stat_field(pid, 4)?.map(Pid::from_raw))
+}
+
+/// Parses a numbered field of `/proc/PID/stat`, as documented in `proc(5)`,
+/// yielding `None` if the process isn't running.
+fn proc_stat_field<T: FromStr>(pid: Pid, field: usize) -> Result<Option<T>, Error> {
+    let stat_file = ProcDir::new(pid).join("stat");
+    let stat = match read_to_string(&stat_file) {
+        Ok(stat) => stat,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => Err(err)?,
+    };
+    // The command name is field 2, which is in parentheses and may contain
+    // spaces, so the fields after it are counted from its closing
+    // parenthesis. The state is field 3.
+    let fields: Vec<&str> = match stat.rfind(')') {
+        Some(ix) => stat[ix + 1..].split_whitespace().collect(),
+        None => bail!("Unexpected contents of {:?}: {:?}", stat_file, stat),
+    };
+    match (
+        fields.get(0),
+        fields.get(field - 3).and_then(|value| value.parse().ok()),
+    ) {
+        (Some(&"Z"), _) | (Some(&"X"), _) => Ok(None),
+        (Some(_), Some(value)) => Ok(Some(value)),
+        _ => bail!("Unexpected contents of {:?}: {:?}", stat_file, stat),
+    }
+}
+
+/// Periodically applies the retention policy, by sending requests to the
+/// daemon so that they are handled along with other requests.
+fn run_auto_gc_timer(mzr_dir: &MzrDir, interval: time::Duration) {
+    loop {
+        thread::sleep(interval);
+        let result: Result<Response, Error> = try {
+            let policy = RetentionPolicy::load(mzr_dir)?;
+            run_daemon_command(mzr_dir, &Request::ApplyRetention(policy, false))?
+        };
+        match result {
+            Ok(Response::Removals(_)) => {}
+            Ok(other) => println!(
+                "Unexpected response while applying retention policy: {:?}",
+                other
+            ),
+            Err(err) => println!("Error while applying retention policy: {}", err),
+        }
+    }
+}
+
+/// Periodically asks the daemon to exit if it's idle, by sending requests to
+/// the daemon so that they are handled along with other requests.
+fn run_idle_timer(mzr_dir: &MzrDir, timeout: time::Duration) {
+    let check_interval = timeout.min(MAX_IDLE_CHECK_INTERVAL);
+    loop {
+        thread::sleep(check_interval);
+        let result = run_daemon_command(mzr_dir, &Request::ShutdownIfIdle(timeout.as_secs()));
+        match result {
+            Ok(Response::Success) => {}
+            Ok(other) => println!("Unexpected response while checking idleness: {:?}", other),
+            Err(err) => println!("Error while checking idleness: {}", err),
+        }
+    }
+}
+
+/// Periodically makes checkpoints of the mounted zones, by sending requests
+/// to the daemon so that they are handled along with other requests.
+fn run_checkpoint_timer(mzr_dir: &MzrDir, interval: time::Duration) {
+    loop {
+        thread::sleep(interval);
+        match run_daemon_command(mzr_dir, &Request::CheckpointZones) {
+            Ok(Response::Success) => {}
+            Ok(other) => println!("Unexpected response while checkpointing: {:?}", other),
+            Err(err) => println!("Error while checkpointing: {}", err),
+        }
+    }
+}
+
+/// Makes a checkpoint of the zone, freezing its processes while its changes
+/// are copied, and removes its oldest checkpoints beyond the number kept.
+fn checkpoint_zone(mzr_dir: &MzrDir, state: &DaemonState, zone: &Zone) -> Result<(), Error> {
+    // Zones which were frozen by the user stay frozen.
+    let froze = match state.processes.get(&zone.name) {
+        Some(process) if !freezer::is_frozen(mzr_dir, &zone.name) => {
+            let zone_pid = process.pid.to_pid();
+            let pids = namespaces::pid_namespace_members(zone_pid)?;
+            if pids.is_empty() {
+                false
+            } else {
+                freezer::freeze(mzr_dir, &zone.name, zone_pid, &pids)?;
+                true
+            }
+        }
+        _ => false,
+    };
+    let result = checkpoints::create(zone);
+    if froze {
+        freezer::thaw(mzr_dir, &zone.name)?;
+    }
+    println!("Made checkpoint {} of zone {}", result?, zone.name);
+    for name in checkpoints::prune(zone, checkpoints::KEPT_CHECKPOINTS)? {
+        println!("Removed checkpoint {} of zone {}", name, zone.name);
+    }
+    Ok(())
+}
+
+fn update_metrics<T, F>(state: &DaemonState, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut metrics::DaemonMetrics) -> T,
+{
+    let mut metrics = state
+        .metrics
+        .lock()
+        .map_err(|_| format_err!("Metrics lock was poisoned."))?;
+    Ok(f(&mut metrics))
+}
+
+/// Mounts the zone's overlayfs in the daemon's namespace, if it hasn't
+/// already been mounted.
+fn ensure_zone_mounted(
+    top_dirs: &TopDirs,
+    state: &mut DaemonState,
+    zone: &Zone,
+) -> Result<(), Error> {
+    if state.mounted_zones.contains(&zone.name) {
+        return Ok(());
+    }
+    // Zones compare against their snapshot when merging, so it must not
+    // have changed.
+    snapshot_manifest::check_unmodified(&top_dirs.mzr_dir, &zone.info.snapshot)?;
+    // Share each of the git repositories in the snapshot, including nested
+    // repositories and submodules, with the user's work dir.
+    let snap_info = SnapInfo::load(&top_dirs.mzr_dir, &zone.info.snapshot)?;
+    let rel_git_dirs = match snap_info.git_dirs {
+        Some(git_dirs) => git_dirs.into_iter().map(RelativeGitRepoDir::new).collect(),
+        None => find_git_dirs(&zone.snap_dir)?,
+    };
+    for rel_git_dir in rel_git_dirs {
+        let is_submodule = rel_git_dir.file_name() != Some(OsStr::new(".git"));
+        // Submodules aren't yet supported with worktrees, since their git
+        // directories are within the superproject's git directory, which is
+        // replaced by a git file.
+        if zone.info.git_worktree && is_submodule {
+            continue;
+        }
+        if let Some(source_git_dir) = ensure_git_repo_bound(top_dirs, state, &rel_git_dir)? {
+            let target_git_dir = zone.ovfs_changes_dir.join(&rel_git_dir);
+            if zone.info.git_worktree {
+                add_worktree(
+                    &source_git_dir,
+                    &zone.snap_dir.join(&rel_git_dir),
+                    &target_git_dir,
+                    &top_dirs.user_work_dir.join(&rel_git_dir),
+                    &zone.name,
+                )?;
+            } else {
+                symlink_git_repo(&source_git_dir, &target_git_dir)?;
+            }
+        }
+    }
+    // Share the jj repositories in the same way. Snapshots which predate
+    // recording them have complete copies.
+    for rel_jj_dir in snap_info.jj_dirs.unwrap_or_default() {
+        if let Some(source_jj_dir) = ensure_jj_repo_bound(top_dirs, state, &rel_jj_dir)? {
+            jj::symlink_jj_repo(&source_jj_dir, &zone.ovfs_changes_dir.join(&rel_jj_dir))?;
+        }
+    }
+    // Mercurial repositories are shared via its share extension's format, so
+    // that each zone has its own dirstate.
+    for rel_hg_dir in snap_info.hg_dirs.unwrap_or_default() {
+        if let Some(source_hg_dir) = ensure_hg_repo_bound(top_dirs, state, &rel_hg_dir)? {
+            hg::share_hg_repo(
+                &source_hg_dir,
+                &zone.snap_dir.join(&rel_hg_dir),
+                &zone.ovfs_changes_dir.join(&rel_hg_dir),
+            )?;
+        }
+    }
+    // Decompress any compacted files before the changes dir becomes the
+    // overlay's upper dir.
+    compaction::restore(&zone)?;
+    // TODO: Looks like this does not yet propagate to the mount namespaces
+    // of the existing zone processes, but it needs to.
+    zone.mount().map_err(|err| {
+        kind_error(
+            ErrorKind::MountFailed,
+            format!(
+                "{}. If zone {} was left in a bad state, such as by a crash, {} may fix it.",
+                err,
+                zone.name,
+                color_cmd(&format!("mzr zone repair {}", zone.name))
+            ),
+        )
+    })?;
+    state.mounted_zones.insert(zone.name.clone());
+    update_metrics(state, |metrics| metrics.zones_mounted += 1)?;
+    state.subscribers.notify(&Notification::ZoneMounted {
+        zone: zone.name.clone(),
+    });
+    let subscribers = state.subscribers.clone();
+    let zone_name = zone.name.clone();
+    let on_changes = move |count| subscribers.record_changes(&zone_name, count);
+    match journal::start(zone, on_changes) {
+        Ok(journal_sync) => {
+            state.journals.insert(zone.name.clone(), journal_sync);
+        }
+        Err(err) => println!(
+            "Warning: failed to journal changes to zone {}: {}",
+            zone.name, err
+        ),
+    }
+    if let Some(exposer) = &state.exposer {
+        if let Err(err) = expose_zone(exposer, zone) {
+            println!("Warning: failed to expose zone {}: {}", zone.name, err);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Exposing zones outside of the daemon's namespaces
+ */
+
+/// Forks a process which stays in the mount namespace that the daemon is
+/// started from, and binds zones there upon request. Mounting in that
+/// namespace requires privileges over it, so this only succeeds when the
+/// daemon is started by root.
+fn spawn_exposer() -> Result<UnixStream, Error> {
+    let (daemon_stream, exposer_stream) = UnixStream::pair()?;
+    match fork()? {
+        ForkResult::Child => {
+            drop(daemon_stream);
+            match run_exposer(exposer_stream) {
+                Ok(()) => exit(0),
+                Err(err) => {
+                    println!("{} {}", color_err(&"mzr exposer error:"), err);
+                    exit(1)
+                }
+            }
+        }
+        ForkResult::Parent { .. } => Ok(daemon_stream),
+    }
+}
+
+/// Requests from the daemon to the process forked by `spawn_exposer`.
+#[derive(Debug, Serialize, Deserialize)]
+enum ExposerRequest {
+    /// Binds a zone's overlay, as mounted in the mount namespace of the
+    /// daemon with the given pid, at the target.
+    Bind {
+        source: PathBuf,
+        target: PathBuf,
+        daemon_pid: pid_t,
+        readonly: bool,
+    },
+    /// Unmounts the target. This needs to happen before the daemon unmounts
+    /// the zone, since the bind keeps its overlay in use.
+    Unbind(PathBuf),
+}
+
+/// Handles requests from the daemon to bind zones. Each response is either
+/// success or an error message. Once the daemon closes the connection, the
+/// binds are unmounted and it exits.
+fn run_exposer(stream: UnixStream) -> Result<(), Error> {
+    let mut channel = Channel::new(stream);
+    let mut targets: HashSet<PathBuf> = HashSet::new();
+    loop {
+        let request: ExposerRequest = match channel.recv() {
+            Ok(request) => request,
+            Err(ref err) if ipc::is_closed(err) => break,
+            Err(err) => return Err(err),
+        };
+        let result: Result<(), Error> = try {
+            match request {
+                ExposerRequest::Bind {
+                    source,
+                    target,
+                    daemon_pid,
+                    readonly,
+                } => {
+                    // The overlay is only mounted in the daemon's mount
+                    // namespace, and bind mounts can't be made from other
+                    // namespaces, so a detached copy of it is made there and
+                    // attached here.
+                    let detached = namespaces::in_mount_of(Pid::from_raw(daemon_pid), || {
+                        mount::clone_detached(&source)
+                    })?;
+                    mount::attach(&detached, &target)?;
+                    if readonly {
+                        if let Err(err) = mount::remount_readonly(&target) {
+                            // Better to not bind the zone than to bind it
+                            // writable.
+                            umount2(target.as_path(), MntFlags::MNT_DETACH)?;
+                            Err(err)?
+                        }
+                    }
+                    targets.insert(target);
+                }
+                ExposerRequest::Unbind(target) => {
+                    if targets.remove(&target) {
+                        umount2(target.as_path(), MntFlags::MNT_DETACH)?;
+                    }
+                }
+            }
+        };
+        channel.send(&result.map_err(|err| err.to_string()))?;
+    }
+    for target in targets {
+        if let Err(err) = umount2(target.as_path(), MntFlags::MNT_DETACH) {
+            println!("Warning: failed to unmount {:?}: {}", target, err);
+        }
+    }
+    Ok(())
+}
+
+fn send_exposer_request(exposer: &UnixStream, request: &ExposerRequest) -> Result<(), Error> {
+    let mut channel = Channel::new(exposer);
+    channel.send(request)?;
+    match channel.recv::<Result<(), String>>()? {
+        Ok(()) => Ok(()),
+        Err(err) => bail!("{}", err),
+    }
+}
+
+/// Binds the zone's overlay at the target in the namespace the daemon was
+/// started from. Its mount in the daemon's namespace needs to stay mounted
+/// while it's bound, see `unbind_outside`.
+fn bind_outside(
+    exposer: &UnixStream,
+    zone: &Zone,
+    target: &Path,
+    readonly: bool,
+) -> Result<(), Error> {
+    send_exposer_request(
+        exposer,
+        &ExposerRequest::Bind {
+            source: zone.ovfs_mount_dir.to_path_buf(),
+            target: target.to_path_buf(),
+            daemon_pid: pid_t::from(getpid()),
+            readonly,
+        },
+    )
+}
+
+fn unbind_outside(exposer: &UnixStream, target: &Path) -> Result<(), Error> {
+    send_exposer_request(exposer, &ExposerRequest::Unbind(target.to_path_buf()))
+}
+
+fn expose_zone(exposer: &UnixStream, zone: &Zone) -> Result<(), Error> {
+    let exposed_dir = ExposedZoneDir::new(&zone.mzr_dir, &zone.name);
+    create_dir_all(&exposed_dir)?;
+    bind_outside(exposer, zone, &exposed_dir, true)?;
+    println!("Exposed zone {} read-only.", zone.name);
+    Ok(())
+}
+
+fn fork_zone_process(
+    work_dir: &UserWorkDir,
+    user: Uid,
+    group: Gid,
+    zone: &Zone,
+    scratch_dirs: &[PathBuf],
+    home_dirs: &[PathBuf],
+    shared_cache_dirs: &[PathBuf],
+) -> Result<ZonePid, Error> {
+    let home = match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home),
+        None if home_dirs.is_empty() => PathBuf::new(),
+        None => bail!("HOME isn't set, so isolated home dirs can't be bound."),
+    };
+    let (mut server_channel, mut client_channel) = Channel::pair()?;
+    let pid = namespaces::with_unshared_user_mount_and_pid(
+        |child_process| namespaces::map_root_to_user(child_process, user, group),
+        || {
+            // The zone process intentionally outlives the daemon if it exits
+            // unexpectedly, so that processes within the zone aren't killed.
+            // A restarted daemon adopts it, see `restore_processes`.
+            //
+            // It's forked from the daemon, so it starts out with the
+            // daemon's sockets and client connections, which processes in
+            // the zone shouldn't be able to get at.
+            privileges::close_inherited_fds(&[client_channel.get_ref().as_raw_fd()])?;
+            let result: Result<(), Error> = try {
+                // Bind mount zone over the user's work-dir.
+                zone.bind_to(work_dir)?;
+                // Mount tmpfs over scratch dirs, so that writes to them
+                // bypass the zone's changes dir.
+                zone.mount_scratch_dirs(work_dir, scratch_dirs)?;
+                // Bind the zone's own versions of isolated home dirs, such
+                // as caches, over the user's.
+                zone.bind_home_dirs(&home, home_dirs)?;
+                // Bind shared caches, so that zones don't each fill their
+                // own.
+                zone.bind_shared_cache_dirs(work_dir, shared_cache_dirs)?;
+            };
+            // Indicate to the daemon whether the zone is ready, so that it
+            // can report why not.
+            let status = result.as_ref().map(|_| ()).map_err(|err| err.to_string());
+            client_channel.send(&status)?;
+            result?;
+            // Nothing more is set up by the zone process, so it gives up
+            // its connection to the daemon and its capabilities.
+            privileges::close_inherited_fds(&[])?;
+            privileges::restrict(&[])?;
+            // Processes run within the zone are in its PID namespace, so
+            // act as their init until the zone is released.
+            namespaces::run_init()
+        },
+    )?;
+    drop(client_channel);
+    match server_channel.recv::<Result<(), String>>() {
+        Ok(Ok(())) => {
+            println!("Zone process forked for zone named \"{}\"", zone.name);
+            Ok(ZonePid::from_pid(pid))
+        }
+        Ok(Err(err)) => bail!("Zone process for {} failed to start: {}", zone.name, err),
+        Err(err) => bail!(
+            "Zone process for {} exited without indicating that it's ready: {}",
+            zone.name,
+            err
+        ),
+    }
+}
+
+/*
+ * Functions for daemon receiving requests and sending responses.
+ */
+
+fn parse_request(message: &Message) -> Result<Request, Error> {
+    let request: Request = message.decode()?;
+    println!("==> {:?}", request);
+    Ok(request)
+}
+
+/// Sends the response framed with the given protocol version, or for
+/// clients which predate framing, as JSON which is terminated by closing the
+/// connection.
+fn send_response(
+    stream: &UnixStream,
+    response: &Response,
+    version: Option<u16>,
+) -> Result<(), Error> {
+    match version {
+        Some(version) => ipc::write_version(stream, version, response)?,
+        None => serde_json::to_writer(stream, &response)?,
+    }
+    println!("<== {:?}", response);
+    Ok(())
+}
+
+/// Sends a JSON-RPC response, terminated by a newline so that clients can
+/// read it as a line.
+fn send_rpc_response(mut stream: &UnixStream, response: &RpcResponse) -> Result<(), Error> {
+    serde_json::to_writer(stream, response)?;
+    stream.write_all(b"\n")?;
+    println!("<== {:?}", response);
+    Ok(())
+}
+
+/*
+ * Functions for client sending requests and receiving responses.
+ */
+
+/// Sends the request and receives the response. The response is framed
+/// unless the daemon predates framing, in which case it's read until the
+/// connection is closed.
+fn exchange<S: Read + Write + Timeouts>(
+    stream: S,
+    request: &Request,
+    timeout: time::Duration,
+) -> Result<Response, Error> {
+    let mut channel = Channel::new(stream);
+    channel.set_timeouts(Some(SEND_TIMEOUT), Some(timeout))?;
+    channel.send(request)?;
+    channel.recv()
+}
+
+/// Whether the error is due to a read or write timeout on the stream.
+fn is_timeout(err: &Error) -> bool {
+    match err.downcast_ref::<io::Error>() {
+        Some(io_err) => match io_err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// How long clients keep trying to connect to a daemon which is running but
+/// not yet accepting connections, such as one which was just started.
+const CONNECT_RETRY_WINDOW: time::Duration = time::Duration::from_secs(3);
+
+/// Delay before the first retry, which doubles after each attempt.
+const CONNECT_INITIAL_BACKOFF: time::Duration = time::Duration::from_millis(10);
+
+/// How long to wait for a response to `Request::Ping`.
+const PING_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// How long to wait for responses to most requests.
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// How long to wait for responses to requests which may take a while, see
+/// `Request::timeout`.
+const SLOW_REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(30 * 60);
+
+/// How long to wait while sending a request.
+const SEND_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Environment variable which overrides how many seconds clients wait for
+/// responses from the daemon.
+pub const TIMEOUT_VAR: &str = "MZR_DAEMON_TIMEOUT_SECS";
+
+/// Environment variable which, when set, makes clients restart the daemon
+/// when it doesn't respond in time. Set by `--auto-restart-daemon`.
+pub const AUTO_RESTART_VAR: &str = "MZR_AUTO_RESTART_DAEMON";
+
+/// How long to wait for the daemon to exit after asking it to, before
+/// killing it.
+const DAEMON_STOP_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Number of lines of the daemon's logs to include in errors about it being
+/// unhealthy.
+const LOG_TAIL_LINES: usize = 10;
+
+fn connect_to_daemon(mzr_dir: &MzrDir) -> Result<UnixStream, Error> {
+    let daemon_dir = DaemonDir::new(&mzr_dir);
+    let socket_path = DaemonSocketFile::new(&daemon_dir);
+    let deadline = time::Instant::now() + CONNECT_RETRY_WINDOW;
+    let mut backoff = CONNECT_INITIAL_BACKOFF;
+    loop {
+        let err = match UnixStream::connect(&socket_path) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => err,
+        };
+        let retryable = match err.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => true,
+            _ => false,
+        };
+        // Only worth retrying if the daemon process exists, otherwise fail
+        // right away.
+        if !retryable || !daemon_process_exists(&daemon_dir) {
+            if err.kind() == io::ErrorKind::NotFound {
+                return Err(kind_error(
+                    ErrorKind::DaemonUnreachable,
+                    format!(
+                        "Failed to connect to {}, because {} does not exist.",
+                        color_cmd(&"mzr daemon"),
+                        socket_path
+                    ),
+                ));
+            }
+            return Err(kind_error(
+                ErrorKind::DaemonUnreachable,
+                format!(
+                    "Failed to connect to {}. Is it running? Error was: {}",
+                    color_cmd(&"mzr daemon"),
+                    err
+                ),
+            ));
+        }
+        if time::Instant::now() + backoff > deadline {
+            return Err(daemon_unhealthy(
+                &daemon_dir,
+                &format!(
+                    "{} is running, but isn't accepting connections on {}. Error was: {}",
+                    color_cmd(&"mzr daemon"),
+                    socket_path,
+                    err
+                ),
+            ));
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+/// Whether the process in the daemon's pid file exists.
+pub fn daemon_process_exists(daemon_dir: &DaemonDir) -> bool {
+    match parse_pid_file(DaemonPidFile::new(daemon_dir)) {
+        Ok(pid) => kill(pid, None).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Error for when the daemon is running but not working, which includes the
+/// end of its logs, since they likely explain why.
+fn daemon_unhealthy(daemon_dir: &DaemonDir, message: &str) -> Error {
+    kind_error(
+        ErrorKind::DaemonUnhealthy,
+        with_log_tails(daemon_dir, message),
+    )
+}
+
+/// Appends the last lines of the daemon's logs to the message.
+fn with_log_tails(daemon_dir: &DaemonDir, message: &str) -> String {
+    let mut message = message.to_string();
+    let log_files = vec![
+        DaemonLogStdoutFile::new(daemon_dir).to_path_buf(),
+        DaemonLogStderrFile::new(daemon_dir).to_path_buf(),
+    ];
+    for log_file in log_files {
+        let contents = match read_to_string(&log_file) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.is_empty() {
+            continue;
+        }
+        let tail = &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..];
+        message.push_str(&format!(
+            "\n\nLast lines of {}:\n{}",
+            color_file(&log_file.display()),
+            tail.join("\n")
+        ));
+    }
+    message
+}
+
+/// Which daemon a client sends requests to.
+#[derive(Debug, Clone)]
+pub enum DaemonAddr {
+    /// The daemon of the mzr dir, via its unix socket.
+    Local(MzrDir),
+    /// A daemon on another machine, via its remote listener at `HOST:PORT`.
+    Remote(String),
+}
+
+impl<'a> From<&'a MzrDir> for DaemonAddr {
+    fn from(mzr_dir: &'a MzrDir) -> Self {
+        DaemonAddr::Local(mzr_dir.clone())
+    }
+}
+
+impl<'a> From<&'a DaemonAddr> for DaemonAddr {
+    fn from(addr: &'a DaemonAddr) -> Self {
+        addr.clone()
+    }
+}
+
+fn run_daemon_command<A: Into<DaemonAddr>>(addr: A, request: &Request) -> Result<Response, Error> {
+    let timeout = request.timeout();
+    match addr.into() {
+        DaemonAddr::Local(mzr_dir) => {
+            let stream = connect_to_daemon(&mzr_dir)?;
+            match exchange(stream, request, timeout) {
+                Err(ref err) if is_timeout(err) => {
+                    Err(response_timed_out(&mzr_dir, request, timeout))
+                }
+                result => result,
+            }
+        }
+        DaemonAddr::Remote(host) => {
+            let stream = remote_daemon::connect(&host)?;
+            match exchange(stream, request, timeout) {
+                Err(ref err) if is_timeout(err) => Err(format_err!(
+                    "The mzr daemon at {} didn't respond to the {} request within {} seconds.",
+                    host,
+                    request_name(request),
+                    timeout.as_secs()
+                )),
+                result => result,
+            }
+        }
+    }
+}
+
+/// Name of the request's variant, for error messages.
+fn request_name(request: &Request) -> String {
+    let debug = format!("{:?}", request);
+    debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Error for when the daemon didn't respond to a request in time. Checks
+/// whether the daemon process is still around, to distinguish a daemon
+/// which died from one which is busy or stuck. When `AUTO_RESTART_VAR` is
+/// set, the daemon also gets restarted.
+fn response_timed_out(mzr_dir: &MzrDir, request: &Request, timeout: time::Duration) -> Error {
+    let daemon_dir = DaemonDir::new(mzr_dir);
+    let daemon_cmd = color_cmd(&"mzr daemon");
+    let restart_cmd = color_cmd(&"mzr daemon --restart");
+    let request_name = request_name(request);
+    let pid = parse_pid_file(DaemonPidFile::new(&daemon_dir))
+        .ok()
+        .filter(|pid| kill(*pid, None).is_ok());
+    let (kind, mut message) = match pid {
+        None => (
+            ErrorKind::DaemonUnreachable,
+            format!(
+                "{} exited without responding to the {} request.",
+                daemon_cmd, request_name
+            ),
+        ),
+        Some(pid) => {
+            let state = match process_state(pid) {
+                Some('D') => "is blocked within the kernel, such as on a hung filesystem",
+                Some('T') | Some('t') => "is stopped",
+                Some('Z') => "has exited, but not yet been reaped",
+                _ => "is still running, so it may be busy or stuck",
+            };
+            (
+                ErrorKind::DaemonUnhealthy,
+                format!(
+                    "{} didn't respond to the {} request within {} seconds. Its process ({}) {}.",
+                    daemon_cmd,
+                    request_name,
+                    timeout.as_secs(),
+                    pid,
+                    state
+                ),
+            )
+        }
+    };
+    // The daemon's own timers also make requests, and shouldn't restart it.
+    if env::var_os(AUTO_RESTART_VAR).is_some() && !IS_DAEMON.load(Ordering::SeqCst) {
+        match restart_in_background() {
+            Ok(()) => message.push_str(&format!(
+                " It has been restarted via {}, so the command can be retried.",
+                restart_cmd
+            )),
+            Err(err) => message.push_str(&format!(
+                " Failed to restart it via {}: {}",
+                restart_cmd, err
+            )),
+        }
+    } else {
+        message.push_str(&format!(
+            " To restart it, run {}, or pass --auto-restart-daemon to do so automatically. \
+             Set {} to wait longer.",
+            restart_cmd, TIMEOUT_VAR
+        ));
+    }
+    kind_error(kind, with_log_tails(&daemon_dir, &message))
+}
+
+/// State of the process from `/proc/PID/stat`, such as 'R' for running or
+/// 'D' for uninterruptible sleep.
+fn process_state(pid: Pid) -> Option<char> {
+    let stat = read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is in parens and may contain spaces, so the state
+    // is found after the last paren.
+    let after_comm = &stat[stat.rfind(')')? + 1..];
+    after_comm.trim_start().chars().next()
+}
+
+/// Runs `mzr daemon --restart`, which daemonizes, so this doesn't wait for
+/// the new daemon to exit.
+fn restart_in_background() -> Result<(), Error> {
+    let status = process::Command::new(env::current_exe()?)
+        .args(&["daemon", "--restart"])
+        .status()?;
+    if !status.success() {
+        bail!("mzr daemon --restart exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Stops the daemon, if it's running, by asking it to terminate and then
+/// killing it if it doesn't exit in time. Zone processes are left running,
+/// and get adopted by the next daemon. Yields whether a daemon was stopped.
+pub fn stop_daemon(mzr_dir: &MzrDir) -> Result<bool, Error> {
+    let daemon_dir = DaemonDir::new(mzr_dir);
+    let pid_file = DaemonPidFile::new(&daemon_dir);
+    let pid = match parse_pid_file(&pid_file) {
+        Ok(pid) if kill(pid, None).is_ok() => pid,
+        _ => return Ok(false),
+    };
+    kill(pid, Signal::SIGTERM)?;
+    let deadline = time::Instant::now() + DAEMON_STOP_TIMEOUT;
+    while kill(pid, None).is_ok() && time::Instant::now() < deadline {
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    if kill(pid, None).is_ok() {
+        println!(
+            "Daemon process {} didn't exit after being asked to, so killing it.",
+            pid
+        );
+        kill(pid, Signal::SIGKILL)?;
+        let deadline = time::Instant::now() + DAEMON_STOP_TIMEOUT;
+        while kill(pid, None).is_ok() {
+            if time::Instant::now() > deadline {
+                bail!("Daemon process {} didn't exit after being killed.", pid);
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+    }
+    // Removed here since the daemon didn't get to, though the socket is
+    // left for the next daemon to replace, in case it's socket activated.
+    if pid_file.exists() {
+        remove_file(&pid_file)?;
+    }
+    events::record(mzr_dir, EventKind::DaemonStopped);
+    Ok(true)
+}
+
+pub fn get_zone_process(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<ZonePid, Error> {
+    let request = Request::ZoneProcess(zone_name.clone());
+    // The zone process forked to handle this inherits the connection, so it
+    // isn't closed after the response. Framing means the response is read
+    // without waiting for that.
+    match run_daemon_command(mzr_dir, &request)? {
+        Response::ZoneProcess(p) => Ok(p),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Asks the daemon to bind the zone's overlay to the target directory, see
+/// `Request::BindZone`.
+pub fn bind_zone(mzr_dir: &MzrDir, zone_name: &ZoneName, target: &PathBuf) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::BindZone(zone_name.clone(), target.clone()),
+    )?)
+}
+
+/// Asks the daemon to unmount a zone previously bound via `bind_zone`.
+pub fn unbind_zone(mzr_dir: &MzrDir, target: &PathBuf) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::UnbindZone(target.clone()),
+    )?)
+}
+
+/// Checks that the daemon responds to requests, yielding its status.
+pub fn ping<A: Into<DaemonAddr>>(addr: A) -> Result<DaemonHealth, Error> {
+    let mzr_dir = match addr.into() {
+        DaemonAddr::Local(mzr_dir) => mzr_dir,
+        remote => {
+            return match run_daemon_command(remote, &Request::Ping)? {
+                Response::Health(health) => Ok(health),
+                Response::Error(e) => bail!("Response from daemon was {:?}", e),
+                other => bail!("Unexpected response from daemon: {:?}", other),
+            };
+        }
+    };
+    let daemon_dir = DaemonDir::new(&mzr_dir);
+    let stream = connect_to_daemon(&mzr_dir)?;
+    match exchange(stream, &Request::Ping, PING_TIMEOUT) {
+        Ok(Response::Health(health)) => Ok(health),
+        Ok(other) => bail!("Unexpected response from daemon: {:?}", other),
+        Err(err) => Err(daemon_unhealthy(
+            &daemon_dir,
+            &format!(
+                "{} accepted a connection, but didn't respond to a ping: {}",
+                color_cmd(&"mzr daemon"),
+                err
+            ),
+        )),
+    }
+}
+
+/// Asks the daemon for its metrics.
+pub fn get_metrics<A: Into<DaemonAddr>>(addr: A) -> Result<MetricsReport, Error> {
+    match run_daemon_command(addr, &Request::Metrics)? {
+        Response::Metrics(report) => Ok(report),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Asks the daemon to apply the retention policy, yielding what was removed,
+/// or would be removed in the case of a dry run.
+pub fn apply_retention(
+    mzr_dir: &MzrDir,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<Vec<Removal>, Error> {
+    match run_daemon_command(mzr_dir, &Request::ApplyRetention(policy.clone(), dry_run))? {
+        Response::Removals(removals) => Ok(removals),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Whether the daemon's socket exists, which is the case when it's running,
+/// or it didn't shut down cleanly.
+pub fn socket_exists(mzr_dir: &MzrDir) -> bool {
+    DaemonSocketFile::new(&DaemonDir::new(mzr_dir)).exists()
+}
+
+/// Informs the daemon that a zone's changes have been merged, so that it is
+/// reflected in the daemon's metrics.
+pub fn record_merge(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    summary: &PlanSummary,
+) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::RecordMerge(zone_name.clone(), summary.clone()),
+    )?)
+}
+
+pub fn remove_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::RemoveZone(zone_name.clone()),
+    )?)
+}
+
+/// Stops the zone's process and unmounts it. It gets started again when the
+/// zone is next entered.
+pub fn stop_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::StopZone(zone_name.clone()),
+    )?)
+}
+
+/// Copies the paths from the source zone into the destination zone, yielding
+/// the number of entries copied. The destination's processes are stopped,
+/// since it needs remounting.
+pub fn copy_into_zone(
+    mzr_dir: &MzrDir,
+    source: &ZoneName,
+    dest: &ZoneName,
+    rel_paths: &[PathBuf],
+) -> Result<usize, Error> {
+    let request = Request::CopyIntoZone {
+        source: source.clone(),
+        dest: dest.clone(),
+        rel_paths: rel_paths.to_vec(),
+    };
+    match run_daemon_command(mzr_dir, &request)? {
+        Response::Copied(count) => Ok(count),
+        Response::Error(e) => bail!("{}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Restores the zone to the checkpoint, yielding the name of the checkpoint
+/// made of its changes beforehand. The zone's processes are stopped, since
+/// it needs remounting.
+pub fn restore_checkpoint(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    checkpoint: &str,
+) -> Result<String, Error> {
+    let request = Request::RestoreCheckpoint(zone_name.clone(), String::from(checkpoint));
+    match run_daemon_command(mzr_dir, &request)? {
+        Response::Checkpoint(previous) => Ok(previous),
+        Response::Error(e) => bail!("{}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Repairs the zone and mounts it again, yielding descriptions of the
+/// repairs. The zone's processes are stopped, since it needs remounting.
+pub fn repair_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Vec<String>, Error> {
+    match run_daemon_command(mzr_dir, &Request::RepairZone(zone_name.clone()))? {
+        Response::Repairs(repairs) => Ok(repairs),
+        Response::Error(e) => Err(kind_error(ErrorKind::MountFailed, e)),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Discards the zone's changes to the paths, yielding those which had
+/// changes. The zone's processes are stopped, since it needs remounting.
+pub fn revert_zone(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    rel_paths: &[PathBuf],
+) -> Result<Vec<PathBuf>, Error> {
+    match run_daemon_command(
+        mzr_dir,
+        &Request::RevertZone(zone_name.clone(), rel_paths.to_vec()),
+    )? {
+        Response::Paths(paths) => Ok(paths),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Registers the current process as waiting for a shell within the zone.
+pub fn register_shell(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::RegisterShell(zone_name.clone()),
+    )?)
+}
+
+pub fn deregister_shell(mzr_dir: &MzrDir) -> Result<(), Error> {
+    expect_success(run_daemon_command(mzr_dir, &Request::DeregisterShell)?)
+}
+
+/// Lists the shells within zones, ordered by zone.
+pub fn list_shells<A: Into<DaemonAddr>>(addr: A) -> Result<Vec<ShellInfo>, Error> {
+    match run_daemon_command(addr, &Request::ListShells)? {
+        Response::Shells(shells) => Ok(shells),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Lists the zones along with their status, including daemon-side state
+/// such as whether they're running.
+pub fn list_zones<A: Into<DaemonAddr>>(addr: A) -> Result<Vec<ZoneStatus>, Error> {
+    match run_daemon_command(addr, &Request::ListZones)? {
+        Response::Zones(zones) => Ok(zones),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Lists the snapshots along with the zones which use them.
+pub fn list_snapshots<A: Into<DaemonAddr>>(addr: A) -> Result<Vec<SnapshotStatus>, Error> {
+    match run_daemon_command(addr, &Request::ListSnapshots)? {
+        Response::Snapshots(snapshots) => Ok(snapshots),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Asks the daemon for its health, mounts, and zone processes.
+pub fn daemon_info<A: Into<DaemonAddr>>(addr: A) -> Result<DaemonInfo, Error> {
+    match run_daemon_command(addr, &Request::DaemonInfo)? {
+        Response::Info(info) => Ok(info),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Reports the shells, processes, and mounts which are using the zone.
+pub fn zone_usage(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<ZoneUsage, Error> {
+    match run_daemon_command(mzr_dir, &Request::ZoneUsage(zone_name.clone()))? {
+        Response::Usage(usage) => Ok(usage),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Finds the zone of the shell which the current process was started from,
+/// if any. Unlike `MZR_ZONE`, this doesn't rely on the environment being
+/// inherited.
+pub fn zone_of_current_process(mzr_dir: &MzrDir) -> Result<Option<ZoneName>, Error> {
+    match run_daemon_command(mzr_dir, &Request::ZoneOfClient)? {
+        Response::Zone(zone_name) => Ok(zone_name),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/// Pauses all processes running within the zone, until `thaw_zone`.
+pub fn freeze_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::FreezeZone(zone_name.clone()),
+    )?)
+}
+
+pub fn thaw_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::ThawZone(zone_name.clone()),
+    )?)
+}
+
+/// Waits for the daemon to journal all changes made to the zone so far, so
+/// that the journal can be used to plan merges. Fails if the daemon isn't
+/// journaling changes to the zone.
+pub fn sync_journal(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    // The daemon handles one request at a time, so asking itself would never
+    // get a response.
+    if IS_DAEMON.load(Ordering::SeqCst) {
+        bail!("The daemon can't sync journals while handling a request.");
+    }
+    expect_success(run_daemon_command(
+        mzr_dir,
+        &Request::SyncJournal(zone_name.clone()),
+    )?)
+}
+
+fn expect_success(response: Response) -> Result<(), Error> {
+    match response {
+        Response::Success => Ok(()),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        other => bail!("Unexpected response from daemon: {:?}", other),
+    }
+}
+
+/*
+ * Functions for entering zone process namespaces.
+ */
+
+pub fn enter_zone_process_mount(zone_pid: &ZonePid) -> Result<(), Error> {
+    namespaces::enter_mount(zone_pid.to_pid())
+}
+
+pub fn enter_zone_process_user_and_mount(zone_pid: &ZonePid) -> Result<(), Error> {
     namespaces::enter_user_and_mount(zone_pid.to_pid())
 }
+
+/// Enters the zone's PID namespace, which only applies to child processes
+/// created afterwards. Must be called after entering the zone's user
+/// namespace, which owns it.
+pub fn enter_zone_process_pid(zone_pid: &ZonePid) -> Result<(), Error> {
+    namespaces::enter_pid(zone_pid.to_pid())
+}
+
+/// Lists the processes running within the zone, other than the zone process.
+pub fn zone_process_members(zone_pid: &ZonePid) -> Result<Vec<Pid>, Error> {
+    namespaces::pid_namespace_members(zone_pid.to_pid())
+}
@@ -0,0 +1,204 @@
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, symlink_metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Disk usage of a directory tree.
+#[derive(Debug, Default, Clone)]
+pub struct DirSize {
+    /// Sum of the lengths of the files, as reported by `ls -l`.
+    pub apparent: u64,
+    /// Space allocated on disk, with files that are hardlinked multiple times
+    /// counted once. Reflinked files can't be detected, and so are counted
+    /// separately.
+    pub actual: u64,
+    /// Number of directory entries which couldn't be read.
+    pub errors: u64,
+}
+
+/// Identifies a file by its device and inode numbers.
+type FileId = (u64, u64);
+
+/// Results accumulated by a single worker thread.
+#[derive(Default)]
+struct Partial {
+    /// Sizes for each root, not counting files with multiple links.
+    sizes: Vec<DirSize>,
+    /// Allocated size of each file with multiple links, along with which roots
+    /// it was seen within.
+    linked: HashMap<FileId, (u64, HashSet<usize>)>,
+}
+
+/// Directories waiting to be scanned, along with the index of their root.
+struct Queue {
+    dirs: Vec<(usize, u64, PathBuf)>,
+    /// Number of workers that are currently scanning a directory, and so
+    /// might add more directories to the queue.
+    busy: usize,
+}
+
+/// Measures the disk usage of each of the directory trees, using the
+/// specified number of threads. Directories on other filesystems than their
+/// root aren't descended into. Also yields the total disk usage, with files
+/// that are hardlinked from multiple trees counted once.
+pub fn measure(roots: &[PathBuf], threads: usize) -> Result<(Vec<DirSize>, DirSize), Error> {
+    let mut dirs = Vec::new();
+    for (ix, root) in roots.iter().enumerate() {
+        if let Ok(metadata) = symlink_metadata(root) {
+            if metadata.is_dir() {
+                dirs.push((ix, metadata.dev(), root.clone()));
+            }
+        }
+    }
+    let queue = Arc::new((Mutex::new(Queue { dirs, busy: 0 }), Condvar::new()));
+    let mut workers = Vec::new();
+    for _ in 0..threads.max(1) {
+        let queue = queue.clone();
+        let root_count = roots.len();
+        workers.push(thread::spawn(move || work(&queue, root_count)));
+    }
+    let mut sizes = vec![DirSize::default(); roots.len()];
+    let mut linked: HashMap<FileId, (u64, HashSet<usize>)> = HashMap::new();
+    for worker in workers {
+        let partial = worker
+            .join()
+            .map_err(|_| format_err!("Thread measuring disk usage panicked."))??;
+        for (size, partial_size) in sizes.iter_mut().zip(partial.sizes.iter()) {
+            add_to(size, partial_size);
+        }
+        for (file_id, (blocks, roots)) in partial.linked {
+            linked
+                .entry(file_id)
+                .or_insert_with(|| (blocks, HashSet::new()))
+                .1
+                .extend(roots);
+        }
+    }
+    let mut total = DirSize::default();
+    for size in sizes.iter() {
+        add_to(&mut total, size);
+    }
+    for (blocks, roots) in linked.values() {
+        total.actual += blocks;
+        for ix in roots {
+            sizes[*ix].actual += blocks;
+        }
+    }
+    Ok((sizes, total))
+}
+
+fn work(queue: &Arc<(Mutex<Queue>, Condvar)>, root_count: usize) -> Result<Partial, Error> {
+    let (lock, condvar) = &**queue;
+    let mut partial = Partial::default();
+    partial.sizes = vec![DirSize::default(); root_count];
+    loop {
+        let item = {
+            let mut guard = lock
+                .lock()
+                .map_err(|_| format_err!("Disk usage queue lock was poisoned."))?;
+            loop {
+                if let Some(item) = guard.dirs.pop() {
+                    guard.busy += 1;
+                    break Some(item);
+                }
+                if guard.busy == 0 {
+                    break None;
+                }
+                guard = condvar
+                    .wait(guard)
+                    .map_err(|_| format_err!("Disk usage queue lock was poisoned."))?;
+            }
+        };
+        match item {
+            None => {
+                // Wake up the other workers, so that they also notice that
+                // there is nothing left to do.
+                condvar.notify_all();
+                return Ok(partial);
+            }
+            Some((ix, dev, dir)) => {
+                let subdirs = scan_dir(&mut partial, ix, dev, &dir);
+                let mut guard = lock
+                    .lock()
+                    .map_err(|_| format_err!("Disk usage queue lock was poisoned."))?;
+                guard.dirs.extend(subdirs);
+                guard.busy -= 1;
+                condvar.notify_all();
+            }
+        }
+    }
+}
+
+/// Adds the sizes of the directory's entries to the partial results, and
+/// yields its subdirectories.
+fn scan_dir(
+    partial: &mut Partial,
+    ix: usize,
+    dev: u64,
+    dir: &PathBuf,
+) -> Vec<(usize, u64, PathBuf)> {
+    let mut subdirs = Vec::new();
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            partial.sizes[ix].errors += 1;
+            return subdirs;
+        }
+    };
+    for entry_or_err in entries {
+        let path = match entry_or_err {
+            Ok(entry) => entry.path(),
+            Err(_) => {
+                partial.sizes[ix].errors += 1;
+                continue;
+            }
+        };
+        let metadata = match symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                partial.sizes[ix].errors += 1;
+                continue;
+            }
+        };
+        // Allocated size, as st_blocks is always in units of 512 bytes.
+        let blocks = metadata.blocks() * 512;
+        if metadata.is_dir() {
+            if metadata.dev() == dev {
+                partial.sizes[ix].actual += blocks;
+                subdirs.push((ix, dev, path));
+            }
+        } else {
+            partial.sizes[ix].apparent += metadata.len();
+            if metadata.nlink() > 1 {
+                partial
+                    .linked
+                    .entry((metadata.dev(), metadata.ino()))
+                    .or_insert_with(|| (blocks, HashSet::new()))
+                    .1
+                    .insert(ix);
+            } else {
+                partial.sizes[ix].actual += blocks;
+            }
+        }
+    }
+    subdirs
+}
+
+fn add_to(size: &mut DirSize, other: &DirSize) {
+    size.apparent += other.apparent;
+    size.actual += other.actual;
+    size.errors += other.errors;
+}
+
+/// Number of online processors, used as the default number of threads.
+pub fn cpu_count() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if count < 1 {
+        1
+    } else {
+        count as usize
+    }
+}
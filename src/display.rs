@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+
+/// How times and sizes are rendered in listings. By default they're made
+/// human-friendly, such as `2 hours ago` and `1.4 GiB`, while `--absolute`
+/// gives the raw values, which are easier for scripts to consume.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Humanize {
+    pub absolute: bool,
+}
+
+impl Humanize {
+    pub fn new(absolute: bool) -> Humanize {
+        Humanize { absolute }
+    }
+
+    /// Either a time relative to now, or an RFC 3339 timestamp.
+    pub fn time(&self, time: &DateTime<Utc>) -> String {
+        if self.absolute {
+            time.to_rfc3339()
+        } else {
+            relative_time(time, &Utc::now())
+        }
+    }
+
+    /// Either a size in binary units, or a number of bytes.
+    pub fn size(&self, bytes: u64) -> String {
+        if self.absolute {
+            bytes.to_string()
+        } else {
+            format_size(bytes)
+        }
+    }
+}
+
+/// Describes the time relative to `now`, such as `2 hours ago`, using the
+/// largest unit which fits at least once.
+pub fn relative_time(time: &DateTime<Utc>, now: &DateTime<Utc>) -> String {
+    const UNITS: [(&str, i64); 6] = [
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let seconds = now.signed_duration_since(*time).num_seconds();
+    if seconds.abs() < 10 {
+        return String::from("just now");
+    }
+    for (unit, unit_seconds) in UNITS.iter() {
+        let count = seconds.abs() / unit_seconds;
+        if count > 0 {
+            let plural = if count == 1 { "" } else { "s" };
+            return if seconds > 0 {
+                format!("{} {}{} ago", count, unit, plural)
+            } else {
+                format!("in {} {}{}", count, unit, plural)
+            };
+        }
+    }
+    String::from("just now")
+}
+
+/// Formats a number of bytes using binary units, such as `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,124 @@
+use crate::git::GitError;
+use crate::mount::MountError;
+use crate::top_dirs::MzrDirNotFound;
+use crate::utils::InputRequired;
+use failure::{Error, Fail};
+use serde::Serialize;
+
+/// Kinds of failure which scripts might want to handle differently, each of
+/// which exits mzr with a distinct code. Errors which aren't classified exit
+/// with code 1, and invalid command line arguments exit with code 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Other,
+    MzrDirNotFound,
+    DaemonUnreachable,
+    ZoneNotFound,
+    SnapshotNotFound,
+    SnapshotExists,
+    MountFailed,
+    MergeConflicts,
+    InputRequired,
+    Git,
+    DaemonUnhealthy,
+}
+
+/// Listing of exit codes, included in `mzr --help`.
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1   Unclassified error
+    2   Invalid command line arguments
+    3   No mzr directory was found
+    4   The mzr daemon couldn't be reached
+    5   Zone not found
+    6   Snapshot not found
+    7   Snapshot already exists
+    8   Mounting failed
+    9   Merge conflicts were left unmerged
+    10  Input was required, but --no-input was specified
+    11  Git failed
+    12  The mzr daemon is running, but not responding";
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::MzrDirNotFound => 3,
+            ErrorKind::DaemonUnreachable => 4,
+            ErrorKind::ZoneNotFound => 5,
+            ErrorKind::SnapshotNotFound => 6,
+            ErrorKind::SnapshotExists => 7,
+            ErrorKind::MountFailed => 8,
+            ErrorKind::MergeConflicts => 9,
+            ErrorKind::InputRequired => 10,
+            ErrorKind::Git => 11,
+            ErrorKind::DaemonUnhealthy => 12,
+        }
+    }
+}
+
+/// Error with a message and a kind, for failures which don't otherwise have a
+/// dedicated error type.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct KindError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+pub fn kind_error<S: Into<String>>(kind: ErrorKind, message: S) -> Error {
+    KindError {
+        kind,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Determines the kind of an error, from the first of its causes that has a
+/// known kind.
+pub fn classify(err: &Error) -> ErrorKind {
+    for cause in err.causes() {
+        if let Some(err) = cause.downcast_ref::<KindError>() {
+            return err.kind;
+        }
+        if cause.downcast_ref::<MountError>().is_some() {
+            return ErrorKind::MountFailed;
+        }
+        if cause.downcast_ref::<MzrDirNotFound>().is_some() {
+            return ErrorKind::MzrDirNotFound;
+        }
+        if cause.downcast_ref::<InputRequired>().is_some() {
+            return ErrorKind::InputRequired;
+        }
+        if cause.downcast_ref::<GitError>().is_some() {
+            return ErrorKind::Git;
+        }
+    }
+    ErrorKind::Other
+}
+
+/// Error as output by `mzr --json`.
+#[derive(Debug, Serialize)]
+pub struct JsonError {
+    pub kind: ErrorKind,
+    pub exit_code: i32,
+    pub message: String,
+    /// Messages of the causes of the error, outermost first.
+    pub causes: Vec<String>,
+}
+
+impl JsonError {
+    pub fn new(err: &Error) -> JsonError {
+        let kind = classify(err);
+        JsonError {
+            kind,
+            exit_code: kind.exit_code(),
+            message: err.to_string(),
+            causes: err
+                .causes()
+                .skip(1)
+                .map(|cause| cause.to_string())
+                .collect(),
+        }
+    }
+}
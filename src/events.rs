@@ -0,0 +1,121 @@
+use crate::colors::*;
+use crate::json;
+use crate::merge::PlanSummary;
+use crate::paths::*;
+use chrono::{DateTime, Utc};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::process;
+
+/// An operation on the mzr directory, recorded in its events log so that
+/// what happened to the work dir can be audited via `mzr history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    /// Value of `USER` for the process which recorded the event.
+    pub user: Option<String>,
+    pub pid: u32,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EventKind {
+    SnapshotCreated {
+        snapshot: SnapName,
+    },
+    ZoneCreated {
+        zone: ZoneName,
+        snapshot: SnapName,
+    },
+    ZoneRemoved {
+        zone: ZoneName,
+    },
+    Merged {
+        zone: ZoneName,
+        target_dir: PathBuf,
+        summary: PlanSummary,
+    },
+    DaemonStarted,
+    DaemonStopped,
+}
+
+impl EventKind {
+    /// Name used to filter events by kind, which is also their tag in the
+    /// log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::SnapshotCreated { .. } => "snapshot-created",
+            EventKind::ZoneCreated { .. } => "zone-created",
+            EventKind::ZoneRemoved { .. } => "zone-removed",
+            EventKind::Merged { .. } => "merged",
+            EventKind::DaemonStarted => "daemon-started",
+            EventKind::DaemonStopped => "daemon-stopped",
+        }
+    }
+
+    /// The zone which the event is about, if any.
+    pub fn zone(&self) -> Option<&ZoneName> {
+        match self {
+            EventKind::ZoneCreated { zone, .. }
+            | EventKind::ZoneRemoved { zone }
+            | EventKind::Merged { zone, .. } => Some(zone),
+            _ => None,
+        }
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            EventKind::SnapshotCreated { snapshot } => write!(f, "Created snapshot {}", snapshot),
+            EventKind::ZoneCreated { zone, snapshot } => {
+                write!(f, "Created zone {} of snapshot {}", zone, snapshot)
+            }
+            EventKind::ZoneRemoved { zone } => write!(f, "Removed zone {}", zone),
+            EventKind::Merged {
+                zone,
+                target_dir,
+                summary,
+            } => write!(
+                f,
+                "Merged {} update(s) from zone {} into {}, leaving {} conflict(s)",
+                summary.updates,
+                zone,
+                color_dir(&target_dir.display()),
+                summary.conflicts
+            ),
+            EventKind::DaemonStarted => write!(f, "Started the daemon"),
+            EventKind::DaemonStopped => write!(f, "Stopped the daemon"),
+        }
+    }
+}
+
+/// Appends the event to the events log. Failing to record an event doesn't
+/// fail the operation, so this only warns.
+pub fn record(mzr_dir: &MzrDir, kind: EventKind) {
+    let event = Event {
+        time: Utc::now(),
+        user: env::var("USER").ok(),
+        pid: process::id(),
+        kind,
+    };
+    let log_file = EventsLogFile::new(mzr_dir);
+    if let Err(err) = json::append_line(&log_file, &event) {
+        println!(
+            "{} failed to record event in {}: {}",
+            color_warn(&"Warning:"),
+            log_file,
+            err
+        );
+    }
+}
+
+/// Reads the events log, oldest first.
+pub fn read(mzr_dir: &MzrDir) -> Result<Vec<Event>, Error> {
+    json::read_lines(&EventsLogFile::new(mzr_dir))
+}
@@ -0,0 +1,102 @@
+use crate::cgroups::FrozenCgroup;
+use crate::json;
+use crate::paths::*;
+use failure::Error;
+use libc::pid_t;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use nix::Error::Sys;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs::remove_file;
+
+/// How a zone's processes were frozen by `mzr zone freeze`, which is recorded
+/// in the zone's frozen file so that they can be thawed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Frozen {
+    /// Frozen via the cgroup v2 freezer.
+    Cgroup(FrozenCgroup),
+    /// Stopped via `SIGSTOP`, used when the cgroup freezer isn't available.
+    Signals(Vec<pid_t>),
+}
+
+impl Display for Frozen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Frozen::Cgroup(_) => write!(f, "via the cgroup freezer"),
+            Frozen::Signals(pids) => write!(f, "by stopping {} process(es)", pids.len()),
+        }
+    }
+}
+
+/// Freezes the processes within a zone, preferring the cgroup freezer, and
+/// records how they were frozen. The cgroup for freezing them is created
+/// alongside the cgroup of the zone process.
+pub fn freeze(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    zone_pid: Pid,
+    pids: &[Pid],
+) -> Result<Frozen, Error> {
+    let cgroup_name = format!("mzr-frozen-{}", zone_name);
+    let frozen = match FrozenCgroup::freeze(&cgroup_name, zone_pid, pids) {
+        Ok(cgroup) => Frozen::Cgroup(cgroup),
+        Err(err) => {
+            println!(
+                "Couldn't use the cgroup freezer for zone {}, so using SIGSTOP instead: {}",
+                zone_name, err
+            );
+            for pid in pids {
+                signal_ignoring_exited(*pid, Signal::SIGSTOP)?;
+            }
+            Frozen::Signals(pids.iter().map(|pid| pid_t::from(*pid)).collect())
+        }
+    };
+    json::write(&frozen_file(mzr_dir, zone_name), &frozen)?;
+    Ok(frozen)
+}
+
+/// Thaws the processes within a zone, if they were frozen.
+pub fn thaw(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Option<Frozen>, Error> {
+    let frozen = match load(mzr_dir, zone_name)? {
+        Some(frozen) => frozen,
+        None => return Ok(None),
+    };
+    match &frozen {
+        Frozen::Cgroup(cgroup) => cgroup.thaw()?,
+        Frozen::Signals(pids) => {
+            for pid in pids {
+                signal_ignoring_exited(Pid::from_raw(*pid), Signal::SIGCONT)?;
+            }
+        }
+    }
+    remove_file(&frozen_file(mzr_dir, zone_name))?;
+    Ok(Some(frozen))
+}
+
+/// Loads the record of how a zone's processes were frozen, yielding `None`
+/// if they aren't frozen.
+pub fn load(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Option<Frozen>, Error> {
+    let frozen_file = frozen_file(mzr_dir, zone_name);
+    if frozen_file.exists() {
+        Ok(Some(json::read(&frozen_file)?.contents))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn is_frozen(mzr_dir: &MzrDir, zone_name: &ZoneName) -> bool {
+    frozen_file(mzr_dir, zone_name).exists()
+}
+
+fn frozen_file(mzr_dir: &MzrDir, zone_name: &ZoneName) -> ZoneFrozenFile {
+    ZoneFrozenFile::new(&ZoneDir::new(mzr_dir, zone_name))
+}
+
+fn signal_ignoring_exited(pid: Pid, signal: Signal) -> Result<(), Error> {
+    match kill(pid, signal) {
+        Ok(()) | Err(Sys(Errno::ESRCH)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
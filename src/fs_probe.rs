@@ -0,0 +1,116 @@
+use crate::colors::*;
+use crate::mountinfo;
+use failure::{Error, ResultExt};
+use std::fs::{canonicalize, metadata, remove_file, symlink_metadata, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Filesystems which overlayfs doesn't support as upper dirs, or which can
+/// change underneath it. FUSE filesystems have types like `fuse.sshfs`.
+const UNSUPPORTED_FS_TYPES: &[&str] = &[
+    "nfs", "nfs4", "cifs", "smb3", "smbfs", "9p", "ceph", "afs", "overlay", "fuse",
+];
+
+/// Problems with the filesystem holding a directory, for mzr's purposes.
+/// Errors make it unusable for zones, whereas warnings only affect some uses.
+#[derive(Debug, Default)]
+pub struct Problems {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Probes the filesystem holding the directory, which must exist, for what
+/// overlayfs needs of the directories holding a zone's changes.
+pub fn probe(dir: &Path) -> Result<Problems, Error> {
+    let mut problems = Problems::default();
+    let dir = canonicalize(dir)?;
+    if let Some(mount) = mountinfo::containing(&dir)? {
+        let base_type = mount.fs_type.split('.').next().unwrap_or("");
+        if UNSUPPORTED_FS_TYPES.contains(&base_type) {
+            problems.errors.push(format!(
+                "{} is on a {} filesystem mounted at {}, which overlayfs can't use for the \
+                 changes of zones.",
+                color_dir(&dir.display()),
+                mount.fs_type,
+                color_dir(&mount.mount_point.display())
+            ));
+        }
+        if mount.has_mount_option("noexec") {
+            problems.warnings.push(format!(
+                "{} is mounted noexec, so programs built within zones may fail to run.",
+                color_dir(&mount.mount_point.display())
+            ));
+        }
+        if mount.has_mount_option("nosuid") {
+            problems.warnings.push(format!(
+                "{} is mounted nosuid, so setuid programs within zones won't gain privileges.",
+                color_dir(&mount.mount_point.display())
+            ));
+        }
+    }
+    if is_case_insensitive(&dir)? {
+        problems.errors.push(format!(
+            "{} is on a case-insensitive filesystem, so files whose names only differ in case \
+             would collide within zones and snapshots.",
+            color_dir(&dir.display())
+        ));
+    }
+    Ok(problems)
+}
+
+/// Creates a file with an uppercase name, and checks whether it can be
+/// found by its lowercase name.
+fn is_case_insensitive(dir: &Path) -> Result<bool, Error> {
+    let upper_path = dir.join(".MZR-CASE-PROBE");
+    let lower_path = dir.join(".mzr-case-probe");
+    if symlink_metadata(&lower_path).is_ok() {
+        remove_file(&lower_path)?;
+    }
+    File::create(&upper_path).context(format_err!(
+        "Failed to create a file to probe the filesystem at {}",
+        color_dir(&dir.display())
+    ))?;
+    let result = symlink_metadata(&lower_path).is_ok();
+    remove_file(&upper_path)?;
+    Ok(result)
+}
+
+/// Checks that the mzr directory can hold zones, printing warnings and
+/// failing with errors which suggest relocating it.
+pub fn check_mzr_dir(mzr_dir: &Path) -> Result<(), Error> {
+    let problems = probe(mzr_dir)?;
+    for warning in problems.warnings.iter() {
+        println!("{} {}", color_warn(&"Warning:"), warning);
+    }
+    if !problems.errors.is_empty() {
+        return Err(unusable(&problems));
+    }
+    Ok(())
+}
+
+fn unusable(problems: &Problems) -> Error {
+    format_err!(
+        "{}\nUse {} to keep the mzr directory on another filesystem.",
+        problems.errors.join("\n"),
+        color_cmd(&"mzr init --store-at DIR")
+    )
+}
+
+/// Checks that a zone's overlayfs changes and work dirs are suitable, which
+/// also requires them to be on the same filesystem. Warnings aren't printed,
+/// since they were printed when the mzr directory was created.
+pub fn check_zone_dirs(changes_dir: &Path, work_dir: &Path) -> Result<(), Error> {
+    let problems = probe(changes_dir)?;
+    if !problems.errors.is_empty() {
+        return Err(unusable(&problems));
+    }
+    if metadata(changes_dir)?.dev() != metadata(work_dir)?.dev() {
+        bail!(
+            "{} and {} are on different filesystems, but overlayfs requires its work directory \
+             to be on the same filesystem as its changes.",
+            color_dir(&changes_dir.display()),
+            color_dir(&work_dir.display())
+        );
+    }
+    Ok(())
+}
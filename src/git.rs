@@ -1,15 +1,38 @@
 use crate::colors::*;
-use crate::paths::{BoundGitRepoDir, RelativeGitRepoDir, SnapName, UserWorkDir};
+use crate::paths::{BoundGitRepoDir, RelativeGitRepoDir, SnapName, UserWorkDir, ZoneName};
 use crate::utils::strip_prefix;
 use failure::{Error, ResultExt};
+use git2::{IndexAddOption, Repository};
 use semver::Version;
 use std::env;
 use std::fmt;
-use std::fs::{create_dir_all, read_link};
+use std::fs::{
+    copy, create_dir_all, read_link, read_to_string, remove_dir_all, set_permissions,
+    symlink_metadata, write, Permissions,
+};
 use std::io::ErrorKind;
-use std::os::unix::fs::symlink;
-use std::path::PathBuf;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
+use walkdir::WalkDir;
+
+/// Paths within the git directory which are symlinked to the shared
+/// repository by `symlink_git_repo`.
+///
+/// Based on list / code at
+/// https://github.com/git/git/blob/e32afab7b0376a7b07601a87cd5c6841ff2a811a/contrib/workdir/git-new-workdir#L82
+pub const SHARED_REPO_PATHS: [&str; 10] = [
+    "config",
+    "refs",
+    "logs/refs",
+    "objects",
+    "info",
+    "hooks",
+    "packed-refs",
+    "remotes",
+    "rr-cache",
+    "svn",
+];
 
 // This implements something very similar to git's old "workdir"
 // approach for having multiple working directories associated with
@@ -18,22 +41,7 @@ use std::process::{Command, ExitStatus, Stdio};
 // Unlike the script there, this is idempotent, but only if the
 // symlinks are correct.
 pub fn symlink_git_repo(source_git_dir: &PathBuf, target_git_dir: &PathBuf) -> Result<(), Error> {
-    // Based on list / code at
-    // https://github.com/git/git/blob/e32afab7b0376a7b07601a87cd5c6841ff2a811a/contrib/workdir/git-new-workdir#L82
-    for shared_path in [
-        "config",
-        "refs",
-        "logs/refs",
-        "objects",
-        "info",
-        "hooks",
-        "packed-refs",
-        "remotes",
-        "rr-cache",
-        "svn",
-    ]
-    .iter()
-    {
+    for shared_path in SHARED_REPO_PATHS.iter() {
         let source_path = source_git_dir.join(shared_path);
         let target_path = target_git_dir.join(shared_path);
         let possibly_existing_link = read_link(&target_path).context(format_err!(
@@ -66,6 +74,162 @@ pub fn symlink_git_repo(source_git_dir: &PathBuf, target_git_dir: &PathBuf) -> R
     Ok(())
 }
 
+/// Name of the worktree that a zone's copy of a repository is registered as.
+fn worktree_name(zone_name: &ZoneName) -> String {
+    format!("mzr-{}", zone_name)
+}
+
+/// Registers a zone's copy of a repository as a worktree of the shared
+/// repository, much like `git worktree add` does, but without checking out
+/// any files since the zone already has them. The HEAD and index of the
+/// worktree are initialized from the snapshot's git directory, with HEAD
+/// detached at the snapshot's commit, and
+/// `target_git_file` is written to refer to the worktree.
+///
+/// `work_tree_git_file` is the path of the worktree's `.git` file as seen from
+/// within the zone. The worktree is locked, since that path is not visible
+/// outside the zone, and so `git worktree prune` would otherwise remove it.
+pub fn add_worktree(
+    bound_git_dir: &BoundGitRepoDir,
+    snap_git_dir: &PathBuf,
+    target_git_file: &PathBuf,
+    work_tree_git_file: &PathBuf,
+    zone_name: &ZoneName,
+) -> Result<(), Error> {
+    let admin_dir = bound_git_dir
+        .join("worktrees")
+        .join(worktree_name(zone_name));
+    if !admin_dir.is_dir() {
+        create_dir_all(&admin_dir)?;
+        write_detached_head(bound_git_dir, snap_git_dir, &admin_dir)?;
+        let snap_index = snap_git_dir.join("index");
+        if snap_index.exists() {
+            copy(&snap_index, admin_dir.join("index"))?;
+        }
+        write(admin_dir.join("commondir"), "../..\n")?;
+        write(
+            admin_dir.join("gitdir"),
+            format!("{}\n", work_tree_git_file.display()),
+        )?;
+        write(
+            admin_dir.join("locked"),
+            format!("Used by mzr zone {}\n", zone_name),
+        )?;
+    }
+    if let Ok(metadata) = symlink_metadata(target_git_file) {
+        if metadata.is_dir() {
+            bail!(
+                "Expected {:?} to be a git file, but it is a directory. \
+                 Was the zone created without using git worktrees?",
+                target_git_file
+            );
+        }
+    }
+    if let Some(parent) = target_git_file.parent() {
+        create_dir_all(parent)?;
+    }
+    // Always written, since the location of the shared repository differs
+    // when zones are copied between mzr directories.
+    write(
+        target_git_file,
+        format!("gitdir: {}\n", admin_dir.display()),
+    )?;
+    Ok(())
+}
+
+/// Writes the worktree's HEAD, detached at the commit of the snapshot's HEAD.
+/// Checking out the snapshot's branch instead would share it with the user's
+/// work dir, so that commits made in the zone would move the user's branch
+/// without updating their index. HEADs of unborn branches are copied as-is,
+/// since there's no commit to detach at.
+fn write_detached_head(
+    bound_git_dir: &BoundGitRepoDir,
+    snap_git_dir: &PathBuf,
+    admin_dir: &PathBuf,
+) -> Result<(), Error> {
+    let snap_head = snap_git_dir.join("HEAD");
+    let head = read_to_string(&snap_head)?;
+    let head = head.trim();
+    if !head.starts_with("ref: ") {
+        return Ok(write(admin_dir.join("HEAD"), format!("{}\n", head))?);
+    }
+    let repo = Repository::open(bound_git_dir)?;
+    let commit = repo
+        .revparse_single(&head["ref: ".len()..])
+        .and_then(|object| object.peel_to_commit());
+    match commit {
+        Ok(commit) => write(admin_dir.join("HEAD"), format!("{}\n", commit.id()))?,
+        Err(_) => {
+            copy(&snap_head, admin_dir.join("HEAD"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the metadata for the zone's worktree from the user's git
+/// directory, if it was registered.
+pub fn remove_worktree(user_git_dir: &PathBuf, zone_name: &ZoneName) -> Result<(), Error> {
+    let admin_dir = user_git_dir
+        .join("worktrees")
+        .join(worktree_name(zone_name));
+    if admin_dir.is_dir() {
+        remove_dir_all(&admin_dir).context(format_err!(
+            "Failed to remove git worktree metadata at {:?}",
+            admin_dir
+        ))?;
+    }
+    Ok(())
+}
+
+/// Finds the git directories within a tree, relative to its root. These are
+/// the `.git` directories of repositories, along with the directories that
+/// git uses for their submodules, like `.git/modules/NAME`. Files named `.git`,
+/// which refer to a git directory elsewhere, are not included.
+pub fn find_git_dirs(root: &PathBuf) -> Result<Vec<RelativeGitRepoDir>, Error> {
+    let mut git_dirs = Vec::new();
+    let mut walker = WalkDir::new(root).same_file_system(true).into_iter();
+    while let Some(entry_or_err) = walker.next() {
+        // Directories which can't be read are unlikely to contain
+        // repositories that need to be shared.
+        let entry = match entry_or_err {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if entry.file_type().is_dir() && entry.file_name() == ".git" {
+            add_git_dir(root, entry.path(), &mut git_dirs)?;
+            walker.skip_current_dir();
+        }
+    }
+    Ok(git_dirs)
+}
+
+/// Adds the git directory, along with the git directories of its submodules.
+fn add_git_dir(
+    root: &PathBuf,
+    git_dir: &Path,
+    git_dirs: &mut Vec<RelativeGitRepoDir>,
+) -> Result<(), Error> {
+    git_dirs.push(RelativeGitRepoDir::new(git_dir.strip_prefix(root)?));
+    let modules_dir = git_dir.join("modules");
+    if !modules_dir.is_dir() {
+        return Ok(());
+    }
+    // Submodule names may contain slashes, so the git directories are
+    // identified by their HEAD files rather than their depth.
+    let mut walker = WalkDir::new(&modules_dir).min_depth(1).into_iter();
+    while let Some(entry_or_err) = walker.next() {
+        let entry = match entry_or_err {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if entry.file_type().is_dir() && entry.path().join("HEAD").is_file() {
+            add_git_dir(root, entry.path(), git_dirs)?;
+            walker.skip_current_dir();
+        }
+    }
+    Ok(())
+}
+
 pub fn default_snap_name(work_dir: &UserWorkDir) -> Result<SnapName, Error> {
     match current_ref_or_short_sha(&work_dir) {
         Err(e) => Err(format_err!(
@@ -87,6 +251,26 @@ pub fn default_snap_name(work_dir: &UserWorkDir) -> Result<SnapName, Error> {
 }
 
 fn current_ref_or_short_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
+    with_fallback(
+        work_dir,
+        |repo| {
+            let head = repo.head()?;
+            if head.is_branch() {
+                return head
+                    .shorthand()
+                    .map(String::from)
+                    .ok_or_else(|| git2::Error::from_str("Branch name is not valid unicode"));
+            }
+            match head.target() {
+                Some(oid) => Ok(oid.to_string()[..6].to_string()),
+                None => Err(git2::Error::from_str("HEAD does not refer to a commit")),
+            }
+        },
+        || git_current_ref_or_short_sha(work_dir),
+    )
+}
+
+fn git_current_ref_or_short_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
     match symbolic_ref_short(work_dir) {
         Ok(result) => Ok(result),
         Err(e) => match e {
@@ -95,7 +279,7 @@ fn current_ref_or_short_sha(work_dir: &UserWorkDir) -> Result<String, GitError>
                 // 32768 is reported instead of what I get in bash, 128. So
                 // going to just match on message instead.
                 if output.ends_with("is not a symbolic ref\n") {
-                    let sha = head_sha(work_dir)?;
+                    let sha = git_head_sha(work_dir)?;
                     Ok(sha[..6].to_string())
                 } else {
                     Err(GitError::ExitStatus(cmd, output, status))
@@ -120,7 +304,18 @@ fn symbolic_ref_short(work_dir: &UserWorkDir) -> Result<String, GitError> {
     .map(|x| x.trim().to_string())
 }
 
-fn head_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
+pub fn head_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
+    with_fallback(
+        work_dir,
+        |repo| match repo.head()?.target() {
+            Some(oid) => Ok(oid.to_string()),
+            None => Err(git2::Error::from_str("HEAD does not refer to a commit")),
+        },
+        || git_head_sha(work_dir),
+    )
+}
+
+fn git_head_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
     collect_output(
         Command::new("git")
             .stdin(Stdio::null())
@@ -131,7 +326,338 @@ fn head_sha(work_dir: &UserWorkDir) -> Result<String, GitError> {
     .map(|x| x.trim().to_string())
 }
 
+/// Paths in the repository's work tree which have uncommitted changes,
+/// including untracked files, relative to the root of the work tree. For
+/// renames, both the old and new paths are included.
+pub fn uncommitted_paths(work_dir: &Path) -> Result<Vec<PathBuf>, GitError> {
+    let output = collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("status")
+            .arg("--porcelain")
+            .arg("-z")
+            .arg("--untracked-files=all"),
+    )?;
+    let mut paths = Vec::new();
+    let mut entries = output.split('\0').filter(|entry| !entry.is_empty());
+    while let Some(entry) = entries.next() {
+        // Entries are two status characters and a space, followed by the
+        // path. Renames and copies are followed by the original path.
+        if entry.len() < 4 {
+            continue;
+        }
+        paths.push(PathBuf::from(&entry[3..]));
+        if entry.starts_with('R') || entry.starts_with('C') {
+            if let Some(original) = entries.next() {
+                paths.push(PathBuf::from(original));
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Whether the repository's index has changes which aren't committed.
+pub fn has_staged_changes(work_dir: &Path) -> Result<bool, GitError> {
+    let cmd = Command::new("git")
+        .stdin(Stdio::null())
+        .current_dir(work_dir)
+        .arg("diff")
+        .arg("--cached")
+        .arg("--quiet")
+        .status();
+    match cmd {
+        Ok(status) => match status.code() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(GitError::ExitStatus(
+                String::from("git diff --cached --quiet"),
+                String::new(),
+                status,
+            )),
+        },
+        Err(err) => match err.kind() {
+            ErrorKind::NotFound => Err(GitError::NotFound),
+            _ => Err(GitError::OtherError(err.into())),
+        },
+    }
+}
+
+/// Stages the paths, relative to the root of the work tree, including their
+/// removals, and commits them. Ignored files aren't staged. Paths are
+/// matched literally rather than as pathspecs, so that files with names
+/// like `*.rs` don't stage other files. The commit is
+/// made by git, so that hooks run and signing is configured as usual. Yields
+/// the commit's sha, or `None` if there was nothing to commit.
+pub fn commit_paths(
+    work_dir: &Path,
+    paths: &[PathBuf],
+    message: &str,
+) -> Result<Option<String>, Error> {
+    let repo = Repository::open(work_dir)?;
+    let mut index = repo.index()?;
+    let (existing, removed): (Vec<&PathBuf>, Vec<&PathBuf>) = paths
+        .iter()
+        .partition(|path| symlink_metadata(work_dir.join(path)).is_ok());
+    index.add_all(
+        existing.iter().map(|path| path.as_path()),
+        IndexAddOption::DISABLE_PATHSPEC_MATCH,
+        None,
+    )?;
+    for path in removed {
+        index.remove_path(path)?;
+        index.remove_dir(path, 0)?;
+    }
+    index.write()?;
+    if !has_staged_changes(work_dir)? {
+        return Ok(None);
+    }
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("commit")
+            .arg("--quiet")
+            .arg("-m")
+            .arg(message),
+    )?;
+    Ok(Some(git_head_sha(&UserWorkDir::new(
+        &work_dir.to_path_buf(),
+    ))?))
+}
+
+/// Writes a tree of the work tree's files, as `git add -A` would stage them
+/// on top of the base commit. A separate index file is used, so that the
+/// repository's own index is left alone. Yields the tree's sha.
+pub fn write_work_tree(work_dir: &Path, base: &str, index_file: &Path) -> Result<String, GitError> {
+    let git = || {
+        let mut cmd = Command::new("git");
+        cmd.stdin(Stdio::null())
+            .current_dir(work_dir)
+            .env("GIT_INDEX_FILE", index_file);
+        cmd
+    };
+    collect_output(git().arg("read-tree").arg(base))?;
+    collect_output(git().arg("add").arg("--all"))?;
+    collect_output(git().arg("write-tree")).map(|x| x.trim().to_string())
+}
+
+/// Sha of the tree of the commit.
+pub fn commit_tree_sha(work_dir: &Path, commit: &str) -> Result<String, GitError> {
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("rev-parse")
+            .arg(format!("{}^{{tree}}", commit)),
+    )
+    .map(|x| x.trim().to_string())
+}
+
+/// Creates a commit of the tree, with the given parent, without updating
+/// any refs. Yields the commit's sha.
+pub fn commit_tree(
+    work_dir: &Path,
+    tree: &str,
+    parent: &str,
+    message: &str,
+) -> Result<String, GitError> {
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("commit-tree")
+            .arg(tree)
+            .arg("-p")
+            .arg(parent)
+            .arg("-m")
+            .arg(message),
+    )
+    .map(|x| x.trim().to_string())
+}
+
+/// Formats the commit as a patch, with `git format-patch`. When an output
+/// directory is given, the patch is written there and its path is yielded,
+/// otherwise the patch itself is yielded.
+pub fn format_patch(
+    work_dir: &Path,
+    commit: &str,
+    output_dir: Option<&Path>,
+) -> Result<String, GitError> {
+    let mut cmd = Command::new("git");
+    cmd.stdin(Stdio::null())
+        .current_dir(work_dir)
+        .arg("format-patch")
+        .arg("-1");
+    match output_dir {
+        Some(dir) => cmd.arg("--output-directory").arg(dir),
+        None => cmd.arg("--stdout"),
+    };
+    collect_output(cmd.arg(commit))
+}
+
+/// Creates a bundle with the commits reachable from the commit but not from
+/// the base, so the base is a prerequisite for using it. Bundles list refs
+/// rather than commits, so the ref is created for the duration.
+pub fn create_bundle(
+    work_dir: &Path,
+    bundle: &Path,
+    base: &str,
+    commit: &str,
+    ref_name: &str,
+) -> Result<(), GitError> {
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("update-ref")
+            .arg(ref_name)
+            .arg(commit)
+            // Fails if the ref already exists.
+            .arg(""),
+    )?;
+    let result = collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("bundle")
+            .arg("create")
+            .arg(bundle)
+            .arg(format!("{}..{}", base, ref_name)),
+    );
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("update-ref")
+            .arg("-d")
+            .arg(ref_name)
+            .arg(commit),
+    )?;
+    result.map(|_| ())
+}
+
+/// Hooks installed by `install_hooks`, along with the conditions under which
+/// they take a snapshot.
+const AUTO_SNAPSHOT_HOOKS: [(&str, &str); 2] = [
+    ("post-commit", ""),
+    // The third argument is 1 when switching branches, rather than checking
+    // out files.
+    ("post-checkout", "[ \"$3\" = 1 ] || exit 0\n"),
+];
+
+/// Command run by the hooks, which is also used to recognize them.
+const AUTO_SNAPSHOT_ARGS: &str = "snap --auto";
+
+/// Installs hooks into the repository which run `mzr snap --auto` after
+/// commits and checkouts. Yields the paths of the hooks which were installed,
+/// not including those which were already installed. Fails if the repository
+/// already has other hooks of the same names.
+pub fn install_hooks(work_dir: &UserWorkDir, mzr_exe: &Path) -> Result<Vec<PathBuf>, Error> {
+    let hooks_dir = work_dir.join(get_git_dir(work_dir)?).join("hooks");
+    create_dir_all(&hooks_dir)?;
+    // Quoted for the shell, with any single quotes escaped.
+    let quoted_exe = format!("'{}'", mzr_exe.to_string_lossy().replace("'", "'\\''"));
+    let mut installed = Vec::new();
+    for (hook_name, condition) in AUTO_SNAPSHOT_HOOKS.iter() {
+        let hook_path = hooks_dir.join(hook_name);
+        if hook_path.exists() {
+            if read_to_string(&hook_path)?.contains(AUTO_SNAPSHOT_ARGS) {
+                continue;
+            }
+            bail!(
+                "There is already a {} hook at {:?}. To take snapshots automatically, \
+                 add a line running \"mzr {}\" to it.",
+                hook_name,
+                hook_path,
+                AUTO_SNAPSHOT_ARGS
+            );
+        }
+        let script = format!(
+            "#!/bin/sh\n\
+             # Installed by mzr git install-hooks, to take snapshots for use by mzr zones.\n\
+             {}\
+             # Snapshots are of the work dir, so git's variables aren't needed.\n\
+             unset GIT_DIR GIT_WORK_TREE GIT_INDEX_FILE\n\
+             exec {} {}\n",
+            condition, quoted_exe, AUTO_SNAPSHOT_ARGS
+        );
+        write(&hook_path, script)?;
+        set_permissions(&hook_path, Permissions::from_mode(0o755))?;
+        installed.push(hook_path);
+    }
+    Ok(installed)
+}
+
+/// Creates a branch at the current commit, and checks it out.
+pub fn create_branch(work_dir: &UserWorkDir, branch: &str) -> Result<(), GitError> {
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("checkout")
+            .arg("-b")
+            .arg(branch),
+    )
+    .map(|_| ())
+}
+
+/// Whether the branch has been merged into the current commit.
+pub fn is_branch_merged(work_dir: &UserWorkDir, branch: &str) -> Result<bool, GitError> {
+    let mut cmd = Command::new("git");
+    cmd.stdin(Stdio::null())
+        .current_dir(work_dir)
+        .arg("merge-base")
+        .arg("--is-ancestor")
+        .arg(branch)
+        .arg("HEAD");
+    let (status, _, stderr) = collect_output_base(&mut cmd)?;
+    // Exit code 1 indicates that the branch isn't an ancestor, whereas other
+    // failures indicate errors like the branch not existing.
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(GitError::ExitStatus(format!("{:?}", cmd), stderr, status)),
+    }
+}
+
+/// Deletes the branch, which git only allows if it has been merged.
+pub fn delete_branch(work_dir: &UserWorkDir, branch: &str) -> Result<(), GitError> {
+    collect_output(
+        Command::new("git")
+            .stdin(Stdio::null())
+            .current_dir(work_dir)
+            .arg("branch")
+            .arg("-d")
+            .arg(branch),
+    )
+    .map(|_| ())
+}
+
 pub fn get_git_dir(work_dir: &UserWorkDir) -> Result<RelativeGitRepoDir, GitError> {
+    with_fallback(
+        work_dir,
+        |repo| {
+            // Like "git rev-parse --git-dir", this is relative when the git
+            // directory is within the work dir.
+            let git_dir = repo.path();
+            let canonical_work_dir = work_dir.canonicalize().ok();
+            let rel_git_dir = git_dir
+                .strip_prefix(work_dir)
+                .ok()
+                .or_else(|| {
+                    canonical_work_dir
+                        .as_ref()
+                        .and_then(|dir| git_dir.strip_prefix(dir).ok())
+                })
+                .unwrap_or(git_dir);
+            Ok(RelativeGitRepoDir::new(rel_git_dir))
+        },
+        || git_get_git_dir(work_dir),
+    )
+}
+
+fn git_get_git_dir(work_dir: &UserWorkDir) -> Result<RelativeGitRepoDir, GitError> {
     collect_output(
         Command::new("git")
             .stdin(Stdio::null())
@@ -142,6 +668,35 @@ pub fn get_git_dir(work_dir: &UserWorkDir) -> Result<RelativeGitRepoDir, GitErro
     .map(|x| RelativeGitRepoDir::new(x.trim()))
 }
 
+/// Runs a query using libgit2, which doesn't depend on the installed version
+/// of git or on parsing its output. Falls back on running git if that fails,
+/// for example due to the repository using features libgit2 doesn't support.
+///
+/// libgit2 isn't used when git's environment variables are set, since they
+/// should affect the queries.
+fn with_fallback<T, L, G>(
+    work_dir: &UserWorkDir,
+    libgit2_query: L,
+    git_query: G,
+) -> Result<T, GitError>
+where
+    L: FnOnce(&Repository) -> Result<T, git2::Error>,
+    G: FnOnce() -> Result<T, GitError>,
+{
+    if env::var_os("GIT_DIR").is_some() || env::var_os("GIT_WORK_TREE").is_some() {
+        return git_query();
+    }
+    let libgit2_err = match Repository::discover(work_dir).and_then(|repo| libgit2_query(&repo)) {
+        Ok(result) => return Ok(result),
+        Err(err) => err,
+    };
+    match git_query() {
+        // Since git isn't installed, libgit2's error is more informative.
+        Err(GitError::NotFound) => Err(GitError::Libgit2(libgit2_err)),
+        result => result,
+    }
+}
+
 fn collect_output(cmd: &mut Command) -> Result<String, GitError> {
     match collect_output_base(cmd) {
         Err(err) => Err(err),
@@ -237,6 +792,7 @@ pub enum GitError {
     NotFound,
     TooOld(Version),
     ExitStatus(String, String, ExitStatus),
+    Libgit2(git2::Error),
     OtherError(Error),
 }
 
@@ -255,6 +811,7 @@ impl fmt::Display for GitError {
                 color_cmd(cmd),
                 color_err(status)
             ),
+            GitError::Libgit2(err) => write!(f, "libgit2 error: {}", err.message()),
             GitError::OtherError(err) => err.fmt(f),
         }
     }
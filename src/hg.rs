@@ -0,0 +1,116 @@
+use crate::paths::{SnapName, UserWorkDir};
+use failure::{Error, ResultExt};
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Paths within the `.hg` directory which zones share with the work dir, and
+/// so aren't copied into snapshots. The store has the repository's history,
+/// while the rest, such as the dirstate and bookmarks, is per checkout.
+pub const SHARED_REPO_PATHS: [&str; 1] = ["store"];
+
+/// Requirement which marks a repository as a share of another, whose `.hg`
+/// directory is given by the `sharedpath` file.
+const SHARED_REQUIREMENT: &str = "shared";
+
+/// Finds the Mercurial directories at the given directories, which are
+/// relative to the work dir. Unlike git repositories, nested Mercurial
+/// repositories aren't searched for, so only those at the root of the work
+/// dir or of a project set's members are found.
+pub fn find_hg_dirs(work_dir: &Path, candidates: &[PathBuf]) -> Vec<PathBuf> {
+    candidates
+        .iter()
+        .map(|dir| dir.join(".hg"))
+        .filter(|rel_hg_dir| work_dir.join(rel_hg_dir).join("store").is_dir())
+        .collect()
+}
+
+/// Makes the zone's copy of the `.hg` directory a share of the source
+/// repository, like `hg share` does, but without checking out files. Its
+/// store is then the source's, while its dirstate and bookmarks are its own.
+/// The requirements are copied from the snapshot, since the target is the
+/// zone's changes dir, which doesn't have them until modified.
+///
+/// Like `git::symlink_git_repo`, this is idempotent.
+pub fn share_hg_repo(
+    source_hg_dir: &Path,
+    snap_hg_dir: &Path,
+    target_hg_dir: &Path,
+) -> Result<(), Error> {
+    create_dir_all(target_hg_dir)?;
+    let shared_path_file = target_hg_dir.join("sharedpath");
+    let shared_path = source_hg_dir.to_string_lossy().to_string();
+    match read_to_string(&shared_path_file) {
+        Ok(existing) => {
+            if existing != shared_path {
+                bail!(
+                    "Expected {:?} to refer to {:?}, but instead it refers to {:?}",
+                    shared_path_file,
+                    shared_path,
+                    existing
+                );
+            }
+        }
+        Err(_) => write(&shared_path_file, &shared_path).context(format_err!(
+            "Failed to write {:?} to share the Mercurial repository at {:?}",
+            shared_path_file,
+            source_hg_dir
+        ))?,
+    }
+    let requires_file = target_hg_dir.join("requires");
+    let requires = match read_to_string(&requires_file) {
+        Ok(requires) => requires,
+        Err(_) => read_to_string(snap_hg_dir.join("requires")).unwrap_or_default(),
+    };
+    if !requires.lines().any(|line| line == SHARED_REQUIREMENT) {
+        let mut lines: Vec<&str> = requires.lines().collect();
+        lines.push(SHARED_REQUIREMENT);
+        lines.sort();
+        write(&requires_file, format!("{}\n", lines.join("\n")))?;
+    }
+    Ok(())
+}
+
+/// Names the snapshot after the active bookmark, or otherwise the named
+/// branch when it isn't `default`, or otherwise the short hash of the working
+/// directory's parent.
+pub fn default_snap_name(work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+    let output = Command::new("hg")
+        .stdin(Stdio::null())
+        .current_dir(work_dir)
+        .args(&[
+            "log",
+            "-r",
+            ".",
+            "-T",
+            "{activebookmark}\\n{branch}\\n{node|short}\\n",
+        ])
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => bail!(
+            "There's a Mercurial repository at {}, but 'hg' isn't on your PATH environment \
+             variable.",
+            work_dir
+        ),
+        Err(err) => Err(err)?,
+    };
+    if !output.status.success() {
+        bail!(
+            "Since no snapshot was specified, attempted to query hg for the current bookmark, \
+             branch or hash. hg exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let fields: Vec<&str> = stdout.lines().collect();
+    let name = match fields.as_slice() {
+        [bookmark, _, _] if !bookmark.is_empty() => bookmark,
+        [_, branch, _] if *branch != "default" => branch,
+        [_, _, node] => node,
+        _ => bail!("Unexpected output from hg log: {:?}", stdout),
+    };
+    SnapName::new(name.to_string())
+}
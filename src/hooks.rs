@@ -0,0 +1,86 @@
+use crate::colors::*;
+use crate::config::Config;
+use crate::paths::*;
+use crate::utils::run_process;
+use failure::{Error, ResultExt};
+use std::fmt::{self, Display, Formatter};
+use std::process::{Command, Stdio};
+
+/// Points in the lifecycle of zones, snapshots, and merges at which
+/// user-provided commands are run. For each hook, the executable at
+/// `HOOKS_DIR/NAME` is run if it exists, followed by the commands configured
+/// for it in the config's `hooks`, which are run via `sh -c`.
+///
+/// Context is passed via `MZR_HOOK_*` environment variables, rather than the
+/// `MZR_DIR` and `MZR_ZONE` variables which are set within zones, since hooks
+/// run outside of zones.
+#[derive(Debug, Clone, Copy)]
+pub enum Hook {
+    /// After a zone is created, with `MZR_HOOK_ZONE` and
+    /// `MZR_HOOK_SNAPSHOT`.
+    ZoneCreate,
+    /// Before entering a zone via `mzr shell`, `mzr exec`, or `mzr run`,
+    /// with `MZR_HOOK_ZONE`.
+    ZoneEnter,
+    /// Before planning a merge, with `MZR_HOOK_ZONE` and
+    /// `MZR_HOOK_TARGET_DIR`. If it fails, the merge is cancelled.
+    PreMerge,
+    /// After a merge is applied, with the same variables as `PreMerge`.
+    PostMerge,
+    /// After a snapshot of the work dir is taken, with `MZR_HOOK_SNAPSHOT`.
+    SnapshotCreate,
+}
+
+impl Hook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hook::ZoneCreate => "zone-create",
+            Hook::ZoneEnter => "zone-enter",
+            Hook::PreMerge => "pre-merge",
+            Hook::PostMerge => "post-merge",
+            Hook::SnapshotCreate => "snapshot-create",
+        }
+    }
+}
+
+impl Display for Hook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Runs the hook's script and configured commands, stopping at the first
+/// which fails. `vars` are set in their environment, along with
+/// `MZR_HOOK` and `MZR_HOOK_MZR_DIR`.
+pub fn run(mzr_dir: &MzrDir, hook: Hook, vars: &[(&str, &str)]) -> Result<(), Error> {
+    let mut cmds = Vec::new();
+    let script = HooksDir::new(mzr_dir).join(hook.name());
+    if script.is_file() {
+        cmds.push(Command::new(&script));
+    }
+    if let Some(configured) = Config::load(mzr_dir)?.hooks.get(hook.name()) {
+        for cmd_string in configured {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(cmd_string);
+            cmds.push(cmd);
+        }
+    }
+    for mut cmd in cmds {
+        cmd.stdin(Stdio::null())
+            .env("MZR_HOOK", hook.name())
+            .env("MZR_HOOK_MZR_DIR", mzr_dir.as_os_str());
+        for (name, value) in vars {
+            cmd.env(name, value);
+        }
+        run_process(&mut cmd).context(format_err!("The {} hook failed", hook))?;
+    }
+    Ok(())
+}
+
+/// Like `run`, but only warns on failure, for hooks which don't gate the
+/// operation.
+pub fn run_or_warn(mzr_dir: &MzrDir, hook: Hook, vars: &[(&str, &str)]) {
+    if let Err(err) = run(mzr_dir, hook, vars) {
+        println!("{} {}", color_warn(&"Warning:"), err);
+    }
+}
@@ -0,0 +1,74 @@
+use failure::Error;
+use libc::{c_int, c_ulong};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// ioctls for the inode flags shown by `lsattr`, from `linux/fs.h`.
+const FS_IOC_GETFLAGS: c_ulong = 0x8008_6601;
+const FS_IOC_SETFLAGS: c_ulong = 0x4008_6602;
+
+/// Flag which prevents any modification of the inode, as set by `chattr +i`.
+const FS_IMMUTABLE_FL: c_int = 0x10;
+
+/// Sets or clears the immutable attribute of the directory and everything
+/// within it. Setting it requires `CAP_LINUX_IMMUTABLE` in the initial user
+/// namespace, and a filesystem which supports it, so yields `false` rather
+/// than failing when it isn't permitted. Only directories and regular files
+/// are changed, since opening other entries may follow symlinks or have side
+/// effects.
+///
+/// Hardlinked files, such as those deduplicated into the objects store, share
+/// their inode with other snapshots, so the attribute isn't set on them.
+/// Otherwise they'd become immutable in snapshots which aren't, and couldn't
+/// be replaced when those are updated. It's still cleared from them, for
+/// snapshots made immutable before this was the case.
+pub fn set_tree(dir: &Path, immutable: bool) -> Result<bool, Error> {
+    for entry in WalkDir::new(dir).same_file_system(true) {
+        let entry = entry?;
+        let file_type = entry.file_type();
+        if !file_type.is_dir() && !file_type.is_file() {
+            continue;
+        }
+        if immutable && file_type.is_file() && entry.metadata()?.nlink() > 1 {
+            continue;
+        }
+        match set_flag(entry.path(), immutable) {
+            Ok(()) => {}
+            Err(err) => match err.raw_os_error() {
+                Some(libc::EPERM)
+                | Some(libc::EACCES)
+                | Some(libc::ENOTTY)
+                | Some(libc::EOPNOTSUPP)
+                | Some(libc::EINVAL) => return Ok(false),
+                _ => Err(err)?,
+            },
+        }
+    }
+    Ok(true)
+}
+
+fn set_flag(path: &Path, immutable: bool) -> io::Result<()> {
+    let file: File = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)?;
+    let mut flags: c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let new_flags = if immutable {
+        flags | FS_IMMUTABLE_FL
+    } else {
+        flags & !FS_IMMUTABLE_FL
+    };
+    if new_flags != flags
+        && unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS, &new_flags) } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
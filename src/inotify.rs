@@ -0,0 +1,237 @@
+use failure::Error;
+use libc::{c_char, c_int, c_void};
+use nix::poll::{poll, EventFlags, PollFd};
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use walkdir::WalkDir;
+
+// The libc crate doesn't yet have bindings for inotify, so they are declared
+// here.
+extern "C" {
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+}
+
+pub const IN_MODIFY: u32 = 0x0000_0002;
+pub const IN_ATTRIB: u32 = 0x0000_0004;
+pub const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+pub const IN_MOVED_FROM: u32 = 0x0000_0040;
+pub const IN_MOVED_TO: u32 = 0x0000_0080;
+pub const IN_CREATE: u32 = 0x0000_0100;
+pub const IN_DELETE: u32 = 0x0000_0200;
+pub const IN_Q_OVERFLOW: u32 = 0x0000_4000;
+pub const IN_IGNORED: u32 = 0x0000_8000;
+pub const IN_ONLYDIR: u32 = 0x0100_0000;
+pub const IN_ISDIR: u32 = 0x4000_0000;
+
+/// Events which indicate that the contents or metadata of a path changed.
+pub const IN_CHANGES: u32 =
+    IN_MODIFY | IN_ATTRIB | IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE;
+
+/// Layout of the fixed size header of each event, as in `sys/inotify.h`. It
+/// is followed by `len` bytes of NUL padded name.
+#[repr(C)]
+struct RawEvent {
+    wd: c_int,
+    mask: u32,
+    cookie: u32,
+    len: u32,
+}
+
+#[derive(Debug)]
+pub struct Event {
+    pub wd: c_int,
+    pub mask: u32,
+    /// Name of the file within the watched directory, if the event is about
+    /// a directory entry.
+    pub name: Option<OsString>,
+}
+
+/// An inotify instance, which is closed when dropped.
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> Result<Inotify, Error> {
+        let fd = unsafe { inotify_init1(libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Inotify { fd })
+    }
+
+    pub fn add_watch<P: AsRef<Path>>(&self, path: P, mask: u32) -> Result<c_int, Error> {
+        let path = path.as_ref();
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { inotify_add_watch(self.fd, c_path.as_ptr(), mask) };
+        if wd < 0 {
+            bail!("Failed to watch {:?}: {}", path, io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Waits for events to be available, yielding false if the timeout elapses
+    /// first.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, Error> {
+        let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+        let mut fds = [PollFd::new(self.fd, EventFlags::POLLIN)];
+        Ok(poll(&mut fds, millis.min(c_int::max_value() as u64) as c_int)? > 0)
+    }
+
+    /// Reads the available events, blocking until there are some.
+    pub fn read_events(&self) -> Result<Vec<Event>, Error> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let len = len as usize;
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + size_of::<RawEvent>() <= len {
+            let raw = unsafe { &*(buffer.as_ptr().add(offset) as *const RawEvent) };
+            let name_start = offset + size_of::<RawEvent>();
+            let name_end = name_start + raw.len as usize;
+            let name = if raw.len > 0 {
+                let bytes = &buffer[name_start..name_end];
+                let nul = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                Some(OsString::from_vec(bytes[..nul].to_vec()))
+            } else {
+                None
+            };
+            events.push(Event {
+                wd: raw.wd,
+                mask: raw.mask,
+                name,
+            });
+            offset = name_end;
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Paths that changed within a watched tree.
+pub enum Changes {
+    /// Paths relative to the root of the tree.
+    Paths(Vec<PathBuf>),
+    /// The kernel's event queue overflowed, so some changes weren't reported.
+    Overflow,
+}
+
+/// Watches a directory tree for changes, including directories created after
+/// the watch started.
+pub struct TreeWatcher {
+    inotify: Inotify,
+    root: PathBuf,
+    /// Maps watch descriptors to the directories they watch, relative to the
+    /// root.
+    dirs: HashMap<c_int, PathBuf>,
+}
+
+impl TreeWatcher {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<TreeWatcher, Error> {
+        Ok(TreeWatcher::new_listing_paths(root)?.0)
+    }
+
+    /// Starts watching the tree, also yielding the paths of the files and
+    /// directories within it, relative to the root. Since watches are added
+    /// before directories are listed, any path not in the listing will be
+    /// reported as a change.
+    pub fn new_listing_paths<P: AsRef<Path>>(
+        root: P,
+    ) -> Result<(TreeWatcher, Vec<PathBuf>), Error> {
+        let mut watcher = TreeWatcher {
+            inotify: Inotify::new()?,
+            root: root.as_ref().to_path_buf(),
+            dirs: HashMap::new(),
+        };
+        let paths = watcher.watch_tree(&PathBuf::new())?;
+        Ok((watcher, paths))
+    }
+
+    /// Waits for changes, yielding false if the timeout elapses first.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, Error> {
+        self.inotify.wait(timeout)
+    }
+
+    /// Reads the changes, blocking until there are some.
+    pub fn read_changes(&mut self) -> Result<Changes, Error> {
+        let mut paths = Vec::new();
+        for event in self.inotify.read_events()? {
+            if event.mask & IN_Q_OVERFLOW != 0 {
+                return Ok(Changes::Overflow);
+            }
+            // The watch was removed, because the directory was deleted.
+            if event.mask & IN_IGNORED != 0 {
+                self.dirs.remove(&event.wd);
+                continue;
+            }
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir.clone(),
+                None => continue,
+            };
+            let rel_path = match &event.name {
+                Some(name) => dir.join(name),
+                None => continue,
+            };
+            let is_new_dir =
+                event.mask & IN_ISDIR != 0 && event.mask & (IN_CREATE | IN_MOVED_TO) != 0;
+            if is_new_dir {
+                // Files may have been created in the directory before it was
+                // watched, so they are reported as changed too, along with
+                // the directory itself.
+                paths.extend(self.watch_tree(&rel_path)?);
+            } else {
+                paths.push(rel_path);
+            }
+        }
+        Ok(Changes::Paths(paths))
+    }
+
+    /// Watches the directory and its subdirectories, yielding the paths of
+    /// the directory and the entries within it, other than the root of the
+    /// tree. Directories are included so that created and opaque directories
+    /// get planned.
+    fn watch_tree(&mut self, rel_dir: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
+        for entry in WalkDir::new(self.root.join(rel_dir)).same_file_system(true) {
+            // Entries may be removed while walking.
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let rel_path = entry.path().strip_prefix(&self.root)?.to_path_buf();
+            if entry.file_type().is_dir() {
+                match self
+                    .inotify
+                    .add_watch(entry.path(), IN_CHANGES | IN_ONLYDIR)
+                {
+                    Ok(wd) => {
+                        self.dirs.insert(wd, rel_path.clone());
+                    }
+                    Err(_) if !entry.path().exists() => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if !rel_path.as_os_str().is_empty() {
+                paths.push(rel_path);
+            }
+        }
+        Ok(paths)
+    }
+}
@@ -0,0 +1,311 @@
+//! Communication between mzr processes: clients and the daemon, the daemon
+//! and the processes it forks, and parents and children across namespaces.
+//! Each uses a `Channel`, which sends serde values as framed messages.
+
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fmt::Debug;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Starts each framed message. Since it begins with a zero byte, it can't be
+/// confused with the JSON lines sent by older clients and by JSON-RPC
+/// clients, see `Message::Line`.
+const MAGIC: &[u8; 4] = b"\0mzr";
+
+/// Version of the messages exchanged by mzr and its daemon, sent in each
+/// frame's header. It's incremented when a change to `Request` or `Response`
+/// can't be handled by older versions, such as a changed variant. Adding
+/// optional fields doesn't need a new version, since unknown fields are
+/// ignored when decoding. Payloads of this version are CBOR.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Protocol version whose payloads are JSON. It's still decoded, and used
+/// for responses to clients which send it.
+pub const JSON_PROTOCOL_VERSION: u16 = 1;
+
+/// Largest payload accepted, so that a corrupt length doesn't cause a huge
+/// allocation.
+const MAX_PAYLOAD_BYTES: u32 = 256 * 1024 * 1024;
+
+/// Environment variable which, when set to 1, causes each message sent or
+/// received to be logged to stderr along with its frame header.
+pub const DEBUG_VAR: &str = "MZR_DEBUG_IPC";
+
+/// A connection over which framed messages are sent and received.
+pub struct Channel<S: Read + Write> {
+    reader: BufReader<S>,
+}
+
+impl<S: Read + Write> Channel<S> {
+    pub fn new(stream: S) -> Channel<S> {
+        Channel {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    pub fn send<T: Serialize + Debug>(&mut self, value: &T) -> Result<(), Error> {
+        write(self.reader.get_mut(), value)
+    }
+
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Result<T, Error> {
+        read(&mut self.reader)
+    }
+
+    /// Receives a message without decoding it, which may also be a JSON
+    /// line, see `Message::Line`.
+    pub fn recv_message(&mut self) -> Result<Message, Error> {
+        read_message(&mut self.reader)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+}
+
+impl Channel<UnixStream> {
+    /// Connected channels, for a process to communicate with a child it
+    /// forks. Both ends are close-on-exec, so they aren't inherited by
+    /// commands either process runs.
+    pub fn pair() -> Result<(Channel<UnixStream>, Channel<UnixStream>), Error> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Channel::new(a), Channel::new(b)))
+    }
+}
+
+/// Streams whose sends and receives can time out.
+pub trait Timeouts {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()>;
+}
+
+impl Timeouts for UnixStream {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()> {
+        self.set_write_timeout(send)?;
+        self.set_read_timeout(recv)
+    }
+}
+
+impl<'a> Timeouts for &'a UnixStream {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()> {
+        (*self).set_timeouts(send, recv)
+    }
+}
+
+impl Timeouts for TcpStream {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()> {
+        self.set_write_timeout(send)?;
+        self.set_read_timeout(recv)
+    }
+}
+
+impl<S: Read + Write + Timeouts> Channel<S> {
+    /// Sets how long sends and receives wait before failing with an io
+    /// error of kind `WouldBlock` or `TimedOut`. `None` waits indefinitely.
+    pub fn set_timeouts(
+        &self,
+        send: Option<Duration>,
+        recv: Option<Duration>,
+    ) -> Result<(), Error> {
+        Ok(self.get_ref().set_timeouts(send, recv)?)
+    }
+}
+
+/// A message received by the daemon.
+#[derive(Debug)]
+pub enum Message {
+    /// A framed message, consisting of `MAGIC`, the protocol version as a
+    /// big endian u16, the payload length as a big endian u32, and then the
+    /// payload, which is the message serialized as CBOR, or as JSON for
+    /// `JSON_PROTOCOL_VERSION`.
+    Frame { version: u16, payload: Vec<u8> },
+    /// A newline terminated JSON message, as sent by JSON-RPC clients and
+    /// by mzr before messages were framed.
+    Line(Vec<u8>),
+}
+
+impl Message {
+    /// The serialized message, without its header.
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Message::Frame { payload, .. } => payload,
+            Message::Line(line) => line,
+        }
+    }
+
+    /// Protocol version to respond to the message with, being the newest
+    /// one that both ends understand. `None` for lines, which are responded
+    /// to with unframed JSON.
+    pub fn response_version(&self) -> Option<u16> {
+        match self {
+            Message::Frame { version, .. } => Some((*version).min(PROTOCOL_VERSION)),
+            Message::Line(_) => None,
+        }
+    }
+
+    /// Decodes the payload, failing if it's from a newer protocol version.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let result = match self {
+            Message::Frame { version, payload } if *version == PROTOCOL_VERSION => {
+                serde_cbor::from_slice(payload).map_err(Error::from)
+            }
+            Message::Frame { version, payload } if *version == JSON_PROTOCOL_VERSION => {
+                serde_json::from_slice(payload).map_err(Error::from)
+            }
+            Message::Frame { version, .. } => bail!(
+                "Received a message with protocol version {}, but this mzr only understands \
+                 versions {} to {}. mzr and its daemon likely differ in version, which mzr \
+                 daemon --restart fixes.",
+                version,
+                JSON_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ),
+            Message::Line(line) => serde_json::from_slice(line).map_err(Error::from),
+        };
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => bail!(
+                "Failed to decode message, which may be due to mzr and its daemon differing \
+                 in version: {}",
+                err
+            ),
+        }
+    }
+
+    /// Encodes the message as it was received, for forwarding it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Message::Frame { version, payload } => frame(*version, payload),
+            Message::Line(line) => line.clone(),
+        }
+    }
+}
+
+/// Frames the value with the current protocol version.
+pub fn encode<T: Serialize + Debug>(value: &T) -> Result<Vec<u8>, Error> {
+    encode_version(PROTOCOL_VERSION, value)
+}
+
+/// Frames the value with the given protocol version, see
+/// `Message::response_version`.
+pub fn encode_version<T: Serialize + Debug>(version: u16, value: &T) -> Result<Vec<u8>, Error> {
+    let payload = if version == JSON_PROTOCOL_VERSION {
+        serde_json::to_vec(value)?
+    } else {
+        serde_cbor::to_vec(value)?
+    };
+    if debug_enabled() {
+        eprintln!(
+            "mzr ipc: --> v{} ({} bytes) {:?}",
+            version,
+            payload.len(),
+            value
+        );
+    }
+    Ok(frame(version, &payload))
+}
+
+fn frame(version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 6 + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    let length = payload.len() as u32;
+    bytes.extend_from_slice(&[(version >> 8) as u8, version as u8]);
+    bytes.extend_from_slice(&[
+        (length >> 24) as u8,
+        (length >> 16) as u8,
+        (length >> 8) as u8,
+        length as u8,
+    ]);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Writes the value as a framed message.
+pub fn write<W: Write, T: Serialize + Debug>(writer: W, value: &T) -> Result<(), Error> {
+    write_version(writer, PROTOCOL_VERSION, value)
+}
+
+/// Writes the value as a framed message of the given protocol version.
+pub fn write_version<W: Write, T: Serialize + Debug>(
+    mut writer: W,
+    version: u16,
+    value: &T,
+) -> Result<(), Error> {
+    writer.write_all(&encode_version(version, value)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a message, which is either framed or a JSON line.
+pub fn read_message<R: BufRead>(reader: &mut R) -> Result<Message, Error> {
+    let is_frame = match reader.fill_buf()?.first() {
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed before a message was received",
+        ))?,
+        Some(byte) => *byte == MAGIC[0],
+    };
+    if !is_frame {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        return Ok(Message::Line(line));
+    }
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        bail!("Received a message with an invalid header: {:?}", header);
+    }
+    let version = header[4..6]
+        .iter()
+        .fold(0u16, |acc, byte| (acc << 8) | u16::from(*byte));
+    let length = header[6..10]
+        .iter()
+        .fold(0u32, |acc, byte| (acc << 8) | u32::from(*byte));
+    if length > MAX_PAYLOAD_BYTES {
+        bail!(
+            "Received a message claiming to be {} bytes, which is larger than the limit of {}.",
+            length,
+            MAX_PAYLOAD_BYTES
+        );
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    let message = Message::Frame { version, payload };
+    if debug_enabled() {
+        // CBOR isn't readable as is, so it's logged decoded.
+        let decoded = if version == PROTOCOL_VERSION {
+            match serde_cbor::from_slice::<serde_cbor::Value>(message.payload()) {
+                Ok(value) => format!("{:?}", value),
+                Err(err) => format!("(invalid CBOR: {})", err),
+            }
+        } else {
+            String::from_utf8_lossy(message.payload()).into_owned()
+        };
+        eprintln!("mzr ipc: <-- v{} ({} bytes) {}", version, length, decoded);
+    }
+    Ok(message)
+}
+
+/// Reads a framed message and decodes it. Since the length is known, this
+/// doesn't rely on the sender closing the connection.
+pub fn read<R: BufRead, T: DeserializeOwned>(reader: &mut R) -> Result<T, Error> {
+    read_message(reader)?.decode()
+}
+
+/// Whether the error is due to the connection being closed between
+/// messages, which is how a peer indicates it has nothing more to send.
+pub fn is_closed(err: &Error) -> bool {
+    match err.downcast_ref::<io::Error>() {
+        Some(io_err) => io_err.kind() == io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
+}
+
+fn debug_enabled() -> bool {
+    env::var(DEBUG_VAR)
+        .map(|value| value == "1")
+        .unwrap_or(false)
+}
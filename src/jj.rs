@@ -0,0 +1,116 @@
+use crate::paths::{SnapName, UserWorkDir};
+use failure::{Error, ResultExt};
+use std::fs::{create_dir_all, read_link};
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Paths within the `.jj` directory which are symlinked to the shared
+/// repository by `symlink_jj_repo`. The `repo` dir has the operation log and
+/// the store, while `working_copy` is specific to each checkout, so zones
+/// keep their own copy of it.
+pub const SHARED_REPO_PATHS: [&str; 1] = ["repo"];
+
+/// Finds jj directories, relative to the work dir. jj repositories are
+/// usually colocated with git, so rather than walking the whole work dir,
+/// this checks the work dir itself and the directories containing its git
+/// repositories.
+pub fn find_jj_dirs(work_dir: &Path, rel_git_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::new()];
+    for rel_git_dir in rel_git_dirs {
+        if rel_git_dir.file_name().map_or(false, |name| name == ".git") {
+            if let Some(parent) = rel_git_dir.parent() {
+                candidates.push(parent.to_path_buf());
+            }
+        }
+    }
+    candidates.sort();
+    candidates.dedup();
+    candidates
+        .into_iter()
+        .map(|dir| dir.join(".jj"))
+        .filter(|rel_jj_dir| work_dir.join(rel_jj_dir).join("repo").exists())
+        .collect()
+}
+
+/// Symlinks the shared parts of the jj directory to the source repository,
+/// like `git::symlink_git_repo`. Since the `.jj/repo` of a colocated
+/// repository refers to its git repository by relative path, the zone's jj
+/// uses the zone's git directory, which in turn shares the git repository.
+///
+/// The zone's working copy has the same workspace id as the work dir's, so
+/// after jj is used in one of them, the other may find its working copy
+/// stale, which `jj workspace update-stale` fixes.
+pub fn symlink_jj_repo(source_jj_dir: &Path, target_jj_dir: &Path) -> Result<(), Error> {
+    for shared_path in SHARED_REPO_PATHS.iter() {
+        let source_path = source_jj_dir.join(shared_path);
+        let target_path = target_jj_dir.join(shared_path);
+        match read_link(&target_path) {
+            Err(_) => {
+                create_dir_all(target_jj_dir)?;
+                symlink(&source_path, &target_path).context(format_err!(
+                    "Failed to create jj repo symlink at {:?}, pointing to {:?}",
+                    target_path,
+                    source_path
+                ))?;
+            }
+            Ok(existing_link) => {
+                if existing_link != source_path {
+                    bail!(
+                        "Expected {:?} to be a symbolic link to {:?}, but instead it points at {:?}",
+                        &target_path,
+                        &source_path,
+                        &existing_link
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names the snapshot after a bookmark on the working copy commit or its
+/// parent, or otherwise the working copy's change id. The working copy isn't
+/// snapshotted, so that this doesn't add to the operation log.
+pub fn default_snap_name(work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+    let output = Command::new("jj")
+        .stdin(Stdio::null())
+        .current_dir(work_dir)
+        .args(&["log", "--ignore-working-copy", "--no-graph", "-r", "@ | @-"])
+        .args(&["-T", "change_id.short() ++ \" \" ++ bookmarks ++ \"\\n\""])
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => bail!(
+            "There's a jj repository at {}, but 'jj' isn't on your PATH environment variable.",
+            work_dir
+        ),
+        Err(err) => Err(err)?,
+    };
+    if !output.status.success() {
+        bail!(
+            "Since no snapshot was specified, attempted to query jj for the current change \
+             id or bookmark. jj exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let commits: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    // Bookmarks which differ from their remotes are suffixed with `*`, and
+    // conflicted ones with `??`.
+    let bookmark = commits
+        .iter()
+        .filter_map(|fields| fields.get(1))
+        .map(|bookmark| bookmark.trim_end_matches(|c| c == '*' || c == '?'))
+        .find(|bookmark| !bookmark.is_empty());
+    match (bookmark, commits.first().and_then(|fields| fields.first())) {
+        (Some(bookmark), _) => SnapName::new(bookmark.to_string()),
+        (None, Some(change_id)) => SnapName::new(change_id.to_string()),
+        (None, None) => bail!("jj didn't list the working copy commit."),
+    }
+}
@@ -0,0 +1,126 @@
+use crate::daemon;
+use crate::inotify::{Changes, TreeWatcher};
+use crate::paths::*;
+use crate::zone::Zone;
+use failure::Error;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, remove_file, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+/// First line of journal files.
+const HEADER: &[u8] = b"mzr-journal\n";
+
+/// Channel used to ask the journaling thread to write all of the changes that
+/// inotify has reported so far. It replies once they are in the journal.
+pub type JournalSync = Sender<Sender<()>>;
+
+/// Starts maintaining a journal of the paths which might have changed within
+/// the zone's changes dir, so that merge planning can avoid walking the whole
+/// directory. The journal starts with a listing of the changes dir, including
+/// its directories, and
+/// then paths are appended to it as inotify reports changes to them.
+///
+/// Journaling stops when the returned sender is dropped. If inotify reports
+/// that events were lost, then the journal is removed, and merge planning
+/// falls back on walking the changes dir.
+///
+/// `on_changes` is called with the number of paths in each batch of changes
+/// written to the journal.
+pub fn start<F>(zone: &Zone, on_changes: F) -> Result<JournalSync, Error>
+where
+    F: Fn(usize) + Send + 'static,
+{
+    let journal_file = ChangeJournalFile::new(&zone.zone_dir);
+    let (mut watcher, paths) = TreeWatcher::new_listing_paths(&zone.ovfs_changes_dir)?;
+    let mut writer = BufWriter::new(File::create(&journal_file)?);
+    writer.write_all(HEADER)?;
+    write_paths(&mut writer, &paths)?;
+    let (sync_sender, sync_receiver) = channel();
+    let zone_name = zone.name.clone();
+    thread::spawn(move || {
+        let result = journal_changes(&mut watcher, &mut writer, &sync_receiver, &on_changes);
+        if let Err(err) = result {
+            println!("Stopped journaling changes to zone {}: {}", zone_name, err);
+        }
+        let _ = remove_file(&journal_file);
+    });
+    Ok(sync_sender)
+}
+
+fn journal_changes(
+    watcher: &mut TreeWatcher,
+    writer: &mut BufWriter<File>,
+    sync_receiver: &Receiver<Sender<()>>,
+    on_changes: &dyn Fn(usize),
+) -> Result<(), Error> {
+    loop {
+        if watcher.wait(Duration::from_millis(100))? {
+            on_changes(write_changes(watcher, writer)?);
+        }
+        loop {
+            match sync_receiver.try_recv() {
+                Ok(reply) => {
+                    // Any changes made before the sync request was sent have
+                    // already been queued by the kernel.
+                    while watcher.wait(Duration::from_millis(0))? {
+                        on_changes(write_changes(watcher, writer)?);
+                    }
+                    let _ = reply.send(());
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Writes the changes reported by inotify, yielding how many paths changed.
+fn write_changes(watcher: &mut TreeWatcher, writer: &mut BufWriter<File>) -> Result<usize, Error> {
+    match watcher.read_changes()? {
+        Changes::Paths(paths) => {
+            write_paths(writer, &paths)?;
+            Ok(paths.len())
+        }
+        Changes::Overflow => bail!("Some changes weren't reported by inotify."),
+    }
+}
+
+/// Reads the paths within the zone's changes dir that might have changed,
+/// relative to the changes dir. Yields `None` if there isn't an up to date
+/// journal, which is the case when the daemon isn't journaling the zone.
+pub fn read(zone: &Zone) -> Result<Option<BTreeSet<PathBuf>>, Error> {
+    let journal_file = ChangeJournalFile::new(&zone.zone_dir);
+    if !journal_file.exists() || daemon::sync_journal(&zone.mzr_dir, &zone.name).is_err() {
+        return Ok(None);
+    }
+    let contents = fs::read(&journal_file)?;
+    if !contents.starts_with(HEADER) {
+        bail!("Journal file {} has an unexpected header.", journal_file);
+    }
+    let mut segments: Vec<&[u8]> = contents[HEADER.len()..].split(|b| *b == 0).collect();
+    // The last segment is either empty or a path which is still being
+    // written, and so is ignored.
+    segments.pop();
+    Ok(Some(
+        segments
+            .into_iter()
+            .map(|path| PathBuf::from(OsString::from_vec(path.to_vec())))
+            .collect(),
+    ))
+}
+
+/// Writes NUL terminated paths, since paths may contain newlines.
+fn write_paths(writer: &mut BufWriter<File>, paths: &[PathBuf]) -> Result<(), Error> {
+    for path in paths {
+        writer.write_all(path.as_os_str().as_bytes())?;
+        writer.write_all(b"\0")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
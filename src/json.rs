@@ -1,10 +1,11 @@
 use chrono::{DateTime, Utc};
-use failure::Error;
+use failure::{Error, ResultExt};
 use semver::Version;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::path::PathBuf;
 
 const VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
@@ -37,9 +38,71 @@ pub fn write<T: Serialize>(path: &PathBuf, value: &T) -> Result<(), Error> {
     Ok(())
 }
 
+/// Checks that a JSON file was written by a version of mzr which uses a
+/// compatible format. Before version 1.0, differing minor versions are
+/// considered incompatible.
+pub fn check_writer(writer: &WriterInfo) -> Result<(), Error> {
+    let current = Version::parse(VERSION_STRING)?;
+    let other = &writer.mzr_version;
+    if writer.program != "mzr" {
+        bail!(
+            "Expected file to be written by mzr, but it was written by {:?}",
+            writer.program
+        );
+    }
+    if other.major != current.major || (current.major == 0 && other.minor != current.minor) {
+        bail!(
+            "File was written by mzr version {}, which is incompatible with this version {}",
+            other,
+            current
+        );
+    }
+    Ok(())
+}
+
 pub fn read<T>(path: &PathBuf) -> Result<JsonFile<T>, Error>
 where
     T: DeserializeOwned,
 {
     Ok(serde_json::from_reader(File::open(path)?)?)
 }
+
+/// Appends the value to a file of JSON values, one per line, creating the
+/// file if necessary. Unlike `write`, there's no writer info, since each line
+/// is written separately. The line is written with a single write to a file
+/// opened for appending, so lines from concurrent writers aren't interleaved.
+pub fn append_line<T: Serialize>(path: &PathBuf, value: &T) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Reads a file written by `append_line`. A missing file has no lines.
+pub fn read_lines<T>(path: &PathBuf) -> Result<Vec<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut values = Vec::new();
+    for (ix, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        values.push(serde_json::from_str(&line).context(format_err!(
+            "Failed to parse line {} of {:?}",
+            ix + 1,
+            path
+        ))?);
+    }
+    Ok(values)
+}
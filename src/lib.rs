@@ -11,212 +11,4891 @@
 #[macro_use]
 extern crate failure;
 
+mod baseline;
+mod cgroups;
+mod checkpoints;
+mod cleanup;
 pub mod colors;
+mod compaction;
+mod config;
+mod conflicts;
+mod copier;
 mod daemon;
+mod dir_size;
+mod display;
+mod errors;
+mod events;
+mod freezer;
+mod fs_probe;
 mod git;
+mod hg;
+mod hooks;
+mod immutable;
+mod inotify;
+mod ipc;
+mod jj;
+mod journal;
 mod json;
+mod lsp_proxy;
 mod merge;
+mod merge_txn;
+mod metrics;
+mod mount;
+mod mountinfo;
 mod namespaces;
+mod objects;
+mod overlay;
 mod paths;
+mod privileges;
+mod project_set;
+mod rebase;
+mod remote;
+mod remote_daemon;
+mod retention;
+mod rpc;
+mod run_info;
+mod run_matrix;
+mod sandbox;
+#[cfg(feature = "self-update")]
+mod self_update;
+mod shared;
 mod snapshot;
+mod snapshot_archive;
+mod snapshot_manifest;
+mod subscriptions;
+mod template;
 mod top_dirs;
+mod ui;
 mod utils;
+mod version;
+mod watch;
 mod zone;
+mod zone_bundle;
+mod zone_copy;
+mod zone_patch;
+mod zone_stats;
 
+use crate::cgroups::{ByteSize, Cgroup, Limits};
+use crate::cleanup::Interrupts;
 use crate::colors::color_dir;
+use crate::compaction::Criteria;
+use crate::config::Config;
+use crate::daemon::DaemonAddr;
+use crate::display::Humanize;
+use crate::errors::{kind_error, ErrorKind, JsonError};
+use crate::events::EventKind;
+use crate::hooks::Hook;
 use crate::merge::{interactive_merge, Mode};
-use crate::paths::{SnapName, ZoneName};
-use crate::top_dirs::TopDirs;
+use crate::paths::{
+    ConfigFile, DaemonDir, DaemonLogStderrFile, DaemonLogStdoutFile, MergeBackupsDir, ObjectsDir,
+    SnapDir, SnapName, ZoneDir, ZoneName,
+};
+use crate::project_set::ProjectSet;
+use crate::remote::Remote;
+use crate::retention::{Removal, RetentionPolicy};
+use crate::run_info::{tmp_run_name, RunInfo};
+use crate::sandbox::Sandbox;
+use crate::snapshot::SnapInfo;
+use crate::snapshot_manifest::SnapManifest;
+use crate::top_dirs::{TopDirs, PROJECT_VAR, WORK_DIR_VAR};
 use crate::utils::{execvp, exit_with_status, find_existent_parent_dir, maybe_strip_prefix};
-use crate::zone::Zone;
-use failure::Error;
+use crate::zone::{Location, Zone};
+use crate::zone_stats::ZoneStats;
+use chrono::Utc;
+use clap::{Args, Parser, Subcommand};
+use failure::{Error, ResultExt};
 use nix::unistd::Pid;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, PathBuf};
 use std::process::Command;
-use structopt::StructOpt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 use void::unreachable;
 
 /*
  * CLI options enum and runner
  */
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "mzr", author = "Michael Sloan <mgsloan@gmail.com>")]
+#[derive(Parser, Debug)]
+#[command(
+    name = "mzr",
+    author = "Michael Sloan <mgsloan@gmail.com>",
+    version,
+    after_help = errors::EXIT_CODES_HELP
+)]
+pub struct Opts {
+    #[arg(
+        long = "yes",
+        global = true,
+        help = "Answer yes to any prompts. Can also be enabled by setting MZR_ASSUME_YES=1."
+    )]
+    yes: bool,
+    #[arg(
+        long = "no-input",
+        global = true,
+        help = "Fail instead of prompting for input. Can also be enabled by setting \
+                MZR_NO_INPUT=1."
+    )]
+    no_input: bool,
+    #[arg(
+        short = 'C',
+        long = "project",
+        global = true,
+        help = "Work directory of the project to use, rather than finding it from the current \
+                directory. Can also be set via MZR_PROJECT."
+    )]
+    project: Option<PathBuf>,
+    #[arg(
+        long = "json",
+        global = true,
+        help = "Print errors as JSON, including their kind and exit code."
+    )]
+    json: bool,
+    #[arg(
+        long = "color",
+        global = true,
+        value_parser = ["auto", "always", "never"],
+        help = "Whether to color output. Defaults to auto, which colors it when printing to a \
+                terminal. Can also be set via MZR_COLOR."
+    )]
+    color: Option<String>,
+    #[arg(
+        long = "auto-restart-daemon",
+        global = true,
+        help = "Restart the daemon if it doesn't respond to a request in time. Can also be \
+                enabled by setting MZR_AUTO_RESTART_DAEMON=1."
+    )]
+    auto_restart_daemon: bool,
+    #[arg(
+        long = "host",
+        global = true,
+        help = "HOST:PORT of a daemon on another machine to query, as started with mzr daemon \
+                --remote-addr. Applies to mzr list, status, ping, and metrics. The daemon's \
+                token must be given via MZR_DAEMON_TOKEN. Unless HOST is a loopback address, \
+                TLS is used, verifying the daemon's certificate against the system's CAs, or \
+                those in MZR_DAEMON_CA_FILE."
+    )]
+    host: Option<String>,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand, Debug)]
 pub enum Cmd {
-    #[structopt(name = "daemon", about = "Run mzr daemon")]
-    Daemon {},
-    #[structopt(name = "shell", about = "Enter a mzr shell")]
+    #[command(name = "init", about = "Create a mzr directory for a work directory")]
+    Init {
+        #[command(flatten)]
+        opts: InitOpts,
+    },
+    #[command(
+        name = "share",
+        about = "Share the mzr directory with a group, keeping each user's zones private"
+    )]
+    Share {
+        #[command(flatten)]
+        opts: ShareOpts,
+    },
+    #[command(name = "daemon", about = "Run mzr daemon")]
+    Daemon {
+        #[command(flatten)]
+        opts: DaemonOpts,
+    },
+    #[command(name = "metrics", about = "Print metrics from the running mzr daemon")]
+    Metrics {},
+    #[command(name = "ping", about = "Check that the mzr daemon is responding")]
+    Ping {},
+    #[command(
+        name = "status",
+        about = "Show the current zone, and which zones have open shells"
+    )]
+    Status {
+        #[command(flatten)]
+        opts: ListingOpts,
+    },
+    #[command(
+        name = "ui",
+        about = "Show a dashboard of the zones, with keys to enter, merge, and remove them"
+    )]
+    Ui {},
+    #[command(name = "shell", about = "Enter a mzr shell")]
     Shell {
-        #[structopt(flatten)]
+        #[command(flatten)]
         opts: ShellOpts,
     },
-    #[structopt(
+    #[command(
         name = "run",
         about = "Run a command with a temporary snapshot and zone."
     )]
-    Run {
-        #[structopt(flatten)]
-        opts: RunOpts,
-    },
-    #[structopt(name = "snap", about = "Create mzr snapshot of working directory")]
-    Snap {
-        #[structopt(flatten)]
-        opts: SnapOpts,
-    },
-    /*
-    #[structopt(
-        name = "go",
-        about = "Switch working directory to a different zone"
+    Run {
+        #[command(flatten)]
+        opts: RunOpts,
+    },
+    #[command(name = "exec", about = "Run a command within an existing zone")]
+    Exec {
+        #[command(flatten)]
+        opts: ExecOpts,
+    },
+    #[command(name = "snap", about = "Create mzr snapshot of working directory")]
+    Snap {
+        #[command(flatten)]
+        opts: SnapOpts,
+    },
+    #[command(
+        name = "list",
+        about = "List zones, along with their snapshots and branches"
+    )]
+    List {
+        #[command(flatten)]
+        opts: ListingOpts,
+    },
+    #[command(
+        name = "conflicts",
+        about = "Find paths changed within more than one zone of the same snapshot"
+    )]
+    Conflicts {
+        #[command(flatten)]
+        opts: ConflictsOpts,
+    },
+    #[command(name = "du", about = "Show disk usage of snapshots and zones")]
+    Du {
+        #[command(flatten)]
+        opts: DuOpts,
+    },
+    #[command(name = "gc", about = "Remove unreferenced data from the mzr directory")]
+    Gc {
+        #[command(flatten)]
+        opts: GcOpts,
+    },
+    #[command(
+        name = "compact",
+        about = "Deduplicate identical files across snapshots, reporting the space reclaimed"
+    )]
+    Compact {
+        #[command(flatten)]
+        opts: CompactOpts,
+    },
+    #[command(
+        name = "push",
+        about = "Transfer a snapshot and zones to a mzr directory on another machine"
+    )]
+    Push {
+        #[command(flatten)]
+        opts: RemoteOpts,
+    },
+    #[command(
+        name = "pull",
+        about = "Transfer a snapshot and zones from a mzr directory on another machine"
+    )]
+    Pull {
+        #[command(flatten)]
+        opts: RemoteOpts,
+    },
+    #[command(
+        name = "lsp-proxy",
+        about = "Run a language server within a zone, translating paths for editors outside of it"
+    )]
+    LspProxy {
+        #[command(flatten)]
+        opts: LspProxyOpts,
+    },
+    #[command(
+        name = "mount",
+        about = "Bind a zone to a directory other than the work directory. Requires the daemon \
+                 to be started by root with --expose-zones"
+    )]
+    Mount {
+        #[command(flatten)]
+        opts: MountOpts,
+    },
+    #[command(name = "umount", about = "Unbind a zone previously bound by mzr mount")]
+    Umount {
+        #[command(flatten)]
+        opts: UmountOpts,
+    },
+    #[command(
+        name = "watch",
+        about = "Continuously apply a zone's changes to the work directory"
+    )]
+    Watch {
+        #[command(flatten)]
+        opts: WatchOpts,
+    },
+    #[command(
+        name = "merge",
+        about = "Apply a zone's non-conflicting changes to the work directory"
+    )]
+    Merge {
+        #[command(flatten)]
+        opts: MergeOpts,
+    },
+    #[command(
+        name = "revert",
+        about = "Discard a zone's changes, so that the snapshot's contents show through again"
+    )]
+    Revert {
+        #[command(flatten)]
+        opts: RevertOpts,
+    },
+    #[command(
+        name = "which",
+        about = "Show where a zone's layers store a path, or which path a layer's file is for"
+    )]
+    Which {
+        #[command(flatten)]
+        opts: WhichOpts,
+    },
+    #[command(
+        name = "cp",
+        about = "Copy files from one zone into another, without merging through the work dir"
+    )]
+    Cp {
+        #[command(flatten)]
+        opts: CpOpts,
+    },
+    #[command(
+        name = "history",
+        about = "Show the log of snapshots, zones, merges, and daemon runs"
+    )]
+    History {
+        #[command(flatten)]
+        opts: HistoryOpts,
+    },
+    #[command(name = "git", about = "Manage mzr's integration with git")]
+    Git {
+        #[command(subcommand)]
+        cmd: GitCmd,
+    },
+    #[command(name = "zone", about = "Manage mzr zones")]
+    Zone {
+        #[command(subcommand)]
+        cmd: ZoneCmd,
+    },
+    #[command(name = "version", about = "Print the version of mzr")]
+    Version {
+        #[command(flatten)]
+        opts: VersionOpts,
+    },
+    #[command(
+        name = "self-update",
+        about = "Replace this mzr executable with the latest release"
+    )]
+    SelfUpdate {
+        #[command(flatten)]
+        opts: SelfUpdateOpts,
+    },
+    /*
+    #[command(
+        name = "go",
+        about = "Switch working directory to a different zone"
+    )]
+    Go {
+        #[command(flatten)]
+        opts: GoOpts,
+    },
+    */
+}
+
+/// Parses an argument via its `FromStr` impl, rendering the error as the
+/// string clap reports.
+fn parse_arg<T: FromStr<Err = Error>>(arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|err: Error| err.to_string())
+}
+
+pub fn run_opts(opts: &Opts) -> Result<(), Error> {
+    // These are communicated via the environment so that they also apply to
+    // mzr processes spawned by this one.
+    if opts.yes {
+        env::set_var(utils::ASSUME_YES_VAR, "1");
+    }
+    if opts.no_input {
+        env::set_var(utils::NO_INPUT_VAR, "1");
+    }
+    if opts.auto_restart_daemon {
+        env::set_var(daemon::AUTO_RESTART_VAR, "1");
+    }
+    if let Some(host) = &opts.host {
+        env::set_var(remote_daemon::HOST_VAR, host);
+    }
+    if let Some(project) = &opts.project {
+        env::set_var(PROJECT_VAR, canonicalize_dir(project)?);
+    }
+    if let Some(color) = &opts.color {
+        env::set_var(colors::COLOR_VAR, color);
+    }
+    colors::configure();
+    run_cmd(&opts.cmd)
+}
+
+/// Prints the error, and yields the exit code for its kind.
+pub fn report_error(opts: &Opts, err: &Error) -> i32 {
+    let json_error = JsonError::new(err);
+    if opts.json {
+        println!("{}", serde_json::json!({ "error": json_error }));
+    } else {
+        println!();
+        println!("{} {}", colors::color_err(&"mzr error:"), err);
+    }
+    json_error.exit_code
+}
+
+pub fn run_cmd(cmd: &Cmd) -> Result<(), Error> {
+    match cmd {
+        Cmd::Init { opts } => init(&opts),
+        Cmd::Share { opts } => share(&opts),
+        Cmd::Daemon { opts } => daemon(&opts),
+        Cmd::Metrics {} => metrics(),
+        Cmd::Ping {} => ping(),
+        Cmd::Status { opts } => status(&opts),
+        Cmd::Ui {} => ui(),
+        Cmd::Shell { opts } => shell(&opts),
+        Cmd::Run { opts } => run(&opts),
+        Cmd::Exec { opts } => exec(&opts),
+        Cmd::Snap { opts } => snap(&opts),
+        Cmd::List { opts } => list(&opts),
+        Cmd::Conflicts { opts } => conflicts(&opts),
+        Cmd::Du { opts } => du(&opts),
+        Cmd::Gc { opts } => gc(&opts),
+        Cmd::Compact { opts } => compact(&opts),
+        Cmd::Push { opts } => push(&opts),
+        Cmd::Pull { opts } => pull(&opts),
+        Cmd::LspProxy { opts } => lsp_proxy(&opts),
+        Cmd::Mount { opts } => mount(&opts),
+        Cmd::Umount { opts } => umount(&opts),
+        Cmd::Watch { opts } => watch(&opts),
+        Cmd::Merge { opts } => merge(&opts),
+        Cmd::Revert { opts } => revert(&opts),
+        Cmd::Which { opts } => which(&opts),
+        Cmd::Cp { opts } => cp(&opts),
+        Cmd::History { opts } => history(&opts),
+        Cmd::Git { cmd } => git_cmd(&cmd),
+        Cmd::Zone { cmd } => zone_cmd(&cmd),
+        Cmd::Version { opts } => version(&opts),
+        Cmd::SelfUpdate { opts } => self_update(&opts),
+        // Cmd::Go { opts } => go(&opts),
+    }
+}
+
+/*
+ * Options shared by listings
+ */
+
+#[derive(Args, Debug)]
+pub struct ListingOpts {
+    #[arg(
+        long = "absolute",
+        help = "Print times as RFC 3339 timestamps and sizes in bytes, rather than relative \
+                times and sizes in binary units."
+    )]
+    absolute: bool,
+}
+
+impl ListingOpts {
+    fn humanize(&self) -> Humanize {
+        Humanize::new(self.absolute)
+    }
+}
+
+/*
+ * "mzr init"
+ */
+
+#[derive(Args, Debug)]
+pub struct InitOpts {
+    #[arg(
+        long = "here",
+        help = "Use the current directory as the work directory, even if it's within a git \
+                repository."
+    )]
+    here: bool,
+    #[arg(
+        value_name = "PATH",
+        help = "Work directory to create a mzr directory for. Defaults to the root of the git \
+                repository containing the current directory, or else the current directory."
+    )]
+    path: Option<PathBuf>,
+    #[arg(
+        long = "snapshot",
+        help = "Take an initial snapshot of the work directory."
+    )]
+    snapshot: bool,
+    #[arg(
+        long = "member",
+        help = "Directory to include in a project set, which versions several sibling \
+                directories together, such as repositories which depend on each other. Can be \
+                given multiple times. The work directory is their parent, and snapshots only \
+                include the members."
+    )]
+    members: Vec<PathBuf>,
+    #[arg(
+        long = "store-at",
+        help = "Directory to keep the mzr directory in, which gets symlinked from beside the \
+                work directory. For when the work directory's filesystem can't hold zones, \
+                such as NFS. The directory must be empty or not exist."
+    )]
+    store_at: Option<PathBuf>,
+}
+
+fn init(opts: &InitOpts) -> Result<(), Error> {
+    let mut project_set = None;
+    let top_dirs = match (&opts.path, opts.here) {
+        _ if !opts.members.is_empty() => {
+            if opts.path.is_some() || opts.here {
+                bail!("PATH and --here can't be specified along with --member.");
+            }
+            let mut member_dirs = Vec::new();
+            for member in opts.members.iter() {
+                member_dirs.push(canonicalize_dir(member)?);
+            }
+            let (work_dir, members) = ProjectSet::from_member_dirs(&member_dirs)?;
+            project_set = Some(members);
+            TopDirs::new_at(&work_dir, false)?
+        }
+        (Some(_), true) => bail!("PATH can't be specified along with --here."),
+        (Some(path), false) => TopDirs::new_at(&canonicalize_dir(path)?, false)?,
+        (None, true) => TopDirs::new_at(&env::current_dir()?, false)?,
+        (None, false) => match env::var_os(PROJECT_VAR) {
+            Some(project) => TopDirs::new_at(&PathBuf::from(project), false)?,
+            None => TopDirs::new_at(&env::current_dir()?, true)?,
+        },
+    };
+    if top_dirs.mzr_dir.is_dir() {
+        bail!(
+            "There is already a mzr directory at {}",
+            color_dir(&top_dirs.mzr_dir)
+        );
+    }
+    top_dirs.create(opts.store_at.as_ref().map(|dir| dir.as_path()))?;
+    if let Some(project_set) = &project_set {
+        let mut config = Config::load(&top_dirs.mzr_dir)?;
+        config.project_set = project_set.members.clone();
+        config.write(&top_dirs.mzr_dir)?;
+        let names: Vec<String> = project_set
+            .members
+            .iter()
+            .map(|member| member.display().to_string())
+            .collect();
+        println!(
+            "Snapshots will include the project set's members: {}",
+            names.join(", ")
+        );
+    }
+    println!(
+        "{} mzr directory initialized at {}.",
+        colors::color_success(&"Success:"),
+        color_dir(&top_dirs.mzr_dir)
+    );
+    if opts.snapshot {
+        let snap_name = default_snap_name(&top_dirs, &None)?;
+        println!("Taking a snapshot named {}", snap_name);
+        snapshot::of_workdir(&top_dirs, &snap_name)?;
+        println!(
+            "{} snapshot named {} taken.",
+            colors::color_success(&"Success:"),
+            snap_name
+        );
+    }
+    println!();
+    println!("Next steps:");
+    println!(
+        "  {} to start the daemon, which manages zones.",
+        colors::color_cmd(&"mzr daemon")
+    );
+    println!(
+        "  {} to create a zone and enter a shell within it.",
+        colors::color_cmd(&"mzr shell ZONE_NAME")
+    );
+    println!(
+        "  {} to run a command in a temporary zone.",
+        colors::color_cmd(&"mzr run CMD")
+    );
+    Ok(())
+}
+
+/*
+ * "mzr share"
+ */
+
+#[derive(Args, Debug)]
+pub struct ShareOpts {
+    #[arg(
+        value_name = "GROUP",
+        help = "Unix group whose users share the mzr directory's snapshots."
+    )]
+    group: String,
+}
+
+fn share(opts: &ShareOpts) -> Result<(), Error> {
+    if env::var_os("MZR_ZONE").is_some() {
+        bail!("mzr share needs to be run outside of mzr zones.");
+    }
+    let top_dirs = TopDirs::find("share")?;
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        bail!("The mzr daemon needs to be stopped before sharing, since its files are moved.");
+    }
+    let info = shared::share(&top_dirs.mzr_dir, &opts.group)?;
+    println!(
+        "{} shared {} with group {}. Snapshots are shared, while each user's zones and \
+         daemon are only accessible to them.",
+        colors::color_success(&"Success:"),
+        color_dir(&top_dirs.mzr_dir),
+        info.group
+    );
+    println!(
+        "Members of the group should use a umask like 002, so that the snapshots they take \
+         are group-writable."
+    );
+    Ok(())
+}
+
+/*
+ * "mzr daemon"
+ */
+
+// TODO(friendliness): Perhaps other commands should automatically
+// start daemon?  Ideally we wouldn't even need one, but it's not
+// entirely clear to me how to do all the mount sharing without
+// one. It may also be helpful in the future if a root daemon is
+// supported (instead of using user namespaces).
+//
+// The daemon can be started on demand via systemd socket activation, by
+// having a user socket unit listen at PROJECT.mzr/daemon/socket,
+// and a service unit with "ExecStart=mzr daemon --idle-timeout-mins 30" run
+// in the work dir. When activated, the daemon stays in the foreground, and
+// on exit leaves the socket in place for systemd to listen on.
+
+#[derive(Args, Debug)]
+pub struct DaemonOpts {
+    #[command(flatten)]
+    start: DaemonStartOpts,
+    #[command(subcommand)]
+    cmd: Option<DaemonCmd>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DaemonCmd {
+    #[command(
+        name = "start",
+        about = "Run mzr daemon, same as mzr daemon without a subcommand"
+    )]
+    Start {
+        #[command(flatten)]
+        opts: DaemonStartOpts,
+    },
+    #[command(name = "stop", about = "Stop the running mzr daemon")]
+    Stop {},
+    #[command(
+        name = "status",
+        about = "Check that the mzr daemon is responding, same as mzr ping"
+    )]
+    Status {},
+    #[command(name = "logs", about = "Print the end of the mzr daemon's logs")]
+    Logs {
+        #[command(flatten)]
+        opts: DaemonLogsOpts,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct DaemonStartOpts {
+    #[arg(
+        long = "expose-zones",
+        help = "Expose a read-only view of each mounted zone at .mzr/mnt/ZONE, for tools \
+                outside of mzr zones, and allow zones to be bound elsewhere via mzr mount. \
+                This requires privileges over the current mount namespace, so typically only \
+                works when run as root."
+    )]
+    expose_zones: bool,
+    #[arg(
+        long = "metrics-addr",
+        help = "Address to serve metrics on over HTTP in the Prometheus text format, \
+                such as 127.0.0.1:9100"
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[arg(
+        long = "auto-gc-interval-hours",
+        help = "Periodically remove snapshots and zones according to the retention policy \
+                set via mzr gc --save-policy."
+    )]
+    auto_gc_interval_hours: Option<u64>,
+    #[arg(
+        long = "idle-timeout-mins",
+        help = "Exit once there have been no zone processes, zones mounted via mzr mount, or \
+                requests for this many minutes."
+    )]
+    idle_timeout_mins: Option<u64>,
+    #[arg(
+        long = "checkpoint-interval-mins",
+        help = "Periodically copy the changes of each mounted zone into a checkpoint, which \
+                mzr zone restore can roll the zone back to."
+    )]
+    checkpoint_interval_mins: Option<u64>,
+    #[arg(
+        long = "restart",
+        help = "Stop the daemon if it's already running, such as when it has stopped \
+                responding. Zone processes keep running, and are adopted by the new daemon."
+    )]
+    restart: bool,
+    #[arg(
+        long = "remote-addr",
+        help = "Also accept requests on this TCP address, from clients which send the token \
+                in daemon/remote-token, such as mzr --host. Unless this is a loopback address, \
+                --tls-cert and --tls-key are required. Loopback addresses don't use TLS, for \
+                use with a TLS terminator such as stunnel, or an SSH tunnel."
+    )]
+    remote_addr: Option<SocketAddr>,
+    #[arg(
+        long = "tls-cert",
+        requires = "remote_addr",
+        requires = "tls_key",
+        help = "PEM file with the certificate chain for TLS connections to --remote-addr."
+    )]
+    tls_cert: Option<PathBuf>,
+    #[arg(
+        long = "tls-key",
+        requires = "tls_cert",
+        help = "PEM file with the private key for TLS connections to --remote-addr."
+    )]
+    tls_key: Option<PathBuf>,
+}
+
+fn daemon(opts: &DaemonOpts) -> Result<(), Error> {
+    match &opts.cmd {
+        Some(DaemonCmd::Start { opts }) => daemon_start(opts),
+        Some(DaemonCmd::Stop {}) => daemon_stop(),
+        Some(DaemonCmd::Status {}) => ping(),
+        Some(DaemonCmd::Logs { opts }) => daemon_logs(opts),
+        None => daemon_start(&opts.start),
+    }
+}
+
+fn daemon_start(opts: &DaemonStartOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("start mzr daemon")?;
+    if opts.restart && daemon::stop_daemon(&top_dirs.mzr_dir)? {
+        println!("Stopped the running mzr daemon.");
+    }
+    let auto_gc_interval = opts
+        .auto_gc_interval_hours
+        .map(|hours| Duration::from_secs(hours * 60 * 60));
+    let idle_timeout = opts
+        .idle_timeout_mins
+        .map(|mins| Duration::from_secs(mins * 60));
+    let checkpoint_interval = opts
+        .checkpoint_interval_mins
+        .map(|mins| Duration::from_secs(mins * 60));
+    // Made absolute, since the daemon changes directory before loading them.
+    let remote_tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(remote_daemon::TlsFiles {
+            cert: fs::canonicalize(cert)
+                .context(format_err!("Failed to find TLS certificate {:?}", cert))?,
+            key: fs::canonicalize(key)
+                .context(format_err!("Failed to find TLS private key {:?}", key))?,
+        }),
+        _ => None,
+    };
+    daemon::run(
+        &top_dirs,
+        opts.expose_zones,
+        opts.metrics_addr,
+        auto_gc_interval,
+        idle_timeout,
+        checkpoint_interval,
+        opts.remote_addr.map(|addr| (addr, remote_tls)),
+    )
+}
+
+/*
+ * "mzr daemon stop"
+ */
+
+fn daemon_stop() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("stop mzr daemon")?;
+    if daemon::stop_daemon(&top_dirs.mzr_dir)? {
+        println!(
+            "{} stopped the mzr daemon.",
+            colors::color_success(&"Success:")
+        );
+    } else {
+        println!("The mzr daemon isn't running.");
+    }
+    Ok(())
+}
+
+/*
+ * "mzr daemon logs"
+ */
+
+#[derive(Args, Debug)]
+pub struct DaemonLogsOpts {
+    #[arg(
+        short = 'n',
+        long = "lines",
+        default_value = "50",
+        help = "Number of lines to print from the end of each log."
+    )]
+    lines: usize,
+}
+
+fn daemon_logs(opts: &DaemonLogsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show mzr daemon logs")?;
+    let daemon_dir = DaemonDir::new(&top_dirs.mzr_dir);
+    let log_files = vec![
+        DaemonLogStdoutFile::new(&daemon_dir).to_path_buf(),
+        DaemonLogStderrFile::new(&daemon_dir).to_path_buf(),
+    ];
+    for log_file in log_files {
+        if !log_file.exists() {
+            continue;
+        }
+        let contents = fs::read_to_string(&log_file)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.is_empty() {
+            continue;
+        }
+        println!("==> {} <==", colors::color_file(&log_file.display()));
+        for line in &lines[lines.len().saturating_sub(opts.lines)..] {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Daemon to send queries to, being the remote one given by `--host`, or
+/// otherwise the daemon of the current project.
+fn query_daemon_addr(purpose: &str) -> Result<DaemonAddr, Error> {
+    match remote_daemon::host() {
+        Some(host) => Ok(DaemonAddr::Remote(host)),
+        None => Ok(DaemonAddr::Local(TopDirs::find(purpose)?.mzr_dir)),
+    }
+}
+
+/*
+ * "mzr metrics"
+ */
+
+fn metrics() -> Result<(), Error> {
+    let addr = query_daemon_addr("get metrics from mzr daemon")?;
+    let report = daemon::get_metrics(&addr)?;
+    print!("{}", report.to_prometheus());
+    Ok(())
+}
+
+/*
+ * "mzr ping"
+ */
+
+fn ping() -> Result<(), Error> {
+    let addr = query_daemon_addr("ping mzr daemon")?;
+    let health = daemon::ping(&addr)?;
+    println!(
+        "mzr daemon {} (PID {}) is responding. It has been running for {}s, and has {} zone \
+         process(es).",
+        health.version, health.pid, health.uptime_secs, health.zone_processes
+    );
+    if health.version != version::VERSION {
+        println!(
+            "{} the daemon's version differs from this mzr, which is version {}. \
+             Consider restarting it.",
+            colors::color_warn(&"Warning:"),
+            version::VERSION
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr status"
+ */
+
+fn status(opts: &ListingOpts) -> Result<(), Error> {
+    let humanize = opts.humanize();
+    // A remote daemon's project may not exist locally, so only its daemon's
+    // status is shown.
+    let (top_dirs, addr) = match remote_daemon::host() {
+        Some(host) => (None, DaemonAddr::Remote(host)),
+        None => {
+            let top_dirs = TopDirs::find("show mzr status")?;
+            match zone::current_location(&top_dirs)? {
+                Location::Outside => println!("Not within a zone."),
+                Location::Within(None) => println!("Within an unknown zone."),
+                Location::Within(Some(zone_name)) => println!("Within zone {}.", zone_name),
+            }
+            if !daemon::socket_exists(&top_dirs.mzr_dir) {
+                println!("The mzr daemon isn't running, so there are no open shells.");
+                return Ok(());
+            }
+            let addr = DaemonAddr::from(&top_dirs.mzr_dir);
+            (Some(top_dirs), addr)
+        }
+    };
+    let info = daemon::daemon_info(&addr)?;
+    println!(
+        "The mzr daemon (PID {}) has been running for {}s, with {} zone(s) mounted and {} \
+         running.",
+        info.health.pid,
+        info.health.uptime_secs,
+        info.mounted_zones.len(),
+        info.running_zones.len()
+    );
+    for (target, zone_name) in info.workspaces.iter() {
+        println!(
+            "Zone {} is mounted at {}",
+            zone_name,
+            color_dir(&target.display())
+        );
+    }
+    let shells = daemon::list_shells(&addr)?;
+    if shells.is_empty() {
+        println!("There are no open shells.");
+    }
+    let mut zone_shells: Vec<(&ZoneName, Vec<&daemon::ShellInfo>)> = Vec::new();
+    for shell in shells.iter() {
+        match zone_shells.last_mut() {
+            Some((zone_name, group)) if **zone_name == shell.zone_name => group.push(shell),
+            _ => zone_shells.push((&shell.zone_name, vec![shell])),
+        }
+    }
+    for (zone_name, group) in zone_shells {
+        let is_frozen = match &top_dirs {
+            Some(top_dirs) => freezer::is_frozen(&top_dirs.mzr_dir, zone_name),
+            None => false,
+        };
+        let frozen = if is_frozen {
+            format!(", {}", colors::color_warn(&"frozen"))
+        } else {
+            String::new()
+        };
+        println!("{} ({} open shell(s){})", zone_name, group.len(), frozen);
+        for shell in group {
+            println!(
+                "  PID {}, opened {}",
+                shell.pid,
+                humanize.time(&shell.registered)
+            );
+        }
+    }
+    for zone in daemon::list_zones(&addr)? {
+        if let Some(stats) = zone.stats.filter(ZoneStats::is_large) {
+            println!(
+                "{} Zone {} has {}. If it contains build outputs, consider making their \
+                 directories scratch dirs, via mzr shell --scratch or the scratch_dirs config.",
+                colors::color_warn(&"Warning:"),
+                zone.name,
+                stats.describe(&humanize)
+            );
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr ui"
+ */
+
+fn ui() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show mzr dashboard")?;
+    ui::run(&top_dirs)
+}
+
+/*
+ * "mzr shell"
+ */
+
+#[derive(Args, Debug)]
+pub struct ShellOpts {
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name of the zone to load or create. Required unless --tmp is used."
+    )]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        value_name = "SNAP_NAME",
+        value_parser = parse_arg::<SnapName>,
+        help = "Name of the snapshot to use. \
+                If creating a new zone and this is unspecified, a new snapshot will be taken."
+    )]
+    snap_name: Option<SnapName>,
+    #[arg(
+        long = "git-worktree",
+        help = "When creating a new zone, register its git repositories as worktrees of \
+                the shared repositories, instead of symlinking parts of them. \
+                Submodules are not yet supported in this mode."
+    )]
+    git_worktree: bool,
+    #[arg(
+        long = "branch",
+        help = "When creating a new zone, create a git branch with this name and check it \
+                out within the zone."
+    )]
+    branch: Option<String>,
+    #[arg(
+        long = "scratch",
+        help = "When creating a new zone, mount a tmpfs over this path whenever the zone is \
+                entered, so that files written there don't go to the zone's changes. \
+                Relative paths are relative to the work dir. May be repeated."
+    )]
+    scratch_dirs: Vec<PathBuf>,
+    #[arg(
+        long = "env",
+        help = "When creating a new zone, set the environment variable whenever the zone is \
+                entered, given as NAME=VALUE. Within the value, ${MZR_ZONE}, ${MZR_DIR}, and \
+                ${MZR_ZONE_DIR} refer to the zone. May be repeated."
+    )]
+    env_vars: Vec<String>,
+    #[arg(
+        long = "isolate-home",
+        help = "When creating a new zone, give it its own version of this directory within \
+                your home directory, such as .cache, so that tools writing there don't share \
+                it with other zones. Relative to the home directory. May be repeated."
+    )]
+    isolated_home_dirs: Vec<PathBuf>,
+    #[arg(
+        long = "tmp",
+        help = "Create a temporary zone for the shell, which is deleted when the shell exits, \
+                after offering to merge its changes. Unless SNAP_NAME is given, it's based on a \
+                temporary snapshot of the work dir."
+    )]
+    tmp: bool,
+}
+
+fn shell(opts: &ShellOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("enter mzr shell")?;
+    let zone_name = match (&opts.zone_name, opts.tmp) {
+        (Some(zone_name), false) => zone_name.clone(),
+        (None, false) => bail!("ZONE_NAME is required, unless --tmp is used."),
+        (Some(_), true) => {
+            bail!("ZONE_NAME can't be specified along with --tmp, since it's named automatically.")
+        }
+        (None, true) => ZoneName::new(tmp_shell_name(Pid::this()))?,
+    };
+    if opts.tmp && opts.branch.is_some() {
+        bail!("--branch can't be used along with --tmp, since the zone is deleted on exit.");
+    }
+    let creating = opts.tmp || !Zone::exists(&top_dirs.mzr_dir, &zone_name);
+    if !creating && opts.branch.is_some() {
+        bail!(
+            "Zone {} already exists, so --branch can't be used.",
+            zone_name
+        );
+    }
+    if !creating && !opts.scratch_dirs.is_empty() {
+        bail!(
+            "Zone {} already exists, so --scratch can't be used.",
+            zone_name
+        );
+    }
+    if !creating && !opts.env_vars.is_empty() {
+        bail!("Zone {} already exists, so --env can't be used.", zone_name);
+    }
+    if !creating && !opts.isolated_home_dirs.is_empty() {
+        bail!(
+            "Zone {} already exists, so --isolate-home can't be used.",
+            zone_name
+        );
+    }
+    if let Some(home_dir) = opts.isolated_home_dirs.iter().find(|dir| dir.is_absolute()) {
+        bail!(
+            "--isolate-home should be given a path relative to the home directory, but got {:?}",
+            home_dir
+        );
+    }
+    let env_vars = parse_env_vars(&opts.env_vars)?;
+    let mut tmp_snap_name = None;
+    if creating {
+        let snap_name = match &opts.snap_name {
+            None if opts.tmp => {
+                let snap_name = SnapName::new(zone_name.to_string())?;
+                let interrupts = Interrupts::install()?;
+                let snap_cleanup = on_interrupt_remove(
+                    &interrupts,
+                    &top_dirs,
+                    Removal::Snapshot(snap_name.clone()),
+                );
+                println!("Taking temporary snapshot named {}", snap_name);
+                snapshot::of_workdir(&top_dirs, &snap_name)?;
+                drop(snap_cleanup);
+                interrupts.uninstall()?;
+                tmp_snap_name = Some(snap_name.clone());
+                snap_name
+            }
+            _ => default_snap_name(&top_dirs, &opts.snap_name)?,
+        };
+        /* TODO(friendliness): What should the snapshot creation logic be?
+        println!("Taking a snapshot named {}", snap_name);
+        snapshot::create(&top_dirs.user_work_dir, &top_dirs.mzr_dir, &snap_name)?;
+        println!("Finished taking snapshot.");
+        */
+        if opts.tmp {
+            println!(
+                "Creating temporary zone {}, which will be deleted when the shell exits.",
+                zone_name
+            );
+        } else {
+            println!("Requested zone does not yet exist, so attempting to create it.");
+        }
+        let mut zone = Zone::create(&top_dirs.mzr_dir, &zone_name, &snap_name, opts.git_worktree)?;
+        if !opts.scratch_dirs.is_empty()
+            || !env_vars.is_empty()
+            || !opts.isolated_home_dirs.is_empty()
+            || opts.tmp
+        {
+            zone.info.scratch_dirs = opts.scratch_dirs.clone();
+            zone.info.env = env_vars;
+            zone.info.isolated_home_dirs = opts.isolated_home_dirs.clone();
+            zone.info.temporary = opts.tmp;
+            zone.write_info()?;
+        }
+    };
+    if opts.tmp {
+        // Finishing up needs to happen outside of the zone, so that merging
+        // can modify the work dir.
+        let top_dirs = top_dirs.clone();
+        let zone_name = zone_name.clone();
+        namespaces::continue_in_child_then(move || {
+            if let Err(err) = finish_tmp_shell(&top_dirs, &zone_name, tmp_snap_name.as_ref()) {
+                println!("{} {}", colors::color_warn(&"Warning:"), err);
+            }
+        })?;
+    }
+    enter_zone(&top_dirs, &zone_name)?;
+    if let Some(branch) = &opts.branch {
+        // Within the zone, so that the branch is only checked out there.
+        git::create_branch(&top_dirs.user_work_dir, branch)?;
+        let mut zone = Zone::load(&top_dirs.mzr_dir, &zone_name)?;
+        zone.info.branch = Some(branch.clone());
+        zone.write_info()?;
+        println!("Checked out new branch {} in zone {}.", branch, zone.name);
+    }
+    // Lets the daemon know which zone the shell is in, so that nested mzr
+    // commands and `mzr status` can find it.
+    let registered = match daemon::register_shell(&top_dirs.mzr_dir, &zone_name) {
+        Ok(()) => true,
+        Err(err) => {
+            println!(
+                "{} failed to register shell with the daemon: {}",
+                colors::color_warn(&"Warning:"),
+                err
+            );
+            false
+        }
+    };
+    // The shell needs to be a child process to be within the zone's PID
+    // namespace.
+    namespaces::continue_in_child_then(|| {
+        if registered {
+            // The daemon also forgets shells which have exited, so failing
+            // to deregister isn't a problem.
+            let _ = daemon::deregister_shell(&top_dirs.mzr_dir);
+        }
+    })?;
+    restrict_zone_privileges(&top_dirs)?;
+    let void = execvp("/bin/bash")?;
+    unreachable(void)
+}
+
+fn tmp_shell_name(pid: Pid) -> String {
+    format!("shell-{}", pid)
+}
+
+/// Offers to merge the changes made in a temporary zone from `mzr shell
+/// --tmp`, and then removes it along with its temporary snapshot. If it has
+/// changes, removing it is confirmed, so that they can be kept around when
+/// not everything got merged.
+fn finish_tmp_shell(
+    top_dirs: &TopDirs,
+    zone_name: &ZoneName,
+    tmp_snap_name: Option<&SnapName>,
+) -> Result<(), Error> {
+    let zone = Zone::load(&top_dirs.mzr_dir, zone_name)?;
+    let changed_count = conflicts::changed_paths(&zone)?.len();
+    if changed_count > 0 {
+        let query = format!(
+            "Temporary zone {} has {} changed path(s). Merge them into the work dir",
+            zone_name, changed_count
+        );
+        if let utils::Confirmed::Yes = utils::confirm(&query)? {
+            merge(&MergeOpts {
+                zone_name: Some(zone_name.clone()),
+                continue_merge: false,
+                abort: false,
+                undo_last: false,
+                target_dir: None,
+                dry_run: false,
+                format: String::from("table"),
+                paths: Vec::new(),
+                excludes: Vec::new(),
+                force: false,
+                commit: false,
+            })?;
+        }
+        let query = format!("Delete temporary zone {}", zone_name);
+        if let utils::Confirmed::No = utils::confirm(&query)? {
+            println!(
+                "Kept temporary zone {}. It can be removed later via mzr zone remove {}",
+                zone_name, zone_name
+            );
+            return Ok(());
+        }
+    }
+    // The daemon stops the zone's process and unmounts it before removing it.
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        daemon::remove_zone(&top_dirs.mzr_dir, zone_name)?;
+    } else {
+        zone.remove(&top_dirs.user_work_dir)?;
+    }
+    if let Some(snap_name) = tmp_snap_name {
+        retention::apply(top_dirs, &[Removal::Snapshot(snap_name.clone())])?;
+    }
+    println!("Removed temporary zone {}.", zone_name);
+    Ok(())
+}
+
+/*
+ * "mzr run"
+ */
+
+#[derive(Args, Debug)]
+pub struct RunOpts {
+    #[arg(
+        long = "show-last",
+        help = "Show the record of the most recent run, instead of running a command."
+    )]
+    show_last: bool,
+    #[arg(
+        long = "cmd",
+        help = "Shell command to run in its own temporary zone. May be repeated to run \
+                multiple commands concurrently, in zones based on one temporary snapshot. Their \
+                zones are merged one at a time once they've all exited."
+    )]
+    cmds: Vec<String>,
+    #[arg(
+        long = "matrix",
+        help = "File listing shell commands, one per line, to each run in their own \
+                temporary zone."
+    )]
+    matrix: Option<PathBuf>,
+    #[arg(
+        long = "jobs",
+        short = 'j',
+        help = "Maximum number of commands to run at once, when using --cmd or --matrix. \
+                Defaults to running all of them at once."
+    )]
+    jobs: Option<usize>,
+    #[arg(
+        long = "isolate-network",
+        help = "Run the command in its own network namespace, so that it has no network \
+                access. Useful for checking that builds are hermetic."
+    )]
+    isolate_network: bool,
+    #[arg(
+        long = "loopback",
+        help = "With --isolate-network, bring up a loopback interface in the command's \
+                network namespace."
+    )]
+    loopback: bool,
+    #[arg(
+        long = "memory",
+        value_parser = parse_arg::<ByteSize>,
+        help = "Limit the memory used by the command, in bytes with an optional K, M, G, or T \
+                suffix. Resource limits are applied via a cgroup, which requires cgroups v2 \
+                with the parent cgroup delegated to your user."
+    )]
+    memory: Option<ByteSize>,
+    #[arg(
+        long = "cpus",
+        help = "Limit the command to this many CPUs worth of time, which may be fractional."
+    )]
+    cpus: Option<f64>,
+    #[arg(
+        long = "pids-limit",
+        help = "Limit the number of processes and threads the command may have at once."
+    )]
+    pids_limit: Option<u64>,
+    #[arg(
+        long = "sandbox",
+        help = "Use Landlock to only allow the command to write files within the zone, /tmp, \
+                and /dev. Requires Linux 5.13 or later."
+    )]
+    sandbox: bool,
+    #[arg(
+        long = "seccomp",
+        help = "With --sandbox, also use a seccomp filter to deny syscalls which affect things \
+                beyond the filesystem, such as ptrace, mount, and loading kernel modules."
+    )]
+    seccomp: bool,
+    #[arg(
+        long = "snapshot",
+        value_parser = parse_arg::<SnapName>,
+        help = "Run the command in a zone based on this existing snapshot, rather than taking \
+                a temporary snapshot of the work directory."
+    )]
+    snapshot: Option<SnapName>,
+    #[arg(
+        long = "no-merge",
+        help = "Don't merge the zone's changes once the command exits. They can be merged \
+                later via mzr merge."
+    )]
+    no_merge: bool,
+    #[arg(value_name = "CMD")]
+    cmd: Option<String>,
+    #[arg(value_name = "ARGS")]
+    args: Vec<String>,
+}
+
+fn run(opts: &RunOpts) -> Result<(), Error> {
+    if opts.show_last {
+        return show_last_run();
+    }
+    if !opts.cmds.is_empty() || opts.matrix.is_some() {
+        return run_matrix(opts);
+    }
+    let cmd = match &opts.cmd {
+        Some(cmd) => cmd,
+        None => bail!("A command to run is required, unless --show-last is specified."),
+    };
+    check_run_isolation_opts(opts)?;
+    let top_dirs = TopDirs::find_or_prompt_create("run command in temp mzr zone")?;
+    // TODO(friendliness) Things to consider basing tmp zone /
+    // snapshot on:
+    //
+    // * Command run
+    // * Current date / time
+    // * Current PID
+    //
+    // For now just going with something based on PID..
+    let tmp_name = tmp_run_name(Pid::this());
+    let zone_name = ZoneName::new(tmp_name.clone())?;
+    // Within a zone, the temporary zone is layered on it rather than on a
+    // snapshot of its view of the work dir, and the results get merged back
+    // into it.
+    let parent_zone = match zone::current_location(&top_dirs)? {
+        Location::Within(Some(parent_name)) => Some(Zone::load(&top_dirs.mzr_dir, &parent_name)?),
+        Location::Within(None) => {
+            println!(
+                "{} couldn't determine which zone this is within, so taking a snapshot of it.",
+                colors::color_warn(&"Warning:")
+            );
+            None
+        }
+        Location::Outside => None,
+    };
+    if parent_zone.is_some() && opts.snapshot.is_some() {
+        bail!("--snapshot can't be used within a zone, since the zone is layered on it instead.");
+    }
+    let snap_name = match &opts.snapshot {
+        Some(snap_name) => snap_name.clone(),
+        None => SnapName::new(tmp_name.clone())?,
+    };
+    let interrupts = Interrupts::install()?;
+    let snap_cleanup = match &parent_zone {
+        Some(_) => None,
+        None if opts.snapshot.is_some() => None,
+        None => {
+            let snap_cleanup =
+                on_interrupt_remove(&interrupts, &top_dirs, Removal::Snapshot(snap_name.clone()));
+            println!("Taking temporary snapshot named {}", snap_name);
+            snapshot::of_workdir(&top_dirs, &snap_name)?;
+            Some(snap_cleanup)
+        }
+    };
+    let zone_cleanup =
+        on_interrupt_remove(&interrupts, &top_dirs, Removal::Zone(zone_name.clone()));
+    let zone = match &parent_zone {
+        Some(parent_zone) => Zone::create_layered(&top_dirs.mzr_dir, &zone_name, parent_zone)?,
+        None => Zone::create(&top_dirs.mzr_dir, &zone_name, &snap_name, false)?,
+    };
+    let limits = run_limits(opts);
+    let cgroup = if limits.is_empty() {
+        None
+    } else {
+        Some(Cgroup::create(&format!("mzr-{}", tmp_name), &limits)?)
+    };
+    match &parent_zone {
+        Some(parent_zone) => println!(
+            "Running {} inside temporary zone named {}, layered on zone {}\n",
+            cmd, zone_name, parent_zone.name
+        ),
+        None => println!(
+            "Running {} inside temporary zone named {}\n",
+            cmd, zone_name
+        ),
+    }
+    // Once the zone is entered, it's kept for inspection if interrupted, but
+    // its process gets stopped.
+    drop(zone_cleanup);
+    drop(snap_cleanup);
+    interrupts.uninstall()?;
+    // Run process within the temporary zone, inheriting stdio.
+    enter_zone(&top_dirs, &zone_name)?;
+    let interrupts = Interrupts::install()?;
+    let mzr_dir = top_dirs.mzr_dir.clone();
+    let stop_zone_name = zone_name.clone();
+    let _zone_cleanup = interrupts.on_interrupt(
+        &format!("stopping temporary zone {}", zone_name),
+        move || daemon::stop_zone(&mzr_dir, &stop_zone_name),
+    );
+    if opts.isolate_network {
+        namespaces::unshare_network(opts.loopback)?;
+    }
+    if let Some(cgroup) = &cgroup {
+        cgroup.enter()?;
+    }
+    let start_time = Utc::now();
+    let start_instant = Instant::now();
+    let mut command = Command::new(cmd);
+    command.args(&opts.args);
+    // Only the command is restricted, since this process still stops the
+    // zone and merges afterwards.
+    let capabilities = zone_capabilities(&top_dirs)?;
+    privileges::cloexec_inherited_fds(&[])?;
+    // Restricting only makes syscalls, so it's safe to do between fork and
+    // exec.
+    unsafe {
+        command.pre_exec(move || privileges::restrict(&capabilities));
+    }
+    if opts.sandbox {
+        let writable_dirs = vec![
+            top_dirs.user_work_dir.to_path_buf(),
+            PathBuf::from("/tmp"),
+            PathBuf::from("/dev"),
+        ];
+        let sandbox = Sandbox::new(&writable_dirs, opts.seccomp)?;
+        // Applying the sandbox only makes syscalls, so it's safe to do
+        // between fork and exec.
+        unsafe {
+            command.pre_exec(move || sandbox.apply());
+        }
+    }
+    let mut child = command.spawn()?;
+    let child_forwarding = interrupts.forward_to(child.id());
+    let status = child.wait()?;
+    drop(child_forwarding);
+    let duration = start_instant.elapsed();
+    let resource_usage = match cgroup {
+        None => None,
+        Some(cgroup) => {
+            cgroup.leave()?;
+            Some(cgroup.usage()?)
+        }
+    };
+    // TODO: I suppose the next steps here are:
+    //
+    // 1) Have this handled by the daemon, so that it has write access to the original working copy.
+    //
+    // 2) Summarize updates and display conflicts and skips. Ask about the conflicts and skips
+    //
+    // 3) Delete zone and snap if specified.
+    //
+    // 4) Should store in the zone and snap metadata that they are temporary.
+    let plan = match &parent_zone {
+        // Only planned, so that the record says what a merge would do.
+        _ if opts.no_merge => {
+            let target_dir = match &parent_zone {
+                Some(parent_zone) => parent_zone.ovfs_mount_dir.to_path_buf(),
+                None => top_dirs.user_work_dir.to_path_buf(),
+            };
+            merge::plan(&zone, &target_dir, &[], &merge::PathFilter::default())?.summary()
+        }
+        // The parent zone's overlayfs is mounted in the daemon's namespace,
+        // which zone processes inherit, so updates can be written through it.
+        Some(parent_zone) => {
+            let target_dir = parent_zone.ovfs_mount_dir.to_path_buf();
+            let target_dir_string = target_dir.to_string_lossy().into_owned();
+            let hook_vars = [
+                ("MZR_HOOK_ZONE", zone.name.as_str()),
+                ("MZR_HOOK_TARGET_DIR", target_dir_string.as_str()),
+            ];
+            hooks::run(&top_dirs.mzr_dir, Hook::PreMerge, &hook_vars)?;
+            let plan = merge::apply_updates(&top_dirs.mzr_dir, &zone, &target_dir, &[])?;
+            println!(
+                "Merged {} update(s) into zone {}.",
+                plan.updates.len(),
+                parent_zone.name
+            );
+            events::record(
+                &top_dirs.mzr_dir,
+                EventKind::Merged {
+                    zone: zone.name.clone(),
+                    target_dir: target_dir.clone(),
+                    summary: plan.summary(),
+                },
+            );
+            hooks::run_or_warn(&top_dirs.mzr_dir, Hook::PostMerge, &hook_vars);
+            plan.summary()
+        }
+        None => interactive_merge(
+            &zone,
+            top_dirs.user_work_dir.as_ref(),
+            Mode::AutoApplyUpdates,
+        )?,
+    };
+    if !opts.no_merge {
+        if let Err(err) = daemon::record_merge(&top_dirs.mzr_dir, &zone_name, &plan) {
+            println!(
+                "{} failed to record merge in daemon metrics: {}",
+                colors::color_warn(&"Warning:"),
+                err
+            );
+        }
+    }
+    let mut run_info = RunInfo::new(cmd, &opts.args, &zone, start_time, duration, status, plan);
+    run_info.resource_usage = resource_usage;
+    run_info.parent_zone = zone.info.parent.clone();
+    run_info.write(&zone.zone_dir)?;
+    println!();
+    println!("{}", run_info);
+    if status.success() && !opts.no_merge && run_info.plan.conflicts > 0 {
+        return Err(kind_error(
+            ErrorKind::MergeConflicts,
+            format!(
+                "The command succeeded, but {} conflicting file(s) were not merged.",
+                run_info.plan.conflicts
+            ),
+        ));
+    }
+    let _void = exit_with_status(status);
+    unreachable(_void)
+}
+
+fn run_matrix(opts: &RunOpts) -> Result<(), Error> {
+    if opts.cmd.is_some() {
+        bail!("CMD can't be specified along with --cmd or --matrix.");
+    }
+    if opts.snapshot.is_some() || opts.no_merge {
+        bail!("--snapshot and --no-merge can't be specified along with --cmd or --matrix.");
+    }
+    let top_dirs = TopDirs::find_or_prompt_create("run commands in temp mzr zones")?;
+    if let Location::Within(_) = zone::current_location(&top_dirs)? {
+        bail!("--cmd and --matrix can't be used within a zone.");
+    }
+    let mut cmds = opts.cmds.clone();
+    if let Some(matrix) = &opts.matrix {
+        cmds.extend(run_matrix::read_matrix_file(matrix)?);
+    }
+    if cmds.is_empty() {
+        bail!("No commands to run were specified.");
+    }
+    let jobs = opts.jobs.unwrap_or_else(|| cmds.len());
+    check_run_isolation_opts(opts)?;
+    // The commands share one snapshot, so that they all start from the same
+    // files, and aren't each snapshotting the work dir at once.
+    let snap_name = SnapName::new(tmp_run_name(Pid::this()))?;
+    let interrupts = Interrupts::install()?;
+    let snap_cleanup =
+        on_interrupt_remove(&interrupts, &top_dirs, Removal::Snapshot(snap_name.clone()));
+    println!("Taking temporary snapshot named {}", snap_name);
+    snapshot::of_workdir(&top_dirs, &snap_name)?;
+    drop(snap_cleanup);
+    interrupts.uninstall()?;
+    let mut run_args = run_isolation_args(opts);
+    run_args.push(format!("--snapshot={}", snap_name));
+    run_args.push(String::from("--no-merge"));
+    if run_matrix::run(&top_dirs, cmds, jobs, run_args)? {
+        Ok(())
+    } else {
+        bail!("Not all commands succeeded.")
+    }
+}
+
+/// Registers removal of a temporary zone or snapshot if interrupted, since it
+/// may only be partially created.
+fn on_interrupt_remove(
+    interrupts: &Interrupts,
+    top_dirs: &TopDirs,
+    removal: Removal,
+) -> cleanup::Registration {
+    let top_dirs = top_dirs.clone();
+    interrupts.on_interrupt(&format!("removing {}", removal), move || {
+        let exists = match &removal {
+            Removal::Zone(zone_name) => Zone::exists(&top_dirs.mzr_dir, zone_name),
+            Removal::Snapshot(snap_name) => snapshot::exists(&top_dirs.mzr_dir, snap_name),
+            Removal::MergeBackup(name) => {
+                MergeBackupsDir::new(&top_dirs.mzr_dir).join(name).exists()
+            }
+        };
+        if exists {
+            retention::apply(&top_dirs, &[removal.clone()])?;
+        }
+        Ok(())
+    })
+}
+
+fn check_run_isolation_opts(opts: &RunOpts) -> Result<(), Error> {
+    if opts.loopback && !opts.isolate_network {
+        bail!("--loopback can only be used along with --isolate-network.");
+    }
+    if opts.seccomp && !opts.sandbox {
+        bail!("--seccomp can only be used along with --sandbox.");
+    }
+    match opts.cpus {
+        Some(cpus) if cpus.is_nan() || cpus <= 0.0 => bail!("--cpus must be positive."),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Arguments to pass along to each `mzr run` invoked for --cmd or --matrix,
+/// so that they're isolated and limited in the same way.
+fn run_isolation_args(opts: &RunOpts) -> Vec<String> {
+    let mut args = Vec::new();
+    if opts.isolate_network {
+        args.push(String::from("--isolate-network"));
+    }
+    if opts.loopback {
+        args.push(String::from("--loopback"));
+    }
+    if opts.sandbox {
+        args.push(String::from("--sandbox"));
+    }
+    if opts.seccomp {
+        args.push(String::from("--seccomp"));
+    }
+    if let Some(memory) = opts.memory {
+        args.push(format!("--memory={}", memory));
+    }
+    if let Some(cpus) = opts.cpus {
+        args.push(format!("--cpus={}", cpus));
+    }
+    if let Some(pids_limit) = opts.pids_limit {
+        args.push(format!("--pids-limit={}", pids_limit));
+    }
+    args
+}
+
+fn run_limits(opts: &RunOpts) -> Limits {
+    Limits {
+        memory: opts.memory,
+        cpus: opts.cpus,
+        pids: opts.pids_limit,
+    }
+}
+
+fn show_last_run() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show the most recent run")?;
+    match RunInfo::load_last(&top_dirs.mzr_dir)? {
+        None => println!(
+            "No record of any {} invocations found.",
+            colors::color_cmd(&"mzr run")
+        ),
+        Some(run_info) => println!("{}", run_info),
+    }
+    Ok(())
+}
+
+/*
+ * "mzr exec"
+ */
+
+#[derive(Args, Debug)]
+pub struct ExecOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to run the command in.")]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "CMD",
+        help = "Command to run. Use -- before it if it has flags."
+    )]
+    cmd: String,
+    #[arg(value_name = "ARGS")]
+    args: Vec<String>,
+}
+
+fn exec(opts: &ExecOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("run command in mzr zone")?;
+    if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
+        return Err(kind_error(
+            ErrorKind::ZoneNotFound,
+            format!("Zone {} does not exist.", opts.zone_name),
+        ));
+    }
+    enter_zone(&top_dirs, &opts.zone_name)?;
+    // The command needs to be a child process to be within the zone's PID
+    // namespace. The parent exits with the command's exit code.
+    namespaces::continue_in_child()?;
+    restrict_zone_privileges(&top_dirs)?;
+    let err = Command::new(&opts.cmd).args(&opts.args).exec();
+    bail!(
+        "Failed to execute {}: {}",
+        colors::color_cmd(&opts.cmd),
+        err
+    )
+}
+
+/*
+ * "mzr snap"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapOpts {
+    #[command(flatten)]
+    create: SnapCreateOpts,
+    #[command(subcommand)]
+    cmd: Option<SnapCmd>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapCreateOpts {
+    #[arg(
+        value_name = "SNAP_NAME",
+        value_parser = parse_arg::<SnapName>,
+        help = "Name of the snapshot to create. \
+                If unspecified, a name will be generated based on the current git branch name. \
+                If that name is already taken, a _vN suffix is added to it."
+    )]
+    snap_name: Option<SnapName>,
+    #[arg(
+        long = "reuse",
+        help = "If a snapshot with the name already exists, use it as-is rather than taking \
+                a new snapshot."
+    )]
+    reuse: bool,
+    #[arg(
+        long = "update",
+        help = "Bring an existing snapshot up to date with the working directory, \
+                copying only what has changed."
+    )]
+    update: bool,
+    #[arg(
+        long = "force",
+        help = "With --update, update the snapshot even though zones use it. Their mounts \
+                may misbehave until they're remounted, and merging them compares against the \
+                updated snapshot, so can overwrite changes made in the work directory."
+    )]
+    force: bool,
+    #[arg(
+        long = "dedupe",
+        help = "Store the snapshot's files in the content-addressed objects store, so that \
+                files identical to those in other deduplicated snapshots are stored once. \
+                Useful on filesystems which don't support reflinks."
+    )]
+    dedupe: bool,
+    #[arg(
+        long = "from-zone",
+        help = "Allow taking the snapshot from within a zone, in which case it includes the \
+                zone's changes."
+    )]
+    from_zone: bool,
+    #[arg(
+        long = "path",
+        help = "Only include this file or directory in the snapshot, which can be given \
+                multiple times. Zones of the snapshot only overlay these paths, with the rest \
+                of the work directory being shared with it, so that mzr can be used on part \
+                of a large repository."
+    )]
+    paths: Vec<PathBuf>,
+    #[arg(
+        long = "auto",
+        help = "Take a snapshot of the current git commit, if automatic snapshots are \
+                enabled and the commit doesn't already have one. This is run by the hooks \
+                installed by mzr git install-hooks."
+    )]
+    auto: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapCmd {
+    #[command(
+        name = "create",
+        about = "Create mzr snapshot of working directory, same as mzr snap without a subcommand"
+    )]
+    Create {
+        #[command(flatten)]
+        opts: SnapCreateOpts,
+    },
+    #[command(
+        name = "list",
+        about = "List snapshots, along with the zones using them"
+    )]
+    List {
+        #[command(flatten)]
+        opts: ListingOpts,
+    },
+    #[command(
+        name = "remove",
+        about = "Remove snapshots which no zones use",
+        alias = "rm"
+    )]
+    Remove {
+        #[command(flatten)]
+        opts: SnapRemoveOpts,
+    },
+    #[command(
+        name = "export",
+        about = "Export a snapshot as a zstd compressed tarball"
+    )]
+    Export {
+        #[command(flatten)]
+        opts: SnapExportOpts,
+    },
+    #[command(
+        name = "import",
+        about = "Import a snapshot from a tarball created by mzr snap export"
+    )]
+    Import {
+        #[command(flatten)]
+        opts: SnapImportOpts,
+    },
+    #[command(
+        name = "pin",
+        about = "Protect a snapshot from being removed by the retention policy"
+    )]
+    Pin {
+        #[command(flatten)]
+        opts: SnapPinOpts,
+    },
+    #[command(name = "unpin", about = "Undo mzr snap pin")]
+    Unpin {
+        #[command(flatten)]
+        opts: SnapPinOpts,
+    },
+    #[command(
+        name = "verify",
+        about = "Check a snapshot's files against the hashes recorded when it was taken"
+    )]
+    Verify {
+        #[command(flatten)]
+        opts: SnapVerifyOpts,
+    },
+}
+
+fn snap(opts: &SnapOpts) -> Result<(), Error> {
+    match &opts.cmd {
+        Some(SnapCmd::Create { opts }) => snap_create(opts),
+        Some(SnapCmd::List { opts }) => snap_list(opts),
+        Some(SnapCmd::Remove { opts }) => snap_remove(opts),
+        Some(SnapCmd::Export { opts }) => snap_export(opts),
+        Some(SnapCmd::Import { opts }) => snap_import(opts),
+        Some(SnapCmd::Pin { opts }) => snap_pin(opts, true),
+        Some(SnapCmd::Unpin { opts }) => snap_pin(opts, false),
+        Some(SnapCmd::Verify { opts }) => snap_verify(opts),
+        None => snap_create(&opts.create),
+    }
+}
+
+fn snap_create(opts: &SnapCreateOpts) -> Result<(), Error> {
+    if opts.auto {
+        return snap_auto();
+    }
+    let top_dirs = TopDirs::find_or_prompt_create("take mzr snapshot")?;
+    if let (false, Location::Within(zone_name)) =
+        (opts.from_zone, zone::current_location(&top_dirs)?)
+    {
+        let zone_desc = match zone_name {
+            Some(zone_name) => format!("zone {}", zone_name),
+            None => "a zone".to_string(),
+        };
+        bail!(
+            "The work directory is currently a view of {}, so a snapshot of it would include \
+             the zone's changes. Run mzr snap outside of the zone, or use --from-zone if \
+             that's intended.",
+            zone_desc
+        );
+    }
+    let mut snap_name = default_snap_name(&top_dirs, &opts.snap_name)?;
+    if opts.update {
+        if !opts.paths.is_empty() {
+            bail!("--path can't be used along with --update, which keeps the snapshot's paths.");
+        }
+        return snap_update(&top_dirs, &snap_name, opts.force);
+    }
+    let current_dir = env::current_dir()?;
+    let mut rel_paths = Vec::new();
+    for path in opts.paths.iter() {
+        let rel_path = rel_path_within_work_dir(&top_dirs.user_work_dir, &current_dir.join(path))?;
+        if rel_path.as_os_str().is_empty() {
+            bail!("--path {:?} is the whole work directory.", path);
+        }
+        if fs::symlink_metadata(top_dirs.user_work_dir.join(&rel_path)).is_err() {
+            bail!("--path {:?} doesn't exist.", path);
+        }
+        if rel_paths
+            .iter()
+            .any(|other: &PathBuf| other.starts_with(&rel_path) || rel_path.starts_with(other))
+        {
+            bail!("--path {:?} overlaps with another --path.", path);
+        }
+        rel_paths.push(rel_path);
+    }
+    if snapshot::exists(&top_dirs.mzr_dir, &snap_name) {
+        if opts.reuse {
+            println!("Reusing existing snapshot named {}", snap_name);
+            return Ok(());
+        }
+        if opts.snap_name.is_some() {
+            return Err(kind_error(
+                ErrorKind::SnapshotExists,
+                format!(
+                    "A snapshot named {} already exists. Use --reuse to use it as-is.",
+                    snap_name
+                ),
+            ));
+        }
+        let versioned_name = snapshot::next_versioned_name(&top_dirs.mzr_dir, &snap_name)?;
+        println!(
+            "A snapshot named {} already exists, so instead using {}",
+            snap_name, versioned_name
+        );
+        snap_name = versioned_name;
+    }
+    println!("Taking a snapshot named {}", snap_name);
+    let interrupts = Interrupts::install()?;
+    let snap_cleanup =
+        on_interrupt_remove(&interrupts, &top_dirs, Removal::Snapshot(snap_name.clone()));
+    let snap_dir = snapshot::of_workdir_paths(&top_dirs, &snap_name, &rel_paths)?;
+    drop(snap_cleanup);
+    println!(
+        "{} snapshot named {} taken.",
+        colors::color_success(&"Success:"),
+        snap_name
+    );
+    if opts.dedupe {
+        println!("Deduplicating snapshot files.");
+        let stats = snapshot::with_writable(&top_dirs.mzr_dir, &[snap_name.clone()], || {
+            objects::dedupe_snapshot(&top_dirs.mzr_dir, &snap_dir)
+        })?;
+        println!(
+            "{} {} file(s) are now in the objects store, saving {} bytes.",
+            colors::color_success(&"Success:"),
+            stats.linked_files,
+            stats.saved_bytes
+        );
+    }
+    Ok(())
+}
+
+fn snap_auto() -> Result<(), Error> {
+    // The hooks are shared with zones, but snapshots are taken of the user's
+    // work dir, so commits within zones are ignored.
+    if env::var_os("MZR_DIR").is_some() {
+        return Ok(());
+    }
+    let top_dirs = TopDirs::find("take automatic mzr snapshot")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    if !Config::load(mzr_dir)?.auto_snapshot {
+        return Ok(());
+    }
+    let commit = git::head_sha(&top_dirs.user_work_dir)?;
+    for snap_name in snapshot::list_names(mzr_dir)? {
+        if SnapInfo::load(mzr_dir, &snap_name)?.git_commit.as_ref() == Some(&commit) {
+            println!(
+                "Snapshot {} was already taken at commit {}.",
+                snap_name,
+                &commit[..7]
+            );
+            return Ok(());
+        }
+    }
+    let mut snap_name = git::default_snap_name(&top_dirs.user_work_dir)?;
+    if snapshot::exists(mzr_dir, &snap_name) {
+        snap_name = snapshot::next_versioned_name(mzr_dir, &snap_name)?;
+    }
+    println!(
+        "Taking a snapshot named {} of commit {}",
+        snap_name,
+        &commit[..7]
+    );
+    snapshot::of_workdir(&top_dirs, &snap_name)?;
+    println!(
+        "{} snapshot named {} taken.",
+        colors::color_success(&"Success:"),
+        snap_name
+    );
+    Ok(())
+}
+
+/// Updates the snapshot in place. Zones use their snapshot as the lower dir
+/// of their overlay, which overlayfs doesn't expect to change while mounted,
+/// and as the baseline when merging, so this is refused when zones use the
+/// snapshot, unless forced.
+fn snap_update(top_dirs: &TopDirs, snap_name: &SnapName, force: bool) -> Result<(), Error> {
+    let mut users = Vec::new();
+    for zone_name in Zone::list_names(&top_dirs.mzr_dir)? {
+        let zone = Zone::load(&top_dirs.mzr_dir, &zone_name)?;
+        if zone.info.snapshot.as_str() == snap_name.as_str() {
+            users.push(zone_name.to_string());
+        }
+    }
+    if !users.is_empty() {
+        if !force {
+            bail!(
+                "Refusing to update snapshot {}, since it's used by zones {}. Updating it \
+                 would change their files underneath them, and make merging them overwrite \
+                 changes in the work directory. Take a new snapshot instead, or use --force \
+                 to update it anyway.",
+                snap_name,
+                users.join(", ")
+            );
+        }
+        println!(
+            "{} the following zones use snapshot {}, and will see the updated contents. \
+             Remount them with mzr zone repair, and take care when merging them:",
+            colors::color_warn(&"Warning:"),
+            snap_name
+        );
+        for zone_name in users {
+            println!("* {}", zone_name);
+        }
+    }
+    println!("Updating snapshot named {}", snap_name);
+    let info = snapshot::update_from_workdir(top_dirs, snap_name)?;
+    println!(
+        "{} snapshot named {} updated to version {}.",
+        colors::color_success(&"Success:"),
+        snap_name,
+        info.version
+    );
+    Ok(())
+}
+
+/*
+ * "mzr snap export"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapExportOpts {
+    #[arg(value_name = "SNAP_NAME", value_parser = parse_arg::<SnapName>, help = "Name of the snapshot to export.")]
+    snap_name: SnapName,
+    #[arg(
+        value_name = "ARCHIVE",
+        help = "Path of the archive to create, such as snapshot.tar.zst"
+    )]
+    archive: PathBuf,
+}
+
+fn snap_export(opts: &SnapExportOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("export mzr snapshot")?;
+    println!("Exporting snapshot named {}", opts.snap_name);
+    snapshot_archive::export(&top_dirs, &opts.snap_name, &opts.archive)?;
+    println!(
+        "{} snapshot named {} exported to {}.",
+        colors::color_success(&"Success:"),
+        opts.snap_name,
+        colors::color_file(&opts.archive.display())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr snap import"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapImportOpts {
+    #[arg(
+        value_name = "ARCHIVE",
+        help = "Path of an archive created by mzr snap export."
+    )]
+    archive: PathBuf,
+    #[arg(
+        value_name = "SNAP_NAME",
+        value_parser = parse_arg::<SnapName>,
+        help = "Name for the imported snapshot. Defaults to the name it was exported with."
+    )]
+    snap_name: Option<SnapName>,
+}
+
+fn snap_import(opts: &SnapImportOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("import mzr snapshot")?;
+    let snap_name = snapshot_archive::import(&top_dirs.mzr_dir, &opts.archive, &opts.snap_name)?;
+    println!(
+        "{} snapshot named {} imported.",
+        colors::color_success(&"Success:"),
+        snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr snap list"
+ */
+
+fn snap_list(opts: &ListingOpts) -> Result<(), Error> {
+    let humanize = opts.humanize();
+    let top_dirs = TopDirs::find("list mzr snapshots")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    let mut snap_names = snapshot::list_names(mzr_dir)?;
+    snap_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    let mut snap_zones: HashMap<String, Vec<ZoneName>> = HashMap::new();
+    for zone_name in Zone::list_names(mzr_dir)? {
+        let zone = Zone::load(mzr_dir, &zone_name)?;
+        snap_zones
+            .entry(zone.info.snapshot.as_str().to_string())
+            .or_insert_with(Vec::new)
+            .push(zone_name);
+    }
+    if snap_names.is_empty() {
+        println!("There are no snapshots.");
+    }
+    for snap_name in snap_names {
+        let info = SnapInfo::load(mzr_dir, &snap_name)?;
+        let mut state = format!("created {}", humanize.time(&info.creation_time));
+        if let Some(update_time) = &info.update_time {
+            state.push_str(&format!(", updated {}", humanize.time(update_time)));
+        }
+        if let Some(git_commit) = &info.git_commit {
+            state.push_str(&format!(
+                ", commit {}",
+                &git_commit[..git_commit.len().min(10)]
+            ));
+        }
+        if info.pinned {
+            state.push_str(", pinned");
+        }
+        match snap_zones.get(snap_name.as_str()) {
+            Some(zone_names) => {
+                let names: Vec<&str> = zone_names.iter().map(|name| name.as_str()).collect();
+                state.push_str(&format!(", used by {}", names.join(", ")));
+            }
+            None => state.push_str(", unused"),
+        }
+        println!("{} ({})", snap_name, state);
+    }
+    Ok(())
+}
+
+/*
+ * "mzr snap remove"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapRemoveOpts {
+    #[arg(value_name = "SNAP_NAME", value_parser = parse_arg::<SnapName>, help = "Names of the snapshots to remove.")]
+    snap_names: Vec<SnapName>,
+}
+
+fn snap_remove(opts: &SnapRemoveOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("remove mzr snapshot")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    if opts.snap_names.is_empty() {
+        bail!("No snapshots were specified to remove.");
+    }
+    let mut removals = Vec::new();
+    for snap_name in opts.snap_names.iter() {
+        if !snapshot::exists(mzr_dir, snap_name) {
+            bail!("Snapshot {} does not exist.", snap_name);
+        }
+        if SnapInfo::load(mzr_dir, snap_name)?.pinned {
+            bail!(
+                "Snapshot {} is pinned, so it wasn't removed. Use {} first to remove it.",
+                snap_name,
+                colors::color_cmd(&format!("mzr snap unpin {}", snap_name))
+            );
+        }
+        removals.push(Removal::Snapshot(snap_name.clone()));
+    }
+    for zone_name in Zone::list_names(mzr_dir)? {
+        let zone = Zone::load(mzr_dir, &zone_name)?;
+        let snapshot = zone.info.snapshot.as_str();
+        if opts.snap_names.iter().any(|name| name.as_str() == snapshot) {
+            bail!(
+                "Snapshot {} is used by zone {}, so no snapshots were removed. Remove the zone \
+                 first with {}.",
+                zone.info.snapshot,
+                zone_name,
+                colors::color_cmd(&format!("mzr zone remove {}", zone_name))
+            );
+        }
+    }
+    retention::apply(&top_dirs, &removals)?;
+    for removal in removals {
+        println!(
+            "{} removed {}.",
+            colors::color_success(&"Success:"),
+            removal
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr snap pin" and "mzr snap unpin"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapPinOpts {
+    #[arg(value_name = "SNAP_NAME", value_parser = parse_arg::<SnapName>, help = "Name of the snapshot.")]
+    snap_name: SnapName,
+}
+
+fn snap_pin(opts: &SnapPinOpts, pinned: bool) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("pin mzr snapshot")?;
+    let was_pinned = snapshot::set_pinned(&top_dirs.mzr_dir, &opts.snap_name, pinned)?;
+    match (was_pinned, pinned) {
+        (true, true) => println!("Snapshot {} was already pinned.", opts.snap_name),
+        (false, false) => println!("Snapshot {} wasn't pinned.", opts.snap_name),
+        (_, true) => println!(
+            "{} snapshot {} pinned, so the retention policy won't remove it.",
+            colors::color_success(&"Success:"),
+            opts.snap_name
+        ),
+        (_, false) => println!(
+            "{} snapshot {} unpinned.",
+            colors::color_success(&"Success:"),
+            opts.snap_name
+        ),
+    }
+    Ok(())
+}
+
+/*
+ * "mzr snap verify"
+ */
+
+#[derive(Args, Debug)]
+pub struct SnapVerifyOpts {
+    #[arg(value_name = "SNAP_NAME", value_parser = parse_arg::<SnapName>, help = "Name of the snapshot to verify.")]
+    snap_name: SnapName,
+    #[arg(
+        long = "record",
+        help = "Record the hashes of the snapshot's current files as its manifest, rather than \
+                verifying them. Useful for snapshots taken before manifests were recorded."
+    )]
+    record: bool,
+}
+
+fn snap_verify(opts: &SnapVerifyOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("verify mzr snapshot")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    let snap_name = &opts.snap_name;
+    if !snapshot::exists(mzr_dir, snap_name) {
+        return Err(kind_error(
+            ErrorKind::SnapshotNotFound,
+            format!("Snapshot {} does not exist.", snap_name),
+        ));
+    }
+    if opts.record {
+        let manifest = snapshot_manifest::record(mzr_dir, snap_name)?;
+        println!(
+            "{} recorded the hashes of {} file(s) in snapshot {}.",
+            colors::color_success(&"Success:"),
+            manifest.files.len(),
+            snap_name
+        );
+        return Ok(());
+    }
+    let manifest = match SnapManifest::load(mzr_dir, snap_name)? {
+        Some(manifest) => manifest,
+        None => bail!(
+            "Snapshot {} has no manifest, since it was taken by an older version of mzr. \
+             Use --record to record the hashes of its current files.",
+            snap_name
+        ),
+    };
+    let verification = snapshot_manifest::verify(mzr_dir, snap_name, &manifest)?;
+    let problems = [
+        ("missing", &verification.missing),
+        ("corrupted", &verification.corrupted),
+        ("unexpected", &verification.unexpected),
+    ];
+    for (description, paths) in problems.iter() {
+        for path in paths.iter() {
+            println!(
+                "{} {}",
+                colors::color_warn(&format!("{}:", description)),
+                colors::color_file(&path.display())
+            );
+        }
+    }
+    if !verification.is_ok() {
+        bail!(
+            "Snapshot {} doesn't match its manifest: {} missing, {} corrupted, and {} unexpected \
+             file(s), out of {} recorded.",
+            snap_name,
+            verification.missing.len(),
+            verification.corrupted.len(),
+            verification.unexpected.len(),
+            verification.checked
+        );
+    }
+    println!(
+        "{} all {} file(s) in snapshot {} match its manifest.",
+        colors::color_success(&"Success:"),
+        verification.checked,
+        snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr list"
+ */
+
+fn list(opts: &ListingOpts) -> Result<(), Error> {
+    let humanize = opts.humanize();
+    if let Some(host) = remote_daemon::host() {
+        return list_remote(&host, &humanize);
+    }
+    let top_dirs = TopDirs::find("list mzr zones")?;
+    let mut zone_names = Zone::list_names(&top_dirs.mzr_dir)?;
+    zone_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    let mut pinned_snapshots = BTreeSet::new();
+    // When the daemon is running, it also reports which zones are running
+    // and where they're mounted.
+    let mut zone_statuses = HashMap::new();
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        for status in daemon::list_zones(&top_dirs.mzr_dir)? {
+            zone_statuses.insert(status.name.as_str().to_string(), status);
+        }
+        for status in daemon::list_snapshots(&top_dirs.mzr_dir)? {
+            if status.info.pinned {
+                pinned_snapshots.insert(status.name.as_str().to_string());
+            }
+        }
+    } else {
+        for snap_name in snapshot::list_names(&top_dirs.mzr_dir)? {
+            if SnapInfo::load(&top_dirs.mzr_dir, &snap_name)?.pinned {
+                pinned_snapshots.insert(snap_name.as_str().to_string());
+            }
+        }
+    }
+    if zone_names.is_empty() {
+        println!("There are no zones.");
+    }
+    for zone_name in zone_names {
+        let zone = Zone::load(&top_dirs.mzr_dir, &zone_name)?;
+        let frozen = if freezer::is_frozen(&top_dirs.mzr_dir, &zone_name) {
+            format!(", {}", colors::color_warn(&"frozen"))
+        } else {
+            String::new()
+        };
+        let temporary = if zone.info.temporary {
+            ", temporary"
+        } else {
+            ""
+        };
+        let mut daemon_state = String::new();
+        let status = zone_statuses.get(zone_name.as_str());
+        if let Some(status) = status {
+            if status.running {
+                daemon_state.push_str(", running");
+            }
+            for target in status.mounted_at.iter() {
+                daemon_state.push_str(&format!(", mounted at {}", color_dir(&target.display())));
+            }
+        }
+        let stats = match status {
+            Some(status) => status.stats.clone(),
+            None => ZoneStats::get(&zone).ok(),
+        };
+        if let Some(stats) = stats {
+            daemon_state.push_str(&format!(", {}", stats.describe(&humanize)));
+        }
+        let pinned = if pinned_snapshots.contains(zone.info.snapshot.as_str()) {
+            " [pinned]"
+        } else {
+            ""
+        };
+        let created = humanize.time(&zone.info.creation_time);
+        match &zone.info.branch {
+            None => println!(
+                "{} (snapshot {}{}, created {}{}{}{})",
+                zone.name, zone.info.snapshot, pinned, created, temporary, daemon_state, frozen
+            ),
+            Some(branch) => println!(
+                "{} (snapshot {}{}, branch {}, created {}{}{}{})",
+                zone.name,
+                zone.info.snapshot,
+                pinned,
+                branch,
+                created,
+                temporary,
+                daemon_state,
+                frozen
+            ),
+        }
+        if let Some(description) = &zone.info.description {
+            println!("  {}", description);
+        }
+    }
+    if !pinned_snapshots.is_empty() {
+        let names: Vec<&str> = pinned_snapshots.iter().map(String::as_str).collect();
+        println!("Pinned snapshots: {}", names.join(", "));
+    }
+    Ok(())
+}
+
+/// Lists the zones of a remote daemon, which reports everything that's
+/// listed, since its project may not exist locally.
+fn list_remote(host: &str, humanize: &Humanize) -> Result<(), Error> {
+    let addr = DaemonAddr::Remote(host.to_string());
+    let mut zones = daemon::list_zones(&addr)?;
+    zones.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+    if zones.is_empty() {
+        println!("There are no zones.");
+    }
+    for zone in zones {
+        let mut state = String::new();
+        if let Some(branch) = &zone.info.branch {
+            state.push_str(&format!(", branch {}", branch));
+        }
+        state.push_str(&format!(
+            ", created {}",
+            humanize.time(&zone.info.creation_time)
+        ));
+        if zone.info.temporary {
+            state.push_str(", temporary");
+        }
+        if zone.running {
+            state.push_str(", running");
+        }
+        for target in zone.mounted_at.iter() {
+            state.push_str(&format!(", mounted at {}", color_dir(&target.display())));
+        }
+        if zone.frozen {
+            state.push_str(&format!(", {}", colors::color_warn(&"frozen")));
+        }
+        if let Some(stats) = &zone.stats {
+            state.push_str(&format!(", {}", stats.describe(humanize)));
+        }
+        println!("{} (snapshot {}{})", zone.name, zone.info.snapshot, state);
+        if let Some(description) = &zone.info.description {
+            println!("  {}", description);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr conflicts"
+ */
+
+#[derive(Args, Debug)]
+pub struct ConflictsOpts {
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Only report paths which this zone has changed. Defaults to all zones."
+    )]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        long = "include-runs",
+        help = "Also compare the zones of finished mzr run invocations."
+    )]
+    include_runs: bool,
+}
+
+fn conflicts(opts: &ConflictsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("find conflicting mzr zones")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    if let Some(zone_name) = &opts.zone_name {
+        Zone::load(mzr_dir, zone_name)?;
+    }
+    let mut zones = Vec::new();
+    for zone_name in Zone::list_names(mzr_dir)? {
+        let is_selected = opts.zone_name.as_ref() == Some(&zone_name);
+        if !is_selected && !opts.include_runs && RunInfo::load(mzr_dir, &zone_name)?.is_some() {
+            continue;
+        }
+        zones.push(Zone::load(mzr_dir, &zone_name)?);
+    }
+    // Zones share the user's git directory, so git's own files would
+    // otherwise show up as overlapping.
+    let excluded_dirs: Vec<PathBuf> = git::get_git_dir(&top_dirs.user_work_dir)
+        .into_iter()
+        .map(|rel_git_dir| rel_git_dir.to_path_buf())
+        .collect();
+    let mut overlaps = conflicts::find_overlaps(&zones, &excluded_dirs)?;
+    if let Some(zone_name) = &opts.zone_name {
+        overlaps.retain(|overlap| overlap.zones.contains(zone_name));
+    }
+    if overlaps.is_empty() {
+        println!(
+            "{} no paths have been changed within more than one zone.",
+            colors::color_success(&"Success:")
+        );
+        return Ok(());
+    }
+    let mut last_snapshot: Option<&SnapName> = None;
+    for overlap in overlaps.iter() {
+        if last_snapshot.map(|snap| snap.as_str()) != Some(overlap.snapshot.as_str()) {
+            println!("Zones of snapshot {}:", overlap.snapshot);
+            last_snapshot = Some(&overlap.snapshot);
+        }
+        let zone_names: Vec<&str> = overlap.zones.iter().map(|name| name.as_str()).collect();
+        println!(
+            "  {} changed in {}",
+            overlap.rel_path.display(),
+            zone_names.join(", ")
+        );
+    }
+    println!(
+        "{} {} path(s) have been changed within more than one zone, so merging one of those \
+         zones will cause conflicts when merging the others.",
+        colors::color_warn(&"Warning:"),
+        overlaps.len()
+    );
+    Ok(())
+}
+
+/*
+ * "mzr du"
+ */
+
+#[derive(Args, Debug)]
+pub struct DuOpts {
+    #[arg(
+        long = "jobs",
+        short = 'j',
+        help = "Number of threads to use. Defaults to the number of processors."
+    )]
+    jobs: Option<usize>,
+    #[command(flatten)]
+    listing: ListingOpts,
+}
+
+fn du(opts: &DuOpts) -> Result<(), Error> {
+    let humanize = opts.listing.humanize();
+    let top_dirs = TopDirs::find("show disk usage")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    let mut labels = Vec::new();
+    let mut roots = Vec::new();
+    for snap_name in snapshot::list_names(mzr_dir)? {
+        roots.push(SnapDir::new(mzr_dir, &snap_name).to_path_buf());
+        labels.push(format!("snap {}", snap_name));
+    }
+    for zone_name in Zone::list_names(mzr_dir)? {
+        roots.push(ZoneDir::new(mzr_dir, &zone_name).to_path_buf());
+        labels.push(format!("zone {}", zone_name));
+    }
+    roots.push(ObjectsDir::new(mzr_dir).to_path_buf());
+    labels.push(String::from("objects"));
+    let jobs = opts.jobs.unwrap_or_else(dir_size::cpu_count);
+    let (sizes, total) = dir_size::measure(&roots, jobs)?;
+    let mut rows: Vec<(String, dir_size::DirSize)> = labels.into_iter().zip(sizes).collect();
+    rows.sort_by(|(_, a), (_, b)| b.actual.cmp(&a.actual));
+    println!("{:>12} {:>12}  NAME", "APPARENT", "ACTUAL");
+    for (label, size) in rows.iter() {
+        println!(
+            "{:>12} {:>12}  {}",
+            humanize.size(size.apparent),
+            humanize.size(size.actual),
+            label
+        );
+    }
+    println!(
+        "{:>12} {:>12}  total, with files hardlinked from multiple places counted once",
+        humanize.size(total.apparent),
+        humanize.size(total.actual)
+    );
+    if total.errors > 0 {
+        println!(
+            "{} {} entries couldn't be read, and so weren't counted.",
+            colors::color_warn(&"Warning:"),
+            total.errors
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr gc"
+ */
+
+#[derive(Args, Debug)]
+pub struct GcOpts {
+    #[arg(
+        long = "auto",
+        help = "Also remove snapshots and zones according to the retention policy."
+    )]
+    auto: bool,
+    #[arg(
+        long = "dry-run",
+        help = "Show what the retention policy would remove, without removing anything."
+    )]
+    dry_run: bool,
+    #[arg(
+        long = "keep-snapshots-per-branch",
+        help = "Number of snapshots to keep for each git branch, overriding the retention policy."
+    )]
+    keep_snapshots_per_branch: Option<usize>,
+    #[arg(
+        long = "max-run-zone-age-days",
+        help = "Number of days after which mzr run zones are removed, overriding the retention \
+                policy."
+    )]
+    max_run_zone_age_days: Option<i64>,
+    #[arg(
+        long = "keep-merge-backups",
+        help = "Number of merge backups to keep for mzr merge --undo-last, overriding the \
+                retention policy."
+    )]
+    keep_merge_backups: Option<usize>,
+    #[arg(
+        long = "save-policy",
+        help = "Save the retention policy, including any overrides, for future use by \
+                mzr gc --auto and mzr daemon --auto-gc-interval-hours."
+    )]
+    save_policy: bool,
+    #[arg(
+        long = "compact",
+        help = "Also deduplicate the files of all snapshots into the objects store, like \
+                mzr compact."
+    )]
+    compact: bool,
+}
+
+fn gc(opts: &GcOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("garbage collect")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    let mut policy = RetentionPolicy::load(mzr_dir)?;
+    if opts.keep_snapshots_per_branch.is_some() {
+        policy.keep_snapshots_per_branch = opts.keep_snapshots_per_branch;
+    }
+    if opts.max_run_zone_age_days.is_some() {
+        policy.max_run_zone_age_days = opts.max_run_zone_age_days;
+    }
+    if opts.keep_merge_backups.is_some() {
+        policy.keep_merge_backups = opts.keep_merge_backups;
+    }
+    if opts.save_policy {
+        policy.write(mzr_dir)?;
+        println!(
+            "{} saved retention policy: {}.",
+            colors::color_success(&"Success:"),
+            policy
+        );
+    }
+    if opts.auto || opts.dry_run {
+        if policy.is_empty() {
+            println!(
+                "No retention policy is set, so nothing will be removed. \
+                 It can be set via mzr gc --save-policy."
+            );
+        }
+        // When the daemon is running, it applies the policy, since it knows
+        // which zones are in use.
+        let removals = if daemon::socket_exists(mzr_dir) {
+            daemon::apply_retention(mzr_dir, &policy, opts.dry_run)?
+        } else {
+            let removals = retention::plan(mzr_dir, &policy, &HashSet::new())?;
+            if !opts.dry_run {
+                retention::apply(&top_dirs, &removals)?;
+            }
+            removals
+        };
+        for removal in removals.iter() {
+            if opts.dry_run {
+                println!("Would remove {}", removal);
+            } else {
+                println!("Removed {}", removal);
+            }
+        }
+        if opts.dry_run {
+            return Ok(());
+        }
+    }
+    // Within a zone, the daemon's mounts are visible, so they can't be
+    // distinguished from stale mounts.
+    if let Location::Outside = zone::current_location(&top_dirs)? {
+        let daemon_running = daemon::socket_exists(mzr_dir);
+        for mount in mountinfo::stale_mounts(mzr_dir, daemon_running)? {
+            println!(
+                "{} {} is a stale mount, which can be removed with umount.",
+                colors::color_warn(&"Warning:"),
+                color_dir(&mount.mount_point.display())
+            );
+        }
+    }
+    if opts.compact {
+        let snap_names = snapshot::list_names(mzr_dir)?;
+        let stats = compact_snapshots(&top_dirs, &snap_names, false)?;
+        print_compact_stats(&stats, false);
+    }
+    let stats = objects::gc(mzr_dir)?;
+    println!(
+        "{} removed {} unreferenced object(s), freeing {} bytes.",
+        colors::color_success(&"Success:"),
+        stats.removed_objects,
+        stats.removed_bytes
+    );
+    Ok(())
+}
+
+/*
+ * "mzr compact"
+ */
+
+#[derive(Args, Debug)]
+pub struct CompactOpts {
+    #[arg(
+        value_name = "SNAP_NAME",
+        value_parser = parse_arg::<SnapName>,
+        help = "Names of the snapshots to compact. If unspecified, all snapshots are compacted."
+    )]
+    snap_names: Vec<SnapName>,
+    #[arg(
+        long = "reflink",
+        help = "Make identical files share their contents via reflinks, rather than hardlinking \
+                them into the objects store. Files keep their own metadata, so more files can \
+                be shared. Requires a filesystem which supports reflinks, such as Btrfs or XFS."
+    )]
+    reflink: bool,
+}
+
+fn compact(opts: &CompactOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("compact mzr snapshots")?;
+    let snap_names = if opts.snap_names.is_empty() {
+        snapshot::list_names(&top_dirs.mzr_dir)?
+    } else {
+        for snap_name in opts.snap_names.iter() {
+            if !snapshot::exists(&top_dirs.mzr_dir, snap_name) {
+                return Err(kind_error(
+                    ErrorKind::SnapshotNotFound,
+                    format!("Snapshot {} does not exist.", snap_name),
+                ));
+            }
+        }
+        opts.snap_names.clone()
+    };
+    let stats = compact_snapshots(&top_dirs, &snap_names, opts.reflink)?;
+    print_compact_stats(&stats, opts.reflink);
+    Ok(())
+}
+
+/// Deduplicates the files of the snapshots, either by hardlinking them into
+/// the objects store, or by reflinking them to each other.
+fn compact_snapshots(
+    top_dirs: &TopDirs,
+    snap_names: &[SnapName],
+    reflink: bool,
+) -> Result<objects::DedupeStats, Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    let snap_dirs: Vec<SnapDir> = snap_names
+        .iter()
+        .map(|snap_name| SnapDir::new(mzr_dir, snap_name))
+        .collect();
+    println!("Compacting {} snapshot(s).", snap_dirs.len());
+    snapshot::with_writable(mzr_dir, snap_names, || {
+        if reflink {
+            return objects::reflink_snapshots(&snap_dirs);
+        }
+        let mut stats = objects::DedupeStats::default();
+        for snap_dir in snap_dirs.iter() {
+            let snap_stats = objects::dedupe_snapshot(mzr_dir, snap_dir)?;
+            stats.linked_files += snap_stats.linked_files;
+            stats.saved_bytes += snap_stats.saved_bytes;
+        }
+        Ok(stats)
+    })
+}
+
+/// Files which were already reflinked can't be distinguished, so with
+/// reflinks the space reclaimed is an upper bound.
+fn print_compact_stats(stats: &objects::DedupeStats, reflink: bool) {
+    println!(
+        "{} deduplicated {} file(s), reclaiming {}{}.",
+        colors::color_success(&"Success:"),
+        stats.linked_files,
+        if reflink { "up to " } else { "" },
+        Humanize::default().size(stats.saved_bytes)
+    );
+}
+
+/*
+ * "mzr push" and "mzr pull"
+ */
+
+#[derive(Args, Debug)]
+pub struct RemoteOpts {
+    #[arg(
+        long = "remote",
+        value_parser = parse_arg::<Remote>,
+        help = "Remote mzr directory, in the form [USER@]HOST:PATH"
+    )]
+    remote: Remote,
+    #[arg(value_name = "SNAP_NAME", value_parser = parse_arg::<SnapName>, help = "Name of the snapshot to transfer.")]
+    snap_name: SnapName,
+    #[arg(
+        long = "zone",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name of a zone based on the snapshot to also transfer. May be repeated."
+    )]
+    zones: Vec<ZoneName>,
+}
+
+fn push(opts: &RemoteOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("push mzr snapshot")?;
+    remote::push(&top_dirs, &opts.remote, &opts.snap_name, &opts.zones)?;
+    println!(
+        "{} snapshot {} pushed to {}.",
+        colors::color_success(&"Success:"),
+        opts.snap_name,
+        opts.remote
+    );
+    Ok(())
+}
+
+fn pull(opts: &RemoteOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("pull mzr snapshot")?;
+    remote::pull(&top_dirs, &opts.remote, &opts.snap_name, &opts.zones)?;
+    println!(
+        "{} snapshot {} pulled from {}.",
+        colors::color_success(&"Success:"),
+        opts.snap_name,
+        opts.remote
+    );
+    Ok(())
+}
+
+/*
+ * "mzr lsp-proxy"
+ */
+
+#[derive(Args, Debug)]
+pub struct LspProxyOpts {
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name of the zone to run the language server in."
+    )]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "CMD",
+        help = "Language server command, such as rust-analyzer."
+    )]
+    cmd: String,
+    #[arg(value_name = "ARGS")]
+    args: Vec<String>,
+}
+
+fn lsp_proxy(opts: &LspProxyOpts) -> Result<(), Error> {
+    // Note that this doesn't prompt to create a mzr directory, since stdin
+    // and stdout are used to communicate with the editor.
+    let top_dirs = TopDirs::find("run language server in mzr zone")?;
+    let status = lsp_proxy::run(&top_dirs, &opts.zone_name, &opts.cmd, &opts.args)?;
+    let _void = exit_with_status(status);
+    unreachable(_void)
+}
+
+/*
+ * "mzr mount"
+ */
+
+#[derive(Args, Debug)]
+pub struct MountOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to mount.")]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "TARGET_DIR",
+        help = "Existing directory to bind the zone to."
+    )]
+    target_dir: PathBuf,
+}
+
+fn mount(opts: &MountOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("mount mzr zone")?;
+    // Paths are sent to the daemon, which has a different current directory.
+    let target_dir = canonicalize_dir(&opts.target_dir)?;
+    daemon::bind_zone(&top_dirs.mzr_dir, &opts.zone_name, &target_dir)?;
+    println!(
+        "{} zone {} is now mounted at {}.",
+        colors::color_success(&"Success:"),
+        opts.zone_name,
+        color_dir(&target_dir.display())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr umount"
+ */
+
+#[derive(Args, Debug)]
+pub struct UmountOpts {
+    #[arg(
+        value_name = "TARGET_DIR",
+        help = "Directory that a zone was mounted at via mzr mount."
+    )]
+    target_dir: PathBuf,
+}
+
+fn umount(opts: &UmountOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("unmount mzr zone")?;
+    let target_dir = canonicalize_dir(&opts.target_dir)?;
+    daemon::unbind_zone(&top_dirs.mzr_dir, &target_dir)?;
+    println!(
+        "{} unmounted {}.",
+        colors::color_success(&"Success:"),
+        color_dir(&target_dir.display())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr watch"
+ */
+
+#[derive(Args, Debug)]
+pub struct WatchOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to watch.")]
+    zone_name: ZoneName,
+    #[arg(
+        long = "target-dir",
+        help = "Directory to apply changes to. Defaults to the work directory."
+    )]
+    target_dir: Option<PathBuf>,
+    #[arg(
+        long = "debounce-ms",
+        default_value = "500",
+        help = "Milliseconds to wait for changes to settle before applying them."
+    )]
+    debounce_ms: u64,
+}
+
+fn watch(opts: &WatchOpts) -> Result<(), Error> {
+    if env::var_os("MZR_DIR").is_some() {
+        bail!("mzr watch needs to be run outside of mzr zones, so that it can modify the work directory.");
+    }
+    let top_dirs = TopDirs::find("watch mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let target_dir = match &opts.target_dir {
+        Some(dir) => canonicalize_dir(dir)?,
+        None => top_dirs.user_work_dir.to_path_buf(),
+    };
+    watch::run(
+        &top_dirs,
+        &zone,
+        &target_dir,
+        Duration::from_millis(opts.debounce_ms),
+    )
+}
+
+/*
+ * "mzr merge"
+ */
+
+#[derive(Args, Debug)]
+pub struct MergeOpts {
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name of the zone to merge. Required unless --continue, --abort or --undo-last \
+                is used."
+    )]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        long = "continue",
+        help = "Finish applying a merge which was interrupted."
+    )]
+    continue_merge: bool,
+    #[arg(
+        long = "abort",
+        help = "Roll back a merge which was interrupted, restoring the files it replaced."
+    )]
+    abort: bool,
+    #[arg(
+        long = "undo-last",
+        help = "Undo the most recent merge, restoring the files it replaced or removed from \
+                its backup. Backups are kept according to the retention policy, see mzr gc."
+    )]
+    undo_last: bool,
+    #[arg(
+        long = "target-dir",
+        help = "Directory to apply changes to. Defaults to the work directory."
+    )]
+    target_dir: Option<PathBuf>,
+    #[arg(
+        long = "dry-run",
+        help = "Show what would be merged, along with why conflicting files won't be, without \
+                applying anything."
+    )]
+    dry_run: bool,
+    #[arg(
+        long = "format",
+        default_value = "table",
+        value_parser = ["table", "json"],
+        help = "How to print the merge plan."
+    )]
+    format: String,
+    #[arg(
+        long = "path",
+        help = "Only merge changes within this path, relative to the current directory. May be \
+                repeated."
+    )]
+    paths: Vec<PathBuf>,
+    #[arg(
+        long = "exclude",
+        help = "Leave out changes to paths matching this glob, along with everything within \
+                them. Globs without a '/' match any component of the path, and others match \
+                the path relative to the work directory. May be repeated."
+    )]
+    excludes: Vec<String>,
+    #[arg(
+        long = "force",
+        help = "Merge even when the merge would modify files which have uncommitted changes in \
+                the target's git repositories."
+    )]
+    force: bool,
+    #[arg(
+        long = "commit",
+        help = "Commit the merged paths to the target's git repositories, with a message naming \
+                the zone and its snapshot. Other changes aren't included, so the repositories \
+                must not have staged changes."
+    )]
+    commit: bool,
+}
+
+fn merge(opts: &MergeOpts) -> Result<(), Error> {
+    if env::var_os("MZR_DIR").is_some() {
+        bail!("mzr merge needs to be run outside of mzr zones, so that it can modify the work directory.");
+    }
+    let top_dirs = TopDirs::find("merge mzr zone")?;
+    if opts.undo_last {
+        if opts.zone_name.is_some() || opts.continue_merge || opts.abort {
+            bail!("--undo-last can't be used along with ZONE_NAME, --continue or --abort.");
+        }
+        let state = merge_txn::undo_last(&top_dirs.mzr_dir)?;
+        println!(
+            "{} undid merge of zone {} into {}.",
+            colors::color_success(&"Success:"),
+            state.zone_name,
+            color_dir(&state.target_dir.display())
+        );
+        return Ok(());
+    }
+    let zone_name = match (&opts.zone_name, opts.continue_merge, opts.abort) {
+        (None, false, false) => {
+            bail!("ZONE_NAME is required, unless --continue, --abort or --undo-last is used.")
+        }
+        (Some(_), true, _) | (Some(_), _, true) => {
+            bail!("ZONE_NAME can't be specified along with --continue or --abort.")
+        }
+        (None, true, true) => bail!("--continue and --abort can't be used together."),
+        (None, true, false) => {
+            let state = merge_txn::resume(&top_dirs.mzr_dir)?;
+            println!(
+                "{} finished merging zone {} into {}.",
+                colors::color_success(&"Success:"),
+                state.zone_name,
+                color_dir(&state.target_dir.display())
+            );
+            return Ok(());
+        }
+        (None, false, true) => {
+            let state = merge_txn::abort(&top_dirs.mzr_dir)?;
+            println!(
+                "{} rolled back merge of zone {} into {}.",
+                colors::color_success(&"Success:"),
+                state.zone_name,
+                color_dir(&state.target_dir.display())
+            );
+            return Ok(());
+        }
+        (Some(zone_name), false, false) => zone_name,
+    };
+    let zone = Zone::load(&top_dirs.mzr_dir, zone_name)?;
+    let target_dir = match &opts.target_dir {
+        Some(dir) => canonicalize_dir(dir)?,
+        None => top_dirs.user_work_dir.to_path_buf(),
+    };
+    // The zone's git directory is shared with the work dir, so its files
+    // aren't merged, as with mzr watch. Each member of a project set has its
+    // own.
+    let project_set = ProjectSet::load(&top_dirs.mzr_dir)?;
+    let excluded_dirs: Vec<PathBuf> = match &project_set {
+        Some(project_set) => project_set.git_dirs(&top_dirs.user_work_dir),
+        None => git::get_git_dir(&top_dirs.user_work_dir)
+            .into_iter()
+            .map(|rel_git_dir| rel_git_dir.to_path_buf())
+            .collect(),
+    };
+    let current_dir = env::current_dir()?;
+    let mut prefixes = Vec::new();
+    for path in opts.paths.iter() {
+        prefixes.push(rel_path_within_work_dir(
+            &top_dirs.user_work_dir,
+            &current_dir.join(path),
+        )?);
+    }
+    let filter = merge::PathFilter {
+        prefixes,
+        excludes: opts.excludes.clone(),
+    };
+    let target_dir_string = target_dir.to_string_lossy().into_owned();
+    let hook_vars = [
+        ("MZR_HOOK_ZONE", zone.name.as_str()),
+        ("MZR_HOOK_TARGET_DIR", target_dir_string.as_str()),
+    ];
+    // Run before planning, so that changes the hook makes within the zone,
+    // such as formatting, are included in the merge.
+    if !opts.dry_run {
+        hooks::run(&top_dirs.mzr_dir, Hook::PreMerge, &hook_vars)?;
+    }
+    let plan = merge::plan(&zone, &target_dir, &excluded_dirs, &filter)?;
+    let report = plan.report(&zone.ovfs_changes_dir);
+    if opts.format == "json" {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print_table(&target_dir);
+    }
+    let force_hint = format!("use {}", colors::color_cmd(&"mzr merge --force"));
+    let warning = merge::check_uncommitted(
+        &target_dir,
+        &excluded_dirs,
+        &plan,
+        opts.force || opts.dry_run,
+        &force_hint,
+    )?;
+    if let Some(message) = warning {
+        // Kept off stdout when it has the JSON plan.
+        if opts.format == "json" {
+            eprintln!("{} {}", colors::color_warn(&"Warning:"), message);
+        } else {
+            println!("{} {}", colors::color_warn(&"Warning:"), message);
+        }
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+    if opts.commit {
+        for rel_repo_dir in merge::target_repo_dirs(&target_dir, &excluded_dirs) {
+            let repo_dir = target_dir.join(&rel_repo_dir);
+            if git::has_staged_changes(&repo_dir)? {
+                bail!(
+                    "{} has staged changes, which --commit would include. Commit or unstage \
+                     them first.",
+                    color_dir(&repo_dir.display())
+                );
+            }
+        }
+    }
+    merge_txn::apply(&top_dirs.mzr_dir, &zone, &plan, &target_dir)?;
+    events::record(
+        &top_dirs.mzr_dir,
+        EventKind::Merged {
+            zone: zone.name.clone(),
+            target_dir: target_dir.clone(),
+            summary: plan.summary(),
+        },
+    );
+    if opts.commit {
+        commit_merge(
+            &target_dir,
+            &excluded_dirs,
+            &zone,
+            &plan,
+            opts.format == "json",
+        )?;
+    }
+    hooks::run_or_warn(&top_dirs.mzr_dir, Hook::PostMerge, &hook_vars);
+    if opts.format != "json" {
+        println!(
+            "{} applied {} update(s), {} rename(s) and {} directory change(s) from zone {}.",
+            colors::color_success(&"Success:"),
+            plan.updates.len(),
+            plan.renames.len(),
+            plan.dir_updates.len(),
+            zone.name
+        );
+        if let Some(project_set) = &project_set {
+            for (member, [updates, renames, dir_updates, conflicts]) in
+                project_set.plan_counts(&plan)
+            {
+                println!(
+                    "  {}: {} update(s), {} rename(s), {} directory change(s), {} conflict(s)",
+                    color_dir(&member.display()),
+                    updates,
+                    renames,
+                    dir_updates,
+                    conflicts
+                );
+            }
+        }
+    }
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        if let Err(err) = daemon::record_merge(&top_dirs.mzr_dir, &zone.name, &plan.summary()) {
+            println!(
+                "{} failed to record merge in daemon metrics: {}",
+                colors::color_warn(&"Warning:"),
+                err
+            );
+        }
+    }
+    if !plan.conflicts.is_empty() {
+        return Err(kind_error(
+            ErrorKind::MergeConflicts,
+            format!(
+                "{} conflicting file(s) were not merged.",
+                plan.conflicts.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Commits the paths the plan modified to each of the target's git
+/// repositories, for `mzr merge --commit`. When `quiet` is set, such as when
+/// stdout has the JSON plan, the commits aren't reported.
+fn commit_merge(
+    target_dir: &PathBuf,
+    rel_git_dirs: &[PathBuf],
+    zone: &Zone,
+    plan: &merge::Plan,
+    quiet: bool,
+) -> Result<(), Error> {
+    let plan_paths = plan.target_paths();
+    let mut message = format!(
+        "Merge mzr zone {} (snapshot {})",
+        zone.name.as_str(),
+        zone.info.snapshot.as_str()
+    );
+    if let Some(description) = &zone.info.description {
+        message.push_str(&format!("\n\n{}", description));
+    }
+    message.push_str(&format!(
+        "\n\nApplied {} update(s), {} rename(s) and {} directory change(s) from zone {}, \
+         which is based on snapshot {}.",
+        plan.updates.len(),
+        plan.renames.len(),
+        plan.dir_updates.len(),
+        zone.name.as_str(),
+        zone.info.snapshot.as_str()
+    ));
+    for rel_repo_dir in merge::target_repo_dirs(target_dir, rel_git_dirs) {
+        let repo_dir = target_dir.join(&rel_repo_dir);
+        let paths: Vec<PathBuf> = plan_paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(&rel_repo_dir).ok())
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| path.to_path_buf())
+            .collect();
+        if paths.is_empty() {
+            continue;
+        }
+        let commit = git::commit_paths(&repo_dir, &paths, &message)?;
+        if quiet {
+            continue;
+        }
+        match commit {
+            Some(sha) => println!(
+                "Committed the merged changes to {} as {}.",
+                color_dir(&repo_dir.display()),
+                &sha[..sha.len().min(12)]
+            ),
+            None => println!(
+                "Nothing to commit in {}, since the merged files match its HEAD.",
+                color_dir(&repo_dir.display())
+            ),
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr revert"
+ */
+
+#[derive(Args, Debug)]
+pub struct RevertOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to discard changes of.")]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "PATHS",
+        help = "Files or directories to discard changes to. Defaults to all of the zone's \
+                changes."
+    )]
+    paths: Vec<PathBuf>,
+    #[arg(
+        long = "force",
+        help = "Revert the zone even if it or a zone layered on it is in use, stopping the \
+                processes within them and unmounting them from where mzr mount bound them."
+    )]
+    force: bool,
+}
+
+fn revert(opts: &RevertOpts) -> Result<(), Error> {
+    if env::var("MZR_ZONE").ok().as_ref().map(String::as_str) == Some(opts.zone_name.as_str()) {
+        bail!(
+            "mzr revert needs to be run outside of zone {}, since reverting stops its processes.",
+            opts.zone_name
+        );
+    }
+    let top_dirs = TopDirs::find("revert mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let current_dir = env::current_dir()?;
+    let mut rel_paths = Vec::new();
+    for path in opts.paths.iter() {
+        rel_paths.push(rel_path_within_work_dir(
+            &top_dirs.user_work_dir,
+            &current_dir.join(path),
+        )?);
+    }
+    if rel_paths.is_empty() || rel_paths.iter().any(|p| p.as_os_str().is_empty()) {
+        let query = format!("Discard all of the changes made within zone {}", zone.name);
+        if let utils::Confirmed::No = utils::confirm(&query)? {
+            bail!("Zone {} wasn't reverted.", zone.name);
+        }
+    }
+    // When the daemon is running, it reverts the zone, since the zone needs
+    // to be unmounted first.
+    let reverted = if daemon::socket_exists(&top_dirs.mzr_dir) {
+        // Zones layered on the zone are stopped along with it, since its
+        // changes dir is one of their lower dirs.
+        let mut affected = vec![zone.name.clone()];
+        let mut ix = 0;
+        while ix < affected.len() {
+            let layered = Zone::list_layered_on(&top_dirs.mzr_dir, &affected[ix])?;
+            affected.extend(layered);
+            ix += 1;
+        }
+        let mut usages = Vec::new();
+        for zone_name in affected {
+            let usage = daemon::zone_usage(&top_dirs.mzr_dir, &zone_name)?;
+            if !usage.is_empty() {
+                print_zone_usage(&zone_name, &usage)?;
+                usages.push(usage);
+            }
+        }
+        if !usages.is_empty() {
+            if !opts.force {
+                bail!(
+                    "Reverting zone {} would stop what's using it, so it wasn't reverted. Use {} \
+                     to stop its processes and revert it anyway.",
+                    zone.name,
+                    colors::color_cmd(&format!("mzr revert --force {}", zone.name))
+                );
+            }
+            for usage in usages.iter() {
+                for target in usage.mounted_at.iter() {
+                    daemon::unbind_zone(&top_dirs.mzr_dir, target)?;
+                }
+            }
+        }
+        daemon::revert_zone(&top_dirs.mzr_dir, &zone.name, &rel_paths)?
+    } else {
+        zone.revert(&rel_paths)?
+    };
+    if reverted.is_empty() {
+        println!("Zone {} had no changes to discard.", zone.name);
+    } else {
+        println!(
+            "{} discarded changes to {} path(s) within zone {}:",
+            colors::color_success(&"Success:"),
+            reverted.len(),
+            zone.name
+        );
+        for rel_path in reverted {
+            println!("  {}", colors::color_file(&rel_path.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Expresses the path relative to the work dir, bailing if it's outside of
+/// it. Unlike canonicalizing, this works for paths which don't exist.
+fn rel_path_within_work_dir(
+    work_dir: &paths::UserWorkDir,
+    path: &PathBuf,
+) -> Result<PathBuf, Error> {
+    let path = path_within_work_dir(work_dir, path);
+    let mut rel_path = PathBuf::new();
+    match path.strip_prefix(work_dir) {
+        Err(_) => bail!(
+            "{} is not within the work directory {}.",
+            colors::color_file(&path.display()),
+            color_dir(&work_dir.display())
+        ),
+        Ok(suffix) => {
+            for component in suffix.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        if !rel_path.pop() {
+                            bail!(
+                                "{} is not within the work directory {}.",
+                                colors::color_file(&path.display()),
+                                color_dir(&work_dir.display())
+                            );
+                        }
+                    }
+                    component => rel_path.push(component),
+                }
+            }
+        }
+    }
+    Ok(rel_path)
+}
+
+/*
+ * "mzr cp"
+ */
+
+#[derive(Args, Debug)]
+pub struct CpOpts {
+    #[arg(
+        value_name = "PATH",
+        required = true,
+        help = "Paths within the work dir to copy, as they appear within the source zone. \
+                They're copied to the same paths within the destination zone."
+    )]
+    paths: Vec<PathBuf>,
+    #[arg(
+        long = "from",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Zone to copy from. Defaults to the current zone."
+    )]
+    from: Option<ZoneName>,
+    #[arg(long = "to", value_parser = parse_arg::<ZoneName>, help = "Zone to copy into.")]
+    to: ZoneName,
+}
+
+fn cp(opts: &CpOpts) -> Result<(), Error> {
+    if env::var("MZR_ZONE").ok().as_ref().map(String::as_str) == Some(opts.to.as_str()) {
+        bail!(
+            "mzr cp needs to be run outside of zone {}, since copying into it stops its \
+             processes.",
+            opts.to
+        );
+    }
+    let top_dirs = TopDirs::find("copy between mzr zones")?;
+    let from = match &opts.from {
+        Some(zone_name) => zone_name.clone(),
+        None => match zone::current_location(&top_dirs)? {
+            Location::Within(Some(zone_name)) => zone_name,
+            _ => bail!("Not within a known zone, so --from is needed."),
+        },
+    };
+    let source_zone = Zone::load(&top_dirs.mzr_dir, &from)?;
+    let dest_zone = Zone::load(&top_dirs.mzr_dir, &opts.to)?;
+    let current_dir = env::current_dir()?;
+    let mut rel_paths = Vec::new();
+    for path in opts.paths.iter() {
+        let rel_path = rel_path_within_work_dir(&top_dirs.user_work_dir, &current_dir.join(path))?;
+        if rel_path.as_os_str().is_empty() {
+            bail!("Copying the whole work dir isn't supported, use mzr merge instead.");
+        }
+        rel_paths.push(rel_path);
+    }
+    // When the daemon is running, it does the copying, since the destination
+    // zone needs to be unmounted first.
+    let count = if daemon::socket_exists(&top_dirs.mzr_dir) {
+        daemon::copy_into_zone(
+            &top_dirs.mzr_dir,
+            &source_zone.name,
+            &dest_zone.name,
+            &rel_paths,
+        )?
+    } else {
+        let mut count = 0;
+        for rel_path in rel_paths.iter() {
+            count += zone_copy::copy(&source_zone, &dest_zone, rel_path)?;
+        }
+        count
+    };
+    println!(
+        "{} copied {} entries from zone {} into zone {}.",
+        colors::color_success(&"Success:"),
+        count,
+        source_zone.name,
+        dest_zone.name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr which"
+ */
+
+#[derive(Args, Debug)]
+pub struct WhichOpts {
+    #[arg(
+        value_name = "PATH",
+        help = "Path within the work dir, whose location within the zone's layers is shown. \
+                Alternatively, a path within a snapshot or a zone's changes, in which case the \
+                corresponding path within the work dir is printed."
+    )]
+    path: PathBuf,
+    #[arg(
+        long = "zone",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Zone whose layers to look in. Defaults to the current zone."
+    )]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        long = "changes",
+        help = "Only print where the zone's changes store the path, failing if the zone hasn't \
+                changed it.",
+        conflicts_with = "snapshot"
+    )]
+    changes: bool,
+    #[arg(
+        long = "snapshot",
+        help = "Only print where the zone's snapshot stores the path, failing if the snapshot \
+                doesn't have it."
+    )]
+    snapshot: bool,
+}
+
+fn which(opts: &WhichOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("translate path")?;
+    let path = env::current_dir()?.join(&opts.path);
+    if let Some((layer, work_dir_path)) = work_dir_path_of_layer_path(&top_dirs, &path)? {
+        if opts.changes || opts.snapshot || opts.zone_name.is_some() {
+            bail!(
+                "{} is within {}, so it can only be translated to the work dir.",
+                colors::color_file(&path.display()),
+                layer
+            );
+        }
+        println!("{}", work_dir_path.display());
+        return Ok(());
+    }
+    let zone_name = match &opts.zone_name {
+        Some(zone_name) => zone_name.clone(),
+        None => match zone::current_location(&top_dirs)? {
+            Location::Within(Some(zone_name)) => zone_name,
+            _ => bail!("Not within a known zone, so --zone is needed."),
+        },
+    };
+    let zone = Zone::load(&top_dirs.mzr_dir, &zone_name)?;
+    let rel_path = rel_path_within_work_dir(&top_dirs.user_work_dir, &path)?;
+    if opts.changes || opts.snapshot {
+        let layer_path = if opts.changes {
+            zone.ovfs_changes_dir.join(&rel_path)
+        } else {
+            zone.snap_dir.join(&rel_path)
+        };
+        if fs::symlink_metadata(&layer_path).is_err() {
+            if opts.changes {
+                bail!("Zone {} hasn't changed {}.", zone.name, rel_path.display());
+            } else {
+                bail!(
+                    "Snapshot {} doesn't have {}.",
+                    zone.info.snapshot,
+                    rel_path.display()
+                );
+            }
+        }
+        println!("{}", layer_path.display());
+        return Ok(());
+    }
+    // Lists the layers from topmost to bottommost. Overlayfs merges
+    // directories with those below them, whereas anything else hides the
+    // layers below.
+    let mut layers = vec![(
+        format!("changes of zone {}", zone.name),
+        zone.ovfs_changes_dir.to_path_buf(),
+    )];
+    for lower_dir in zone.lower_dirs()? {
+        let label = if lower_dir == zone.snap_dir.to_path_buf() {
+            format!("snapshot {}", zone.info.snapshot)
+        } else {
+            String::from("changes of a zone it's layered on")
+        };
+        layers.push((label, lower_dir));
+    }
+    println!(
+        "Layers of zone {} for {}:",
+        zone.name,
+        colors::color_file(&rel_path.display())
+    );
+    let mut hidden = false;
+    for (label, layer_dir) in layers {
+        let layer_path = layer_dir.join(&rel_path);
+        let (state, hides_below) = match fs::symlink_metadata(&layer_path) {
+            Err(_) => {
+                println!("  {}: absent", label);
+                continue;
+            }
+            Ok(ref metadata) if merge::is_whiteout(metadata) => ("deleted", true),
+            Ok(ref metadata) if metadata.is_dir() && merge::is_opaque_dir(&layer_path) => {
+                ("opaque directory", true)
+            }
+            Ok(ref metadata) if metadata.is_dir() => ("directory", false),
+            Ok(_) => ("present", true),
+        };
+        println!(
+            "  {}: {}{}\n    {}",
+            label,
+            state,
+            if hidden {
+                ", hidden by a layer above"
+            } else {
+                ""
+            },
+            colors::color_file(&layer_path.display())
+        );
+        hidden = hidden || hides_below;
+    }
+    Ok(())
+}
+
+/// When the path is within a snapshot or a zone's changes, yields a
+/// description of that layer along with the corresponding path within the
+/// work dir.
+fn work_dir_path_of_layer_path(
+    top_dirs: &TopDirs,
+    path: &PathBuf,
+) -> Result<Option<(String, PathBuf)>, Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    if !path.starts_with(mzr_dir) {
+        return Ok(None);
+    }
+    for zone_name in Zone::list_names(mzr_dir)? {
+        let changes_dir = paths::OvfsChangesDir::new(&ZoneDir::new(mzr_dir, &zone_name));
+        if let Ok(rel_path) = path.strip_prefix(&changes_dir) {
+            let layer = format!("the changes of zone {}", zone_name);
+            return Ok(Some((layer, top_dirs.user_work_dir.join(rel_path))));
+        }
+    }
+    for snap_name in snapshot::list_names(mzr_dir)? {
+        if let Ok(rel_path) = path.strip_prefix(&SnapDir::new(mzr_dir, &snap_name)) {
+            let layer = format!("snapshot {}", snap_name);
+            return Ok(Some((layer, top_dirs.user_work_dir.join(rel_path))));
+        }
+    }
+    Ok(None)
+}
+
+/*
+ * "mzr history"
+ */
+
+#[derive(Args, Debug)]
+pub struct HistoryOpts {
+    #[arg(long = "zone", value_parser = parse_arg::<ZoneName>, help = "Only show events about this zone.")]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        long = "kind",
+        value_parser = [
+            "snapshot-created",
+            "zone-created",
+            "zone-removed",
+            "merged",
+            "daemon-started",
+            "daemon-stopped",
+        ],
+        help = "Only show events of this kind. May be repeated."
+    )]
+    kinds: Vec<String>,
+    #[arg(
+        long = "limit",
+        short = 'n',
+        help = "Only show this many of the most recent events."
+    )]
+    limit: Option<usize>,
+    #[arg(
+        long = "format",
+        default_value = "table",
+        value_parser = ["table", "json"],
+        help = "How to print the events. With json, they're printed one per line, as logged."
+    )]
+    format: String,
+    #[command(flatten)]
+    listing: ListingOpts,
+}
+
+fn history(opts: &HistoryOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show mzr history")?;
+    let humanize = opts.listing.humanize();
+    let mut events: Vec<events::Event> = events::read(&top_dirs.mzr_dir)?
+        .into_iter()
+        .filter(|event| {
+            opts.zone_name
+                .as_ref()
+                .map_or(true, |zone_name| event.kind.zone() == Some(zone_name))
+                && (opts.kinds.is_empty()
+                    || opts.kinds.iter().any(|kind| kind == event.kind.name()))
+        })
+        .collect();
+    if let Some(limit) = opts.limit {
+        let skipped = events.len().saturating_sub(limit);
+        events.drain(..skipped);
+    }
+    if events.is_empty() && opts.format != "json" {
+        println!("No events have been recorded.");
+    }
+    for event in events {
+        if opts.format == "json" {
+            println!("{}", serde_json::to_string(&event)?);
+        } else {
+            let user = match &event.user {
+                Some(user) => format!(" by {}", user),
+                None => String::new(),
+            };
+            println!("{}{}: {}", humanize.time(&event.time), user, event.kind);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr zone"
+ */
+
+#[derive(Subcommand, Debug)]
+pub enum ZoneCmd {
+    #[command(
+        name = "create",
+        about = "Create a zone without entering a shell within it"
+    )]
+    Create {
+        #[command(flatten)]
+        opts: ZoneCreateOpts,
+    },
+    #[command(
+        name = "compact",
+        about = "Compress large, infrequently accessed files in a zone's changes"
+    )]
+    Compact {
+        #[command(flatten)]
+        opts: ZoneCompactOpts,
+    },
+    #[command(
+        name = "list",
+        about = "List zones, along with their snapshots and branches, same as mzr list"
+    )]
+    List {
+        #[command(flatten)]
+        opts: ListingOpts,
+    },
+    #[command(
+        name = "remove",
+        about = "Remove a zone, discarding its changes",
+        alias = "rm"
+    )]
+    Remove {
+        #[command(flatten)]
+        opts: ZoneRemoveOpts,
+    },
+    #[command(name = "ps", about = "List the processes running within a zone")]
+    Ps {
+        #[command(flatten)]
+        opts: ZonePsOpts,
+    },
+    #[command(
+        name = "freeze",
+        about = "Pause all processes running within a zone, until it's thawed"
+    )]
+    Freeze {
+        #[command(flatten)]
+        opts: ZoneFreezeOpts,
+    },
+    #[command(name = "thaw", about = "Resume processes paused by mzr zone freeze")]
+    Thaw {
+        #[command(flatten)]
+        opts: ZoneFreezeOpts,
+    },
+    #[command(
+        name = "export",
+        about = "Export a zone's changes as a bundle, to be imported elsewhere"
+    )]
+    Export {
+        #[command(flatten)]
+        opts: ZoneExportOpts,
+    },
+    #[command(
+        name = "format-patch",
+        about = "Write a zone's changes as a git patch or bundle, to share them without merging"
+    )]
+    FormatPatch {
+        #[command(flatten)]
+        opts: ZoneFormatPatchOpts,
+    },
+    #[command(
+        name = "import",
+        about = "Create a zone from a bundle created by mzr zone export"
+    )]
+    Import {
+        #[command(flatten)]
+        opts: ZoneImportOpts,
+    },
+    #[command(
+        name = "rebase",
+        about = "Move a zone onto a new snapshot of the work directory, keeping its changes"
+    )]
+    Rebase {
+        #[command(flatten)]
+        opts: ZoneRebaseOpts,
+    },
+    #[command(
+        name = "repair",
+        about = "Fix problems which stop a zone from mounting, such as those left by a crash"
+    )]
+    Repair {
+        #[command(flatten)]
+        opts: ZoneRepairOpts,
+    },
+    #[command(
+        name = "checkpoints",
+        about = "List the checkpoints of a zone made by mzr daemon --checkpoint-interval-mins"
+    )]
+    Checkpoints {
+        #[command(flatten)]
+        opts: ZoneCheckpointsOpts,
+    },
+    #[command(
+        name = "restore",
+        about = "Roll a zone's changes back to one of its checkpoints"
+    )]
+    Restore {
+        #[command(flatten)]
+        opts: ZoneRestoreOpts,
+    },
+}
+
+fn zone_cmd(cmd: &ZoneCmd) -> Result<(), Error> {
+    match cmd {
+        ZoneCmd::Create { opts } => zone_create(&opts),
+        ZoneCmd::Compact { opts } => zone_compact(&opts),
+        ZoneCmd::List { opts } => list(&opts),
+        ZoneCmd::Remove { opts } => zone_remove(&opts),
+        ZoneCmd::Ps { opts } => zone_ps(&opts),
+        ZoneCmd::Freeze { opts } => zone_freeze(&opts),
+        ZoneCmd::Thaw { opts } => zone_thaw(&opts),
+        ZoneCmd::Export { opts } => zone_export(&opts),
+        ZoneCmd::FormatPatch { opts } => zone_format_patch(&opts),
+        ZoneCmd::Import { opts } => zone_import(&opts),
+        ZoneCmd::Rebase { opts } => zone_rebase(&opts),
+        ZoneCmd::Repair { opts } => zone_repair(&opts),
+        ZoneCmd::Checkpoints { opts } => zone_checkpoints(&opts),
+        ZoneCmd::Restore { opts } => zone_restore(&opts),
+    }
+}
+
+/// Parses `--env` assignments, given as `NAME=VALUE`.
+fn parse_env_vars(assignments: &[String]) -> Result<BTreeMap<String, String>, Error> {
+    let mut env_vars = BTreeMap::new();
+    for assignment in assignments.iter() {
+        match assignment.find('=') {
+            Some(ix) if ix > 0 => {
+                env_vars.insert(
+                    assignment[..ix].to_string(),
+                    assignment[ix + 1..].to_string(),
+                );
+            }
+            _ => bail!(
+                "Expected --env to be given NAME=VALUE, but got {:?}",
+                assignment
+            ),
+        }
+    }
+    Ok(env_vars)
+}
+
+/*
+ * "mzr zone create"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneCreateOpts {
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name of the zone to create. Required unless --from-file is used."
+    )]
+    zone_name: Option<ZoneName>,
+    #[arg(
+        long = "snap",
+        value_parser = parse_arg::<SnapName>,
+        help = "Name of the snapshot to use. Defaults to the snapshot named after the current \
+                git ref or sha."
+    )]
+    snap_name: Option<SnapName>,
+    #[arg(
+        long = "branch",
+        help = "Create and check out a new git branch within the zone. This mounts the zone."
+    )]
+    branch: Option<String>,
+    #[arg(
+        long = "desc",
+        help = "Description of what the zone is for, shown by mzr list."
+    )]
+    description: Option<String>,
+    #[arg(
+        long = "git-worktree",
+        help = "Register the zone's git repositories as worktrees of the work directory's \
+                repositories, rather than symlinking into them."
+    )]
+    git_worktree: bool,
+    #[arg(
+        long = "env",
+        help = "Set the environment variable whenever the zone is entered, given as \
+                NAME=VALUE. May be repeated."
+    )]
+    env_vars: Vec<String>,
+    #[arg(
+        long = "mount",
+        help = "Have the daemon mount the zone now, rather than when it's first entered."
+    )]
+    mount: bool,
+    #[arg(
+        long = "from-file",
+        help = "Create each of the zones declared in this JSON manifest, which has a \"zones\" \
+                list of objects with a \"name\", and optionally \"snapshot\", \"branch\", \
+                \"description\", \"env\" (an object of variables), \"git_worktree\", and \
+                \"mount\"."
+    )]
+    from_file: Option<PathBuf>,
+}
+
+fn zone_create(opts: &ZoneCreateOpts) -> Result<(), Error> {
+    let zone_name = match (&opts.zone_name, &opts.from_file) {
+        (Some(zone_name), None) => zone_name,
+        (None, Some(manifest_file)) => return zone_create_from_file(opts, manifest_file),
+        (None, None) => bail!("ZONE_NAME is required, unless --from-file is used."),
+        (Some(_), Some(_)) => bail!("ZONE_NAME can't be specified along with --from-file."),
+    };
+    let env_vars = parse_env_vars(&opts.env_vars)?;
+    let top_dirs = TopDirs::find_or_prompt_create("create mzr zone")?;
+    if Zone::exists(&top_dirs.mzr_dir, zone_name) {
+        bail!("Zone {} already exists.", zone_name);
+    }
+    let snap_name = default_snap_name(&top_dirs, &opts.snap_name)?;
+    let mut zone = Zone::create(&top_dirs.mzr_dir, zone_name, &snap_name, opts.git_worktree)?;
+    if opts.description.is_some() || !env_vars.is_empty() {
+        zone.info.description = opts.description.clone();
+        zone.info.env = env_vars;
+        zone.write_info()?;
+    }
+    println!(
+        "{} created zone {} of snapshot {} at {}",
+        colors::color_success(&"Success:"),
+        zone.name,
+        snap_name,
+        color_dir(&zone.zone_dir.display())
+    );
+    if !opts.mount && opts.branch.is_none() {
+        return Ok(());
+    }
+    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, &zone.name)?;
+    println!("Zone {} is mounted.", zone.name);
+    if let Some(branch) = &opts.branch {
+        // Within the zone's mount namespace, so that the branch is only
+        // checked out there. Nothing else happens in this process
+        // afterwards, so it's left within the zone.
+        daemon::enter_zone_process_user_and_mount(&zone_pid)?;
+        git::create_branch(&top_dirs.user_work_dir, branch)?;
+        let mut zone = Zone::load(&top_dirs.mzr_dir, &zone.name)?;
+        zone.info.branch = Some(branch.clone());
+        zone.write_info()?;
+        println!("Checked out new branch {} in zone {}.", branch, zone.name);
+    }
+    Ok(())
+}
+
+/// Manifest given to `mzr zone create --from-file`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneManifest {
+    zones: Vec<ZoneManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneManifestEntry {
+    name: ZoneName,
+    snapshot: Option<SnapName>,
+    branch: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    git_worktree: bool,
+    #[serde(default)]
+    mount: bool,
+}
+
+/// Creates each zone of the manifest via its own `mzr zone create`
+/// process, since checking out a branch leaves the process within the
+/// zone. Failures are reported and the remaining zones are still created.
+fn zone_create_from_file(opts: &ZoneCreateOpts, manifest_file: &PathBuf) -> Result<(), Error> {
+    if opts.snap_name.is_some()
+        || opts.branch.is_some()
+        || opts.description.is_some()
+        || !opts.env_vars.is_empty()
+        || opts.git_worktree
+        || opts.mount
+    {
+        bail!("With --from-file, zone options must be given in the manifest rather than flags.");
+    }
+    let manifest: ZoneManifest = serde_json::from_str(&fs::read_to_string(manifest_file)?)
+        .context(format_err!(
+            "Failed to parse zone manifest {}",
+            colors::color_file(&manifest_file.display())
+        ))?;
+    // Checked up front, so that a typo doesn't leave some zones created.
+    TopDirs::find_or_prompt_create("create mzr zones")?;
+    let exe = env::current_exe()?;
+    let mut failed = Vec::new();
+    for entry in manifest.zones.iter() {
+        println!(
+            "Creating zone {}",
+            colors::color_zone_name(&entry.name.as_str())
+        );
+        let mut cmd = Command::new(&exe);
+        cmd.arg("zone").arg("create").arg(entry.name.as_str());
+        if let Some(snap_name) = &entry.snapshot {
+            cmd.arg("--snap").arg(snap_name.as_str());
+        }
+        if let Some(branch) = &entry.branch {
+            cmd.arg("--branch").arg(branch);
+        }
+        if let Some(description) = &entry.description {
+            cmd.arg("--desc").arg(description);
+        }
+        for (name, value) in entry.env.iter() {
+            cmd.arg("--env").arg(format!("{}={}", name, value));
+        }
+        if entry.git_worktree {
+            cmd.arg("--git-worktree");
+        }
+        if entry.mount {
+            cmd.arg("--mount");
+        }
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => failed.push((&entry.name, format!("exited with {}", status))),
+            Err(err) => failed.push((&entry.name, err.to_string())),
+        }
+    }
+    let created = manifest.zones.len() - failed.len();
+    println!("Created {} of {} zone(s).", created, manifest.zones.len());
+    if !failed.is_empty() {
+        for (zone_name, reason) in failed.iter() {
+            println!("  {} {}", colors::color_err(&zone_name.as_str()), reason);
+        }
+        bail!("Failed to create {} zone(s).", failed.len());
+    }
+    Ok(())
+}
+
+/*
+ * "mzr zone repair"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneRepairOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to repair.")]
+    zone_name: ZoneName,
+}
+
+fn zone_repair(opts: &ZoneRepairOpts) -> Result<(), Error> {
+    if env::var("MZR_ZONE").ok().as_ref().map(String::as_str) == Some(opts.zone_name.as_str()) {
+        bail!(
+            "mzr zone repair needs to be run outside of zone {}, since repairing stops its \
+             processes.",
+            opts.zone_name
+        );
+    }
+    let top_dirs = TopDirs::find("repair mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let daemon_running = daemon::socket_exists(&top_dirs.mzr_dir);
+    let repairs = if daemon_running {
+        let open_shells = daemon::list_shells(&top_dirs.mzr_dir)?
+            .iter()
+            .filter(|shell| shell.zone_name == zone.name)
+            .count();
+        if open_shells > 0 {
+            let query = format!(
+                "Zone {} has {} open shell(s), which will stop working. Repair it anyway",
+                zone.name, open_shells
+            );
+            if let utils::Confirmed::No = utils::confirm(&query)? {
+                bail!("Zone {} wasn't repaired.", zone.name);
+            }
+        }
+        daemon::repair_zone(&top_dirs.mzr_dir, &zone.name)?
+    } else {
+        // Without a daemon, nothing is mounted, so the zone can be repaired
+        // directly.
+        zone.repair()?
+    };
+    if repairs.is_empty() {
+        println!("Found nothing to repair in zone {}.", zone.name);
+    }
+    for repair in repairs.iter() {
+        println!("{}", repair);
+    }
+    if daemon_running {
+        println!(
+            "{} zone {} is mounted.",
+            colors::color_success(&"Success:"),
+            zone.name
+        );
+    } else {
+        println!(
+            "{} zone {} gets mounted when it's next entered, once {} is running.",
+            colors::color_success(&"Repaired:"),
+            zone.name,
+            colors::color_cmd(&"mzr daemon")
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr zone checkpoints"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneCheckpointsOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to list checkpoints of.")]
+    zone_name: ZoneName,
+    #[command(flatten)]
+    listing: ListingOpts,
+}
+
+fn zone_checkpoints(opts: &ZoneCheckpointsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list mzr zone checkpoints")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let checkpoints = checkpoints::list(&zone)?;
+    if checkpoints.is_empty() {
+        println!(
+            "Zone {} has no checkpoints. They're made by {}.",
+            zone.name,
+            colors::color_cmd(&"mzr daemon --checkpoint-interval-mins MINS")
+        );
+    }
+    let humanize = opts.listing.humanize();
+    for checkpoint in checkpoints.iter().rev() {
+        println!("{}  {}", checkpoint.name, humanize.time(&checkpoint.time));
+    }
+    Ok(())
+}
+
+/*
+ * "mzr zone restore"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneRestoreOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to restore.")]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "CHECKPOINT",
+        help = "Name of the checkpoint to restore, as listed by mzr zone checkpoints."
+    )]
+    checkpoint: String,
+}
+
+fn zone_restore(opts: &ZoneRestoreOpts) -> Result<(), Error> {
+    if env::var("MZR_ZONE").ok().as_ref().map(String::as_str) == Some(opts.zone_name.as_str()) {
+        bail!(
+            "mzr zone restore needs to be run outside of zone {}, since restoring stops its \
+             processes.",
+            opts.zone_name
+        );
+    }
+    let top_dirs = TopDirs::find("restore mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    // When the daemon is running, it restores the zone, since the zone needs
+    // to be unmounted first.
+    let previous = if daemon::socket_exists(&top_dirs.mzr_dir) {
+        daemon::restore_checkpoint(&top_dirs.mzr_dir, &zone.name, &opts.checkpoint)?
+    } else {
+        checkpoints::restore(&zone, &opts.checkpoint)?
+    };
+    println!(
+        "{} zone {} restored to checkpoint {}. Its changes beforehand were saved as checkpoint \
+         {}.",
+        colors::color_success(&"Success:"),
+        zone.name,
+        opts.checkpoint,
+        previous
+    );
+    Ok(())
+}
+
+/*
+ * "mzr git"
+ */
+
+#[derive(Subcommand, Debug)]
+pub enum GitCmd {
+    #[command(
+        name = "install-hooks",
+        about = "Install git hooks which take a snapshot after each commit and branch checkout"
+    )]
+    InstallHooks {},
+    #[command(
+        name = "auto-snapshot",
+        about = "Enable or disable the snapshots taken by the hooks from mzr git install-hooks"
+    )]
+    AutoSnapshot {
+        #[command(flatten)]
+        opts: GitAutoSnapshotOpts,
+    },
+}
+
+fn git_cmd(cmd: &GitCmd) -> Result<(), Error> {
+    match cmd {
+        GitCmd::InstallHooks {} => git_install_hooks(),
+        GitCmd::AutoSnapshot { opts } => git_auto_snapshot(&opts),
+    }
+}
+
+/*
+ * "mzr git install-hooks"
+ */
+
+fn git_install_hooks() -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("install git hooks")?;
+    let installed = git::install_hooks(&top_dirs.user_work_dir, &env::current_exe()?)?;
+    for hook_path in installed.iter() {
+        println!(
+            "Installed hook {}",
+            colors::color_file(&hook_path.display())
+        );
+    }
+    set_auto_snapshot(&top_dirs, true)
+}
+
+/*
+ * "mzr git auto-snapshot"
+ */
+
+#[derive(Args, Debug)]
+pub struct GitAutoSnapshotOpts {
+    #[arg(
+        long = "disable",
+        help = "Disable automatic snapshots, rather than enabling them."
+    )]
+    disable: bool,
+}
+
+fn git_auto_snapshot(opts: &GitAutoSnapshotOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("configure automatic snapshots")?;
+    set_auto_snapshot(&top_dirs, !opts.disable)
+}
+
+fn set_auto_snapshot(top_dirs: &TopDirs, enabled: bool) -> Result<(), Error> {
+    let mut config = Config::load(&top_dirs.mzr_dir)?;
+    config.auto_snapshot = enabled;
+    config.write(&top_dirs.mzr_dir)?;
+    println!(
+        "{} automatic snapshots are {}.",
+        colors::color_success(&"Success:"),
+        if enabled { "enabled" } else { "disabled" }
+    );
+    Ok(())
+}
+
+/*
+ * "mzr zone compact"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneCompactOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to compact.")]
+    zone_name: ZoneName,
+    #[arg(
+        long = "min-size",
+        default_value = "1048576",
+        help = "Minimum size in bytes of files to compress."
+    )]
+    min_size: u64,
+    #[arg(
+        long = "min-idle-days",
+        default_value = "7",
+        help = "Minimum number of days since files were last accessed."
     )]
-    Go {
-        #[structopt(flatten)]
-        opts: GoOpts,
-    },
-    */
+    min_idle_days: u64,
 }
 
-pub fn run_cmd(cmd: &Cmd) -> Result<(), Error> {
-    match cmd {
-        Cmd::Daemon {} => daemon(),
-        Cmd::Shell { opts } => shell(&opts),
-        Cmd::Run { opts } => run(&opts),
-        Cmd::Snap { opts } => snap(&opts),
-        // Cmd::Go { opts } => go(&opts),
+fn zone_compact(opts: &ZoneCompactOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("compact mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let criteria = Criteria {
+        min_size: opts.min_size,
+        min_idle: Duration::from_secs(opts.min_idle_days * 24 * 60 * 60),
+    };
+    match compaction::compact(&top_dirs.mzr_dir, &zone, &criteria)? {
+        None => println!("No files in zone {} matched the criteria for compaction.", zone.name),
+        Some(archive) => println!(
+            "{} compressed {} file(s) totalling {} bytes. They will be restored when zone {} is next entered.",
+            colors::color_success(&"Success:"),
+            archive.files.len(),
+            archive.original_size,
+            zone.name
+        ),
     }
+    Ok(())
 }
 
 /*
- * "mzr daemon"
+ * "mzr zone remove"
  */
 
-// TODO(friendliness): Perhaps other commands should automatically
-// start daemon?  Ideally we wouldn't even need one, but it's not
-// entirely clear to me how to do all the mount sharing without
-// one. It may also be helpful in the future if a root daemon is
-// supported (instead of using user namespaces).
+#[derive(Args, Debug)]
+pub struct ZoneRemoveOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to remove.")]
+    zone_name: ZoneName,
+    #[arg(
+        long = "delete-branch",
+        help = "Also delete the git branch created for the zone by mzr shell --branch. \
+                The zone is only removed if the branch has been merged into the current \
+                commit of the work directory."
+    )]
+    delete_branch: bool,
+    #[arg(
+        long = "force",
+        help = "Remove the zone even if it's in use, stopping the processes within it and \
+                unmounting it from where mzr mount bound it."
+    )]
+    force: bool,
+}
 
-fn daemon() -> Result<(), Error> {
-    let top_dirs = TopDirs::find_or_prompt_create("start mzr daemon")?;
-    daemon::run(&top_dirs)
+fn zone_remove(opts: &ZoneRemoveOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("remove mzr zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let layered = Zone::list_layered_on(&top_dirs.mzr_dir, &zone.name)?;
+    if !layered.is_empty() {
+        let names: Vec<&str> = layered.iter().map(|name| name.as_str()).collect();
+        bail!(
+            "Zone {} can't be removed while zones are layered on it: {}",
+            zone.name,
+            names.join(", ")
+        );
+    }
+    let branch = if opts.delete_branch {
+        match &zone.info.branch {
+            None => bail!("Zone {} doesn't have a branch to delete.", zone.name),
+            Some(branch) => {
+                if !git::is_branch_merged(&top_dirs.user_work_dir, branch)? {
+                    bail!(
+                        "Branch {} hasn't been merged, so zone {} wasn't removed.",
+                        branch,
+                        zone.name
+                    );
+                }
+                Some(branch)
+            }
+        }
+    } else {
+        None
+    };
+    // When the daemon is running, it removes the zone, since it may need to
+    // stop the zone's process and unmount it first.
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        let usage = daemon::zone_usage(&top_dirs.mzr_dir, &zone.name)?;
+        if !usage.is_empty() {
+            print_zone_usage(&zone.name, &usage)?;
+            if !opts.force {
+                bail!(
+                    "Zone {} is in use, so it wasn't removed. Use {} to stop its processes and \
+                     remove it anyway.",
+                    zone.name,
+                    colors::color_cmd(&format!("mzr zone remove --force {}", zone.name))
+                );
+            }
+            for target in usage.mounted_at.iter() {
+                daemon::unbind_zone(&top_dirs.mzr_dir, target)?;
+            }
+        }
+        daemon::remove_zone(&top_dirs.mzr_dir, &zone.name)?;
+    } else {
+        zone.remove(&top_dirs.user_work_dir)?;
+    }
+    println!(
+        "{} zone {} removed.",
+        colors::color_success(&"Success:"),
+        zone.name
+    );
+    // Deleted after the zone is removed, since git won't delete a branch that
+    // is checked out in a worktree.
+    if let Some(branch) = branch {
+        git::delete_branch(&top_dirs.user_work_dir, branch)?;
+        println!(
+            "{} branch {} deleted.",
+            colors::color_success(&"Success:"),
+            branch
+        );
+    }
+    Ok(())
+}
+
+/// Lists the shells, processes, and mounts using a zone.
+fn print_zone_usage(zone_name: &ZoneName, usage: &daemon::ZoneUsage) -> Result<(), Error> {
+    println!("Zone {} is in use by:", zone_name);
+    for pid in usage.shells.iter() {
+        println!("  shell   {:>8} {}", pid, process_cmdline(pid));
+    }
+    if let Some(zone_pid) = &usage.process {
+        for pid in daemon::zone_process_members(zone_pid)? {
+            println!("  process {:>8} {}", pid, process_cmdline(pid));
+        }
+    }
+    for target in usage.mounted_at.iter() {
+        println!("  mount   {}", color_dir(&target.display()));
+    }
+    Ok(())
 }
 
 /*
- * "mzr shell"
+ * "mzr zone ps"
  */
 
-#[derive(StructOpt, Debug)]
-pub struct ShellOpts {
-    #[structopt(name = "ZONE_NAME", help = "Name of the zone to load or create.")]
+#[derive(Args, Debug)]
+pub struct ZonePsOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to list processes of.")]
     zone_name: ZoneName,
-    #[structopt(
-        name = "SNAP_NAME",
-        help = "Name of the snapshot to use. \
-                If creating a new zone and this is unspecified, a new snapshot will be taken."
+}
+
+fn zone_ps(opts: &ZonePsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list mzr zone processes")?;
+    if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
+        return Err(kind_error(
+            ErrorKind::ZoneNotFound,
+            format!("Zone {} does not exist.", opts.zone_name),
+        ));
+    }
+    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let pids = daemon::zone_process_members(&zone_pid)?;
+    if pids.is_empty() {
+        println!("No processes are running in zone {}.", opts.zone_name);
+    }
+    for pid in pids {
+        println!("{:>8} {}", pid, process_cmdline(pid));
+    }
+    Ok(())
+}
+
+/// Command line of the process, which is empty if it has since exited.
+fn process_cmdline<P: fmt::Display>(pid: P) -> String {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    args.join(" ")
+}
+
+/*
+ * "mzr zone freeze" and "mzr zone thaw"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneFreezeOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone.")]
+    zone_name: ZoneName,
+}
+
+fn zone_freeze(opts: &ZoneFreezeOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("freeze mzr zone")?;
+    if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
+        return Err(kind_error(
+            ErrorKind::ZoneNotFound,
+            format!("Zone {} does not exist.", opts.zone_name),
+        ));
+    }
+    daemon::freeze_zone(&top_dirs.mzr_dir, &opts.zone_name)?;
+    println!(
+        "{} zone {} frozen. Use {} to resume it.",
+        colors::color_success(&"Success:"),
+        opts.zone_name,
+        colors::color_cmd(&format!("mzr zone thaw {}", opts.zone_name))
+    );
+    Ok(())
+}
+
+fn zone_thaw(opts: &ZoneFreezeOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("thaw mzr zone")?;
+    if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
+        return Err(kind_error(
+            ErrorKind::ZoneNotFound,
+            format!("Zone {} does not exist.", opts.zone_name),
+        ));
+    }
+    daemon::thaw_zone(&top_dirs.mzr_dir, &opts.zone_name)?;
+    println!(
+        "{} zone {} thawed.",
+        colors::color_success(&"Success:"),
+        opts.zone_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr zone export"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneExportOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to export.")]
+    zone_name: ZoneName,
+    #[arg(
+        value_name = "BUNDLE",
+        help = "Path of the bundle to create, such as zone.mzr"
     )]
-    snap_name: Option<SnapName>,
+    bundle: PathBuf,
 }
 
-fn shell(opts: &ShellOpts) -> Result<(), Error> {
-    let top_dirs = TopDirs::find_or_prompt_create("enter mzr shell")?;
+fn zone_export(opts: &ZoneExportOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("export mzr zone")?;
+    zone_bundle::export(&top_dirs, &opts.zone_name, &opts.bundle)?;
+    println!(
+        "{} zone {} exported to {}.",
+        colors::color_success(&"Success:"),
+        opts.zone_name,
+        colors::color_file(&opts.bundle.display())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr zone format-patch"
+ */
+
+#[derive(Args, Debug)]
+pub struct ZoneFormatPatchOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone whose changes to format.")]
+    zone_name: ZoneName,
+    #[arg(
+        short = 'o',
+        long = "output-directory",
+        help = "Directory to write the patch to. Defaults to the current directory."
+    )]
+    output_dir: Option<PathBuf>,
+    #[arg(
+        long = "stdout",
+        help = "Print the patch rather than writing it to a file.",
+        conflicts_with = "output_dir"
+    )]
+    stdout: bool,
+    #[arg(
+        long = "bundle",
+        help = "Write a git bundle to this path rather than a patch. Its commit's parent is the \
+                commit of the zone's snapshot, which recipients need to have.",
+        conflicts_with_all = ["output_dir", "stdout"]
+    )]
+    bundle: Option<PathBuf>,
+    #[arg(
+        short = 'm',
+        long = "message",
+        help = "Commit message of the patch. Defaults to the first line of the zone's \
+                description, or otherwise a line naming the zone."
+    )]
+    message: Option<String>,
+}
+
+/// Diffs the zone's files against the git commit of its snapshot, and writes
+/// the changes as a patch or bundle, as if they were committed. Unlike mzr
+/// merge, neither the work dir nor its git refs are modified.
+fn zone_format_patch(opts: &ZoneFormatPatchOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("format mzr zone changes as a patch")?;
     if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
-        let snap_name = default_git_snap_name(&top_dirs, &opts.snap_name)?;
-        /* TODO(friendliness): What should the snapshot creation logic be?
-        println!("Taking a snapshot named {}", snap_name);
-        snapshot::create(&top_dirs.user_work_dir, &top_dirs.mzr_dir, &snap_name)?;
-        println!("Finished taking snapshot.");
-        */
-        println!("Requested zone does not yet exist, so attempting to create it.");
-        Zone::create(&top_dirs.mzr_dir, &opts.zone_name, &snap_name)?;
+        return Err(kind_error(
+            ErrorKind::ZoneNotFound,
+            format!("Zone {} does not exist.", opts.zone_name),
+        ));
+    }
+    let zone_commit = zone_patch::commit_zone(
+        &top_dirs,
+        &opts.zone_name,
+        opts.message.as_ref().map(String::as_str),
+    )?;
+    let zone_commit = match zone_commit {
+        Some(zone_commit) => zone_commit,
+        None => {
+            eprintln!(
+                "Zone {} has no changes relative to the git commit of its snapshot.",
+                opts.zone_name
+            );
+            return Ok(());
+        }
     };
-    enter_zone(&top_dirs, &opts.zone_name)?;
-    let void = execvp("/bin/bash")?;
-    unreachable(void)
+    // git runs within the work dir, so paths are made absolute.
+    let current_dir = env::current_dir()?;
+    if let Some(bundle) = &opts.bundle {
+        let bundle = current_dir.join(bundle);
+        git::create_bundle(
+            &top_dirs.user_work_dir,
+            &bundle,
+            &zone_commit.base,
+            &zone_commit.commit,
+            &zone_patch::bundle_ref_name(&opts.zone_name),
+        )?;
+        println!(
+            "{} wrote the changes of zone {} to {}.",
+            colors::color_success(&"Success:"),
+            opts.zone_name,
+            colors::color_file(&bundle.display())
+        );
+        println!("{}", zone_patch::bundle_hint(&bundle, &opts.zone_name));
+    } else if opts.stdout {
+        print!(
+            "{}",
+            git::format_patch(&top_dirs.user_work_dir, &zone_commit.commit, None)?
+        );
+    } else {
+        let output_dir = match &opts.output_dir {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir,
+        };
+        let output = git::format_patch(
+            &top_dirs.user_work_dir,
+            &zone_commit.commit,
+            Some(&output_dir),
+        )?;
+        for patch in output.lines() {
+            println!(
+                "{} wrote the changes of zone {} to {}.",
+                colors::color_success(&"Success:"),
+                opts.zone_name,
+                colors::color_file(&patch)
+            );
+        }
+    }
+    Ok(())
 }
 
 /*
- * "mzr run"
+ * "mzr zone import"
  */
 
-#[derive(StructOpt, Debug)]
-pub struct RunOpts {
-    #[structopt(name = "CMD")]
-    cmd: String,
-    #[structopt(name = "ARGS")]
-    args: Vec<String>,
+#[derive(Args, Debug)]
+pub struct ZoneImportOpts {
+    #[arg(
+        value_name = "BUNDLE",
+        help = "Path of a bundle created by mzr zone export."
+    )]
+    bundle: PathBuf,
+    #[arg(
+        value_name = "ZONE_NAME",
+        value_parser = parse_arg::<ZoneName>,
+        help = "Name for the imported zone. Defaults to the name it was exported with."
+    )]
+    zone_name: Option<ZoneName>,
 }
 
-fn run(opts: &RunOpts) -> Result<(), Error> {
-    let top_dirs = TopDirs::find_or_prompt_create("run command in temp mzr zone")?;
-    // TODO(friendliness) Things to consider basing tmp zone /
-    // snapshot on:
-    //
-    // * Command run
-    // * Current date / time
-    // * Current PID
-    //
-    // For now just going with something based on PID..
-    let tmp_name = format!("run-{}", Pid::this());
-    let snap_name = SnapName::new(tmp_name.clone())?;
-    let zone_name = ZoneName::new(tmp_name.clone())?;
-    println!("Taking temporary snapshot named {}", snap_name);
-    snapshot::of_workdir(&top_dirs, &snap_name)?;
-    let zone = Zone::create(&top_dirs.mzr_dir, &zone_name, &snap_name)?;
+fn zone_import(opts: &ZoneImportOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("import mzr zone")?;
+    let zone_name = zone_bundle::import(&top_dirs.mzr_dir, &opts.bundle, &opts.zone_name)?;
     println!(
-        "Running {} inside temporary zone named {}\n",
-        opts.cmd, zone_name
+        "{} zone {} imported.",
+        colors::color_success(&"Success:"),
+        zone_name
     );
-    // Run process within the temporary zone, inheriting stdio.
-    enter_zone(&top_dirs, &zone_name)?;
-    let mut child = Command::new(&opts.cmd).args(&opts.args).spawn()?;
-    let status = child.wait()?;
-    // TODO: I suppose the next steps here are:
-    //
-    // 1) Have this handled by the daemon, so that it has write access to the original working copy.
-    //
-    // 2) Know which zone 'run' is being invoked from, if any.
-    //
-    // 3) Summarize updates and display conflicts and skips. Ask about the conflicts and skips
-    //
-    // 4) Delete zone and snap if specified.
-    //
-    // 5) Should store in the zone and snap metadata that they are temporary.
-    interactive_merge(
-        &zone,
-        top_dirs.user_work_dir.as_ref(),
-        Mode::AutoApplyUpdates,
-    )?;
-    let _void = exit_with_status(status);
-    unreachable(_void)
+    Ok(())
 }
 
 /*
- * "mzr snap"
+ * "mzr zone rebase"
  */
 
-#[derive(StructOpt, Debug)]
-pub struct SnapOpts {
-    #[structopt(
-        name = "SNAP_NAME",
-        help = "Name of the snapshot to create. \
-                If unspecified, a name will be generated based on the current git branch name."
+#[derive(Args, Debug)]
+pub struct ZoneRebaseOpts {
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to rebase.")]
+    zone_name: ZoneName,
+    #[arg(
+        long = "onto",
+        value_parser = parse_arg::<SnapName>,
+        help = "Existing snapshot to rebase the zone onto. By default, a new snapshot of the \
+                work directory is taken."
     )]
-    snap_name: Option<SnapName>,
+    onto: Option<SnapName>,
+    #[arg(
+        long = "dry-run",
+        help = "Show which of the zone's changes conflict with changes to the work directory, \
+                without taking a snapshot or rebasing."
+    )]
+    dry_run: bool,
+    #[arg(
+        long = "discard-conflicting",
+        help = "Discard the zone's changes to conflicting paths, so that the new snapshot's \
+                versions show through. By default, the zone's versions are kept."
+    )]
+    discard_conflicting: bool,
 }
 
-fn snap(opts: &SnapOpts) -> Result<(), Error> {
-    let top_dirs = TopDirs::find_or_prompt_create("take mzr snapshot")?;
-    let snap_name = default_git_snap_name(&top_dirs, &opts.snap_name)?;
-    println!("Taking a snapshot named {}", snap_name);
-    let _snap_dir = snapshot::of_workdir(&top_dirs, &snap_name)?;
+fn zone_rebase(opts: &ZoneRebaseOpts) -> Result<(), Error> {
+    if env::var("MZR_ZONE").ok().as_ref().map(String::as_str) == Some(opts.zone_name.as_str()) {
+        bail!(
+            "mzr zone rebase needs to be run outside of zone {}, since rebasing stops its \
+             processes.",
+            opts.zone_name
+        );
+    }
+    let top_dirs = TopDirs::find("rebase mzr zone")?;
+    let mut zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if let Some(parent) = &zone.info.parent {
+        bail!(
+            "Zone {} is layered on zone {}, so it can't be rebased separately.",
+            zone.name,
+            parent
+        );
+    }
+    let layered = Zone::list_layered_on(&top_dirs.mzr_dir, &zone.name)?;
+    if !layered.is_empty() {
+        let names: Vec<&str> = layered.iter().map(|name| name.as_str()).collect();
+        bail!(
+            "Zone {} can't be rebased while zones are layered on it: {}",
+            zone.name,
+            names.join(", ")
+        );
+    }
+    if let Some(snap_name) = &opts.onto {
+        if !snapshot::exists(&top_dirs.mzr_dir, snap_name) {
+            return Err(kind_error(
+                ErrorKind::SnapshotNotFound,
+                format!("Snapshot {} does not exist.", snap_name),
+            ));
+        }
+    } else if let Location::Within(_) = zone::current_location(&top_dirs)? {
+        bail!(
+            "The work directory is currently a view of a zone, so a snapshot of it would \
+             include the zone's changes. Run mzr zone rebase outside of zones, or use --onto."
+        );
+    }
+    // The zone's git directory is shared with the work dir, as when merging.
+    let excluded_dirs: Vec<PathBuf> = git::get_git_dir(&top_dirs.user_work_dir)
+        .into_iter()
+        .map(|rel_git_dir| rel_git_dir.to_path_buf())
+        .collect();
+    if opts.dry_run {
+        // Comparing with the work dir gives the same result as comparing with
+        // a snapshot of it, without taking one.
+        let new_dir = match &opts.onto {
+            Some(snap_name) => SnapDir::new(&top_dirs.mzr_dir, snap_name).to_path_buf(),
+            None => top_dirs.user_work_dir.to_path_buf(),
+        };
+        rebase::plan(&zone, &new_dir, &excluded_dirs)?.print_table();
+        return Ok(());
+    }
+    let snap_name = match &opts.onto {
+        Some(snap_name) => snap_name.clone(),
+        None => {
+            let mut snap_name = git::default_snap_name(&top_dirs.user_work_dir)?;
+            if snapshot::exists(&top_dirs.mzr_dir, &snap_name) {
+                snap_name = snapshot::next_versioned_name(&top_dirs.mzr_dir, &snap_name)?;
+            }
+            println!("Taking a snapshot named {}", snap_name);
+            snapshot::of_workdir(&top_dirs, &snap_name)?;
+            snap_name
+        }
+    };
+    let snap_dir = SnapDir::new(&top_dirs.mzr_dir, &snap_name);
+    let plan = rebase::plan(&zone, &snap_dir, &excluded_dirs)?;
+    plan.print_table();
+    // Overlayfs doesn't support changing the layers of a mounted overlay, so
+    // the zone gets remounted onto the new snapshot when it's next entered.
+    if daemon::socket_exists(&top_dirs.mzr_dir) {
+        let open_shells = daemon::list_shells(&top_dirs.mzr_dir)?
+            .iter()
+            .filter(|shell| shell.zone_name == zone.name)
+            .count();
+        if open_shells > 0 {
+            let query = format!(
+                "Zone {} has {} open shell(s), which will stop working. Rebase it anyway",
+                zone.name, open_shells
+            );
+            if let utils::Confirmed::No = utils::confirm(&query)? {
+                bail!(
+                    "Zone {} wasn't rebased. Snapshot {} was kept.",
+                    zone.name,
+                    snap_name
+                );
+            }
+        }
+        daemon::stop_zone(&top_dirs.mzr_dir, &zone.name)?;
+    }
+    if opts.discard_conflicting {
+        let rel_paths: Vec<PathBuf> = plan
+            .conflicts
+            .iter()
+            .map(|conflict| conflict.path.clone())
+            .collect();
+        zone.revert(&rel_paths)?;
+    }
+    let old_snap_name = zone.info.snapshot.clone();
+    zone.info.snapshot = snap_name.clone();
+    zone.write_info()?;
     println!(
-        "{} snapshot named {} taken.",
+        "{} zone {} rebased from snapshot {} onto snapshot {}.",
         colors::color_success(&"Success:"),
+        zone.name,
+        old_snap_name,
         snap_name
     );
+    if !plan.conflicts.is_empty() && !opts.discard_conflicting {
+        println!(
+            "The zone's versions of {} conflicting path(s) were kept, hiding the new snapshot's \
+             versions.",
+            plan.conflicts.len()
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr version"
+ */
+
+#[derive(Args, Debug)]
+pub struct VersionOpts {
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        help = "Also print build information, and which backends are available on this system."
+    )]
+    verbose: bool,
+}
+
+fn version(opts: &VersionOpts) -> Result<(), Error> {
+    version::print_version(opts.verbose);
     Ok(())
 }
 
+/*
+ * "mzr self-update"
+ */
+
+#[derive(Args, Debug)]
+pub struct SelfUpdateOpts {
+    #[arg(
+        long = "release-url",
+        help = "URL of the release to install. Defaults to MZR_RELEASE_URL if set, otherwise the \
+                latest release on GitHub."
+    )]
+    release_url: Option<String>,
+    #[arg(
+        long = "signing-key",
+        help = "Keyring to verify the signature of the release manifest with, via gpgv. The \
+                manifest has the version, target, and checksum of the release binary. Required \
+                unless --insecure is passed."
+    )]
+    signing_key: Option<PathBuf>,
+    #[arg(
+        long = "insecure",
+        conflicts_with = "signing_key",
+        help = "Install the release without verifying the signature of its manifest. Its \
+                checksum then only guards against corrupted downloads."
+    )]
+    insecure: bool,
+    #[arg(
+        long = "force",
+        help = "Install the release even if it isn't newer than this version."
+    )]
+    force: bool,
+}
+
+#[cfg(feature = "self-update")]
+fn self_update(opts: &SelfUpdateOpts) -> Result<(), Error> {
+    self_update::self_update(&self_update::UpdateOptions {
+        release_url: opts.release_url.clone(),
+        signing_key: opts.signing_key.clone(),
+        insecure: opts.insecure,
+        force: opts.force,
+    })
+}
+
+#[cfg(not(feature = "self-update"))]
+fn self_update(_opts: &SelfUpdateOpts) -> Result<(), Error> {
+    bail!(
+        "This build of mzr doesn't include self-update. \
+         Rebuild it with the \"self-update\" feature, or update it the way it was installed."
+    );
+}
+
 /*
  * "mzr go"
  */
@@ -225,9 +4904,9 @@ fn snap(opts: &SnapOpts) -> Result<(), Error> {
 // when the user in the shell was already root.
 
 /*
-#[derive(StructOpt, Debug)]
+#[derive(Args, Debug)]
 pub struct GoOpts {
-    #[structopt(name = "ZONE_NAME", help = "Name of the zone to switch to.")]
+    #[arg(value_name = "ZONE_NAME", value_parser = parse_arg::<ZoneName>, help = "Name of the zone to switch to.")]
     zone_name: ZoneName,
 }
 
@@ -251,19 +4930,25 @@ fn go(opts: &GoOpts) -> Result<(), Error> {
  * belong in main.rs
  */
 
-fn default_git_snap_name(
-    top_dirs: &TopDirs,
-    snap_name: &Option<SnapName>,
-) -> Result<SnapName, Error> {
+fn default_snap_name(top_dirs: &TopDirs, snap_name: &Option<SnapName>) -> Result<SnapName, Error> {
     match snap_name {
         Some(name) => Ok(name.clone()),
         None => {
-            git::warn_env();
-            // TODO: Consider adding "_vN" suffixes to these, to disambiguate
-            // with existing snapshots.
-            let name = git::default_snap_name(&top_dirs.user_work_dir)?;
+            if let Some(project_set) = ProjectSet::load(&top_dirs.mzr_dir)? {
+                git::warn_env();
+                let name = project_set.default_snap_name(&top_dirs.user_work_dir)?;
+                println!(
+                    "Since no snapshot was specified, using the git refs or shas of the \
+                     project set's members: {}",
+                    name
+                );
+                return Ok(name);
+            }
+            let provider = baseline::detect(&top_dirs.user_work_dir);
+            let name = provider.default_snap_name(&top_dirs.user_work_dir)?;
             println!(
-                "Since no snapshot was specified, using the current git ref or sha: {}",
+                "Since no snapshot was specified, using {}: {}",
+                provider.describe_name(),
                 name
             );
             Ok(name)
@@ -272,18 +4957,108 @@ fn default_git_snap_name(
 }
 
 fn enter_zone(top_dirs: &TopDirs, zone_name: &ZoneName) -> Result<(), Error> {
+    // Commands run within zones have their capabilities dropped, see
+    // `restrict_zone_privileges`.
+    if env::var_os("MZR_ZONE").is_some()
+        && !(privileges::has_capability(privileges::CAP_SYS_ADMIN)?
+            && privileges::has_capability(privileges::CAP_SYS_CHROOT)?)
+    {
+        bail!(
+            "Entering zone {} from within a zone requires the sys_admin and sys_chroot \
+             capabilities, which commands run in zones don't keep unless they're listed in \
+             zone_capabilities in {}.",
+            zone_name,
+            ConfigFile::new(&top_dirs.mzr_dir)
+        );
+    }
+    hooks::run_or_warn(
+        &top_dirs.mzr_dir,
+        Hook::ZoneEnter,
+        &[("MZR_HOOK_ZONE", zone_name.as_str())],
+    );
     let current_directory = env::current_dir()?;
+    let env_vars = Zone::load(&top_dirs.mzr_dir, zone_name)?.env_vars()?;
     let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, &zone_name)?;
     daemon::enter_zone_process_user_and_mount(&zone_pid)?;
+    daemon::enter_zone_process_pid(&zone_pid)?;
     change_dir_fallback_parent(&top_dirs.user_work_dir, &current_directory)?;
     env::set_var("MZR_DIR", &top_dirs.mzr_dir);
+    env::set_var(WORK_DIR_VAR, &top_dirs.user_work_dir);
+    env::set_var("MZR_ZONE", zone_name.as_str());
+    for (name, value) in env_vars {
+        env::set_var(name, value);
+    }
     Ok(())
 }
 
+/// Loads the capabilities which commands run in zones keep, from
+/// `Config::zone_capabilities`.
+fn zone_capabilities(top_dirs: &TopDirs) -> Result<Vec<u32>, Error> {
+    privileges::parse_capabilities(&Config::load(&top_dirs.mzr_dir)?.zone_capabilities)
+}
+
+/// Gives up the privileges gained by entering a zone, just before this
+/// process runs a command within it, and keeps it from inheriting fds
+/// other than stdio.
+fn restrict_zone_privileges(top_dirs: &TopDirs) -> Result<(), Error> {
+    let capabilities = zone_capabilities(top_dirs)?;
+    privileges::cloexec_inherited_fds(&[])?;
+    privileges::restrict(&capabilities)?;
+    Ok(())
+}
+
+/// Expresses the path in terms of the work dir, if it's within it. This
+/// matters when the path was found via a different route to the same
+/// directory, such as through a symlink, since only the work dir gets the
+/// zone mounted over it.
+fn path_within_work_dir(work_dir: &paths::UserWorkDir, path: &PathBuf) -> PathBuf {
+    if let Ok(rel_path) = path.strip_prefix(work_dir) {
+        return work_dir.join(rel_path);
+    }
+    match work_dir.canonicalize() {
+        Ok(canonical_work_dir) => match path.strip_prefix(&canonical_work_dir) {
+            Ok(rel_path) => work_dir.join(rel_path),
+            Err(_) => path.clone(),
+        },
+        Err(_) => path.clone(),
+    }
+}
+
+fn canonicalize_dir(dir: &PathBuf) -> Result<PathBuf, Error> {
+    if !dir.is_dir() {
+        bail!(
+            "{} is not an existing directory.",
+            color_dir(&dir.display())
+        );
+    }
+    Ok(dir.canonicalize()?)
+}
+
+/// How many times to attempt changing to the original directory within a
+/// zone, before falling back on a parent directory.
+const CHANGE_DIR_ATTEMPTS: u32 = 3;
+
+/// Changes to the directory within the newly entered zone which corresponds
+/// to `start_dir`. The current directory still refers to the directory from
+/// before the zone was entered, so the path needs to be resolved again. Since
+/// it may briefly be missing while transitioning into the zone, this is
+/// retried before falling back on the closest existing parent.
 fn change_dir_fallback_parent(
     work_dir: &paths::UserWorkDir,
     start_dir: &PathBuf,
 ) -> Result<(), Error> {
+    let start_dir = &path_within_work_dir(work_dir, start_dir);
+    for attempt in 1..=CHANGE_DIR_ATTEMPTS {
+        match env::set_current_dir(start_dir) {
+            Ok(()) => return Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if attempt < CHANGE_DIR_ATTEMPTS {
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            Err(_) => break,
+        }
+    }
     match find_existent_parent_dir(start_dir) {
         Some(existent_dir) => {
             if &existent_dir != start_dir {
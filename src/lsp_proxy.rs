@@ -0,0 +1,234 @@
+use crate::config::Config;
+use crate::daemon;
+use crate::paths::*;
+use crate::privileges;
+use crate::top_dirs::{TopDirs, WORK_DIR_VAR};
+use crate::utils::strip_prefix;
+use crate::zone::Zone;
+use failure::{Error, ResultExt};
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+/// Rewrites of path prefixes that get applied to each message body passed
+/// through the proxy.
+///
+/// The editor sees the zone via its overlayfs mount directory, whereas the
+/// language server runs within the zone's namespaces, where the zone is bound
+/// over the user's work directory.
+#[derive(Debug, Clone)]
+struct Translation {
+    from: String,
+    to: String,
+}
+
+impl Translation {
+    /// Rewrites occurrences of the path, both plain and within percent-encoded
+    /// `file://` URIs. Only occurrences which start a JSON string or a word,
+    /// and end at a path boundary, are rewritten, so that paths which merely
+    /// share a prefix, like `/work-other` for `/work`, or contain the path,
+    /// like `/backup/work`, are left alone.
+    fn apply(&self, body: &str) -> String {
+        let from_uri = format!("file://{}", percent_encode_path(&self.from));
+        let to_uri = format!("file://{}", percent_encode_path(&self.to));
+        let mut result = String::with_capacity(body.len());
+        let mut rest = body;
+        let mut at_boundary = true;
+        while let Some(ch) = rest.chars().next() {
+            let translated = if at_boundary {
+                strip_path_prefix(rest, &from_uri)
+                    .map(|after| (to_uri.as_str(), after))
+                    .or_else(|| {
+                        strip_path_prefix(rest, &self.from).map(|after| (self.to.as_str(), after))
+                    })
+            } else {
+                None
+            };
+            match translated {
+                Some((to, after)) => {
+                    result.push_str(to);
+                    rest = after;
+                    at_boundary = false;
+                }
+                None => {
+                    result.push(ch);
+                    rest = &rest[ch.len_utf8()..];
+                    at_boundary = ch == '"' || ch.is_whitespace();
+                }
+            }
+        }
+        result
+    }
+
+    fn reverse(&self) -> Translation {
+        Translation {
+            from: self.to.clone(),
+            to: self.from.clone(),
+        }
+    }
+}
+
+/// Strips the path from the start of the text, if it's followed by a path
+/// separator, the end of a JSON string, or the end of the text.
+fn strip_path_prefix<'a>(text: &'a str, path: &str) -> Option<&'a str> {
+    if !text.starts_with(path) {
+        return None;
+    }
+    let rest = &text[path.len()..];
+    if rest.is_empty() || rest.starts_with('/') || rest.starts_with('"') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Percent-encodes a path for use in a `file://` URI, leaving only unreserved
+/// characters and path separators unescaped, like editors do.
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Runs a language server within the zone, proxying LSP messages between
+/// stdio and the language server. Note that stdout is used for the protocol,
+/// so all diagnostic output from mzr goes to stderr.
+pub fn run(
+    top_dirs: &TopDirs,
+    zone_name: &ZoneName,
+    cmd: &str,
+    args: &[String],
+) -> Result<ExitStatus, Error> {
+    let zone = Zone::load(&top_dirs.mzr_dir, zone_name)?;
+    let to_zone = Translation {
+        from: path_to_string(&zone.ovfs_mount_dir)?,
+        to: path_to_string(&top_dirs.user_work_dir)?,
+    };
+    let to_host = to_zone.reverse();
+    let env_vars = zone.env_vars()?;
+    let capabilities =
+        privileges::parse_capabilities(&Config::load(&top_dirs.mzr_dir)?.zone_capabilities)?;
+    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, zone_name)?;
+    daemon::enter_zone_process_user_and_mount(&zone_pid)?;
+    daemon::enter_zone_process_pid(&zone_pid)?;
+    env::set_current_dir(&top_dirs.user_work_dir)?;
+    env::set_var("MZR_DIR", &top_dirs.mzr_dir);
+    env::set_var(WORK_DIR_VAR, &top_dirs.user_work_dir);
+    env::set_var("MZR_ZONE", zone_name.as_str());
+    for (name, value) in env_vars {
+        env::set_var(name, value);
+    }
+    eprintln!("Starting language server {} in zone {}", cmd, zone_name);
+    privileges::cloexec_inherited_fds(&[])?;
+    let mut command = Command::new(cmd);
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    // Restricting only makes syscalls, so it's safe to do between fork and
+    // exec.
+    unsafe {
+        command.pre_exec(move || privileges::restrict(&capabilities));
+    }
+    let mut child = command
+        .spawn()
+        .context(format_err!("Failed to start language server {}", cmd))?;
+    let child_stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| format_err!("Unexpected error: language server has no stdin."))?;
+    let child_stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| format_err!("Unexpected error: language server has no stdout."))?;
+    // The thread reading from the editor is intentionally not joined, since
+    // the editor may keep stdin open after the language server exits.
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let result = forward(&mut stdin.lock(), child_stdin, &to_zone);
+        log_forward_result("editor to language server", result);
+    });
+    let from_server = thread::spawn(move || {
+        let stdout = io::stdout();
+        let result = forward(&mut BufReader::new(child_stdout), stdout.lock(), &to_host);
+        log_forward_result("language server to editor", result);
+    });
+    let status = child.wait()?;
+    from_server
+        .join()
+        .map_err(|_| format_err!("Thread forwarding language server output panicked."))?;
+    Ok(status)
+}
+
+fn forward<R: BufRead, W: Write>(
+    reader: &mut R,
+    mut writer: W,
+    translation: &Translation,
+) -> Result<(), Error> {
+    while let Some(body) = read_message(reader)? {
+        let translated = translation.apply(&String::from_utf8(body)?);
+        write_message(&mut writer, translated.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn log_forward_result(direction: &str, result: Result<(), Error>) {
+    if let Err(err) = result {
+        eprintln!(
+            "Error while forwarding LSP messages from {}: {}",
+            direction, err
+        );
+    }
+}
+
+/// Reads one message using the LSP base protocol framing. Yields `None` when
+/// the input has been closed.
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = strip_prefix("Content-Length: ", header) {
+            content_length = Some(len.parse::<usize>().context(format_err!(
+                "Invalid LSP Content-Length header: {:?}",
+                header
+            ))?);
+        }
+    }
+    let len = content_length
+        .ok_or_else(|| format_err!("LSP message header lacked a Content-Length field."))?;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message<W: Write>(writer: &mut W, body: &[u8]) -> Result<(), Error> {
+    write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn path_to_string<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let path = path.as_ref();
+    Ok(path
+        .to_str()
+        .ok_or_else(|| format_err!("Path {:?} is not valid unicode.", path))?
+        .to_string())
+}
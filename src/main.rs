@@ -2,18 +2,14 @@
 #![feature(const_vec_new)]
 #![warn(rust_2018_idioms)]
 
-use mzr::colors::color_err;
+use clap::Parser;
 use mzr::*;
 use std::process::exit;
-use structopt::StructOpt;
 
 pub fn main() {
-    match run_cmd(&Cmd::from_args()) {
+    let opts = Opts::parse();
+    match run_opts(&opts) {
         Ok(()) => {}
-        Err(err) => {
-            println!();
-            println!("{} {}", color_err(&"mzr error:"), err);
-            exit(1);
-        }
+        Err(err) => exit(report_error(&opts, &err)),
     }
 }
@@ -1,13 +1,27 @@
 use crate::colors::*;
-use crate::paths::OvfsChangesDir;
-use crate::utils::run_process;
+use crate::compaction;
+use crate::config::Config;
+use crate::copier::{self, Copier};
+use crate::display::format_size;
+use crate::git;
+use crate::journal;
+use crate::merge_txn;
+use crate::overlay;
+use crate::paths::{MzrDir, OvfsChangesDir};
+use crate::utils;
 use crate::zone::Zone;
-use failure::Error;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
 use std::fs;
 use std::fs::Metadata;
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::hash::Hasher;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
 pub enum Mode {
@@ -16,15 +30,20 @@ pub enum Mode {
     AutoApplyConflicts,
 }
 
-pub fn interactive_merge(zone: &Zone, target_dir: &PathBuf, mode: Mode) -> Result<(), Error> {
-    let plan = plan_merging_zone_changes(zone, &target_dir);
+pub fn interactive_merge(
+    zone: &Zone,
+    target_dir: &PathBuf,
+    mode: Mode,
+) -> Result<PlanSummary, Error> {
+    let plan = plan_merging_zone_changes(zone, &target_dir, &PathFilter::default());
+    let summary = plan.summary();
     if plan.skips.len() > 0 {
         println!("Skipping merging the following paths:");
         for skip in plan.skips {
             // TODO(cleanliness): use option combinator
             match skip.source {
-                None => println!("* <missing>"),
-                Some(path) => println!("* {:?}", path),
+                None => println!("* <missing>: {}", skip.reason),
+                Some(path) => println!("* {:?}: {}", path, skip.reason),
             }
         }
     }
@@ -78,13 +97,583 @@ pub fn interactive_merge(zone: &Zone, target_dir: &PathBuf, mode: Mode) -> Resul
         );
     }
     */
+    Ok(summary)
+}
+
+/// Applies the updates from the zone's changes to the target dir, leaving
+/// conflicts alone. Paths within `excluded_dirs` are not updated, nor are
+/// deletions of files, which overlayfs represents as whiteout files.
+/// Deletions of directories are applied, as long as the directory hasn't
+/// been modified in the target dir. The updates are applied via
+/// `merge_txn::apply`, so they can be rolled back if interrupted.
+pub fn apply_updates(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    target_dir: &PathBuf,
+    excluded_dirs: &[PathBuf],
+) -> Result<Plan, Error> {
+    let plan = plan(zone, target_dir, excluded_dirs, &PathFilter::default())?;
+    merge_txn::apply(mzr_dir, zone, &plan, target_dir)?;
+    Ok(plan)
+}
+
+/// Plans merging the zone's changes into the target dir, omitting the paths
+/// that `apply_updates` leaves alone, and those which the filter excludes.
+pub fn plan(
+    zone: &Zone,
+    target_dir: &PathBuf,
+    excluded_dirs: &[PathBuf],
+    filter: &PathFilter,
+) -> Result<Plan, Error> {
+    compaction::check_not_compacted(zone)?;
+    let mut plan = plan_merging_zone_changes(zone, target_dir, filter);
+    plan.updates
+        .retain(|update| !is_whiteout(&update.source_metadata));
+    plan.conflicts.retain(|conflict| {
+        !is_whiteout(&conflict.source_metadata) || conflict.target_metadata.is_dir()
+    });
+    retain_outside(&mut plan, excluded_dirs);
+    Ok(plan)
+}
+
+/// Plans the updates which `mzr watch` applies. Unlike `plan`, deletions of
+/// files are included, as updates whose source is a whiteout. Also, paths
+/// which the watch already applied are compared against the metadata they
+/// had once applied, recorded in `applied`, rather than against the
+/// snapshot. Otherwise, the target would no longer match the snapshot after
+/// the first update, so later changes would all be conflicts.
+pub fn plan_watch(
+    zone: &Zone,
+    target_dir: &PathBuf,
+    excluded_dirs: &[PathBuf],
+    applied: &HashMap<PathBuf, Metadata>,
+) -> Result<Plan, Error> {
+    compaction::check_not_compacted(zone)?;
+    let mut plan = plan_merging_zone_changes(zone, target_dir, &PathFilter::default());
+    let (updates, conflicts): (Vec<Conflict>, Vec<Conflict>) =
+        plan.conflicts.into_iter().partition(|conflict| {
+            applied.get(&conflict.rel_path).map_or(false, |metadata| {
+                metadata_matches(&conflict.target_metadata, metadata)
+            })
+        });
+    plan.conflicts = conflicts;
+    plan.updates
+        .extend(updates.into_iter().map(|conflict| Update {
+            rel_path: conflict.rel_path,
+            source_metadata: conflict.source_metadata,
+            target_metadata: Some(conflict.target_metadata),
+        }));
+    // Deletions of files which the target lacks have nothing to apply.
+    plan.updates
+        .retain(|update| !is_whiteout(&update.source_metadata) || update.target_metadata.is_some());
+    retain_outside(&mut plan, excluded_dirs);
+    Ok(plan)
+}
+
+/// Removes the plan's entries for paths within `excluded_dirs`.
+fn retain_outside(plan: &mut Plan, excluded_dirs: &[PathBuf]) {
+    plan.updates.retain(|update| {
+        !excluded_dirs
+            .iter()
+            .any(|dir| update.rel_path.starts_with(dir))
+    });
+    plan.conflicts.retain(|conflict| {
+        !excluded_dirs
+            .iter()
+            .any(|dir| conflict.rel_path.starts_with(dir))
+    });
+    plan.dir_updates.retain(|dir_update| {
+        !excluded_dirs
+            .iter()
+            .any(|dir| dir_update.rel_path.starts_with(dir))
+    });
+    plan.renames.retain(|rename| {
+        !excluded_dirs
+            .iter()
+            .any(|dir| rename.from.starts_with(dir) || rename.to.starts_with(dir))
+    });
+}
+
+/// Refuses to merge a plan which modifies paths that have uncommitted changes
+/// in the target's git repositories, unless `force` is set, in which case a
+/// warning listing them is yielded. Conflicts are detected by comparing
+/// against the snapshot, which misses uncommitted changes that the target
+/// already had when it was taken. `force_hint` describes how to merge anyway.
+pub fn check_uncommitted(
+    target_dir: &PathBuf,
+    rel_git_dirs: &[PathBuf],
+    plan: &Plan,
+    force: bool,
+    force_hint: &str,
+) -> Result<Option<String>, Error> {
+    let dirty_paths = uncommitted_plan_paths(target_dir, rel_git_dirs, plan)?;
+    if dirty_paths.is_empty() {
+        return Ok(None);
+    }
+    let listing: Vec<String> = dirty_paths
+        .iter()
+        .map(|path| format!("  {}", path.display()))
+        .collect();
+    let message = format!(
+        "The merge would modify {} path(s) which have uncommitted changes in {}:\n{}",
+        dirty_paths.len(),
+        color_dir(&target_dir.display()),
+        listing.join("\n")
+    );
+    if !force {
+        bail!(
+            "{}\nCommit or stash them first, or {} to merge anyway.",
+            message,
+            force_hint
+        );
+    }
+    Ok(Some(message))
+}
+
+/// Paths which the plan modifies, relative to the target dir, that have
+/// uncommitted changes in the target's git repositories. These are found
+/// within the target at the same relative paths as the work dir's git
+/// directories.
+fn uncommitted_plan_paths(
+    target_dir: &PathBuf,
+    rel_git_dirs: &[PathBuf],
+    plan: &Plan,
+) -> Result<Vec<PathBuf>, Error> {
+    let plan_paths = plan.target_paths();
+    let mut result = Vec::new();
+    for rel_repo_dir in target_repo_dirs(target_dir, rel_git_dirs) {
+        let repo_dir = target_dir.join(&rel_repo_dir);
+        for dirty_path in git::uncommitted_paths(&repo_dir)? {
+            let dirty_path = rel_repo_dir.join(dirty_path);
+            // Untracked directories are listed as a whole, and directory
+            // updates may remove dirty files within them.
+            if plan_paths.iter().any(|plan_path| {
+                plan_path.starts_with(&dirty_path) || dirty_path.starts_with(plan_path)
+            }) {
+                result.push(dirty_path);
+            }
+        }
+    }
+    result.sort();
+    result.dedup();
+    Ok(result)
+}
+
+/// Git repositories within the target dir, relative to it, which are at the
+/// same relative paths as the work dir's git directories.
+pub fn target_repo_dirs(target_dir: &PathBuf, rel_git_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    for rel_git_dir in rel_git_dirs {
+        let rel_repo_dir = match rel_git_dir.parent() {
+            Some(parent) if rel_git_dir.is_relative() => parent.to_path_buf(),
+            _ => PathBuf::new(),
+        };
+        if target_dir.join(&rel_repo_dir).join(".git").exists() && !result.contains(&rel_repo_dir) {
+            result.push(rel_repo_dir);
+        }
+    }
+    result
+}
+
+/// Removes a file, or a directory along with its contents.
+pub fn remove_all(path: &PathBuf) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
     Ok(())
 }
 
+/// Overlayfs represents deleted files as character devices with device number
+/// 0/0.
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Overlayfs marks directories which replace the lower directory of the same
+/// name, rather than being merged with it, via an xattr. This is
+/// `trusted.overlay.opaque`, or `user.overlay.opaque` for overlays mounted
+/// with the `userxattr` option, as happens within user namespaces.
+pub fn is_opaque_dir(path: &PathBuf) -> bool {
+    ["trusted.overlay.opaque", "user.overlay.opaque"]
+        .iter()
+        .any(|name| copier::read_xattr(path, name).ok() == Some(b"y".to_vec()))
+}
+
+/// Restricts which of the zone's changes are planned, for merging only part
+/// of a zone.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    /// When non-empty, only paths within one of these are included. They are
+    /// relative to the work dir.
+    pub prefixes: Vec<PathBuf>,
+    /// Globs of paths to leave out, along with everything within them.
+    /// Globs without a `/` are matched against each component of the path,
+    /// and others against the whole path.
+    pub excludes: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn includes(&self, rel_path: &Path) -> bool {
+        if !self.prefixes.is_empty()
+            && !self
+                .prefixes
+                .iter()
+                .any(|prefix| rel_path.starts_with(prefix))
+        {
+            return false;
+        }
+        !rel_path.ancestors().any(|ancestor| {
+            !ancestor.as_os_str().is_empty()
+                && self
+                    .excludes
+                    .iter()
+                    .any(|glob| glob_matches_path(glob, ancestor))
+        })
+    }
+}
+
+/// Matches a glob against a path relative to the work dir. Globs without a
+/// `/` are matched against the last component of the path, and others
+/// against the whole path.
+fn glob_matches_path(glob: &str, rel_path: &Path) -> bool {
+    if glob.contains('/') {
+        utils::glob_matches(glob.trim_start_matches('/'), &rel_path.to_string_lossy())
+    } else {
+        rel_path.file_name().map_or(false, |name| {
+            utils::glob_matches(glob, &name.to_string_lossy())
+        })
+    }
+}
+
+/// Name of the file at the root of the work dir which lists globs of paths
+/// that are never merged.
+pub const MERGE_IGNORE_FILE: &str = ".mzrignore-merge";
+
+/// Globs of paths which are never merged, such as build outputs and editor
+/// temp files. These come from the config's `merge_ignore`, along with the
+/// zone's version of `MERGE_IGNORE_FILE`, which has a glob per line, leaving
+/// out blank lines and those starting with `#`. Globs are matched like
+/// `PathFilter::excludes`.
+#[derive(Debug, Clone, Default)]
+pub struct MergeIgnore {
+    pub globs: Vec<String>,
+}
+
+impl MergeIgnore {
+    pub fn load(zone: &Zone, lower_dirs: &[PathBuf]) -> Result<MergeIgnore, Error> {
+        let mut globs = Config::load(&zone.mzr_dir)?.merge_ignore;
+        let rel_path = PathBuf::from(MERGE_IGNORE_FILE);
+        let changed_path = zone.ovfs_changes_dir.join(&rel_path);
+        let ignore_file = match get_metadata(&changed_path)? {
+            Some(ref metadata) if is_whiteout(metadata) => None,
+            Some(_) => Some(changed_path),
+            None => lower_path(lower_dirs, &rel_path)?.map(|(path, _)| path),
+        };
+        if let Some(ignore_file) = ignore_file {
+            let contents = fs::read_to_string(&ignore_file)
+                .context(format_err!("Failed to read {:?}", ignore_file))?;
+            globs.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+        Ok(MergeIgnore { globs })
+    }
+
+    fn matching_glob(&self, rel_path: &Path) -> Option<&str> {
+        self.globs
+            .iter()
+            .find(|glob| glob_matches_path(glob, rel_path))
+            .map(String::as_str)
+    }
+}
+
 pub struct Plan {
     pub updates: Vec<Update>,
     pub conflicts: Vec<Conflict>,
     pub skips: Vec<Skip>,
+    pub dir_updates: Vec<DirUpdate>,
+    pub renames: Vec<Rename>,
+    pub ignored: Vec<Ignored>,
+}
+
+/// Counts of the different kinds of entries in a `Plan`, suitable for
+/// reporting and for storing in records.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanSummary {
+    pub updates: usize,
+    pub conflicts: usize,
+    pub skips: usize,
+    #[serde(default)]
+    pub dir_updates: usize,
+    #[serde(default)]
+    pub renames: usize,
+    #[serde(default)]
+    pub ignored: usize,
+}
+
+impl Plan {
+    pub fn summary(&self) -> PlanSummary {
+        PlanSummary {
+            updates: self.updates.len(),
+            conflicts: self.conflicts.len(),
+            skips: self.skips.len(),
+            dir_updates: self.dir_updates.len(),
+            renames: self.renames.len(),
+            ignored: self.ignored.len(),
+        }
+    }
+
+    /// Paths in the target which applying the plan modifies or removes,
+    /// relative to it. Conflicts are left alone, so aren't included.
+    pub fn target_paths(&self) -> Vec<&Path> {
+        let mut paths: Vec<&Path> = Vec::new();
+        paths.extend(self.updates.iter().map(|update| update.rel_path.as_path()));
+        for rename in self.renames.iter() {
+            paths.push(&rename.from);
+            paths.push(&rename.to);
+        }
+        for dir_update in self.dir_updates.iter() {
+            paths.push(&dir_update.rel_path);
+            paths.extend(dir_update.removals.iter().map(PathBuf::as_path));
+        }
+        paths
+    }
+
+    /// Describes each entry of the plan, for previewing it. Skipped paths
+    /// are made relative to the changes dir when possible.
+    pub fn report(&self, changes_dir: &OvfsChangesDir) -> PlanReport {
+        PlanReport {
+            updates: self
+                .updates
+                .iter()
+                .map(|update| EntryReport {
+                    path: update.rel_path.clone(),
+                    reason: None,
+                    explanation: None,
+                    source: FileReport::new(&update.source_metadata),
+                    target: update.target_metadata.as_ref().map(FileReport::new),
+                })
+                .collect(),
+            conflicts: self
+                .conflicts
+                .iter()
+                .map(|conflict| EntryReport {
+                    path: conflict.rel_path.clone(),
+                    reason: Some(conflict.reason),
+                    explanation: Some(conflict.reason.to_string()),
+                    source: FileReport::new(&conflict.source_metadata),
+                    target: Some(FileReport::new(&conflict.target_metadata)),
+                })
+                .collect(),
+            renames: self
+                .renames
+                .iter()
+                .map(|rename| RenameReport {
+                    from: rename.from.clone(),
+                    to: rename.to.clone(),
+                    modified_in_target: rename.modified_in_target,
+                })
+                .collect(),
+            dirs: self
+                .dir_updates
+                .iter()
+                .map(|dir_update| DirReport {
+                    path: dir_update.rel_path.clone(),
+                    action: dir_update.action,
+                    removals: dir_update.removals.clone(),
+                })
+                .collect(),
+            skips: self
+                .skips
+                .iter()
+                .map(|skip| SkipReport {
+                    path: skip.source.as_ref().map(|source| {
+                        source
+                            .strip_prefix(changes_dir)
+                            .map(PathBuf::from)
+                            .unwrap_or_else(|_| source.clone())
+                    }),
+                    reason: skip.reason.to_string(),
+                })
+                .collect(),
+            ignored: self
+                .ignored
+                .iter()
+                .map(|ignored| IgnoredReport {
+                    path: ignored.rel_path.clone(),
+                    reason: ignored.reason.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Description of a `Plan`, which can be printed as a table or serialized
+/// as JSON.
+#[derive(Debug, Serialize)]
+pub struct PlanReport {
+    pub updates: Vec<EntryReport>,
+    pub conflicts: Vec<EntryReport>,
+    pub renames: Vec<RenameReport>,
+    pub dirs: Vec<DirReport>,
+    pub skips: Vec<SkipReport>,
+    pub ignored: Vec<IgnoredReport>,
+}
+
+/// An update or conflict, along with both sides of it. The source is the
+/// file in the zone's changes, and the target is the file it would replace.
+#[derive(Debug, Serialize)]
+pub struct EntryReport {
+    pub path: PathBuf,
+    pub reason: Option<ConflictReason>,
+    pub explanation: Option<String>,
+    pub source: FileReport,
+    pub target: Option<FileReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub kind: &'static str,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl FileReport {
+    pub fn new(metadata: &Metadata) -> FileReport {
+        let kind = if is_whiteout(metadata) {
+            "deleted"
+        } else if metadata.file_type().is_symlink() {
+            "symlink"
+        } else if metadata.is_file() {
+            "file"
+        } else if metadata.is_dir() {
+            "directory"
+        } else {
+            "other"
+        };
+        FileReport {
+            kind,
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        }
+    }
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{} of {}", self.kind, format_size(self.size))?;
+        if let Some(modified) = self.modified {
+            write!(f, ", modified {}", modified.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RenameReport {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub modified_in_target: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DirReport {
+    pub path: PathBuf,
+    pub action: DirAction,
+    pub removals: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkipReport {
+    pub path: Option<PathBuf>,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IgnoredReport {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl PlanReport {
+    /// Prints each entry of the plan, with the reasons for conflicts and
+    /// skips.
+    pub fn print_table(&self, target_dir: &PathBuf) {
+        println!(
+            "{} update(s) to apply to {}:",
+            self.updates.len(),
+            color_dir(&target_dir.display())
+        );
+        for update in self.updates.iter() {
+            println!("  {}", color_file(&update.path.display()));
+            println!("      zone:   {}", update.source);
+            match &update.target {
+                None => println!("      target: absent"),
+                Some(target) => println!("      target: {}", target),
+            }
+        }
+        println!("{} rename(s):", self.renames.len());
+        for rename in self.renames.iter() {
+            println!(
+                "  renamed {} \u{2192} {}",
+                color_file(&rename.from.display()),
+                color_file(&rename.to.display())
+            );
+            if rename.modified_in_target {
+                println!("      keeps the modifications made to it in the target");
+            }
+        }
+        println!("{} directory change(s):", self.dirs.len());
+        for dir in self.dirs.iter() {
+            println!("  {} {}", dir.action, color_dir(&dir.path.display()));
+            for removal in dir.removals.iter() {
+                println!("      removes {}", color_file(&removal.display()));
+            }
+        }
+        println!(
+            "{} conflict(s), which won't be applied:",
+            self.conflicts.len()
+        );
+        for conflict in self.conflicts.iter() {
+            println!("  {}", color_file(&conflict.path.display()));
+            if let Some(explanation) = &conflict.explanation {
+                println!("      {}", color_warn(explanation));
+            }
+            println!("      zone:   {}", conflict.source);
+            if let Some(target) = &conflict.target {
+                println!("      target: {}", target);
+            }
+        }
+        println!("{} skip(s):", self.skips.len());
+        for skip in self.skips.iter() {
+            match &skip.path {
+                None => println!("  {}", color_err(&skip.reason)),
+                Some(path) => println!(
+                    "  {}: {}",
+                    color_file(&path.display()),
+                    color_err(&skip.reason)
+                ),
+            }
+        }
+        println!(
+            "{} ignored path(s), which are never merged:",
+            self.ignored.len()
+        );
+        for ignored in self.ignored.iter() {
+            println!(
+                "  {}: {}",
+                color_file(&ignored.path.display()),
+                ignored.reason
+            );
+        }
+    }
 }
 
 pub struct Update {
@@ -100,56 +689,120 @@ pub struct Conflict {
     pub target_metadata: Metadata,
 }
 
+#[derive(Debug, Clone, Copy, Serialize)]
 pub enum ConflictReason {
     NotInSnapshot,
     ModifiedInTarget,
 }
 
+impl Display for ConflictReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            ConflictReason::NotInSnapshot => write!(
+                f,
+                "Created in both the zone and the target, since it didn't exist in the snapshot."
+            ),
+            ConflictReason::ModifiedInTarget => write!(
+                f,
+                "Modified in the target since the snapshot was taken, so applying the zone's \
+                 version would lose those modifications."
+            ),
+        }
+    }
+}
+
 pub struct Skip {
     pub source: Option<PathBuf>,
     pub reason: Error,
 }
 
+/// A path which is intentionally left out of the merge, since it came from
+/// the zone template or matches a merge-ignore glob.
+pub struct Ignored {
+    pub rel_path: PathBuf,
+    pub reason: String,
+}
+
+/// A file which was renamed within the zone, applied by renaming the
+/// target's file.
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Metadata of the zone's file, at the new path.
+    pub source_metadata: Metadata,
+    /// Metadata of the target's file, at the old path.
+    pub target_metadata: Metadata,
+    /// Whether the target's file was modified since the snapshot, in which
+    /// case renaming it keeps the modifications.
+    pub modified_in_target: bool,
+}
+
+/// A change to the directory structure of the target.
+pub struct DirUpdate {
+    pub rel_path: PathBuf,
+    pub action: DirAction,
+    /// Paths to remove from the target before creating directories and
+    /// updating files, relative to the target. These are unchanged since the
+    /// snapshot, so removing them doesn't lose anything.
+    pub removals: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum DirAction {
+    /// The directory was created within the zone. If the target has a file
+    /// at the path, it's removed.
+    Create,
+    /// The directory was removed within the zone.
+    Remove,
+    /// The directory was removed and then recreated within the zone, which
+    /// overlayfs represents as an opaque directory. Entries of the target
+    /// directory which the zone's directory lacks are removed, other than
+    /// those which weren't in the snapshot.
+    Replace,
+}
+
+impl Display for DirAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            DirAction::Create => write!(f, "create"),
+            DirAction::Remove => write!(f, "remove"),
+            DirAction::Replace => write!(f, "replace"),
+        }
+    }
+}
+
 impl Update {
-    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &PathBuf) -> Result<(), Error> {
-        copy_from_changes_dir(&self.rel_path, changes_dir, target_dir)
+    fn apply(
+        &self,
+        copier: &mut Copier,
+        changes_dir: &OvfsChangesDir,
+        target_dir: &PathBuf,
+    ) -> Result<(), Error> {
+        copy_from_changes_dir(copier, &self.rel_path, changes_dir, target_dir)
     }
 }
 
 impl Conflict {
-    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &PathBuf) -> Result<(), Error> {
-        copy_from_changes_dir(&self.rel_path, changes_dir, target_dir)
+    fn apply(
+        &self,
+        copier: &mut Copier,
+        changes_dir: &OvfsChangesDir,
+        target_dir: &PathBuf,
+    ) -> Result<(), Error> {
+        copy_from_changes_dir(copier, &self.rel_path, changes_dir, target_dir)
     }
 }
 
 // TODO(correctness): Check expected metadata
 fn copy_from_changes_dir(
+    copier: &mut Copier,
     rel_path: &PathBuf,
     changes_dir: &OvfsChangesDir,
     target_dir: &PathBuf,
 ) -> Result<(), Error> {
     let source = changes_dir.join(rel_path.clone());
     let target = target_dir.join(rel_path.clone());
-    copy_file(&source, &target)
-}
-
-/// Copies a file from source path to target path, using cp in order to support reflinks.
-fn copy_file(source: &PathBuf, target: &PathBuf) -> Result<(), Error> {
-    let mut cmd_base = Command::new("cp");
-    let cmd = cmd_base
-        .stdin(Stdio::null())
-        // Preserve all file properties, and preserve symlinks.
-        .arg("--archive")
-        // When using filesystems that support reflinks, use them. Filesystems
-        // like BTRFS and XFS support creating copy-on-write copies of files.
-        // When using reflinks to make a snapshot, it's pretty comparable to
-        // creating a tree of hardlinks, which tends to be much faster.
-        .arg("--reflink=auto")
-        // Don't dereference source symlinks.
-        .arg("--no-dereference")
-        .arg(source)
-        .arg(target);
-    run_process(cmd)
+    copier.copy(&source, &target)
 }
 
 /// This enumerates every file in change directory of `zone`, and creates a `Plan` for applying
@@ -157,79 +810,560 @@ fn copy_file(source: &PathBuf, target: &PathBuf) -> Result<(), Error> {
 ///
 /// This plan will turn these changed files into updates if the file has not been changed in the
 /// target dir. Whether the file has been changed in the target dir is determined by comparing its
-/// metadata to the metadata of the corresponding file in the snapshot.
-fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf) -> Plan {
+/// metadata to the metadata of the corresponding file in the snapshot, or in the changes of the
+/// zones that `zone` is layered on.
+fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf, filter: &PathFilter) -> Plan {
     let source_dir = zone.ovfs_changes_dir.clone();
-    let mut updates = Vec::new();
-    let mut conflicts = Vec::new();
-    let mut skips = Vec::new();
-    for walk_result in WalkDir::new(&source_dir).same_file_system(true) {
-        match walk_result {
-            Err(e) => skips.push(Skip {
-                source: e.path().map(PathBuf::from),
-                reason: Error::from(e),
-            }),
-            Ok(entry) => {
-                let source = PathBuf::from(entry.path());
+    let mut plan = Plan {
+        updates: Vec::new(),
+        conflicts: Vec::new(),
+        skips: Vec::new(),
+        dir_updates: Vec::new(),
+        renames: Vec::new(),
+        ignored: Vec::new(),
+    };
+    let lower_dirs = match zone.lower_dirs() {
+        Ok(lower_dirs) => lower_dirs,
+        Err(reason) => {
+            plan.skips.push(Skip {
+                source: None,
+                reason,
+            });
+            return plan;
+        }
+    };
+    let ignore = match MergeIgnore::load(zone, &lower_dirs) {
+        Ok(ignore) => ignore,
+        Err(reason) => {
+            plan.skips.push(Skip {
+                source: None,
+                reason,
+            });
+            return plan;
+        }
+    };
+    let planned_from_journal = match journal::read(zone) {
+        // Only the paths recorded in the journal might have changed, so
+        // there's no need to walk the whole changes dir.
+        Ok(Some(rel_paths)) => {
+            for rel_path in rel_paths {
+                let source = source_dir.join(&rel_path);
+                if !filter.includes(&rel_path) || ignore_path(zone, &ignore, &rel_path, &mut plan) {
+                    continue;
+                }
                 let result: Result<(), Error> = try {
-                    let source_metadata = entry.metadata()?;
-                    // For now, emulating git's precedent of ignoring dirs.
-                    if !source_metadata.is_dir() {
+                    // Paths which have since been removed from the changes
+                    // dir are no longer changes.
+                    if let Some(source_metadata) = get_metadata(&source)? {
+                        plan_path(
+                            &lower_dirs,
+                            &source_dir,
+                            target_dir,
+                            rel_path,
+                            source_metadata,
+                            &mut plan,
+                        )?;
+                    }
+                };
+                if let Err(reason) = result {
+                    plan.skips.push(Skip {
+                        source: Some(source),
+                        reason,
+                    });
+                }
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            println!("Warning: ignoring change journal: {}", err);
+            false
+        }
+    };
+    if !planned_from_journal {
+        for walk_result in WalkDir::new(&source_dir).same_file_system(true) {
+            match walk_result {
+                Err(e) => plan.skips.push(Skip {
+                    source: e.path().map(PathBuf::from),
+                    reason: Error::from(e),
+                }),
+                Ok(entry) => {
+                    let source = PathBuf::from(entry.path());
+                    let result: Result<(), Error> = try {
+                        let source_metadata = entry.metadata()?;
                         let rel_path = PathBuf::from(source.strip_prefix(&source_dir)?);
-                        let target = target_dir.join(&rel_path);
-                        match get_metadata(&target)? {
-                            None => updates.push(Update {
+                        if filter.includes(&rel_path)
+                            && !ignore_path(zone, &ignore, &rel_path, &mut plan)
+                        {
+                            plan_path(
+                                &lower_dirs,
+                                &source_dir,
+                                target_dir,
                                 rel_path,
                                 source_metadata,
-                                target_metadata: None,
-                            }),
-                            Some(target_metadata) => {
-                                // Note that this relies on snapshotting preserving timestamps.
-                                let snapshot = zone.snap_dir.join(&rel_path);
-                                match get_metadata(&snapshot)? {
-                                    // The file didn't exist in the snapshot, but now exists in both
-                                    // working dirs, so it's a conflict.
-                                    None => conflicts.push(Conflict {
-                                        rel_path,
-                                        reason: ConflictReason::NotInSnapshot,
-                                        source_metadata,
-                                        target_metadata,
-                                    }),
-                                    Some(snapshot_metadata) => {
-                                        if metadata_matches(&target_metadata, &snapshot_metadata) {
-                                            updates.push(Update {
-                                                rel_path,
-                                                source_metadata,
-                                                target_metadata: Some(target_metadata),
-                                            });
-                                        } else {
-                                            conflicts.push(Conflict {
-                                                rel_path,
-                                                reason: ConflictReason::ModifiedInTarget,
-                                                source_metadata,
-                                                target_metadata,
-                                            });
-                                        }
-                                    }
-                                }
-                            }
+                                &mut plan,
+                            )?;
                         }
+                    };
+                    result.err().map(|reason| {
+                        plan.skips.push(Skip {
+                            source: Some(source),
+                            reason,
+                        })
+                    });
+                }
+            }
+        }
+    }
+    detect_renames(&lower_dirs, &source_dir, &mut plan);
+    detect_dir_redirects(&source_dir, target_dir, &mut plan);
+    plan
+}
+
+/// Whether the path is never merged, since it came from the zone template or
+/// matches a merge-ignore glob. Paths within such a path are also ignored,
+/// but only the outermost one is recorded in the plan.
+fn ignore_path(zone: &Zone, ignore: &MergeIgnore, rel_path: &Path, plan: &mut Plan) -> bool {
+    let reason = |path: &Path| -> Option<String> {
+        if zone
+            .info
+            .template_paths
+            .iter()
+            .any(|template_path| template_path == path)
+        {
+            Some(String::from("came from the zone template"))
+        } else {
+            ignore
+                .matching_glob(path)
+                .map(|glob| format!("matches merge-ignore glob {}", glob))
+        }
+    };
+    let within_ignored = rel_path
+        .ancestors()
+        .skip(1)
+        .any(|ancestor| !ancestor.as_os_str().is_empty() && reason(ancestor).is_some());
+    if within_ignored {
+        return true;
+    }
+    match reason(rel_path) {
+        None => false,
+        Some(reason) => {
+            plan.ignored.push(Ignored {
+                rel_path: rel_path.to_path_buf(),
+                reason,
+            });
+            true
+        }
+    }
+}
+
+/// A deleted file which the target still has, which may have been renamed.
+struct Deletion {
+    rel_path: PathBuf,
+    lower_path: PathBuf,
+    target_metadata: Metadata,
+    modified_in_target: bool,
+}
+
+/// Finds files which were renamed within the zone, which overlayfs
+/// represents as a whiteout at the old path and a new file at the new path.
+/// A new file is taken to be a rename of a deleted file when they have the
+/// same size and the same hash of their contents. The deletion and the
+/// creation are replaced with a `Rename` of the target's file, so that
+/// modifications made to it in the target are kept.
+fn detect_renames(lower_dirs: &[PathBuf], source_dir: &PathBuf, plan: &mut Plan) {
+    let mut deletions: HashMap<u64, Vec<Deletion>> = HashMap::new();
+    let deleted_in_updates = plan.updates.iter().filter_map(|update| {
+        update.target_metadata.as_ref().map(|target_metadata| {
+            (
+                &update.rel_path,
+                &update.source_metadata,
+                target_metadata,
+                false,
+            )
+        })
+    });
+    let deleted_in_conflicts = plan.conflicts.iter().map(|conflict| {
+        (
+            &conflict.rel_path,
+            &conflict.source_metadata,
+            &conflict.target_metadata,
+            true,
+        )
+    });
+    for (rel_path, source_metadata, target_metadata, modified_in_target) in
+        deleted_in_updates.chain(deleted_in_conflicts)
+    {
+        if !is_whiteout(source_metadata) || !target_metadata.is_file() {
+            continue;
+        }
+        if let Ok(Some((lower_path, lower_metadata))) = lower_path(lower_dirs, rel_path) {
+            if lower_metadata.is_file() {
+                deletions
+                    .entry(lower_metadata.len())
+                    .or_insert_with(Vec::new)
+                    .push(Deletion {
+                        rel_path: rel_path.clone(),
+                        lower_path,
+                        target_metadata: target_metadata.clone(),
+                        modified_in_target,
+                    });
+            }
+        }
+    }
+    if deletions.is_empty() {
+        return;
+    }
+    let mut lower_hashes: HashMap<PathBuf, Option<u64>> = HashMap::new();
+    let mut renamed_updates = HashSet::new();
+    for (ix, update) in plan.updates.iter().enumerate() {
+        let source_metadata = &update.source_metadata;
+        if !source_metadata.is_file() || update.target_metadata.is_some() {
+            continue;
+        }
+        let candidates = match deletions.get_mut(&source_metadata.len()) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+        // Files which existed before the zone was created can't be renames.
+        match lower_metadata(lower_dirs, &update.rel_path) {
+            Ok(None) => {}
+            _ => continue,
+        }
+        let source_hash = match hash_file(&source_dir.join(&update.rel_path)) {
+            Ok(hash) => hash,
+            Err(_) => continue,
+        };
+        let matching = candidates.iter().position(|deletion| {
+            let lower_hash = lower_hashes
+                .entry(deletion.lower_path.clone())
+                .or_insert_with(|| hash_file(&deletion.lower_path).ok());
+            *lower_hash == Some(source_hash)
+        });
+        if let Some(candidate_ix) = matching {
+            let deletion = candidates.remove(candidate_ix);
+            plan.renames.push(Rename {
+                from: deletion.rel_path,
+                to: update.rel_path.clone(),
+                source_metadata: source_metadata.clone(),
+                target_metadata: deletion.target_metadata,
+                modified_in_target: deletion.modified_in_target,
+            });
+            renamed_updates.insert(ix);
+        }
+    }
+    let renamed_from: HashSet<PathBuf> = plan
+        .renames
+        .iter()
+        .map(|rename| rename.from.clone())
+        .collect();
+    let mut ix = 0;
+    plan.updates.retain(|update| {
+        ix += 1;
+        !renamed_updates.contains(&(ix - 1)) && !renamed_from.contains(&update.rel_path)
+    });
+    plan.conflicts
+        .retain(|conflict| !renamed_from.contains(&conflict.rel_path));
+}
+
+/// Finds directories which were renamed within the zone, when its overlay has
+/// `redirect_dir` enabled. Overlayfs represents these as a whiteout at the
+/// old path, and a directory at the new path which records the old path and
+/// only contains the changed entries. Creating the new directory would lose
+/// the unchanged entries, so the target's directory is renamed instead, and
+/// the changed entries are updated within it.
+fn detect_dir_redirects(source_dir: &PathBuf, target_dir: &PathBuf, plan: &mut Plan) {
+    let mut redirects: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for dir_update in plan.dir_updates.iter() {
+        match dir_update.action {
+            DirAction::Create if dir_update.removals.is_empty() => {}
+            _ => continue,
+        }
+        let origin = match overlay::redirect(&dir_update.rel_path, source_dir) {
+            Some(origin) => origin,
+            None => continue,
+        };
+        let source_metadata = get_metadata(&source_dir.join(&dir_update.rel_path));
+        let target_metadata = get_metadata(&target_dir.join(&origin));
+        if let (Ok(Some(source_metadata)), Ok(Some(target_metadata))) =
+            (source_metadata, target_metadata)
+        {
+            if target_metadata.is_dir() {
+                plan.renames.push(Rename {
+                    from: origin.clone(),
+                    to: dir_update.rel_path.clone(),
+                    source_metadata,
+                    target_metadata,
+                    modified_in_target: false,
+                });
+                redirects.push((origin, dir_update.rel_path.clone()));
+            }
+        }
+    }
+    if redirects.is_empty() {
+        return;
+    }
+    // Directories within the renamed directory which the target already has
+    // at the old path get created by the rename.
+    let created_by_rename = |rel_path: &Path| {
+        redirects
+            .iter()
+            .any(|(origin, to)| match rel_path.strip_prefix(to) {
+                Ok(rest) if rest.as_os_str().is_empty() => true,
+                Ok(rest) => get_metadata(&target_dir.join(origin).join(rest))
+                    .ok()
+                    .and_then(|metadata| metadata)
+                    .map_or(false, |metadata| metadata.is_dir()),
+                Err(_) => false,
+            })
+    };
+    // The whiteout at the old path is part of the rename, rather than a
+    // removal of the directory.
+    let removed_by_rename =
+        |rel_path: &Path| redirects.iter().any(|(origin, _)| origin == rel_path);
+    plan.dir_updates
+        .retain(|dir_update| match dir_update.action {
+            DirAction::Create => !created_by_rename(&dir_update.rel_path),
+            DirAction::Remove => !removed_by_rename(&dir_update.rel_path),
+            DirAction::Replace => true,
+        });
+    plan.conflicts
+        .retain(|conflict| !removed_by_rename(&conflict.rel_path));
+}
+
+fn hash_file(path: &PathBuf) -> Result<u64, Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buffer[..count]);
+    }
+}
+
+/// Adds an update or conflict to the plan for a path within the zone's
+/// changes dir.
+fn plan_path(
+    lower_dirs: &[PathBuf],
+    source_dir: &PathBuf,
+    target_dir: &PathBuf,
+    rel_path: PathBuf,
+    source_metadata: Metadata,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    if source_metadata.is_dir() {
+        return plan_dir(
+            lower_dirs,
+            source_dir,
+            target_dir,
+            rel_path,
+            source_metadata,
+            plan,
+        );
+    }
+    if is_whiteout(&source_metadata) {
+        match lower_metadata(lower_dirs, &rel_path)? {
+            Some(ref lower) if lower.is_dir() => {
+                return plan_dir_removal(lower_dirs, target_dir, rel_path, source_metadata, plan);
+            }
+            _ => {}
+        }
+    }
+    let target = target_dir.join(&rel_path);
+    match get_metadata(&target)? {
+        None => plan.updates.push(Update {
+            rel_path,
+            source_metadata,
+            target_metadata: None,
+        }),
+        // The change has already been merged, as copying preserves metadata.
+        Some(ref target_metadata) if metadata_matches(target_metadata, &source_metadata) => {}
+        Some(target_metadata) => {
+            // Note that this relies on snapshotting preserving timestamps.
+            match lower_metadata(lower_dirs, &rel_path)? {
+                // The file didn't exist in the snapshot, but now exists in both
+                // working dirs, so it's a conflict.
+                None => plan.conflicts.push(Conflict {
+                    rel_path,
+                    reason: ConflictReason::NotInSnapshot,
+                    source_metadata,
+                    target_metadata,
+                }),
+                Some(snapshot_metadata) => {
+                    if metadata_matches(&target_metadata, &snapshot_metadata) {
+                        plan.updates.push(Update {
+                            rel_path,
+                            source_metadata,
+                            target_metadata: Some(target_metadata),
+                        });
+                    } else {
+                        plan.conflicts.push(Conflict {
+                            rel_path,
+                            reason: ConflictReason::ModifiedInTarget,
+                            source_metadata,
+                            target_metadata,
+                        });
                     }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Plans creating a directory which the target lacks, or if the zone's
+/// directory is opaque, removing the target's entries which it lacks.
+fn plan_dir(
+    lower_dirs: &[PathBuf],
+    source_dir: &PathBuf,
+    target_dir: &PathBuf,
+    rel_path: PathBuf,
+    source_metadata: Metadata,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    let source = source_dir.join(&rel_path);
+    let target = target_dir.join(&rel_path);
+    match get_metadata(&target)? {
+        None => plan.dir_updates.push(DirUpdate {
+            rel_path,
+            action: DirAction::Create,
+            removals: Vec::new(),
+        }),
+        Some(ref target_metadata) if target_metadata.is_dir() => {
+            if !is_opaque_dir(&source) {
+                return Ok(());
+            }
+            let mut removals = Vec::new();
+            for entry in fs::read_dir(&target)? {
+                let name = entry?.file_name();
+                if get_metadata(&source.join(&name))?.is_some() {
+                    continue;
+                }
+                let entry_rel_path = rel_path.join(&name);
+                // Entries which were created in the target are kept, since
+                // the zone never had them.
+                if lower_metadata(lower_dirs, &entry_rel_path)?.is_none() {
+                    continue;
+                }
+                if unchanged_since_lower(lower_dirs, target_dir, &entry_rel_path)? {
+                    removals.push(entry_rel_path);
+                } else {
+                    let target_metadata = fs::symlink_metadata(target_dir.join(&entry_rel_path))?;
+                    plan.conflicts.push(Conflict {
+                        rel_path: entry_rel_path,
+                        reason: ConflictReason::ModifiedInTarget,
+                        source_metadata: source_metadata.clone(),
+                        target_metadata,
+                    });
+                }
+            }
+            plan.dir_updates.push(DirUpdate {
+                rel_path,
+                action: DirAction::Replace,
+                removals,
+            });
+        }
+        // The target has a file where the zone has a directory.
+        Some(target_metadata) => {
+            if unchanged_since_lower(lower_dirs, target_dir, &rel_path)? {
+                plan.dir_updates.push(DirUpdate {
+                    rel_path: rel_path.clone(),
+                    action: DirAction::Create,
+                    removals: vec![rel_path],
+                });
+            } else {
+                let reason = match lower_metadata(lower_dirs, &rel_path)? {
+                    None => ConflictReason::NotInSnapshot,
+                    Some(_) => ConflictReason::ModifiedInTarget,
                 };
-                result.err().map(|reason| {
-                    skips.push(Skip {
-                        source: Some(source),
-                        reason,
-                    })
+                plan.conflicts.push(Conflict {
+                    rel_path,
+                    reason,
+                    source_metadata,
+                    target_metadata,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Plans removing a directory which was removed within the zone, as long as
+/// the target's directory hasn't been modified since the snapshot.
+fn plan_dir_removal(
+    lower_dirs: &[PathBuf],
+    target_dir: &PathBuf,
+    rel_path: PathBuf,
+    source_metadata: Metadata,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    match get_metadata(&target_dir.join(&rel_path))? {
+        // Already removed.
+        None => {}
+        Some(target_metadata) => {
+            if unchanged_since_lower(lower_dirs, target_dir, &rel_path)? {
+                plan.dir_updates.push(DirUpdate {
+                    rel_path: rel_path.clone(),
+                    action: DirAction::Remove,
+                    removals: vec![rel_path],
+                });
+            } else {
+                plan.conflicts.push(Conflict {
+                    rel_path,
+                    reason: ConflictReason::ModifiedInTarget,
+                    source_metadata,
+                    target_metadata,
                 });
             }
         }
     }
-    Plan {
-        updates,
-        conflicts,
-        skips,
+    Ok(())
+}
+
+/// Whether the target's path, along with everything within it if it's a
+/// directory, is as the zone started out with it.
+fn unchanged_since_lower(
+    lower_dirs: &[PathBuf],
+    target_dir: &PathBuf,
+    rel_path: &PathBuf,
+) -> Result<bool, Error> {
+    for entry in WalkDir::new(target_dir.join(rel_path)).same_file_system(true) {
+        let entry = entry?;
+        let entry_rel_path = PathBuf::from(entry.path().strip_prefix(target_dir)?);
+        let metadata = entry.metadata()?;
+        match lower_metadata(lower_dirs, &entry_rel_path)? {
+            Some(ref lower) if metadata.is_dir() && lower.is_dir() => {}
+            Some(ref lower) if !metadata.is_dir() && metadata_matches(&metadata, lower) => {}
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Metadata of the path as the zone started out with it, from the topmost of
+/// its lower dirs which has it. Yields `None` if the path is absent, or was
+/// deleted within a parent zone.
+fn lower_metadata(lower_dirs: &[PathBuf], rel_path: &PathBuf) -> Result<Option<Metadata>, Error> {
+    Ok(lower_path(lower_dirs, rel_path)?.map(|(_, metadata)| metadata))
+}
+
+/// Like `lower_metadata`, but also yields the path within the lower dir.
+fn lower_path(
+    lower_dirs: &[PathBuf],
+    rel_path: &PathBuf,
+) -> Result<Option<(PathBuf, Metadata)>, Error> {
+    for lower_dir in lower_dirs {
+        let path = lower_dir.join(rel_path);
+        match get_metadata(&path)? {
+            Some(ref metadata) if is_whiteout(metadata) => return Ok(None),
+            Some(metadata) => return Ok(Some((path, metadata))),
+            None => {}
+        }
     }
+    Ok(None)
 }
 
 fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
@@ -244,7 +1378,7 @@ fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
     }
 }
 
-fn metadata_matches(x: &Metadata, y: &Metadata) -> bool {
+pub fn metadata_matches(x: &Metadata, y: &Metadata) -> bool {
     // Check things that are most likely to differ first.
     if x.len() != y.len() {
         return false;
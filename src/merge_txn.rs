@@ -0,0 +1,536 @@
+use crate::copier::Copier;
+use crate::json;
+use crate::merge::{self, DirAction, Plan};
+use crate::paths::*;
+use crate::zone::Zone;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use failure::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{
+    create_dir, create_dir_all, hard_link, read_dir, remove_dir, remove_dir_all, remove_file,
+    rename, symlink_metadata,
+};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Format of the names of merge backups, which are the time the merge
+/// finished.
+const BACKUP_NAME_FORMAT: &str = "%Y-%m-%d-%H%M%S";
+
+/// Record of a merge which is being applied, kept in the `MergeDir` until the
+/// merge finishes, so that an interrupted merge can be resumed via
+/// `resume` or rolled back via `abort`.
+///
+/// Applying happens in two phases. First, every updated file is copied into
+/// the `MergeStagingDir` within the target dir, which can fail without
+/// affecting the rest of the target dir. Being on the same filesystem as the
+/// target, staged files can be renamed into place. Then
+/// removed paths are moved into the backup dir, created directories are
+/// created, renamed files are moved, and each staged file is renamed over its target, after
+/// hard-linking any existing target file into the backup dir. Whether an
+/// entry has been applied is recorded by its staged file no longer existing,
+/// so the record stays accurate even if interrupted between renames.
+///
+/// Once finished, the record and backup dir are moved into the
+/// `MergeBackupsDir`, so that the merge can be undone via `undo_last`. The
+/// backup dir is copied there if the mzr dir is on another filesystem.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MergeState {
+    pub zone_name: ZoneName,
+    pub target_dir: PathBuf,
+    pub phase: Phase,
+    pub entries: Vec<Entry>,
+    /// Paths removed from the target dir, relative to it.
+    #[serde(default)]
+    pub removals: Vec<PathBuf>,
+    /// Directories created in the target dir, relative to it.
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
+    /// Files renamed within the target dir, as pairs of the old and new
+    /// paths relative to it.
+    #[serde(default)]
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    /// When the merge finished, which is only set for merge backups.
+    #[serde(default)]
+    pub finished_time: Option<DateTime<Utc>>,
+    /// Change times of the paths the merge wrote, as seconds and
+    /// nanoseconds, recorded when it finished. Paths whose change time
+    /// differs have been modified since, see `modified_since_merge`.
+    #[serde(default)]
+    pub written_ctimes: BTreeMap<PathBuf, (i64, i64)>,
+    /// Where files are staged and backed up while the merge is applied.
+    /// `None` for merges started by older versions of mzr, which used the
+    /// `MergeDir`.
+    #[serde(default)]
+    pub staging_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Phase {
+    Staging,
+    Applying,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub rel_path: PathBuf,
+    /// Whether the target dir had a file at the path before the merge.
+    pub had_target: bool,
+}
+
+/// Applies the updates of the plan to the target dir, such that if it fails
+/// partway, `abort` can restore the target dir.
+pub fn apply(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    plan: &Plan,
+    target_dir: &PathBuf,
+) -> Result<(), Error> {
+    let merge_dir = MergeDir::new(mzr_dir);
+    if let Some(state) = load(mzr_dir)? {
+        bail!(
+            "A merge of zone {} into {:?} is already in progress. Use mzr merge --continue to \
+             finish it, or mzr merge --abort to roll it back.",
+            state.zone_name,
+            state.target_dir
+        );
+    }
+    if plan.updates.is_empty() && plan.dir_updates.is_empty() && plan.renames.is_empty() {
+        return Ok(());
+    }
+    let staging_dir = MergeStagingDir::new(target_dir);
+    // Fails if it already exists, since it would then be left by a merge
+    // whose record was lost, and may have the only copy of replaced files.
+    create_dir(&staging_dir).context(format_err!(
+        "Failed to create {}. If it's left from an earlier merge, check its backup dir for \
+         files to keep, and then remove it.",
+        staging_dir
+    ))?;
+    create_dir_all(staged_dir(&staging_dir))?;
+    create_dir_all(backup_dir(&staging_dir))?;
+    create_dir_all(&merge_dir)?;
+    let state = MergeState {
+        zone_name: zone.name.clone(),
+        target_dir: target_dir.clone(),
+        phase: Phase::Staging,
+        entries: plan
+            .updates
+            .iter()
+            .filter(|update| !merge::is_whiteout(&update.source_metadata))
+            .map(|update| Entry {
+                rel_path: update.rel_path.clone(),
+                had_target: update.target_metadata.is_some(),
+            })
+            .collect(),
+        // Updates whose source is a whiteout are deletions of files.
+        removals: plan
+            .dir_updates
+            .iter()
+            .flat_map(|dir_update| dir_update.removals.iter().cloned())
+            .chain(
+                plan.updates
+                    .iter()
+                    .filter(|update| {
+                        merge::is_whiteout(&update.source_metadata)
+                            && update.target_metadata.is_some()
+                    })
+                    .map(|update| update.rel_path.clone()),
+            )
+            .collect(),
+        created_dirs: plan
+            .dir_updates
+            .iter()
+            .filter(|dir_update| match dir_update.action {
+                DirAction::Create => true,
+                _ => false,
+            })
+            .map(|dir_update| dir_update.rel_path.clone())
+            .collect(),
+        renames: plan
+            .renames
+            .iter()
+            .map(|rename| (rename.from.clone(), rename.to.clone()))
+            .collect(),
+        finished_time: None,
+        written_ctimes: BTreeMap::new(),
+        staging_dir: Some(staging_dir.to_path_buf()),
+    };
+    write_state(&merge_dir, &state)?;
+    finish(mzr_dir, state).context(
+        "Merge did not complete. Use mzr merge --continue to finish it, or mzr merge --abort to \
+         roll it back.",
+    )?;
+    Ok(())
+}
+
+/// Loads the record of the merge in progress, if any.
+pub fn load(mzr_dir: &MzrDir) -> Result<Option<MergeState>, Error> {
+    let state_file = MergeStateFile::new(&MergeDir::new(mzr_dir));
+    if state_file.exists() {
+        Ok(Some(json::read(&state_file)?.contents))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Finishes applying an interrupted merge.
+pub fn resume(mzr_dir: &MzrDir) -> Result<MergeState, Error> {
+    match load(mzr_dir)? {
+        None => bail!("There is no merge in progress to continue."),
+        Some(state) => finish(mzr_dir, state),
+    }
+}
+
+/// Rolls back an interrupted merge, restoring the files it replaced or
+/// removed, and removing the files and directories it created. Parent
+/// directories created for new files are left in place.
+pub fn abort(mzr_dir: &MzrDir) -> Result<MergeState, Error> {
+    let merge_dir = MergeDir::new(mzr_dir);
+    let state = match load(mzr_dir)? {
+        None => bail!("There is no merge in progress to abort."),
+        Some(state) => state,
+    };
+    let data_dir = data_dir(&merge_dir, &state);
+    if state.phase == Phase::Applying {
+        roll_back(&data_dir, &state)?;
+    }
+    remove_dir_all_if_exists(&data_dir)?;
+    remove_dir_all(&merge_dir)?;
+    Ok(state)
+}
+
+/// Undoes the most recently finished merge, like `abort`, using its backup.
+/// The backup is then removed, so that undoing again undoes the merge before
+/// it. Fails without changing anything if files the merge touched have been
+/// modified since, since undoing would lose those modifications.
+pub fn undo_last(mzr_dir: &MzrDir) -> Result<MergeState, Error> {
+    if let Some(state) = load(mzr_dir)? {
+        bail!(
+            "A merge of zone {} into {:?} is in progress. Use mzr merge --continue to finish \
+             it, or mzr merge --abort to roll it back.",
+            state.zone_name,
+            state.target_dir
+        );
+    }
+    let backup_name = match list_backups(mzr_dir)?.pop() {
+        None => bail!("There is no merge to undo, since no merge backups are kept."),
+        Some(backup_name) => backup_name,
+    };
+    let backup_dir = MergeBackupsDir::new(mzr_dir).join(&backup_name);
+    let state: MergeState = json::read(&backup_dir.join("state.json"))?.contents;
+    let modified = modified_since_merge(&state)?;
+    if !modified.is_empty() {
+        bail!(
+            "Can't undo the merge of zone {} into {:?}, since these paths have changed since \
+             it finished: {:?}",
+            state.zone_name,
+            state.target_dir,
+            modified
+        );
+    }
+    roll_back(&backup_dir, &state)?;
+    remove_dir_all(&backup_dir)?;
+    Ok(state)
+}
+
+/// Lists the names of the kept merge backups, oldest first.
+pub fn list_backups(mzr_dir: &MzrDir) -> Result<Vec<String>, Error> {
+    let backups_dir = MergeBackupsDir::new(mzr_dir);
+    if !backups_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in read_dir(&backups_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if NaiveDateTime::parse_from_str(&name, BACKUP_NAME_FORMAT).is_ok() {
+            names.push(name);
+        }
+    }
+    // The name format sorts chronologically.
+    names.sort();
+    Ok(names)
+}
+
+/// Restores the files which the merge replaced or removed from the backup
+/// dir, and removes the files and directories it created. The backup dir may
+/// be on another filesystem, for merges which have finished. Entries which
+/// are still staged weren't applied, and so are skipped. Parent directories
+/// created for new files are left in place.
+fn roll_back(dir: &Path, state: &MergeState) -> Result<(), Error> {
+    for (ix, entry) in state.entries.iter().enumerate().rev() {
+        if path_exists(&staged_path(dir, ix)) {
+            continue;
+        }
+        let target = state.target_dir.join(&entry.rel_path);
+        let backup = backup_path(dir, ix);
+        if entry.had_target {
+            move_path(&backup, &target).context(format_err!(
+                "Failed to restore {:?} from {:?}",
+                target,
+                backup
+            ))?;
+        } else {
+            remove_if_exists(&target)?;
+        }
+    }
+    for (from, to) in state.renames.iter().rev() {
+        let from = state.target_dir.join(from);
+        let to = state.target_dir.join(to);
+        if path_exists(&to) && !path_exists(&from) {
+            rename(&to, &from).context(format_err!(
+                "Failed to move {:?} back to {:?}",
+                to,
+                from
+            ))?;
+        }
+    }
+    // Directories which weren't created, or which have since gained
+    // other files, are left alone.
+    for rel_path in state.created_dirs.iter().rev() {
+        let _ = remove_dir(state.target_dir.join(rel_path));
+    }
+    for (ix, rel_path) in state.removals.iter().enumerate().rev() {
+        let target = state.target_dir.join(rel_path);
+        let backup = removed_path(dir, ix);
+        if path_exists(&backup) {
+            move_path(&backup, &target).context(format_err!(
+                "Failed to restore {:?} from {:?}",
+                target,
+                backup
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths which a finished merge wrote, and which have changed since, or
+/// which it removed, and which have been recreated since. Written paths
+/// without a recorded change time are treated as modified.
+fn modified_since_merge(state: &MergeState) -> Result<Vec<PathBuf>, Error> {
+    let mut modified = Vec::new();
+    for rel_path in written_paths(state) {
+        let ctime = symlink_metadata(state.target_dir.join(rel_path))
+            .ok()
+            .map(|metadata| (metadata.ctime(), metadata.ctime_nsec()));
+        if ctime.is_none() || ctime.as_ref() != state.written_ctimes.get(rel_path) {
+            modified.push(rel_path.clone());
+        }
+    }
+    for rel_path in state.removals.iter() {
+        if path_exists(&state.target_dir.join(rel_path)) {
+            modified.push(rel_path.clone());
+        }
+    }
+    Ok(modified)
+}
+
+/// Paths which the merge wrote, relative to the target dir.
+fn written_paths(state: &MergeState) -> impl Iterator<Item = &PathBuf> {
+    state
+        .entries
+        .iter()
+        .map(|entry| &entry.rel_path)
+        .chain(state.renames.iter().map(|(_, to)| to))
+}
+
+fn finish(mzr_dir: &MzrDir, mut state: MergeState) -> Result<MergeState, Error> {
+    let merge_dir = &MergeDir::new(mzr_dir);
+    let data_dir = &data_dir(merge_dir, &state);
+    let changes_dir = OvfsChangesDir::new(&ZoneDir::new(mzr_dir, &state.zone_name));
+    if state.phase == Phase::Staging {
+        // Staged files which were partially copied before an interruption
+        // get replaced.
+        let lower_dirs = Zone::load(mzr_dir, &state.zone_name)?.lower_dirs()?;
+        let mut copier =
+            Copier::for_layers(&changes_dir, lower_dirs).with_owner_of(&state.target_dir)?;
+        for (ix, entry) in state.entries.iter().enumerate() {
+            copier.copy(
+                &changes_dir.join(&entry.rel_path),
+                &staged_path(data_dir, ix),
+            )?;
+        }
+        state.phase = Phase::Applying;
+        write_state(merge_dir, &state)?;
+    }
+    for (ix, rel_path) in state.removals.iter().enumerate() {
+        let target = state.target_dir.join(rel_path);
+        let backup = removed_path(data_dir, ix);
+        if path_exists(&backup) || !path_exists(&target) {
+            continue;
+        }
+        rename(&target, &backup).context(format_err!(
+            "Failed to move {:?} to {:?}",
+            target,
+            backup
+        ))?;
+    }
+    let mut copier = Copier::new().with_owner_of(&state.target_dir)?;
+    for rel_path in state.created_dirs.iter() {
+        let target = state.target_dir.join(rel_path);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        copier.copy(&changes_dir.join(rel_path), &target)?;
+    }
+    // Renames which were already done are recognized by the old path no
+    // longer existing.
+    for (from, to) in state.renames.iter() {
+        let from = state.target_dir.join(from);
+        let to = state.target_dir.join(to);
+        if !path_exists(&from) {
+            continue;
+        }
+        if let Some(parent) = to.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&from, &to).context(format_err!("Failed to move {:?} to {:?}", from, to))?;
+    }
+    for (ix, entry) in state.entries.iter().enumerate() {
+        let staged = staged_path(data_dir, ix);
+        if !path_exists(&staged) {
+            continue;
+        }
+        let target = state.target_dir.join(&entry.rel_path);
+        let backup = backup_path(data_dir, ix);
+        if entry.had_target && !path_exists(&backup) {
+            // Hard-linking leaves the target in place until the rename
+            // replaces it.
+            hard_link(&target, &backup).context(format_err!(
+                "Failed to back up {:?} to {:?}",
+                target,
+                backup
+            ))?;
+        }
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&staged, &target).context(format_err!(
+            "Failed to move {:?} to {:?}",
+            staged,
+            target
+        ))?;
+    }
+    keep_backup(mzr_dir, &mut state)?;
+    Ok(state)
+}
+
+/// Moves the finished merge's record and backup dir into the
+/// `MergeBackupsDir`, named by when it finished, and removes the staging
+/// dir. This may be resumed if interrupted, so steps which were already done
+/// are skipped.
+fn keep_backup(mzr_dir: &MzrDir, state: &mut MergeState) -> Result<(), Error> {
+    let merge_dir = MergeDir::new(mzr_dir);
+    let data_dir = data_dir(&merge_dir, state);
+    let finished_time = Utc::now();
+    state.finished_time = Some(finished_time);
+    let mut written_ctimes = BTreeMap::new();
+    for rel_path in written_paths(state) {
+        if let Ok(metadata) = symlink_metadata(state.target_dir.join(rel_path)) {
+            written_ctimes.insert(rel_path.clone(), (metadata.ctime(), metadata.ctime_nsec()));
+        }
+    }
+    state.written_ctimes = written_ctimes;
+    write_state(&merge_dir, state)?;
+    remove_dir_all_if_exists(&staged_dir(&data_dir))?;
+    if data_dir != merge_dir.to_path_buf() {
+        if path_exists(&backup_dir(&data_dir)) {
+            remove_dir_all_if_exists(&backup_dir(&merge_dir))?;
+            move_path(&backup_dir(&data_dir), &backup_dir(&merge_dir))?;
+        }
+        remove_dir_all_if_exists(&data_dir)?;
+    }
+    let backups_dir = MergeBackupsDir::new(mzr_dir);
+    create_dir_all(&backups_dir)?;
+    let backup_dir = backups_dir.join(finished_time.format(BACKUP_NAME_FORMAT).to_string());
+    // Merges finishing within the same second replace the earlier backup,
+    // rather than failing after the merge has been applied.
+    if path_exists(&backup_dir) {
+        remove_dir_all(&backup_dir)?;
+    }
+    rename(&merge_dir, &backup_dir)?;
+    Ok(())
+}
+
+/// Writes the state via a rename, so that it's never partially written.
+fn write_state(merge_dir: &MergeDir, state: &MergeState) -> Result<(), Error> {
+    let state_file = MergeStateFile::new(merge_dir);
+    let tmp_file = merge_dir.join("state.json.tmp");
+    json::write(&tmp_file, state)?;
+    rename(&tmp_file, &state_file)?;
+    Ok(())
+}
+
+/// Where the merge's files are staged and backed up.
+fn data_dir(merge_dir: &MergeDir, state: &MergeState) -> PathBuf {
+    match &state.staging_dir {
+        Some(staging_dir) => staging_dir.clone(),
+        None => merge_dir.to_path_buf(),
+    }
+}
+
+fn staged_dir(dir: &Path) -> PathBuf {
+    dir.join("staged")
+}
+
+fn backup_dir(dir: &Path) -> PathBuf {
+    dir.join("backup")
+}
+
+/// Entries are staged and backed up by index, so that the directory
+/// structure of the target doesn't need to be recreated.
+fn staged_path(dir: &Path, ix: usize) -> PathBuf {
+    staged_dir(dir).join(ix.to_string())
+}
+
+fn backup_path(dir: &Path, ix: usize) -> PathBuf {
+    backup_dir(dir).join(ix.to_string())
+}
+
+/// Removed paths are moved into the backup dir whole, including directories.
+fn removed_path(dir: &Path, ix: usize) -> PathBuf {
+    backup_dir(dir).join(format!("removed-{}", ix))
+}
+
+/// Unlike `Path::exists`, this doesn't follow symlinks, which may be dangling.
+fn path_exists(path: &PathBuf) -> bool {
+    symlink_metadata(path).is_ok()
+}
+
+fn remove_dir_all_if_exists(path: &Path) -> Result<(), Error> {
+    match remove_dir_all(path) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Renames the path, or when it's on another filesystem, copies it along
+/// with its contents and then removes it. Merge backups are kept in the mzr
+/// dir, which may be on a different filesystem than the target dir.
+fn move_path(from: &Path, to: &Path) -> Result<(), Error> {
+    match rename(from, to) {
+        Err(ref err) if err.raw_os_error() == Some(libc::EXDEV) => {}
+        result => return Ok(result?),
+    }
+    let mut copier = Copier::new();
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(from)?;
+        let target = if rel_path.as_os_str().is_empty() {
+            to.to_path_buf()
+        } else {
+            to.join(rel_path)
+        };
+        copier.copy(entry.path(), &target)?;
+    }
+    merge::remove_all(&from.to_path_buf())
+}
+
+fn remove_if_exists(path: &PathBuf) -> Result<(), Error> {
+    match remove_file(path) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
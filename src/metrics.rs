@@ -0,0 +1,159 @@
+use crate::dir_size;
+use crate::paths::*;
+use crate::zone::Zone;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Counters and gauges maintained by the daemon as it handles requests.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DaemonMetrics {
+    /// Number of zones whose overlayfs has been mounted.
+    pub zones_mounted: u64,
+    /// Number of client requests handled, including failed ones.
+    pub requests_served: u64,
+    /// Number of merges of zone changes back into the work directory, as
+    /// reported by clients.
+    pub merge_operations: u64,
+    /// Number of requests which resulted in an error.
+    pub errors: u64,
+    /// Number of zone processes which the daemon has forked.
+    pub active_zone_processes: u64,
+}
+
+pub type SharedMetrics = Arc<Mutex<DaemonMetrics>>;
+
+/// Daemon metrics along with measurements that are taken when the report is
+/// requested, since they are too expensive to maintain continuously.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsReport {
+    pub daemon: DaemonMetrics,
+    /// Size in bytes allocated for each zone's changes.
+    pub zone_disk_usage: Vec<(ZoneName, u64)>,
+}
+
+impl MetricsReport {
+    pub fn gather(mzr_dir: &MzrDir, daemon: DaemonMetrics) -> Result<MetricsReport, Error> {
+        let zone_names = Zone::list_names(mzr_dir)?;
+        let changes_dirs: Vec<PathBuf> = zone_names
+            .iter()
+            .map(|zone_name| {
+                let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+                OvfsChangesDir::new(&zone_dir).to_path_buf()
+            })
+            .collect();
+        let (sizes, _total) = dir_size::measure(&changes_dirs, 1)?;
+        let zone_disk_usage = zone_names
+            .into_iter()
+            .zip(sizes.into_iter().map(|size| size.actual))
+            .collect();
+        Ok(MetricsReport {
+            daemon,
+            zone_disk_usage,
+        })
+    }
+
+    /// Renders the report in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let daemon = &self.daemon;
+        let metrics: [(&str, &str, &str, u64); 5] = [
+            (
+                "mzr_zones_mounted_total",
+                "counter",
+                "Number of zones whose overlayfs has been mounted.",
+                daemon.zones_mounted,
+            ),
+            (
+                "mzr_requests_served_total",
+                "counter",
+                "Number of client requests handled by the daemon.",
+                daemon.requests_served,
+            ),
+            (
+                "mzr_merge_operations_total",
+                "counter",
+                "Number of merges of zone changes into the work directory.",
+                daemon.merge_operations,
+            ),
+            (
+                "mzr_errors_total",
+                "counter",
+                "Number of client requests which resulted in an error.",
+                daemon.errors,
+            ),
+            (
+                "mzr_active_zone_processes",
+                "gauge",
+                "Number of zone processes forked by the daemon.",
+                daemon.active_zone_processes,
+            ),
+        ];
+        for (name, kind, help, value) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        let name = "mzr_zone_disk_usage_bytes";
+        let _ = writeln!(out, "# HELP {} Size of each zone's changes.", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (zone_name, size) in self.zone_disk_usage.iter() {
+            let label = zone_name
+                .as_str()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"");
+            let _ = writeln!(out, "{}{{zone=\"{}\"}} {}", name, label, size);
+        }
+        out
+    }
+}
+
+/// Serves the metrics over HTTP, in the Prometheus text format. Every request
+/// gets the same response, regardless of its path. Connections are handled one
+/// at a time, which is fine for a scraper polling periodically.
+pub fn serve_http(listener: TcpListener, mzr_dir: MzrDir, metrics: SharedMetrics) {
+    for stream_or_err in listener.incoming() {
+        let result: Result<(), Error> = try {
+            let stream = stream_or_err?;
+            handle_http_client(&mzr_dir, &metrics, stream)?;
+        };
+        if let Err(err) = result {
+            println!("Error while serving metrics: {}", err);
+        }
+    }
+}
+
+fn handle_http_client(
+    mzr_dir: &MzrDir,
+    metrics: &SharedMetrics,
+    mut stream: TcpStream,
+) -> Result<(), Error> {
+    // Skip over the request line and headers.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let daemon_metrics = metrics
+        .lock()
+        .map_err(|_| format_err!("Metrics lock was poisoned."))?
+        .clone();
+    let body = MetricsReport::gather(mzr_dir, daemon_metrics)?.to_prometheus();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
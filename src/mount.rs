@@ -0,0 +1,142 @@
+use failure::Error;
+use libc::{c_long, c_uint};
+use nix::mount::{mount as nix_mount, MsFlags};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+// Syscall numbers of the mount API added in Linux 5.2, which are the same on
+// all architectures, and their flags from <linux/mount.h>.
+const SYS_OPEN_TREE: c_long = 428;
+const SYS_MOVE_MOUNT: c_long = 429;
+const OPEN_TREE_CLONE: c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+
+/// Failure of a `mount(2)` call, recording everything it was called with, so
+/// that the error says exactly what was attempted.
+#[derive(Debug, Fail)]
+pub struct MountError {
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    pub fstype: Option<String>,
+    pub flags: MsFlags,
+    pub data: Option<OsString>,
+    pub err: nix::Error,
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to mount ")?;
+        match (&self.fstype, &self.source) {
+            (Some(fstype), Some(source)) => write!(f, "{} {:?}", fstype, source)?,
+            (Some(fstype), None) => write!(f, "{}", fstype)?,
+            (None, Some(source)) => write!(f, "{:?}", source)?,
+            (None, None) => write!(f, "nothing")?,
+        }
+        write!(f, " at {:?} with flags {:?}", self.target, self.flags)?;
+        if let Some(data) = &self.data {
+            write!(f, " and options {:?}", data)?;
+        }
+        write!(f, ": {}", self.err)
+    }
+}
+
+/// Calls `mount(2)`. The source of pseudo filesystems like tmpfs is just a
+/// name, which is conventionally the same as the filesystem type.
+pub fn mount(
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&OsStr>,
+) -> Result<(), MountError> {
+    nix_mount(source, target, fstype, flags, data).map_err(|err| MountError {
+        source: source.map(Path::to_path_buf),
+        target: target.to_path_buf(),
+        fstype: fstype.map(String::from),
+        flags,
+        data: data.map(OsStr::to_os_string),
+        err,
+    })
+}
+
+/// Binds the source over the target, along with the mounts within the
+/// source, which is also what's permitted within user namespaces.
+pub fn bind(source: &Path, target: &Path) -> Result<(), MountError> {
+    mount(
+        Some(source),
+        target,
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None,
+    )
+}
+
+/// Mounts an empty tmpfs at the target.
+pub fn tmpfs(target: &Path, flags: MsFlags) -> Result<(), MountError> {
+    mount(Some(Path::new("tmpfs")), target, Some("tmpfs"), flags, None)
+}
+
+/// Remounts the bind mount at the target read-only. Only the bind becomes
+/// read-only, not the filesystem it's of.
+pub fn remount_readonly(target: &Path) -> Result<(), MountError> {
+    mount(
+        None,
+        target,
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        None,
+    )
+}
+
+/// Makes a detached copy of the mount at the source, via `open_tree(2)`.
+/// Unlike a bind mount, it can be attached in a different mount namespace
+/// than the source is in, see `attach`.
+pub fn clone_detached(source: &Path) -> Result<File, Error> {
+    let source_cstr = CString::new(source.as_os_str().as_bytes())?;
+    let fd = unsafe {
+        libc::syscall(
+            SYS_OPEN_TREE,
+            libc::AT_FDCWD,
+            source_cstr.as_ptr(),
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as c_uint,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "Failed to copy the mount at {:?}, which requires Linux 5.2 or later: {}",
+            source,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Attaches a mount copied by `clone_detached` at the target, via
+/// `move_mount(2)`.
+pub fn attach(detached: &File, target: &Path) -> Result<(), Error> {
+    let empty_cstr = CString::default();
+    let target_cstr = CString::new(target.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::syscall(
+            SYS_MOVE_MOUNT,
+            detached.as_raw_fd(),
+            empty_cstr.as_ptr(),
+            libc::AT_FDCWD,
+            target_cstr.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if result != 0 {
+        bail!(
+            "Failed to attach mount at {:?}: {}",
+            target,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
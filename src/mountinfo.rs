@@ -0,0 +1,177 @@
+use crate::paths::*;
+use crate::zone::Zone;
+use failure::Error;
+use std::ffi::OsString;
+use std::fs::{canonicalize, read_to_string};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// A mount in the current mount namespace, as listed in
+/// `/proc/self/mountinfo`.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// Path within the mounted filesystem which is mounted, which differs
+    /// from `/` for bind mounts of subdirectories.
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Options of the mount, such as `noexec`.
+    pub mount_options: String,
+    pub fs_type: String,
+    pub source: String,
+    /// Options of the filesystem, such as the layers of an overlayfs.
+    pub super_options: String,
+}
+
+impl MountInfo {
+    pub fn is_overlay(&self) -> bool {
+        self.fs_type == "overlay"
+    }
+
+    pub fn has_mount_option(&self, name: &str) -> bool {
+        self.mount_options.split(',').any(|option| option == name)
+    }
+
+    /// Value of a filesystem option, such as `upperdir` for overlayfs.
+    pub fn super_option(&self, name: &str) -> Option<&str> {
+        self.super_options.split(',').find_map(|option| {
+            let mut parts = option.splitn(2, '=');
+            if parts.next() == Some(name) {
+                parts.next()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Name of the zone whose overlayfs this is, if it's one of the zones in
+    /// the mzr directory. Bind mounts of the overlayfs, like the one at the
+    /// work dir within a zone, are also recognized.
+    pub fn zone_name(&self, mzr_dir: &MzrDir) -> Option<ZoneName> {
+        if !self.is_overlay() {
+            return None;
+        }
+        let upper_dir = unescape_path(self.super_option("upperdir")?);
+        // The kernel lists the upper dir's canonical path, which differs when
+        // the mzr dir is a symlink, such as one made by `mzr init --store-at`.
+        let zones_dir = ZonesDir::new(mzr_dir);
+        let zones_dir = canonicalize(&zones_dir).unwrap_or_else(|_| zones_dir.to_path_buf());
+        let rel_path = upper_dir.strip_prefix(&zones_dir).ok()?;
+        // The upper dir is ZONE/changes.
+        if rel_path.file_name()? != "changes" {
+            return None;
+        }
+        let name = rel_path.parent()?.to_str()?;
+        ZoneName::new(name.to_string()).ok()
+    }
+}
+
+/// Lists the mounts in the current mount namespace.
+pub fn read() -> Result<Vec<MountInfo>, Error> {
+    let contents = read_to_string("/proc/self/mountinfo")?;
+    contents.lines().map(parse_line).collect()
+}
+
+/// Finds the mount at the path, if it is a mount point. If multiple mounts
+/// are stacked there, the one that is visible is yielded.
+pub fn mount_at(path: &Path) -> Result<Option<MountInfo>, Error> {
+    Ok(read()?
+        .into_iter()
+        .filter(|mount| mount.mount_point == path)
+        .last())
+}
+
+/// Finds the mount which contains the path, which should be canonical.
+pub fn containing(path: &Path) -> Result<Option<MountInfo>, Error> {
+    let mut result: Option<MountInfo> = None;
+    for mount in read()? {
+        if !path.starts_with(&mount.mount_point) {
+            continue;
+        }
+        let is_deeper = match &result {
+            Some(other) => {
+                mount.mount_point.components().count() >= other.mount_point.components().count()
+            }
+            None => true,
+        };
+        if is_deeper {
+            result = Some(mount);
+        }
+    }
+    Ok(result)
+}
+
+/// Lists the mounts whose mount points are within the directory.
+pub fn mounts_under(dir: &Path) -> Result<Vec<MountInfo>, Error> {
+    Ok(read()?
+        .into_iter()
+        .filter(|mount| mount.mount_point.starts_with(dir))
+        .collect())
+}
+
+/// Lists mounts within the mzr directory which no longer serve a purpose.
+/// Mounts made by the daemon are in its own mount namespace, so the only ones
+/// expected elsewhere are the read-only views of zones exposed by
+/// `mzr daemon --expose-zones`. These are stale when the daemon isn't running,
+/// or the zone no longer exists.
+pub fn stale_mounts(mzr_dir: &MzrDir, daemon_running: bool) -> Result<Vec<MountInfo>, Error> {
+    let mounts = mounts_under(mzr_dir)?;
+    if !daemon_running {
+        return Ok(mounts);
+    }
+    let exposed_dirs: Vec<PathBuf> = Zone::list_names(mzr_dir)?
+        .iter()
+        .map(|zone_name| ExposedZoneDir::new(mzr_dir, zone_name).to_path_buf())
+        .collect();
+    Ok(mounts
+        .into_iter()
+        .filter(|mount| !exposed_dirs.contains(&mount.mount_point))
+        .collect())
+}
+
+/// Parses a line of mountinfo, which is documented in `proc(5)`. There are a
+/// variable number of optional fields, which are terminated by a `-` field.
+fn parse_line(line: &str) -> Result<MountInfo, Error> {
+    let mut halves = line.splitn(2, " - ");
+    let mount_fields: Vec<&str> = halves.next().unwrap_or("").split(' ').collect();
+    let fs_fields: Vec<&str> = match halves.next() {
+        Some(rest) => rest.split(' ').collect(),
+        None => bail!("Unexpected line in mountinfo: {:?}", line),
+    };
+    if mount_fields.len() < 6 || fs_fields.len() < 3 {
+        bail!("Unexpected line in mountinfo: {:?}", line);
+    }
+    Ok(MountInfo {
+        root: unescape_path(mount_fields[3]),
+        mount_point: unescape_path(mount_fields[4]),
+        mount_options: mount_fields[5].to_string(),
+        fs_type: fs_fields[0].to_string(),
+        source: fs_fields[1].to_string(),
+        super_options: fs_fields[2].to_string(),
+    })
+}
+
+/// Paths in mountinfo have spaces, tabs, newlines and backslashes escaped as
+/// octal.
+fn unescape_path(escaped: &str) -> PathBuf {
+    let bytes = escaped.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut ix = 0;
+    while ix < bytes.len() {
+        let octal = bytes.get(ix + 1..ix + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match octal {
+            Some(byte) if bytes[ix] == b'\\' => {
+                result.push(byte);
+                ix += 4;
+            }
+            _ => {
+                result.push(bytes[ix]);
+                ix += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(result))
+}
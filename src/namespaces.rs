@@ -1,24 +1,24 @@
 use crate::colors::*;
+use crate::ipc::{self, Channel};
 use crate::paths::*;
 use crate::utils::parse_pid_file;
 use failure::{Error, ResultExt};
-use ipc_channel::ipc::{self, IpcOneShotServer, IpcReceiver, IpcSender};
+use libc::pid_t;
 use nix::errno::Errno;
 use nix::sched::{setns, unshare, CloneFlags};
-use nix::sys::wait::{waitpid, WaitStatus::*};
-use nix::unistd::{Gid, Pid, Uid};
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus::*};
+use nix::unistd::{close, fork, getpid, ForkResult, Gid, Pid, Uid};
 use nix::Error::Sys;
-use serde::{Deserialize, Serialize};
 use std::boxed::Box;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::os::unix::io::IntoRawFd;
+use std::fs::{read_dir, read_link, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::process::exit;
+use std::sync::atomic::{AtomicIsize, Ordering};
 use std::{thread, time};
 use yansi::Paint;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Ready;
-
 // TODO(cleanup): Seems to me like from the glibc docs of clone, a
 // stack for the child should only be necessary if CLONE_VM is set.
 // Also, 1mb is certainly overkill.
@@ -50,7 +50,37 @@ where
     Ok(child_pid)
 }
 
-pub fn with_unshared_user_and_mount<F, G>(
+pub fn with_unshared_user_and_mount<F, G>(write_maps_fn: F, child_fn: G) -> Result<Pid, Error>
+where
+    F: FnMut(Pid) -> Result<(), Error>,
+    G: FnMut() -> Result<(), Error>,
+{
+    with_unshared_user(
+        CloneFlags::CLONE_NEWNS,
+        "Error while cloning mzr child with unshared user and mount namespaces.",
+        write_maps_fn,
+        child_fn,
+    )
+}
+
+/// Like `with_unshared_user_and_mount`, but the child is also the init
+/// process of a new PID namespace, see `run_init`.
+pub fn with_unshared_user_mount_and_pid<F, G>(write_maps_fn: F, child_fn: G) -> Result<Pid, Error>
+where
+    F: FnMut(Pid) -> Result<(), Error>,
+    G: FnMut() -> Result<(), Error>,
+{
+    with_unshared_user(
+        CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID,
+        "Error while cloning mzr child with unshared user, mount, and PID namespaces.",
+        write_maps_fn,
+        child_fn,
+    )
+}
+
+fn with_unshared_user<F, G>(
+    other_flags: CloneFlags,
+    error_context: &'static str,
     mut write_maps_fn: F,
     mut child_fn: G,
 ) -> Result<Pid, Error>
@@ -58,10 +88,16 @@ where
     F: FnMut(Pid) -> Result<(), Error>,
     G: FnMut() -> Result<(), Error>,
 {
-    // clone with unshared mount and user namespaces.
-    let clone_flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER;
+    // clone with unshared user namespace, along with the other namespaces.
+    let clone_flags = CloneFlags::CLONE_NEWUSER | other_flags;
     let child_stack: &mut [u8; STACK_SIZE] = &mut [0; STACK_SIZE];
-    let (parent_server, parent_name) = init_ipc()?;
+    // The parent uses the channel to tell the child that it can proceed.
+    // Each process closes the end it doesn't use, so that the child sees the
+    // connection close if the parent fails or exits before sending the
+    // message.
+    let (mut parent_channel, mut child_channel) =
+        Channel::pair().context("Failed to create channel for child process.")?;
+    let parent_fd = parent_channel.get_ref().as_raw_fd();
     let child_pid = ::nix::sched::clone(
         Box::new(|| {
             // Wait for ready message that UID mapping has been setup before
@@ -69,7 +105,14 @@ where
             // child process attempts to exec before the UID mapping has been
             // setup, then the child will lose its capabilities (see
             // "capabilities(7)" man page).
-            match recv_ready(&parent_name).and(child_fn()) {
+            let _ = close(parent_fd);
+            let ready: Result<(), Error> = match child_channel.recv::<()>() {
+                Err(ref err) if ipc::is_closed(err) => Err(format_err!(
+                    "Parent process exited or failed before the child could proceed."
+                )),
+                result => result,
+            };
+            match ready.and_then(|()| child_fn()) {
                 // Exited successfully.
                 Ok(()) => 0,
                 Err(err) => {
@@ -83,45 +126,17 @@ where
         clone_flags,
         None,
     )
-    .context("Error while cloning mzr child with unshared user and mount namespaces.")?;
+    .context(error_context)?;
+    drop(child_channel);
+    // If setting up the maps fails, the channel gets closed without sending
+    // the ready message, so the child exits with an error.
     write_maps_fn(child_pid)?;
-    send_ready(parent_server)?;
+    parent_channel
+        .send(&())
+        .context("Failed to write ready message to child process.")?;
     Ok(child_pid)
 }
 
-// IPC helper functions
-
-fn init_ipc() -> Result<(IpcOneShotServer<IpcSender<Ready>>, String), Error> {
-    wrap_ipc(IpcOneShotServer::new().map_err(|x| x.into()))
-}
-
-// TODO(cleanup): Made up this idiom of using an argumentless closure to still
-// use the "?" error plumbing, while having a helper that modifies the error
-// contents.  Is there a cleaner way to do something like this?
-
-fn send_ready(parent_server: IpcOneShotServer<IpcSender<Ready>>) -> Result<(), Error> {
-    wrap_ipc({
-        let (_, tx1): (_, IpcSender<Ready>) = parent_server.accept()?;
-        tx1.send(Ready)?;
-        Ok(())
-    })
-}
-
-fn recv_ready(parent_name: &str) -> Result<(), Error> {
-    wrap_ipc({
-        // Establish a connection with the parent.
-        let (tx1, rx1): (IpcSender<Ready>, IpcReceiver<Ready>) = ipc::channel()?;
-        let tx0 = IpcSender::connect(parent_name.to_string())?;
-        tx0.send(tx1)?;
-        let Ready = rx1.recv()?;
-        Ok(())
-    })
-}
-
-fn wrap_ipc<T>(x: Result<T, Error>) -> Result<T, Error> {
-    Ok(x.context("Error encountered in interprocess communication mechanism.")?)
-}
-
 pub fn map_user_to_root(child_process: Pid, user: Uid, group: Gid) -> Result<(), Error> {
     let root_user = Uid::from_raw(0);
     let root_group = Gid::from_raw(0);
@@ -180,6 +195,58 @@ pub fn unshare_mount() -> Result<(), Error> {
     Ok(())
 }
 
+/// Unshares the network namespace, so that the current process and its
+/// children have no network access. The new namespace only has a loopback
+/// interface, which starts out down unless `loopback` is set.
+pub fn unshare_network(loopback: bool) -> Result<(), Error> {
+    unshare(CloneFlags::CLONE_NEWNET).context("Failed to unshare network namespace.")?;
+    if loopback {
+        bring_up_loopback().context("Failed to bring up loopback interface.")?;
+    }
+    Ok(())
+}
+
+// Constants from <linux/sockios.h> and <net/if.h>.
+const SIOCGIFFLAGS: libc::c_ulong = 0x8913;
+const SIOCSIFFLAGS: libc::c_ulong = 0x8914;
+const IFF_UP: libc::c_short = 0x1;
+const IFNAMSIZ: usize = 16;
+
+/// The variant of `struct ifreq` used to get and set interface flags.
+#[repr(C)]
+struct IfReqFlags {
+    name: [u8; IFNAMSIZ],
+    flags: libc::c_short,
+    // Pads to the size of the union in `struct ifreq`.
+    _padding: [u8; 22],
+}
+
+fn bring_up_loopback() -> Result<(), Error> {
+    let mut request = IfReqFlags {
+        name: [0; IFNAMSIZ],
+        flags: 0,
+        _padding: [0; 22],
+    };
+    request.name[..2].copy_from_slice(b"lo");
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            Err(io::Error::last_os_error())?;
+        }
+        let mut result = libc::ioctl(sock, SIOCGIFFLAGS as _, &mut request as *mut IfReqFlags);
+        if result == 0 {
+            request.flags |= IFF_UP;
+            result = libc::ioctl(sock, SIOCSIFFLAGS as _, &mut request as *mut IfReqFlags);
+        }
+        let err = io::Error::last_os_error();
+        libc::close(sock);
+        if result != 0 {
+            Err(err)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn enter_mount(pid: Pid) -> Result<(), Error> {
     let proc_dir = ProcDir::new(pid);
     enter_ns(
@@ -200,6 +267,160 @@ pub fn enter_user_and_mount(pid: Pid) -> Result<(), Error> {
     )
 }
 
+/// Runs the function within the mount namespace of the process, and then
+/// returns to the current one. Mounts made there stay there, but detached
+/// copies of mounts, see `mount::clone_detached`, can be brought back.
+pub fn in_mount_of<T, F>(pid: Pid, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let own_ns_file = File::open(ProcNamespaceFile::new_mount(&ProcDir::new(getpid())))?;
+    enter_mount(pid)?;
+    let result = f();
+    setns(own_ns_file.as_raw_fd(), CloneFlags::CLONE_NEWNS)
+        .context("Failed to return to the original mount namespace")?;
+    result
+}
+
+/// Enters the PID namespace of the process. Only children created afterwards
+/// are within the namespace, see `continue_in_child`.
+pub fn enter_pid(pid: Pid) -> Result<(), Error> {
+    let proc_dir = ProcDir::new(pid);
+    enter_ns(
+        &ProcNamespaceFile::new_pid(&proc_dir),
+        CloneFlags::CLONE_NEWPID,
+    )
+}
+
+/// Lists the processes within the PID namespace whose init process is
+/// `init_pid`, other than the init process itself.
+pub fn pid_namespace_members(init_pid: Pid) -> Result<Vec<Pid>, Error> {
+    let ns_link = |pid: &str| read_link(format!("/proc/{}/ns/pid", pid));
+    let init_ns = ns_link(&init_pid.to_string()).context(format_err!(
+        "Failed to read PID namespace of process {}",
+        init_pid
+    ))?;
+    let mut members = Vec::new();
+    for entry in read_dir("/proc")? {
+        let name = entry?.file_name();
+        let pid = match name.to_str().and_then(|name| name.parse::<pid_t>().ok()) {
+            Some(pid) if pid != pid_t::from(init_pid) => pid,
+            _ => continue,
+        };
+        // Processes may exit while listing, or belong to other users.
+        if let Ok(ns) = ns_link(&pid.to_string()) {
+            if ns == init_ns {
+                members.push(Pid::from_raw(pid));
+            }
+        }
+    }
+    members.sort_by_key(|pid| pid_t::from(*pid));
+    Ok(members)
+}
+
+/// How long init waits for processes to exit after forwarding a termination
+/// signal to them. Once init exits, the kernel kills any that remain.
+const INIT_GRACE_PERIOD: time::Duration = time::Duration::from_secs(2);
+
+/// Acts as the init process of a PID namespace, never returning unless
+/// there's an error. Processes in the namespace that get orphaned are
+/// reparented to init, so it reaps them. Termination signals are forwarded to
+/// all other processes in the namespace, after which init exits once they're
+/// gone.
+pub fn run_init() -> Result<(), Error> {
+    let mut signals = SigSet::empty();
+    for signal in &[
+        Signal::SIGCHLD,
+        Signal::SIGTERM,
+        Signal::SIGINT,
+        Signal::SIGHUP,
+    ] {
+        signals.add(*signal);
+    }
+    signals.thread_block()?;
+    loop {
+        let signal = signals.wait()?;
+        reap_children()?;
+        if signal != Signal::SIGCHLD {
+            // Within a PID namespace, pid -1 refers to every process in the
+            // namespace other than init.
+            let _ = kill(Pid::from_raw(-1), signal);
+            let deadline = time::Instant::now() + INIT_GRACE_PERIOD;
+            while time::Instant::now() < deadline {
+                reap_children()?;
+                if let Err(Sys(Errno::ESRCH)) = kill(Pid::from_raw(-1), None) {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(50));
+            }
+            exit(0);
+        }
+    }
+}
+
+fn reap_children() -> Result<(), Error> {
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(StillAlive) | Err(Sys(Errno::ECHILD)) => return Ok(()),
+            Ok(_) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// After entering a PID namespace, only children of the process are within
+/// it. This forks, returning in the child, while the parent waits for the
+/// child and exits with its status. Termination signals received by the
+/// parent are forwarded to the child, and terminal interrupts are left for
+/// the child to handle.
+pub fn continue_in_child() -> Result<(), Error> {
+    continue_in_child_then(|| {})
+}
+
+/// Like `continue_in_child`, but the parent runs `before_exit` after the
+/// child exits.
+pub fn continue_in_child_then<F: FnOnce()>(before_exit: F) -> Result<(), Error> {
+    match fork()? {
+        ForkResult::Child => Ok(()),
+        ForkResult::Parent { child } => {
+            FORWARD_SIGNALS_TO.store(pid_t::from(child) as isize, Ordering::SeqCst);
+            let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+            let forward = SigAction::new(
+                SigHandler::Handler(forward_signal),
+                SaFlags::SA_RESTART,
+                SigSet::empty(),
+            );
+            unsafe {
+                sigaction(Signal::SIGINT, &ignore)?;
+                sigaction(Signal::SIGQUIT, &ignore)?;
+                sigaction(Signal::SIGTERM, &forward)?;
+                sigaction(Signal::SIGHUP, &forward)?;
+            }
+            let code = loop {
+                match waitpid(child, None) {
+                    Ok(Exited(_, code)) => break code,
+                    Ok(Signaled(_, signal, _)) => break 128 + signal as i32,
+                    Ok(_) | Err(Sys(Errno::EINTR)) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            };
+            before_exit();
+            exit(code)
+        }
+    }
+}
+
+static FORWARD_SIGNALS_TO: AtomicIsize = AtomicIsize::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = FORWARD_SIGNALS_TO.load(Ordering::SeqCst) as pid_t;
+    if pid != 0 {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
 fn enter_ns(ns_path: &ProcNamespaceFile, flags: CloneFlags) -> Result<(), Error> {
     // TODO(cleanup): make daemon_cmd a constant.
     let daemon_cmd_str = String::from("mzr daemon");
@@ -0,0 +1,232 @@
+use crate::copier::{self, Copier};
+use crate::paths::*;
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, hard_link, remove_file, rename, symlink_metadata, Metadata};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use walkdir::WalkDir;
+
+#[derive(Debug, Default)]
+pub struct DedupeStats {
+    /// Number of files which are now hardlinks into the store, or reflinks
+    /// of other files.
+    pub linked_files: usize,
+    /// Number of bytes which were already in the store or another file, and
+    /// so are no longer stored separately.
+    pub saved_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub removed_objects: usize,
+    pub removed_bytes: u64,
+}
+
+/// Replaces each regular file in the snapshot with a hardlink into the
+/// objects store, adding it to the store if it isn't already there.
+///
+/// Since hardlinks share metadata, the key for an object includes the file's
+/// mode and modification time in addition to its content hash. This way
+/// deduplication never changes the metadata of snapshot files, which is
+/// important since merging relies on it. Files that are already hardlinked
+/// are left alone.
+///
+/// Hardlinks also share any later changes to metadata, so anything which
+/// changes the metadata of snapshot files in place must `unshare` them first.
+/// For the same reason, `immutable::set_tree` doesn't set the immutable
+/// attribute of hardlinked files.
+pub fn dedupe_snapshot(mzr_dir: &MzrDir, snap_dir: &SnapDir) -> Result<DedupeStats, Error> {
+    let objects_dir = ObjectsDir::new(mzr_dir);
+    let mut candidates: Vec<(PathBuf, Metadata)> = Vec::new();
+    for entry in WalkDir::new(snap_dir).same_file_system(true) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        // Paths are passed to git one per line, so file names with newlines
+        // are skipped.
+        let has_newline = entry.path().as_os_str().as_bytes().contains(&b'\n');
+        if metadata.is_file() && metadata.nlink() == 1 && !has_newline {
+            candidates.push((entry.path().to_path_buf(), metadata));
+        }
+    }
+    let hashes = hash_files(candidates.iter().map(|(path, _)| path.clone()).collect())?;
+    let mut stats = DedupeStats::default();
+    for ((path, metadata), hash) in candidates.iter().zip(hashes.iter()) {
+        let object = object_path(&objects_dir, hash, metadata);
+        if object.exists() {
+            // Link to a temporary path and then rename over the snapshot
+            // file, so that the snapshot file is never missing.
+            let tmp_path = path.with_file_name(".mzr-dedupe-tmp");
+            hard_link(&object, &tmp_path)?;
+            rename(&tmp_path, path)
+                .context(format_err!("Failed to replace {:?} with a hardlink", path))?;
+            stats.saved_bytes += metadata.len();
+        } else {
+            if let Some(parent) = object.parent() {
+                create_dir_all(parent)?;
+            }
+            hard_link(path, &object)
+                .context(format_err!("Failed to add {:?} to the objects store", path))?;
+        }
+        stats.linked_files += 1;
+    }
+    Ok(stats)
+}
+
+/// Replaces a hardlinked file with a copy of its own, so that changing its
+/// metadata doesn't also change that of its object, and of every other
+/// snapshot linking to it. The contents are reflinked when the filesystem
+/// supports it. Yields `false` if the file has no other links.
+pub fn unshare(path: &Path) -> Result<bool, Error> {
+    let metadata = symlink_metadata(path)?;
+    if !metadata.is_file() || metadata.nlink() == 1 {
+        return Ok(false);
+    }
+    // Copied to a temporary path and then renamed over the file, like in
+    // `dedupe_snapshot`, so that the file is never missing.
+    let tmp_path = path.with_file_name(".mzr-unshare-tmp");
+    Copier::new().copy(path, &tmp_path)?;
+    rename(&tmp_path, path).context(format_err!("Failed to replace {:?} with a copy", path))?;
+    Ok(true)
+}
+
+/// Makes files with identical contents across the snapshots share their
+/// extents via reflinks, rather than hardlinking them into the objects store.
+/// Each file keeps its own metadata, so files which differ only in their
+/// mode or modification time are also shared. This requires a filesystem
+/// which supports reflinks, such as Btrfs or XFS.
+///
+/// Files which are already hardlinked, such as those in the objects store,
+/// are left alone, as are read-only files. Files which already share
+/// extents can't be distinguished, so `saved_bytes` is an upper bound.
+pub fn reflink_snapshots(snap_dirs: &[SnapDir]) -> Result<DedupeStats, Error> {
+    let mut candidates: Vec<(PathBuf, Metadata)> = Vec::new();
+    for snap_dir in snap_dirs {
+        for entry in WalkDir::new(snap_dir).same_file_system(true) {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            // See the comment on this in `dedupe_snapshot`.
+            let has_newline = entry.path().as_os_str().as_bytes().contains(&b'\n');
+            if metadata.is_file()
+                && metadata.nlink() == 1
+                && metadata.len() > 0
+                && !metadata.permissions().readonly()
+                && !has_newline
+            {
+                candidates.push((entry.path().to_path_buf(), metadata));
+            }
+        }
+    }
+    let hashes = hash_files(candidates.iter().map(|(path, _)| path.clone()).collect())?;
+    let mut sources: HashMap<&str, &PathBuf> = HashMap::new();
+    let mut stats = DedupeStats::default();
+    for ((path, metadata), hash) in candidates.iter().zip(hashes.iter()) {
+        // The first file with each hash is the one others share extents with.
+        let source = *sources.entry(hash.as_str()).or_insert(path);
+        if source == path {
+            continue;
+        }
+        let reflinked = copier::reflink_over(source, path, metadata).context(format_err!(
+            "Failed to reflink {:?} to {:?}",
+            source,
+            path
+        ))?;
+        if !reflinked {
+            bail!(
+                "The filesystem of {:?} doesn't support reflinks, so instead use the objects \
+                 store.",
+                path
+            );
+        }
+        stats.linked_files += 1;
+        stats.saved_bytes += metadata.len();
+    }
+    Ok(stats)
+}
+
+/// Removes objects which are no longer referenced by any snapshot, which is
+/// the case when the object file is its only link.
+pub fn gc(mzr_dir: &MzrDir) -> Result<GcStats, Error> {
+    let objects_dir = ObjectsDir::new(mzr_dir);
+    let mut stats = GcStats::default();
+    if !objects_dir.is_dir() {
+        return Ok(stats);
+    }
+    for entry in WalkDir::new(&objects_dir) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.nlink() == 1 {
+            remove_file(entry.path())?;
+            stats.removed_objects += 1;
+            stats.removed_bytes += metadata.len();
+        }
+    }
+    Ok(stats)
+}
+
+fn object_path(objects_dir: &ObjectsDir, hash: &str, metadata: &Metadata) -> PathBuf {
+    let (prefix, rest) = hash.split_at(2);
+    objects_dir.join(prefix).join(format!(
+        "{}-{:o}-{}.{}",
+        rest,
+        metadata.mode(),
+        metadata.mtime(),
+        metadata.mtime_nsec()
+    ))
+}
+
+/// Hashes the contents of the files, using `git hash-object`. This yields one
+/// hash per path, in the same order.
+pub fn hash_files(paths: Vec<PathBuf>) -> Result<Vec<String>, Error> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut child = Command::new("git")
+        .arg("hash-object")
+        .arg("--no-filters")
+        .arg("--stdin-paths")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run git hash-object. Is git on your PATH?")?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| format_err!("Unexpected error: git hash-object has no stdin."))?;
+    let path_count = paths.len();
+    // Paths are written from another thread, so that git's output gets read
+    // concurrently, avoiding a deadlock when pipe buffers fill up.
+    let writer = thread::spawn(move || -> Result<(), Error> {
+        for path in paths {
+            stdin.write_all(path.as_os_str().as_bytes())?;
+            stdin.write_all(b"\n")?;
+        }
+        Ok(())
+    });
+    let output = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| format_err!("Thread writing paths to git hash-object panicked."))??;
+    if !output.status.success() {
+        bail!(
+            "git hash-object exited with failure status {}",
+            output.status
+        );
+    }
+    let hashes: Vec<String> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+    if hashes.len() != path_count {
+        bail!(
+            "Expected {} hashes from git hash-object, but got {}",
+            path_count,
+            hashes.len()
+        );
+    }
+    Ok(hashes)
+}
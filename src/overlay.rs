@@ -0,0 +1,205 @@
+use crate::copier::read_xattr;
+use failure::Error;
+use nix::mount::MsFlags;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fs::{read_to_string, symlink_metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Optional overlayfs features to enable when mounting zones, which reduce
+/// the cost of copying up files. They're only used when the kernel supports
+/// them, see `usable_features`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OverlayOptions {
+    /// Index copied up files by their lower inode, so that copying up one
+    /// link of a hardlinked file doesn't break the link.
+    #[serde(default)]
+    pub index: bool,
+    /// Only copy up the metadata of files whose metadata changes, such as
+    /// via `chmod` or `touch`, leaving their data in the lower layer.
+    #[serde(default)]
+    pub metacopy: bool,
+    /// Rename directories from lower layers by recording where they came
+    /// from, rather than failing with `EXDEV`, which makes tools fall back
+    /// on copying the whole directory.
+    #[serde(default)]
+    pub redirect_dir: bool,
+}
+
+impl OverlayOptions {
+    /// Names of the enabled features, which are also the names of the mount
+    /// options and of the overlay module's parameters.
+    pub fn enabled(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if self.index {
+            features.push("index");
+        }
+        if self.metacopy {
+            features.push("metacopy");
+        }
+        if self.redirect_dir {
+            features.push("redirect_dir");
+        }
+        features
+    }
+}
+
+/// Whether the running kernel's overlayfs has the feature, which is the case
+/// when the overlay module has a parameter for it.
+pub fn kernel_supports(feature: &str) -> bool {
+    Path::new("/sys/module/overlay/parameters")
+        .join(feature)
+        .exists()
+}
+
+/// Whether the current process is in the initial user namespace. Overlays
+/// mounted within other user namespaces store their bookkeeping in `user.`
+/// xattrs, and the kernel doesn't allow `metacopy` or `redirect_dir` for
+/// them, since their xattrs could be forged by the user.
+pub fn in_initial_user_namespace() -> bool {
+    match read_to_string("/proc/self/uid_map") {
+        Ok(uid_map) => uid_map.split_whitespace().collect::<Vec<_>>() == ["0", "0", "4294967295"],
+        Err(_) => false,
+    }
+}
+
+/// Splits the enabled features into those which can be used when mounting
+/// from the current process, and those which can't, along with why.
+pub fn usable_features(
+    options: &OverlayOptions,
+) -> (Vec<&'static str>, Vec<(&'static str, String)>) {
+    let mut usable = Vec::new();
+    let mut unusable = Vec::new();
+    for feature in options.enabled() {
+        if !kernel_supports(feature) {
+            unusable.push((
+                feature,
+                String::from("the kernel's overlayfs doesn't support it"),
+            ));
+        } else if feature != "index" && !in_initial_user_namespace() {
+            unusable.push((
+                feature,
+                String::from("it requires mounting as root, outside of a user namespace"),
+            ));
+        } else {
+            usable.push(feature);
+        }
+    }
+    (usable, unusable)
+}
+
+/// Prefixes of the xattrs which overlayfs uses for its bookkeeping, see
+/// `copier::OVERLAY_XATTR_PREFIXES`.
+const XATTR_PREFIXES: &[&str] = &["trusted.overlay.", "user.overlay."];
+
+fn read_overlay_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    XATTR_PREFIXES
+        .iter()
+        .filter_map(|prefix| read_xattr(path, &format!("{}{}", prefix, name)).ok())
+        .next()
+}
+
+/// Whether the file in the changes dir is a metadata-only copy-up, whose data
+/// is still in a lower layer.
+pub fn is_metacopy(path: &Path) -> bool {
+    read_overlay_xattr(path, "metacopy").is_some()
+}
+
+/// Where a renamed directory or metadata-only file in the changes dir came
+/// from, relative to the root of the layers. Overlayfs records either an
+/// absolute path from the root, or just a name within the same parent.
+pub fn redirect(rel_path: &Path, changes_dir: &Path) -> Option<PathBuf> {
+    let value = read_overlay_xattr(&changes_dir.join(rel_path), "redirect")?;
+    let origin = Path::new(OsStr::from_bytes(&value));
+    if origin.is_absolute() {
+        origin.strip_prefix("/").ok().map(PathBuf::from)
+    } else {
+        Some(rel_path.with_file_name(origin))
+    }
+}
+
+/// Finds the data of a metadata-only copy-up within the lower dirs, following
+/// redirects of the file and of the directories containing it.
+pub fn metacopy_data_path(
+    changes_dir: &Path,
+    lower_dirs: &[PathBuf],
+    rel_path: &Path,
+) -> Option<PathBuf> {
+    // The innermost redirect determines the origin, with the remainder of
+    // the path appended to it.
+    let origin = rel_path
+        .ancestors()
+        .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+        .filter_map(|ancestor| {
+            let redirect = redirect(ancestor, changes_dir)?;
+            match rel_path.strip_prefix(ancestor).ok()? {
+                rest if rest.as_os_str().is_empty() => Some(redirect),
+                rest => Some(redirect.join(rest)),
+            }
+        })
+        .next()
+        .unwrap_or_else(|| rel_path.to_path_buf());
+    lower_dirs
+        .iter()
+        .map(|lower_dir| lower_dir.join(&origin))
+        .find(|path| symlink_metadata(path).is_ok())
+}
+
+/// Mounts an overlay with the features enabled. Without an upper dir, the
+/// overlay is read-only, and the features only affect how the lower layers
+/// are read.
+pub fn mount(
+    lower_dirs: &[PathBuf],
+    upper: Option<(&Path, &Path)>,
+    target: &Path,
+    features: &[&str],
+) -> Result<(), Error> {
+    let mut data = OsString::from("lowerdir=");
+    for (ix, lower_dir) in lower_dirs.iter().enumerate() {
+        if ix > 0 {
+            data.push(":");
+        }
+        data.push(option_path(lower_dir)?);
+    }
+    if let Some((upper_dir, work_dir)) = upper {
+        data.push(",upperdir=");
+        data.push(option_path(upper_dir)?);
+        data.push(",workdir=");
+        data.push(option_path(work_dir)?);
+    }
+    for feature in features {
+        match (*feature, upper.is_some()) {
+            // Lower layers which have redirects only need them followed.
+            ("redirect_dir", false) => data.push(",redirect_dir=follow"),
+            ("index", false) => {}
+            (feature, _) => data.push(format!(",{}=on", feature)),
+        }
+    }
+    let flags = if upper.is_some() {
+        MsFlags::empty()
+    } else {
+        MsFlags::MS_RDONLY
+    };
+    crate::mount::mount(
+        Some(Path::new("overlay")),
+        target,
+        Some("overlay"),
+        flags,
+        Some(data.as_os_str()),
+    )?;
+    Ok(())
+}
+
+/// Paths within overlayfs options are separated by `:` and `,`, which aren't
+/// escaped here.
+fn option_path(path: &Path) -> Result<&OsStr, Error> {
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.contains(&b':') || bytes.contains(&b',') {
+        bail!(
+            "Can't mount overlay with {:?}, since its path contains ':' or ','.",
+            path
+        );
+    }
+    Ok(path.as_os_str())
+}
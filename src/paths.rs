@@ -1,4 +1,5 @@
 use crate::colors::*;
+use crate::shared;
 use crate::utils::add_suffix_to_path;
 use failure::Error;
 use nix::libc::pid_t;
@@ -21,6 +22,17 @@ pub struct MzrDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct UserWorkDir(PathBuf);
 
+/// Path to the directory containing all zones - typically something like
+/// `.../PROJECT.mzr/zone`, or `.../PROJECT.mzr/zone/USER` when the mzr
+/// directory is shared.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZonesDir(PathBuf);
+
+/// Path to the directory containing all snapshots - typically something like
+/// `.../PROJECT.mzr/snap`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapsDir(PathBuf);
+
 /// Path to the zone directory within the mzr directory - typically something
 /// like `.../PROJECT.mzr/zone/ZONE`.
 #[derive(Debug, Clone, Shrinkwrap)]
@@ -31,11 +43,140 @@ pub struct ZoneDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct ZoneInfoFile(PathBuf);
 
+/// Path to the record of a `mzr run` invocation within its temporary zone -
+/// typically something like `.../PROJECT.mzr/zone/ZONE/run.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct RunInfoFile(PathBuf);
+
+/// Path to the cached statistics of a zone's changes, see `ZoneStats` -
+/// typically something like `.../PROJECT.mzr/zone/ZONE/stats.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZoneStatsFile(PathBuf);
+
+/// Path to the record of how a zone's processes were frozen by
+/// `mzr zone freeze`, which exists while they are frozen - typically
+/// something like `.../PROJECT.mzr/zone/ZONE/frozen.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZoneFrozenFile(PathBuf);
+
+/// Path to the journal of paths changed within a zone, maintained by the
+/// daemon while the zone is mounted - typically something like
+/// `.../PROJECT.mzr/zone/ZONE/journal`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ChangeJournalFile(PathBuf);
+
+/// Path to the zone's own versions of directories within the user's home
+/// directory, which are bound over them within the zone, see
+/// `ZoneInfo::isolated_home_dirs` - typically something like
+/// `.../PROJECT.mzr/zone/ZONE/home`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZoneHomeDir(PathBuf);
+
+/// Path to the directory containing a zone's checkpoints, which are copies of
+/// its changes made periodically by the daemon - typically something like
+/// `.../PROJECT.mzr/zone/ZONE/checkpoints`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZoneCheckpointsDir(PathBuf);
+
 /// Path to snapshot directory - typically something like
 /// `.../PROJECT.mzr/snap/SNAP`.
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct SnapDir(PathBuf);
 
+/// Path to the directory containing snapshot info files - typically something
+/// like `.../PROJECT.mzr/snap-info`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapInfosDir(PathBuf);
+
+/// Path to the snapshot info file - typically something like
+/// `.../PROJECT.mzr/snap-info/SNAP.json`. This is stored outside of the
+/// snapshot directory, since that is used as the overlayfs lower dir.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapInfoFile(PathBuf);
+
+/// Path to the hashes of a snapshot's files, recorded when it's taken so that
+/// it can be checked by `mzr snap verify` - typically something like
+/// `.../PROJECT.mzr/snap-info/SNAP.manifest.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapManifestFile(PathBuf);
+
+/// Path to the content-addressed store of snapshot file contents - typically
+/// something like `.../PROJECT.mzr/objects`. Snapshot files are hardlinks to
+/// files in this store, so that identical files are only stored once.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ObjectsDir(PathBuf);
+
+/// Path to the retention policy used by `mzr gc --auto` - typically something
+/// like `.../PROJECT.mzr/retention.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct RetentionPolicyFile(PathBuf);
+
+/// Path to the record that the mzr directory is shared between the users of
+/// a group - typically something like `.../PROJECT.mzr/shared.json`. See
+/// `shared::SharedInfo`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SharedFile(PathBuf);
+
+/// Path to the mzr directory's configuration - typically something like
+/// `.../PROJECT.mzr/config.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ConfigFile(PathBuf);
+
+/// Path to the append-only log of operations on the mzr directory, with one
+/// JSON event per line - typically something like
+/// `.../PROJECT.mzr/events.log`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct EventsLogFile(PathBuf);
+
+/// Path to the directory of scripts run at points in the lifecycle of zones,
+/// snapshots, and merges - typically something like
+/// `.../PROJECT.mzr/hooks`. Each script is named after its hook.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct HooksDir(PathBuf);
+
+/// Path to the directory whose contents are copied into the changes of each
+/// new zone - typically something like `.../PROJECT.mzr/template`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZoneTemplateDir(PathBuf);
+
+/// Path to the directory holding caches which are shared by all zones, being
+/// bound into each of them - typically something like
+/// `.../PROJECT.mzr/shared-cache`. See `Config::shared_cache_dirs`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SharedCacheDir(PathBuf);
+
+/// Path to a temporary directory within the mzr directory - typically
+/// something like `.../PROJECT.mzr/tmp/NAME`. Being on the same filesystem as
+/// the rest of the mzr directory allows its contents to be renamed into place.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct MzrTmpDir(PathBuf);
+
+/// Path to the directory which holds the state of a merge while it is being
+/// applied - typically something like `.../PROJECT.mzr/merge`. The files
+/// themselves are staged in the `MergeStagingDir`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct MergeDir(PathBuf);
+
+/// Path to the directory within the target dir of a merge where files are
+/// staged, and where the files it replaces or removes are backed up, while
+/// it is being applied - typically something like
+/// `.../PROJECT/.mzr-merge-staging`. Being on the target's filesystem allows
+/// them to be renamed into and out of place.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct MergeStagingDir(PathBuf);
+
+/// Path to the record of a merge being applied - typically something like
+/// `.../PROJECT.mzr/merge/state.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct MergeStateFile(PathBuf);
+
+/// Path to the directory which keeps the files replaced or removed by
+/// finished merges, so that they can be undone - typically something like
+/// `.../PROJECT.mzr/merge-backups`. Each merge has a subdirectory named by
+/// when it finished.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct MergeBackupsDir(PathBuf);
+
 /// Path to the zone changes directory - typically something like
 /// `.../PROJECT.mzr/zone/ZONE/changes`. This is used as the "upper"
 /// dir of the overlayfs mount, and so changes that overlay the
@@ -55,8 +196,26 @@ pub struct OvfsWorkDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct OvfsMountDir(PathBuf);
 
-/// Path where the user's git directory gets bind-mounted - typically
-/// something like `.../PROJECT.mzr/git-repo`. This allows access to
+/// Path where a zone is exposed read-only to tools outside of its namespaces,
+/// when the daemon is able to - typically something like
+/// `.../PROJECT.mzr/mnt/ZONE`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ExposedZoneDir(PathBuf);
+
+/// Path to the directory storing compressed archives of files from the zone
+/// changes directory - typically something like
+/// `.../PROJECT.mzr/zone/ZONE/compacted`. See `mzr zone compact`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct CompactedDir(PathBuf);
+
+/// Path to the manifest of compacted files - typically something like
+/// `.../PROJECT.mzr/zone/ZONE/compacted/manifest.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct CompactionManifestFile(PathBuf);
+
+/// Path where one of the user's git directories gets bind-mounted - typically
+/// something like `.../PROJECT.mzr/git-repo` for the top level repository, and
+/// `.../PROJECT.mzr/git-repos/SUBDIR/.git` for others. This allows access to
 /// the git repository even though a mount has been placed over the
 /// user's work dir.
 #[derive(Debug, Clone, Shrinkwrap)]
@@ -66,8 +225,21 @@ pub struct BoundGitRepoDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct RelativeGitRepoDir(PathBuf);
 
+/// Path where one of the user's jj directories gets bind-mounted, like
+/// `BoundGitRepoDir` - typically `.../PROJECT.mzr/jj-repos/.jj`, or
+/// `.../PROJECT.mzr/jj-repos/SUBDIR/.jj`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct BoundJjRepoDir(PathBuf);
+
+/// Path where one of the user's Mercurial directories gets bind-mounted,
+/// like `BoundGitRepoDir` - typically `.../PROJECT.mzr/hg-repos/.hg`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct BoundHgRepoDir(PathBuf);
+
 /// Path to the directory containing daemon related files. It is
-/// typically something like `.../PROJECT.mzr/daemon`.
+/// typically something like `.../PROJECT.mzr/daemon`, or
+/// `.../PROJECT.mzr/daemon/USER` when the mzr directory is shared, since
+/// each user runs their own daemon.
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct DaemonDir(PathBuf);
 
@@ -92,6 +264,17 @@ pub struct DaemonLogStderrFile(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct DaemonSocketFile(PathBuf);
 
+/// Path to the file recording the daemon's zone processes, so that they can
+/// be found again if the daemon is restarted - typically something like
+/// `.../PROJECT.mzr/daemon/processes.json`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct DaemonProcessesFile(PathBuf);
+
+/// Path to the token which clients of the daemon's remote listener must
+/// send - typically something like `.../PROJECT.mzr/daemon/remote-token`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct DaemonTokenFile(PathBuf);
+
 /// Path for a process, within the proc filesystem - typically
 /// something like `/proc/PID`, where `PID` is the process identifier
 /// of a running process.
@@ -115,8 +298,22 @@ pub struct ZoneName(String);
 pub struct SnapName(String);
 
 impl MzrDir {
-    pub fn new(work_dir: &UserWorkDir) -> Self {
-        MzrDir(add_suffix_to_path(work_dir, ".mzr"))
+    /// The mzr directory is a sibling of the work directory, so the work
+    /// directory can't be a filesystem root like `/`.
+    pub fn new(work_dir: &UserWorkDir) -> Result<Self, Error> {
+        if work_dir.file_name().is_none() {
+            bail!(
+                "{} can't be used as a work directory, since the mzr directory is a sibling \
+                 of it, named by adding .mzr to its name.",
+                color_dir(&work_dir.display())
+            );
+        }
+        Ok(MzrDir(add_suffix_to_path(work_dir, ".mzr")?))
+    }
+
+    /// The mzr directory at the path, as given via `MZR_DIR`.
+    pub fn from_path(path: &Path) -> Self {
+        MzrDir(path.to_path_buf())
     }
 }
 
@@ -131,11 +328,35 @@ impl UserWorkDir {
     }
 }
 
-impl ZoneDir {
-    pub fn new(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Self {
+impl ZonesDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        ZonesDir(per_user_dir(mzr_dir, "zone"))
+    }
+}
+
+/// When the mzr directory is shared, some of its directories have a
+/// subdirectory for each user, which is used instead. See `shared::share`.
+fn per_user_dir(mzr_dir: &MzrDir, name: &str) -> PathBuf {
+    let mut result = mzr_dir.0.clone();
+    result.push(name);
+    if SharedFile::new(mzr_dir).exists() {
+        result.push(shared::current_user());
+    }
+    result
+}
+
+impl SnapsDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
         let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
         let mut result = mzr_dir_buf.clone();
-        result.push("zone");
+        result.push("snap");
+        SnapsDir(result)
+    }
+}
+
+impl ZoneDir {
+    pub fn new(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Self {
+        let mut result = ZonesDir::new(mzr_dir).0;
         result.push(zone_name);
         ZoneDir(result)
     }
@@ -150,11 +371,62 @@ impl ZoneInfoFile {
     }
 }
 
+impl RunInfoFile {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let zone_dir_buf: &PathBuf = zone_dir.as_ref();
+        let mut result = zone_dir_buf.clone();
+        result.push("run.json");
+        RunInfoFile(result)
+    }
+}
+
+impl ZoneStatsFile {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let zone_dir_buf: &PathBuf = zone_dir.as_ref();
+        let mut result = zone_dir_buf.clone();
+        result.push("stats.json");
+        ZoneStatsFile(result)
+    }
+}
+
+impl ZoneFrozenFile {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let zone_dir_buf: &PathBuf = zone_dir.as_ref();
+        let mut result = zone_dir_buf.clone();
+        result.push("frozen.json");
+        ZoneFrozenFile(result)
+    }
+}
+
+impl ChangeJournalFile {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let mut result = zone_dir.0.clone();
+        result.push("journal");
+        ChangeJournalFile(result)
+    }
+}
+
+impl ZoneHomeDir {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let zone_dir_buf: &PathBuf = zone_dir.as_ref();
+        let mut result = zone_dir_buf.clone();
+        result.push("home");
+        ZoneHomeDir(result)
+    }
+}
+
+impl ZoneCheckpointsDir {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let zone_dir_buf: &PathBuf = zone_dir.as_ref();
+        let mut result = zone_dir_buf.clone();
+        result.push("checkpoints");
+        ZoneCheckpointsDir(result)
+    }
+}
+
 impl SnapDir {
     pub fn new(mzr_dir: &MzrDir, snap_name: &SnapName) -> Self {
-        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
-        let mut result = mzr_dir_buf.clone();
-        result.push("snap");
+        let mut result = SnapsDir::new(mzr_dir).0;
         result.push(snap_name);
         SnapDir(result)
     }
@@ -164,6 +436,133 @@ impl SnapDir {
     }
 }
 
+impl SnapInfosDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("snap-info");
+        SnapInfosDir(result)
+    }
+}
+
+impl SnapInfoFile {
+    pub fn new(mzr_dir: &MzrDir, snap_name: &SnapName) -> Self {
+        let mut result = SnapInfosDir::new(mzr_dir).0;
+        result.push(format!("{}.json", snap_name.as_str()));
+        SnapInfoFile(result)
+    }
+}
+
+impl SnapManifestFile {
+    pub fn new(mzr_dir: &MzrDir, snap_name: &SnapName) -> Self {
+        let mut result = SnapInfosDir::new(mzr_dir).0;
+        result.push(format!("{}.manifest.json", snap_name.as_str()));
+        SnapManifestFile(result)
+    }
+}
+
+impl ObjectsDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("objects");
+        ObjectsDir(result)
+    }
+}
+
+impl RetentionPolicyFile {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("retention.json");
+        RetentionPolicyFile(result)
+    }
+}
+
+impl SharedFile {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("shared.json");
+        SharedFile(result)
+    }
+}
+
+impl ConfigFile {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("config.json");
+        ConfigFile(result)
+    }
+}
+
+impl EventsLogFile {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("events.log");
+        EventsLogFile(result)
+    }
+}
+
+impl HooksDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("hooks");
+        HooksDir(result)
+    }
+}
+
+impl ZoneTemplateDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("template");
+        ZoneTemplateDir(result)
+    }
+}
+
+impl SharedCacheDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("shared-cache");
+        SharedCacheDir(result)
+    }
+}
+
+impl MzrTmpDir {
+    pub fn new(mzr_dir: &MzrDir, name: &str) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("tmp");
+        result.push(name);
+        MzrTmpDir(result)
+    }
+}
+
+impl MergeDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("merge");
+        MergeDir(result)
+    }
+}
+
+impl MergeStagingDir {
+    pub fn new(target_dir: &Path) -> Self {
+        MergeStagingDir(target_dir.join(".mzr-merge-staging"))
+    }
+}
+
+impl MergeStateFile {
+    pub fn new(merge_dir: &MergeDir) -> Self {
+        let mut result = merge_dir.0.clone();
+        result.push("state.json");
+        MergeStateFile(result)
+    }
+}
+
+impl MergeBackupsDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mut result = mzr_dir.0.clone();
+        result.push("merge-backups");
+        MergeBackupsDir(result)
+    }
+}
+
 impl OvfsChangesDir {
     pub fn new(zone_dir: &ZoneDir) -> Self {
         let mut ovfs_changes_dir = zone_dir.0.clone();
@@ -188,14 +587,63 @@ impl OvfsMountDir {
     }
 }
 
+impl ExposedZoneDir {
+    pub fn new(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Self {
+        let mut result = per_user_dir(mzr_dir, "mnt");
+        result.push(zone_name);
+        ExposedZoneDir(result)
+    }
+}
+
+impl CompactedDir {
+    pub fn new(zone_dir: &ZoneDir) -> Self {
+        let mut compacted_dir = zone_dir.0.clone();
+        compacted_dir.push("compacted");
+        CompactedDir(compacted_dir)
+    }
+}
+
+impl CompactionManifestFile {
+    pub fn new(compacted_dir: &CompactedDir) -> Self {
+        let mut result = compacted_dir.0.clone();
+        result.push("manifest.json");
+        CompactionManifestFile(result)
+    }
+}
+
 impl BoundGitRepoDir {
-    pub fn new(mzr_dir: &MzrDir) -> Self {
+    pub fn new(mzr_dir: &MzrDir, rel_git_dir: &RelativeGitRepoDir) -> Self {
         let mut bound_git_repo_dir = mzr_dir.0.clone();
-        bound_git_repo_dir.push("git-repo");
+        // The top level repository keeps the location it had before multiple
+        // repositories were supported, since zones have symlinks into it.
+        if rel_git_dir.0 == Path::new(".git") {
+            bound_git_repo_dir.push("git-repo");
+        } else {
+            bound_git_repo_dir.push("git-repos");
+            bound_git_repo_dir.push(&rel_git_dir.0);
+        }
         BoundGitRepoDir(bound_git_repo_dir)
     }
 }
 
+impl BoundJjRepoDir {
+    pub fn new(mzr_dir: &MzrDir, rel_jj_dir: &Path) -> Self {
+        let mut bound_jj_repo_dir = mzr_dir.0.clone();
+        bound_jj_repo_dir.push("jj-repos");
+        bound_jj_repo_dir.push(rel_jj_dir);
+        BoundJjRepoDir(bound_jj_repo_dir)
+    }
+}
+
+impl BoundHgRepoDir {
+    pub fn new(mzr_dir: &MzrDir, rel_hg_dir: &Path) -> Self {
+        let mut bound_hg_repo_dir = mzr_dir.0.clone();
+        bound_hg_repo_dir.push("hg-repos");
+        bound_hg_repo_dir.push(rel_hg_dir);
+        BoundHgRepoDir(bound_hg_repo_dir)
+    }
+}
+
 impl RelativeGitRepoDir {
     pub fn new<T>(rel_path: T) -> Self
     where
@@ -207,10 +655,7 @@ impl RelativeGitRepoDir {
 
 impl DaemonDir {
     pub fn new(mzr_dir: &MzrDir) -> Self {
-        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
-        let mut result = mzr_dir_buf.clone();
-        result.push("daemon");
-        DaemonDir(result)
+        DaemonDir(per_user_dir(mzr_dir, "daemon"))
     }
 }
 
@@ -250,6 +695,24 @@ impl DaemonSocketFile {
     }
 }
 
+impl DaemonProcessesFile {
+    pub fn new(daemon_dir: &DaemonDir) -> Self {
+        let dir_buf: &PathBuf = daemon_dir.as_ref();
+        let mut result = dir_buf.clone();
+        result.push("processes.json");
+        DaemonProcessesFile(result)
+    }
+}
+
+impl DaemonTokenFile {
+    pub fn new(daemon_dir: &DaemonDir) -> Self {
+        let dir_buf: &PathBuf = daemon_dir.as_ref();
+        let mut result = dir_buf.clone();
+        result.push("remote-token");
+        DaemonTokenFile(result)
+    }
+}
+
 impl ProcDir {
     pub fn new(pid: Pid) -> Self {
         let mut dir_buf = PathBuf::from("/proc");
@@ -267,6 +730,10 @@ impl ProcNamespaceFile {
         Self::new(dir, "user")
     }
 
+    pub fn new_pid(dir: &ProcDir) -> Self {
+        Self::new(dir, "pid")
+    }
+
     fn new<P: AsRef<Path>>(dir: &ProcDir, subdir: P) -> Self {
         let dir_buf: &PathBuf = dir.as_ref();
         let mut result = dir_buf.clone();
@@ -316,171 +783,531 @@ impl AsRef<Path> for UserWorkDir {
     }
 }
 
-impl AsRef<Path> for ZoneDir {
+impl AsRef<Path> for ZonesDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for ZoneInfoFile {
+impl AsRef<Path> for SnapsDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for SnapDir {
+impl AsRef<Path> for ZoneDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for OvfsChangesDir {
+impl AsRef<Path> for ZoneInfoFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for OvfsWorkDir {
+impl AsRef<Path> for RunInfoFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for OvfsMountDir {
+impl AsRef<Path> for ZoneStatsFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for BoundGitRepoDir {
+impl AsRef<Path> for ZoneFrozenFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for RelativeGitRepoDir {
+impl AsRef<Path> for ChangeJournalFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for DaemonDir {
+impl AsRef<Path> for ZoneHomeDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for DaemonPidFile {
+impl AsRef<Path> for ZoneCheckpointsDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for DaemonLogStdoutFile {
+impl AsRef<Path> for SnapDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for DaemonLogStderrFile {
+impl AsRef<Path> for SnapInfosDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for DaemonSocketFile {
+impl AsRef<Path> for SnapInfoFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for ProcDir {
+impl AsRef<Path> for SnapManifestFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for ProcNamespaceFile {
+impl AsRef<Path> for ObjectsDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for ZoneName {
+impl AsRef<Path> for RetentionPolicyFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<Path> for SnapName {
+impl AsRef<Path> for SharedFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for MzrDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for ConfigFile {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for UserWorkDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for EventsLogFile {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for ZoneDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for HooksDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for ZoneInfoFile {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for ZoneTemplateDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for SnapDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for SharedCacheDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for OvfsChangesDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for MzrTmpDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for OvfsWorkDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for MergeDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for OvfsMountDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for MergeStagingDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for BoundGitRepoDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for MergeStateFile {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for RelativeGitRepoDir {
-    fn as_ref(&self) -> &OsStr {
+impl AsRef<Path> for MergeBackupsDir {
+    fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
-impl AsRef<OsStr> for DaemonDir {
-    fn as_ref(&self) -> &OsStr {
-        self.0.as_ref()
+impl AsRef<Path> for OvfsChangesDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for OvfsWorkDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for OvfsMountDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ExposedZoneDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for CompactedDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for CompactionManifestFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for BoundGitRepoDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for BoundJjRepoDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for BoundHgRepoDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for RelativeGitRepoDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonPidFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonLogStdoutFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonLogStderrFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonSocketFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonProcessesFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for DaemonTokenFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ProcDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ProcNamespaceFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ZoneName {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for SnapName {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MzrDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for UserWorkDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZonesDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SnapsDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneInfoFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for RunInfoFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneStatsFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneFrozenFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ChangeJournalFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneHomeDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneCheckpointsDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SnapDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SnapInfosDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SnapInfoFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SnapManifestFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ObjectsDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for RetentionPolicyFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SharedFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ConfigFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for EventsLogFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for HooksDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneTemplateDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for SharedCacheDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MzrTmpDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MergeDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MergeStagingDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MergeStateFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for MergeBackupsDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for OvfsChangesDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for OvfsWorkDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for OvfsMountDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ExposedZoneDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for CompactedDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for CompactionManifestFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for BoundGitRepoDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for BoundJjRepoDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for BoundHgRepoDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for RelativeGitRepoDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for DaemonDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
     }
 }
 
@@ -508,6 +1335,18 @@ impl AsRef<OsStr> for DaemonSocketFile {
     }
 }
 
+impl AsRef<OsStr> for DaemonProcessesFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for DaemonTokenFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<OsStr> for ProcDir {
     fn as_ref(&self) -> &OsStr {
         self.0.as_ref()
@@ -544,6 +1383,18 @@ impl Display for UserWorkDir {
     }
 }
 
+impl Display for ZonesDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for SnapsDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for ZoneDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -556,12 +1407,144 @@ impl Display for ZoneInfoFile {
     }
 }
 
+impl Display for RunInfoFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneStatsFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneFrozenFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ChangeJournalFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneHomeDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneCheckpointsDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for SnapDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
     }
 }
 
+impl Display for SnapInfosDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for SnapInfoFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for SnapManifestFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ObjectsDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for RetentionPolicyFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for SharedFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ConfigFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for EventsLogFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for HooksDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneTemplateDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for SharedCacheDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for MzrTmpDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for MergeDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for MergeStagingDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for MergeStateFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for MergeBackupsDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for OvfsChangesDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -580,12 +1563,42 @@ impl Display for OvfsMountDir {
     }
 }
 
+impl Display for ExposedZoneDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for CompactedDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for CompactionManifestFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for BoundGitRepoDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
     }
 }
 
+impl Display for BoundJjRepoDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for BoundHgRepoDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for RelativeGitRepoDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -622,6 +1635,18 @@ impl Display for DaemonSocketFile {
     }
 }
 
+impl Display for DaemonProcessesFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for DaemonTokenFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for ProcDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -0,0 +1,238 @@
+use failure::Error;
+use libc::{c_int, c_ulong};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::close;
+use std::fs::read_dir;
+use std::io;
+use std::os::unix::io::RawFd;
+
+// Constants from <linux/prctl.h> and <linux/capability.h>.
+const PR_CAPBSET_DROP: c_int = 24;
+const PR_CAP_AMBIENT: c_int = 47;
+const PR_CAP_AMBIENT_CLEAR_ALL: c_ulong = 4;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Capabilities which are needed to enter a zone's namespaces via `setns`.
+pub const CAP_SYS_CHROOT: u32 = 18;
+pub const CAP_SYS_ADMIN: u32 = 21;
+
+/// Highest capability number which is dropped. Numbers beyond those the
+/// kernel knows about are skipped.
+const MAX_CAPABILITY: u32 = 63;
+
+/// Names of the capabilities, indexed by their number, without the `CAP_`
+/// prefix.
+const CAPABILITY_NAMES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Parses capability names, such as `CAP_NET_RAW` or `net_raw`, into their
+/// numbers.
+pub fn parse_capabilities(names: &[String]) -> Result<Vec<u32>, Error> {
+    let mut capabilities = Vec::new();
+    for name in names {
+        let lowercase = name.to_lowercase();
+        let short_name = lowercase.trim_start_matches("cap_");
+        match CAPABILITY_NAMES.iter().position(|x| *x == short_name) {
+            Some(number) => capabilities.push(number as u32),
+            None => bail!("Unknown capability {:?}", name),
+        }
+    }
+    Ok(capabilities)
+}
+
+/// Drops every capability other than those in `keep`, and sets
+/// `no_new_privs`, so that commands run within a zone can't undo the mounts
+/// which make up the zone, or otherwise interfere with it. Processes which
+/// enter a zone have every capability within its user namespace, where they
+/// are root, so the capabilities are also dropped from the bounding set.
+/// Otherwise executing a program would regain them.
+///
+/// Since entering a zone requires `CAP_SYS_ADMIN` and `CAP_SYS_CHROOT`, mzr
+/// commands run within a zone can't enter zones, such as a nested `mzr run`,
+/// unless `Config::zone_capabilities` keeps both.
+///
+/// This only makes syscalls, so it can be used between fork and exec.
+pub fn restrict(keep: &[u32]) -> io::Result<()> {
+    let mut kept_mask: u64 = 0;
+    for capability in keep {
+        kept_mask |= 1 << capability;
+    }
+    unsafe {
+        if libc::prctl(
+            libc::PR_SET_NO_NEW_PRIVS,
+            1 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // Kernels before 4.3 don't have ambient capabilities, in which case
+        // there are none to clear.
+        libc::prctl(
+            PR_CAP_AMBIENT,
+            PR_CAP_AMBIENT_CLEAR_ALL,
+            0 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+        );
+        for capability in 0..=MAX_CAPABILITY {
+            if kept_mask & (1 << capability) != 0 {
+                continue;
+            }
+            if libc::prctl(
+                PR_CAPBSET_DROP,
+                c_ulong::from(capability),
+                0 as c_ulong,
+                0 as c_ulong,
+                0 as c_ulong,
+            ) != 0
+            {
+                let err = io::Error::last_os_error();
+                // Capabilities the kernel doesn't know about.
+                if err.raw_os_error() == Some(libc::EINVAL) {
+                    break;
+                }
+                return Err(err);
+            }
+        }
+        // Dropping from the bounding set requires CAP_SETPCAP, so the
+        // process's own capabilities are dropped last.
+        let header = CapUserHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapUserData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        }; 2];
+        if libc::syscall(libc::SYS_capget, &header, data.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Kept capabilities are only retained if the process has them.
+        for (ix, caps) in data.iter_mut().enumerate() {
+            let mask = (kept_mask >> (32 * ix)) as u32;
+            caps.effective &= mask;
+            caps.permitted &= mask;
+            caps.inheritable &= mask;
+        }
+        if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether the process has the capability in its effective set.
+pub fn has_capability(capability: u32) -> io::Result<bool> {
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData {
+        effective: 0,
+        permitted: 0,
+        inheritable: 0,
+    }; 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &header, data.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let caps = data[(capability / 32) as usize];
+    Ok(caps.effective & (1 << (capability % 32)) != 0)
+}
+
+/// File descriptors which are open in this process, other than stdio and
+/// those in `keep`. This includes the fd used to list them, which has been
+/// closed by the time this returns.
+fn inherited_fds(keep: &[RawFd]) -> Result<Vec<RawFd>, Error> {
+    let mut fds = Vec::new();
+    for entry in read_dir("/proc/self/fd")? {
+        let name = entry?.file_name();
+        if let Some(fd) = name.to_str().and_then(|name| name.parse::<RawFd>().ok()) {
+            if fd > 2 && !keep.contains(&fd) {
+                fds.push(fd);
+            }
+        }
+    }
+    Ok(fds)
+}
+
+/// Marks the open file descriptors other than stdio and those in `keep` as
+/// close-on-exec, so that they aren't inherited by programs this process
+/// runs, such as the daemon's sockets or a terminal's leftover fds.
+pub fn cloexec_inherited_fds(keep: &[RawFd]) -> Result<(), Error> {
+    for fd in inherited_fds(keep)? {
+        if let Ok(flags) = fcntl(fd, FcntlArg::F_GETFD) {
+            let flags = FdFlag::from_bits_truncate(flags) | FdFlag::FD_CLOEXEC;
+            fcntl(fd, FcntlArg::F_SETFD(flags))?;
+        }
+    }
+    Ok(())
+}
+
+/// Closes the open file descriptors other than stdio and those in `keep`.
+/// Used by processes which outlive the connections they inherited, such as
+/// zone processes, which are forked by the daemon.
+pub fn close_inherited_fds(keep: &[RawFd]) -> Result<(), Error> {
+    for fd in inherited_fds(keep)? {
+        let _ = close(fd);
+    }
+    Ok(())
+}
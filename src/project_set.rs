@@ -0,0 +1,147 @@
+use crate::config::Config;
+use crate::git;
+use crate::merge::Plan;
+use crate::paths::*;
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+/// Sibling directories, such as separate repositories, which are versioned
+/// together by one mzr dir. The work dir is their parent, and its other
+/// entries are left out of snapshots, so zones see a composite view of just
+/// the members. Set up by `mzr init --member`, and stored in
+/// `Config::project_set`.
+#[derive(Debug, Clone)]
+pub struct ProjectSet {
+    /// Names of the members, being entries of the work dir.
+    pub members: Vec<PathBuf>,
+}
+
+impl ProjectSet {
+    /// Loads the project set, which only exists if members are configured.
+    pub fn load(mzr_dir: &MzrDir) -> Result<Option<ProjectSet>, Error> {
+        let members = Config::load(mzr_dir)?.project_set;
+        if members.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ProjectSet { members }))
+        }
+    }
+
+    /// Determines the work dir for members given as paths, which must all
+    /// be directories with the same parent. Yields the parent along with
+    /// the members' names.
+    pub fn from_member_dirs(dirs: &[PathBuf]) -> Result<(PathBuf, ProjectSet), Error> {
+        let mut parent: Option<&Path> = None;
+        let mut members = Vec::new();
+        for dir in dirs {
+            if !dir.is_dir() {
+                bail!("Project set member {:?} isn't a directory.", dir);
+            }
+            let (dir_parent, name) = match (dir.parent(), dir.file_name()) {
+                (Some(dir_parent), Some(name)) => (dir_parent, name),
+                _ => bail!("Project set member {:?} has no parent directory.", dir),
+            };
+            match parent {
+                Some(parent) if parent != dir_parent => bail!(
+                    "Project set members must be in the same directory, but {:?} isn't in {:?}.",
+                    dir,
+                    parent
+                ),
+                _ => parent = Some(dir_parent),
+            }
+            let name = PathBuf::from(name);
+            if !members.contains(&name) {
+                members.push(name);
+            }
+        }
+        match parent {
+            Some(parent) => Ok((parent.to_path_buf(), ProjectSet { members })),
+            None => bail!("A project set needs at least one member."),
+        }
+    }
+
+    /// Entries of the work dir which aren't members, and so are excluded
+    /// from snapshots.
+    pub fn non_members(&self, work_dir: &UserWorkDir) -> Result<BTreeSet<PathBuf>, Error> {
+        let mut non_members = BTreeSet::new();
+        for entry in read_dir(work_dir)? {
+            let name = PathBuf::from(entry?.file_name());
+            if !self.members.contains(&name) {
+                non_members.insert(name);
+            }
+        }
+        Ok(non_members)
+    }
+
+    /// Git commit of each member which is a git repository.
+    pub fn git_commits(&self, work_dir: &UserWorkDir) -> BTreeMap<PathBuf, String> {
+        let mut commits = BTreeMap::new();
+        for member in self.members.iter() {
+            let member_dir = UserWorkDir::new(&work_dir.join(member));
+            if let Ok(sha) = git::head_sha(&member_dir) {
+                commits.insert(member.clone(), sha);
+            }
+        }
+        commits
+    }
+
+    /// Git directories of the members, relative to the work dir.
+    pub fn git_dirs(&self, work_dir: &UserWorkDir) -> Vec<PathBuf> {
+        let mut git_dirs = Vec::new();
+        for member in self.members.iter() {
+            let member_dir = UserWorkDir::new(&work_dir.join(member));
+            if let Ok(git_dir) = git::get_git_dir(&member_dir) {
+                git_dirs.push(member.join(git_dir.to_path_buf()));
+            }
+        }
+        git_dirs
+    }
+
+    /// Snapshot name made of each member's current git ref or sha, such as
+    /// `app=main,lib=v2`. Members which aren't git repositories are left out.
+    pub fn default_snap_name(&self, work_dir: &UserWorkDir) -> Result<SnapName, Error> {
+        let mut parts = Vec::new();
+        for member in self.members.iter() {
+            let member_dir = UserWorkDir::new(&work_dir.join(member));
+            if let Ok(name) = git::default_snap_name(&member_dir) {
+                parts.push(format!("{}={}", member.display(), name));
+            }
+        }
+        if parts.is_empty() {
+            bail!(
+                "Since no snapshot was specified, attempted to name it after the git refs of \
+                 the project set's members, but none of them are git repositories."
+            );
+        }
+        SnapName::new(parts.join(","))
+    }
+
+    /// Member which the path, relative to the work dir, is within.
+    pub fn member_of(&self, rel_path: &Path) -> Option<&PathBuf> {
+        self.members
+            .iter()
+            .find(|member| rel_path.starts_with(member))
+    }
+
+    /// Counts the plan's updates, renames, directory changes, and conflicts
+    /// within each member, for reporting merges per member.
+    pub fn plan_counts(&self, plan: &Plan) -> BTreeMap<PathBuf, [usize; 4]> {
+        let mut counts: BTreeMap<PathBuf, [usize; 4]> = BTreeMap::new();
+        let paths = [
+            plan.updates.iter().map(|x| &x.rel_path).collect::<Vec<_>>(),
+            plan.renames.iter().map(|x| &x.to).collect(),
+            plan.dir_updates.iter().map(|x| &x.rel_path).collect(),
+            plan.conflicts.iter().map(|x| &x.rel_path).collect(),
+        ];
+        for (ix, rel_paths) in paths.iter().enumerate() {
+            for rel_path in rel_paths {
+                if let Some(member) = self.member_of(rel_path) {
+                    counts.entry(member.clone()).or_insert([0; 4])[ix] += 1;
+                }
+            }
+        }
+        counts
+    }
+}
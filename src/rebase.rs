@@ -0,0 +1,103 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::conflicts;
+use crate::merge::{self, FileReport};
+use crate::zone::Zone;
+use failure::Error;
+use serde::Serialize;
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Plan for moving a zone onto a newer snapshot. The zone's changes are
+/// kept as they are, becoming changes to the new snapshot. Paths which also
+/// changed between the two snapshots are conflicts, since the zone's version
+/// will hide the new snapshot's version.
+#[derive(Debug, Serialize)]
+pub struct RebasePlan {
+    /// Number of the zone's changed paths which didn't change between the
+    /// snapshots.
+    pub kept: usize,
+    pub conflicts: Vec<RebaseConflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RebaseConflict {
+    pub path: PathBuf,
+    /// The zone's version of the path.
+    pub zone: FileReport,
+    /// The path in the old snapshot, if it existed there.
+    pub old: Option<FileReport>,
+    /// The path in the new snapshot, if it exists there.
+    pub new: Option<FileReport>,
+}
+
+/// Plans rebasing the zone onto the contents of `new_dir`, which is either
+/// the new snapshot or the work dir it will be taken of. Paths within
+/// `excluded_dirs` are ignored.
+pub fn plan(zone: &Zone, new_dir: &Path, excluded_dirs: &[PathBuf]) -> Result<RebasePlan, Error> {
+    compaction::check_not_compacted(zone)?;
+    let mut plan = RebasePlan {
+        kept: 0,
+        conflicts: Vec::new(),
+    };
+    for rel_path in conflicts::changed_paths(zone)? {
+        if excluded_dirs.iter().any(|dir| rel_path.starts_with(dir)) {
+            continue;
+        }
+        let old = get_metadata(&zone.snap_dir.join(&rel_path))?;
+        let new = get_metadata(&new_dir.join(&rel_path))?;
+        let unchanged = match (&old, &new) {
+            (None, None) => true,
+            (Some(old), Some(new)) => {
+                (old.is_dir() && new.is_dir()) || merge::metadata_matches(old, new)
+            }
+            _ => false,
+        };
+        if unchanged {
+            plan.kept += 1;
+        } else {
+            let zone_metadata = fs::symlink_metadata(zone.ovfs_changes_dir.join(&rel_path))?;
+            plan.conflicts.push(RebaseConflict {
+                path: rel_path,
+                zone: FileReport::new(&zone_metadata),
+                old: old.as_ref().map(FileReport::new),
+                new: new.as_ref().map(FileReport::new),
+            });
+        }
+    }
+    Ok(plan)
+}
+
+impl RebasePlan {
+    pub fn print_table(&self) {
+        println!(
+            "{} changed path(s) weren't changed between the snapshots.",
+            self.kept
+        );
+        println!(
+            "{} conflict(s), changed both within the zone and between the snapshots:",
+            self.conflicts.len()
+        );
+        for conflict in self.conflicts.iter() {
+            println!("  {}", color_file(&conflict.path.display()));
+            println!("      zone: {}", conflict.zone);
+            match &conflict.old {
+                None => println!("      old:  absent"),
+                Some(old) => println!("      old:  {}", old),
+            }
+            match &conflict.new {
+                None => println!("      new:  absent"),
+                Some(new) => println!("      new:  {}", new),
+            }
+        }
+    }
+}
+
+fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
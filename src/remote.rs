@@ -0,0 +1,318 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::config::Config;
+use crate::git::{get_git_dir, SHARED_REPO_PATHS};
+use crate::json::{self, JsonFile};
+use crate::paths::*;
+use crate::snapshot::{self, SnapInfo};
+use crate::top_dirs::TopDirs;
+use crate::utils::{run_process, run_process_output};
+use crate::zone::{Zone, ZoneInfo};
+use failure::Error;
+use nix::unistd::Pid;
+use serde::de::DeserializeOwned;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// Location of a mzr directory on another machine, in the `[USER@]HOST:PATH`
+/// syntax used by rsync and scp.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    pub host: String,
+    pub mzr_dir: PathBuf,
+}
+
+impl FromStr for Remote {
+    type Err = Error;
+    fn from_str(remote: &str) -> Result<Self, Self::Err> {
+        match remote.find(':') {
+            Some(ix) if ix > 0 && ix + 1 < remote.len() => Ok(Remote {
+                host: remote[..ix].to_string(),
+                mzr_dir: PathBuf::from(&remote[ix + 1..]),
+            }),
+            _ => bail!(
+                "Expected remote to be of the form [USER@]HOST:PATH, but got {:?}",
+                remote
+            ),
+        }
+    }
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&format!("{}:{}", self.host, self.mzr_dir.display())).fmt(f)
+    }
+}
+
+impl Remote {
+    /// Argument referring to a path within the remote mzr directory. The
+    /// `/./` marks where `rsync --relative` starts recreating the path.
+    fn rsync_arg<P: AsRef<Path>>(&self, rel_path: P) -> OsString {
+        let mut arg = OsString::from(format!("{}:", self.host));
+        arg.push(self.mzr_dir.join(".").join(rel_path).as_os_str());
+        arg
+    }
+}
+
+/// Transfers a snapshot, and optionally some zones based on it, to a remote
+/// mzr directory. The remote directory must already exist, and must not
+/// already have a snapshot or zones of the same names, since rsync would
+/// otherwise merge the two.
+pub fn push(
+    top_dirs: &TopDirs,
+    remote: &Remote,
+    snap_name: &SnapName,
+    zone_names: &[ZoneName],
+) -> Result<(), Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    if !snapshot::exists(mzr_dir, snap_name) {
+        bail!("Can't push snapshot {}, since it doesn't exist.", snap_name);
+    }
+    for zone_name in zone_names {
+        let zone = Zone::load(mzr_dir, zone_name)?;
+        check_zone_snapshot(zone_name, &zone.info, snap_name)?;
+        compaction::check_not_compacted(&zone)?;
+    }
+    if !remote_exists(remote, PathBuf::new())? {
+        bail!("The mzr directory {} doesn't exist.", remote);
+    }
+    // Checks that the remote's files are in a format this version of mzr
+    // writes.
+    let _: Option<Config> = fetch_metadata(top_dirs, remote, config_rel_path(), false)?;
+    if remote_exists(remote, snap_rel_path(snap_name))?
+        || remote_exists(remote, snap_info_rel_path(snap_name))?
+    {
+        bail!(
+            "A snapshot named {} already exists in {}.",
+            snap_name,
+            remote
+        );
+    }
+    for zone_name in zone_names {
+        if remote_exists(remote, Path::new("zone").join(zone_name))? {
+            bail!("A zone named {} already exists in {}.", zone_name, remote);
+        }
+    }
+    // Repository contents which the snapshot shares with the work dir are
+    // pushed along with it, and its info is rewritten to record that it has
+    // complete copies, since the remote's work dir may not have them.
+    let snap_info = SnapInfo::load(mzr_dir, snap_name)?;
+    let shared_paths: Vec<PathBuf> = snapshot::shared_repo_paths(&snap_info)
+        .into_iter()
+        .filter(|path| top_dirs.user_work_dir.join(path).exists())
+        .collect();
+    let tmp_dir = MzrTmpDir::new(mzr_dir, &format!("push-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result: Result<(), Error> = try {
+        let tmp_mzr_dir = MzrDir::from_path(&tmp_dir);
+        SnapInfo {
+            git_dirs: None,
+            jj_dirs: None,
+            hg_dirs: None,
+            ..snap_info
+        }
+        .write(&tmp_mzr_dir, snap_name)?;
+        let mut sources = vec![
+            local_arg(mzr_dir, snap_rel_path(snap_name)),
+            local_arg(&tmp_mzr_dir, snap_info_rel_path(snap_name)),
+        ];
+        for zone_name in zone_names {
+            sources.push(local_arg(mzr_dir, zone_info_rel_path(zone_name)));
+            sources.push(local_arg(mzr_dir, zone_changes_rel_path(zone_name)));
+        }
+        let mut target = OsString::from(format!("{}:", remote.host));
+        target.push(remote.mzr_dir.as_os_str());
+        rsync(top_dirs, sources, target)?;
+        if !shared_paths.is_empty() {
+            let sources = shared_paths
+                .iter()
+                .map(|path| top_dirs.user_work_dir.join(".").join(path).into_os_string())
+                .collect();
+            rsync(
+                top_dirs,
+                sources,
+                remote.rsync_arg(snap_rel_path(snap_name)),
+            )?;
+        }
+    };
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Transfers a snapshot, and optionally some zones based on it, from a remote
+/// mzr directory. Metadata files are transferred first, and checked to have
+/// been written by a compatible version of mzr. Like `push`, the snapshot and
+/// zones must not already exist locally.
+pub fn pull(
+    top_dirs: &TopDirs,
+    remote: &Remote,
+    snap_name: &SnapName,
+    zone_names: &[ZoneName],
+) -> Result<(), Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    if snapshot::exists(mzr_dir, snap_name) || SnapInfoFile::new(mzr_dir, snap_name).exists() {
+        bail!("A snapshot named {} already exists here.", snap_name);
+    }
+    // Snapshots created by older versions of mzr don't have an info file, so
+    // their format can't be checked.
+    let snap_info: SnapInfo =
+        fetch_metadata(top_dirs, remote, snap_info_rel_path(snap_name), false)?.ok_or_else(
+            || {
+                format_err!(
+                    "Snapshot {} on the remote has no info file, which is the case for \
+                     snapshots taken by older versions of mzr, so can't be pulled.",
+                    snap_name
+                )
+            },
+        )?;
+    // The remote's work dir isn't known, so repository contents which the
+    // snapshot shares with it can't be pulled.
+    if !snapshot::shared_repo_paths(&snap_info).is_empty() {
+        bail!(
+            "Snapshot {} on the remote shares its repositories' objects with the remote's \
+             work dir, so can't be pulled on its own. Instead, run mzr snap export on the \
+             remote, and mzr snap import here.",
+            snap_name
+        );
+    }
+    for zone_name in zone_names {
+        let zone_info: ZoneInfo =
+            fetch_metadata(top_dirs, remote, zone_info_rel_path(zone_name), true)?
+                .ok_or_else(|| format_err!("Zone {} not found on remote.", zone_name))?;
+        check_zone_snapshot(zone_name, &zone_info, snap_name)?;
+        if ZoneDir::new(mzr_dir, zone_name).exists() {
+            bail!("A zone named {} already exists here.", zone_name);
+        }
+    }
+    let mut sources = vec![
+        remote.rsync_arg(snap_rel_path(snap_name)),
+        remote.rsync_arg(snap_info_rel_path(snap_name)),
+    ];
+    for zone_name in zone_names {
+        sources.push(remote.rsync_arg(zone_info_rel_path(zone_name)));
+        sources.push(remote.rsync_arg(zone_changes_rel_path(zone_name)));
+    }
+    create_dir_all(mzr_dir)?;
+    rsync(top_dirs, sources, mzr_dir.as_os_str().to_os_string())
+}
+
+/// Copies a remote metadata file to a temporary directory, and checks that it
+/// was written by a compatible version of mzr. If `required` is false, then
+/// the file not existing on the remote is not an error.
+fn fetch_metadata<T: DeserializeOwned>(
+    top_dirs: &TopDirs,
+    remote: &Remote,
+    rel_path: PathBuf,
+    required: bool,
+) -> Result<Option<T>, Error> {
+    let tmp_dir = MzrTmpDir::new(&top_dirs.mzr_dir, &format!("pull-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result: Result<Option<T>, Error> = try {
+        let mut cmd_base = Command::new("rsync");
+        let cmd = cmd_base
+            .stdin(Stdio::null())
+            .arg("--relative")
+            .arg(remote.rsync_arg(&rel_path))
+            .arg(tmp_dir.as_os_str());
+        if !required {
+            cmd.arg("--ignore-missing-args");
+        }
+        run_process(cmd)?;
+        let local_path = tmp_dir.join(&rel_path);
+        if local_path.exists() {
+            let file: JsonFile<T> = json::read(&local_path)?;
+            json::check_writer(&file.writer)?;
+            Some(file.contents)
+        } else {
+            None
+        }
+    };
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Whether the path exists within the remote mzr directory. rsync lists the
+/// path itself, rather than the contents of directories, and lists nothing
+/// for missing paths.
+fn remote_exists<P: AsRef<Path>>(remote: &Remote, rel_path: P) -> Result<bool, Error> {
+    let output = run_process_output(
+        Command::new("rsync")
+            .stdin(Stdio::null())
+            .arg("--list-only")
+            .arg("--ignore-missing-args")
+            .arg(remote.rsync_arg(rel_path)),
+    )?;
+    Ok(!output.trim().is_empty())
+}
+
+fn check_zone_snapshot(
+    zone_name: &ZoneName,
+    zone_info: &ZoneInfo,
+    snap_name: &SnapName,
+) -> Result<(), Error> {
+    if zone_info.snapshot.as_str() != snap_name.as_str() {
+        bail!(
+            "Zone {} is based on snapshot {}, rather than {}",
+            zone_name,
+            zone_info.snapshot,
+            snap_name
+        );
+    }
+    Ok(())
+}
+
+fn rsync(top_dirs: &TopDirs, sources: Vec<OsString>, target: OsString) -> Result<(), Error> {
+    let mut cmd_base = Command::new("rsync");
+    let cmd = cmd_base
+        .stdin(Stdio::null())
+        // Preserve symlinks, permissions, timestamps, and special files.
+        // Preserving timestamps is particularly important, since merging
+        // relies on them.
+        .arg("--archive")
+        .arg("--hard-links")
+        // Overlayfs stores opaque directory markers in xattrs.
+        .arg("--xattrs")
+        // Recreate the paths of the sources relative to the mzr directory.
+        .arg("--relative")
+        .arg("--compress");
+    // Symlinks into the shared git repository point into the local mzr
+    // directory, and get recreated by the daemon.
+    if let Ok(rel_git_dir) = get_git_dir(&top_dirs.user_work_dir) {
+        for shared_path in SHARED_REPO_PATHS.iter() {
+            cmd.arg(format!(
+                "--exclude=/zone/*/changes/{}",
+                rel_git_dir.join(shared_path).display()
+            ));
+        }
+    }
+    cmd.args(sources).arg(target);
+    run_process(cmd)
+}
+
+fn local_arg<P: AsRef<Path>>(mzr_dir: &MzrDir, rel_path: P) -> OsString {
+    mzr_dir.join(".").join(rel_path).into_os_string()
+}
+
+fn snap_rel_path(snap_name: &SnapName) -> PathBuf {
+    Path::new("snap").join(snap_name)
+}
+
+fn config_rel_path() -> PathBuf {
+    PathBuf::from("config.json")
+}
+
+fn snap_info_rel_path(snap_name: &SnapName) -> PathBuf {
+    Path::new("snap-info").join(format!("{}.json", snap_name.as_str()))
+}
+
+fn zone_info_rel_path(zone_name: &ZoneName) -> PathBuf {
+    Path::new("zone").join(zone_name).join("info.json")
+}
+
+fn zone_changes_rel_path(zone_name: &ZoneName) -> PathBuf {
+    Path::new("zone").join(zone_name).join("changes")
+}
@@ -0,0 +1,342 @@
+use crate::ipc::{self, Message, Timeouts};
+use crate::paths::*;
+use failure::{Error, ResultExt};
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream};
+use std::env;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Environment variable holding the `HOST:PORT` of a remote daemon to send
+/// requests to, set by `--host`.
+pub const HOST_VAR: &str = "MZR_DAEMON_HOST";
+
+/// Environment variable holding the token to authenticate with to the
+/// remote daemon, being the contents of its `DaemonTokenFile`.
+pub const TOKEN_VAR: &str = "MZR_DAEMON_TOKEN";
+
+/// Environment variable holding the path of a file of CA certificates to
+/// verify the remote daemon's certificate with, instead of the system's.
+pub const CA_FILE_VAR: &str = "MZR_DAEMON_CA_FILE";
+
+/// Number of random bytes in a generated token.
+const TOKEN_BYTES: usize = 32;
+
+/// Limit on the length of the token line clients send, so that
+/// unauthenticated clients can't make the listener buffer arbitrary amounts.
+/// Generated tokens are hex encoded, so this leaves plenty of room.
+const MAX_TOKEN_LINE_BYTES: u64 = 128;
+
+/// Limit on the number of remote connections being handled at once, each of
+/// which has its own thread. Further connections are closed immediately.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long clients have to complete the TLS handshake and send the token,
+/// so that idle connections don't hold on to one of the `MAX_CONNECTIONS`.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of the remote daemon given by `--host`, if any.
+pub fn host() -> Option<String> {
+    env::var(HOST_VAR).ok().filter(|host| !host.is_empty())
+}
+
+/// PEM files of the certificate chain and private key which the remote
+/// listener uses for TLS.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Listener for connections from other machines, see `bind`.
+pub struct RemoteListener {
+    tcp: TcpListener,
+    tls: Option<SslAcceptor>,
+}
+
+/// Listens for connections from other machines, which are forwarded to the
+/// daemon's unix socket once they've sent the token. With TLS files, the
+/// connections use TLS. Without them, only loopback addresses are allowed,
+/// for use with a TLS terminator such as stunnel, or an SSH tunnel. Clients
+/// connect to loopback addresses without TLS, so TLS can't be used with
+/// those.
+pub fn bind(addr: SocketAddr, tls_files: Option<&TlsFiles>) -> Result<RemoteListener, Error> {
+    let tls = match (tls_files, addr.ip().is_loopback()) {
+        (Some(_), true) => bail!(
+            "TLS isn't used for loopback addresses, since clients connect to those without it, \
+             but got {}",
+            addr
+        ),
+        (None, false) => bail!(
+            "The remote listener must either be given a TLS certificate and key, or listen on \
+             a loopback address, with a TLS terminator such as stunnel forwarding to it. Got {}",
+            addr
+        ),
+        (Some(tls_files), false) => Some(tls_acceptor(tls_files)?),
+        (None, true) => None,
+    };
+    let tcp = TcpListener::bind(addr).context(format_err!(
+        "Failed to listen for remote daemon requests on {}",
+        addr
+    ))?;
+    Ok(RemoteListener { tcp, tls })
+}
+
+fn tls_acceptor(tls_files: &TlsFiles) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_certificate_chain_file(&tls_files.cert)
+        .context(format_err!(
+            "Failed to load TLS certificate chain from {:?}",
+            tls_files.cert
+        ))?;
+    builder
+        .set_private_key_file(&tls_files.key, SslFiletype::PEM)
+        .context(format_err!(
+            "Failed to load TLS private key from {:?}",
+            tls_files.key
+        ))?;
+    builder
+        .check_private_key()
+        .context("TLS private key doesn't match the certificate")?;
+    Ok(builder.build())
+}
+
+/// Loads the token clients must send, generating one if there isn't one
+/// yet. It's only readable by the user.
+pub fn load_or_create_token(daemon_dir: &DaemonDir) -> Result<String, Error> {
+    let token_file = DaemonTokenFile::new(daemon_dir);
+    if token_file.exists() {
+        return Ok(read_to_string(&token_file)?.trim().to_string());
+    }
+    let mut bytes = [0u8; TOKEN_BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&token_file)?;
+    writeln!(file, "{}", token)?;
+    Ok(token)
+}
+
+/// Serves connections to the remote listener, each on its own thread, up to
+/// `MAX_CONNECTIONS` at once. Requests which aren't allowed remotely get an
+/// error response made by `reject`, rather than being forwarded.
+pub fn serve(
+    listener: RemoteListener,
+    socket_path: DaemonSocketFile,
+    token: String,
+    reject: fn(&Message) -> Option<Vec<u8>>,
+) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream_or_err in listener.tcp.incoming() {
+        let stream = match stream_or_err {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Error accepting remote connection: {}", err);
+                continue;
+            }
+        };
+        let connection = match Connection::start(&connections) {
+            Some(connection) => connection,
+            None => {
+                println!(
+                    "Closing remote connection from {:?}, since {} are already being handled.",
+                    stream.peer_addr().ok(),
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+        };
+        let socket_path = socket_path.clone();
+        let token = token.clone();
+        let tls = listener.tls.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            let peer = stream.peer_addr().ok();
+            let result: Result<(), Error> = try {
+                stream.set_read_timeout(Some(TOKEN_TIMEOUT))?;
+                match tls {
+                    Some(acceptor) => {
+                        let stream = acceptor
+                            .accept(stream)
+                            .map_err(|err| format_err!("TLS handshake failed: {}", err))?;
+                        forward(stream, &socket_path, &token, reject)?
+                    }
+                    None => forward(stream, &socket_path, &token, reject)?,
+                }
+            };
+            if let Err(err) = result {
+                println!("Error while handling remote client {:?}: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Checks the token on the first line, and then forwards the request which
+/// follows it to the daemon, copying back everything it responds with. The
+/// stream is expected to have `TOKEN_TIMEOUT` as its read timeout.
+fn forward<S: Read + Write + Timeouts>(
+    stream: S,
+    socket_path: &DaemonSocketFile,
+    token: &str,
+    reject: fn(&Message) -> Option<Vec<u8>>,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream);
+    let mut sent_token = String::new();
+    (&mut reader)
+        .take(MAX_TOKEN_LINE_BYTES)
+        .read_line(&mut sent_token)?;
+    reader.get_ref().set_timeouts(None, None)?;
+    if !constant_time_eq(sent_token.trim_end().as_bytes(), token.as_bytes()) {
+        bail!("Client sent the wrong token.");
+    }
+    let message = ipc::read_message(&mut reader)?;
+    if let Some(response) = reject(&message) {
+        reader.get_mut().write_all(&response)?;
+        return Ok(());
+    }
+    let mut daemon = UnixStream::connect(socket_path)?;
+    daemon.write_all(&message.to_bytes())?;
+    io::copy(&mut daemon, reader.get_mut())?;
+    Ok(())
+}
+
+impl<S: Read + Write + Timeouts> Timeouts for SslStream<S> {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_timeouts(send, recv)
+    }
+}
+
+/// Counts a connection being handled for as long as it's alive.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    /// Counts a new connection, unless `MAX_CONNECTIONS` are already being
+    /// handled.
+    fn start(connections: &Arc<AtomicUsize>) -> Option<Connection> {
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Connection(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Compares the tokens without bailing out at the first difference, so that
+/// timing doesn't reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Connection to a remote daemon, see `connect`.
+pub enum RemoteStream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for RemoteStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RemoteStream::Plain(stream) => stream.read(buf),
+            RemoteStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for RemoteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RemoteStream::Plain(stream) => stream.write(buf),
+            RemoteStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RemoteStream::Plain(stream) => stream.flush(),
+            RemoteStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Timeouts for RemoteStream {
+    fn set_timeouts(&self, send: Option<Duration>, recv: Option<Duration>) -> io::Result<()> {
+        match self {
+            RemoteStream::Plain(stream) => stream.set_timeouts(send, recv),
+            RemoteStream::Tls(stream) => stream.set_timeouts(send, recv),
+        }
+    }
+}
+
+/// Connects to the remote daemon, sending the token from `TOKEN_VAR`. TLS is
+/// used unless the daemon is at a loopback address, verifying its
+/// certificate against the CAs in `CA_FILE_VAR` if it's set, otherwise the
+/// system's.
+pub fn connect(host: &str) -> Result<RemoteStream, Error> {
+    let token = match env::var(TOKEN_VAR) {
+        Ok(token) => token,
+        Err(_) => bail!(
+            "{} must be set to the token in the remote daemon's daemon/remote-token file.",
+            TOKEN_VAR
+        ),
+    };
+    let tcp_stream = TcpStream::connect(host).context(format_err!(
+        "Failed to connect to the mzr daemon at {}",
+        host
+    ))?;
+    let mut stream = if tcp_stream.peer_addr()?.ip().is_loopback() {
+        RemoteStream::Plain(tcp_stream)
+    } else {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(ca_file) = env::var_os(CA_FILE_VAR) {
+            builder.set_ca_file(&ca_file).context(format_err!(
+                "Failed to load CA certificates from {:?}, given by {}",
+                ca_file,
+                CA_FILE_VAR
+            ))?;
+        }
+        let stream = builder
+            .build()
+            .connect(host_name(host), tcp_stream)
+            .map_err(|err| {
+                format_err!(
+                    "TLS handshake with the mzr daemon at {} failed: {}",
+                    host,
+                    err
+                )
+            })?;
+        RemoteStream::Tls(stream)
+    };
+    stream.write_all(token.trim().as_bytes())?;
+    stream.write_all(b"\n")?;
+    Ok(stream)
+}
+
+/// The host of `HOST:PORT`, which the daemon's certificate is checked
+/// against.
+fn host_name(host: &str) -> &str {
+    let name = match host.rfind(':') {
+        Some(ix) => &host[..ix],
+        None => host,
+    };
+    name.trim_start_matches('[').trim_end_matches(']')
+}
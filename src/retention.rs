@@ -0,0 +1,204 @@
+use crate::immutable;
+use crate::json;
+use crate::merge_txn;
+use crate::paths::*;
+use crate::run_info::RunInfo;
+use crate::snapshot::{self, SnapInfo};
+use crate::top_dirs::TopDirs;
+use crate::zone::Zone;
+use chrono::{Duration, Utc};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{remove_dir_all, remove_file};
+
+/// Rules for automatically removing snapshots, zones and merge backups, applied by
+/// `mzr gc --auto`. Rules which are `None` are not applied.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Number of snapshots to keep for each git branch, where versioned
+    /// snapshots like `BRANCH_v2` are considered to be of the same branch as
+    /// `BRANCH`. The most recently created ones are kept. Snapshots used by
+    /// zones are never removed.
+    pub keep_snapshots_per_branch: Option<usize>,
+    /// Number of days after which the temporary zones created by `mzr run`
+    /// are removed, along with their snapshots.
+    pub max_run_zone_age_days: Option<i64>,
+    /// Number of merge backups to keep for `mzr merge --undo-last`. The most
+    /// recent ones are kept.
+    #[serde(default)]
+    pub keep_merge_backups: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Loads the retention policy, which has no rules if it hasn't been set.
+    pub fn load(mzr_dir: &MzrDir) -> Result<RetentionPolicy, Error> {
+        let policy_file = RetentionPolicyFile::new(mzr_dir);
+        if policy_file.exists() {
+            Ok(json::read(&policy_file)?.contents)
+        } else {
+            Ok(RetentionPolicy::default())
+        }
+    }
+
+    pub fn write(&self, mzr_dir: &MzrDir) -> Result<(), Error> {
+        json::write(&RetentionPolicyFile::new(mzr_dir), self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keep_snapshots_per_branch.is_none()
+            && self.max_run_zone_age_days.is_none()
+            && self.keep_merge_backups.is_none()
+    }
+}
+
+impl Display for RetentionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self.keep_snapshots_per_branch {
+            None => write!(f, "keep all snapshots")?,
+            Some(n) => write!(f, "keep {} snapshot(s) per branch", n)?,
+        }
+        match self.max_run_zone_age_days {
+            None => write!(f, ", keep all run zones")?,
+            Some(n) => write!(f, ", remove run zones after {} day(s)", n)?,
+        }
+        match self.keep_merge_backups {
+            None => write!(f, ", keep all merge backups"),
+            Some(n) => write!(f, ", keep {} merge backup(s)", n),
+        }
+    }
+}
+
+/// Something that the retention policy says to remove.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Removal {
+    Zone(ZoneName),
+    Snapshot(SnapName),
+    MergeBackup(String),
+}
+
+impl Display for Removal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Removal::Zone(zone_name) => write!(f, "zone {}", zone_name),
+            Removal::Snapshot(snap_name) => write!(f, "snapshot {}", snap_name),
+            Removal::MergeBackup(name) => write!(f, "merge backup {}", name),
+        }
+    }
+}
+
+/// Determines what the policy says to remove. Zones in `in_use` are kept
+/// regardless of the policy, as are the snapshots they use and pinned
+/// snapshots. When the mzr dir is shared, other users' zones can't be seen,
+/// so `keep_snapshots_per_branch` isn't applied, since it could remove
+/// snapshots they use.
+pub fn plan(
+    mzr_dir: &MzrDir,
+    policy: &RetentionPolicy,
+    in_use: &HashSet<ZoneName>,
+) -> Result<Vec<Removal>, Error> {
+    let mut removals = Vec::new();
+    let mut used_snapshots = HashSet::new();
+    let mut run_snapshots = HashSet::new();
+    let now = Utc::now();
+    for zone_name in Zone::list_names(mzr_dir)? {
+        let zone = Zone::load(mzr_dir, &zone_name)?;
+        let is_expired_run = match (policy.max_run_zone_age_days, in_use.contains(&zone_name)) {
+            (Some(days), false) => match RunInfo::load(mzr_dir, &zone_name)? {
+                Some(run_info) => now - run_info.start_time > Duration::days(days),
+                None => false,
+            },
+            _ => false,
+        };
+        if is_expired_run {
+            // Runs use a snapshot of the same name, only for that run.
+            if zone.info.snapshot.as_str() == zone_name.as_str() {
+                run_snapshots.insert(zone.info.snapshot.as_str().to_string());
+            }
+            removals.push(Removal::Zone(zone_name));
+        } else {
+            used_snapshots.insert(zone.info.snapshot.as_str().to_string());
+        }
+    }
+    let mut branches: BTreeMap<String, Vec<(SnapName, SnapInfo)>> = BTreeMap::new();
+    for snap_name in snapshot::list_names(mzr_dir)? {
+        if used_snapshots.contains(snap_name.as_str()) {
+            continue;
+        }
+        let info = SnapInfo::load(mzr_dir, &snap_name)?;
+        if info.pinned {
+            continue;
+        }
+        if run_snapshots.contains(snap_name.as_str()) {
+            removals.push(Removal::Snapshot(snap_name));
+            continue;
+        }
+        branches
+            .entry(branch_name(&snap_name))
+            .or_insert_with(Vec::new)
+            .push((snap_name, info));
+    }
+    let shared = SharedFile::new(mzr_dir).exists();
+    if let (Some(keep), false) = (policy.keep_snapshots_per_branch, shared) {
+        for (_, mut snapshots) in branches {
+            // Most recently created first.
+            snapshots.sort_by(|(_, a), (_, b)| b.creation_time.cmp(&a.creation_time));
+            for (snap_name, _) in snapshots.into_iter().skip(keep) {
+                removals.push(Removal::Snapshot(snap_name));
+            }
+        }
+    }
+    if let Some(keep) = policy.keep_merge_backups {
+        let backups = merge_txn::list_backups(mzr_dir)?;
+        let excess = backups.len().saturating_sub(keep);
+        for name in backups.into_iter().take(excess) {
+            removals.push(Removal::MergeBackup(name));
+        }
+    }
+    Ok(removals)
+}
+
+/// Removes the zones, snapshots and merge backups.
+pub fn apply(top_dirs: &TopDirs, removals: &[Removal]) -> Result<(), Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    for removal in removals {
+        match removal {
+            Removal::Zone(zone_name) => {
+                Zone::load(mzr_dir, zone_name)?.remove(&top_dirs.user_work_dir)?;
+            }
+            Removal::Snapshot(snap_name) => {
+                let snap_dir = SnapDir::new(mzr_dir, snap_name);
+                if snap_dir.exists() && SnapInfo::load(mzr_dir, snap_name)?.immutable {
+                    immutable::set_tree(&snap_dir, false)?;
+                }
+                remove_dir_all(&snap_dir)?;
+                let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+                if info_file.exists() {
+                    remove_file(&info_file)?;
+                }
+                let manifest_file = SnapManifestFile::new(mzr_dir, snap_name);
+                if manifest_file.exists() {
+                    remove_file(&manifest_file)?;
+                }
+            }
+            Removal::MergeBackup(name) => {
+                remove_dir_all(MergeBackupsDir::new(mzr_dir).join(name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name of the branch that a snapshot was taken of, which is its name without
+/// any `_vN` suffix.
+fn branch_name(snap_name: &SnapName) -> String {
+    let name = snap_name.as_str();
+    if let Some(ix) = name.rfind("_v") {
+        let suffix = &name[ix + 2..];
+        if !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()) {
+            return name[..ix].to_string();
+        }
+    }
+    name.to_string()
+}
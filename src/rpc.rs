@@ -0,0 +1,190 @@
+use crate::paths::*;
+use crate::subscriptions::Topic;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// A JSON-RPC 2.0 request, for clients such as editor plugins which drive
+/// the daemon directly rather than by running mzr commands.
+///
+/// These are sent over the daemon's socket, `MZR_DIR/daemon/socket`, as a
+/// single line of JSON, and the daemon replies with a single line containing
+/// the `RpcResponse`. Like mzr's own requests, each connection carries one
+/// request, except for `subscribe`. Batches and notifications from clients
+/// aren't supported, so `id` is always echoed back, being `null` if it was
+/// omitted.
+///
+/// When the daemon is started with `--remote-addr`, requests can also be
+/// sent over TCP, with TLS unless it's a loopback address, by first sending a
+/// line containing the token in `MZR_DIR/daemon/remote-token`.
+///
+/// Methods and their `params`:
+///
+/// * `ping` - no params. Yields the daemon's version, pid, uptime in
+///   seconds, and number of zone processes.
+///
+/// * `zones.list` - no params. Yields the zones, each with its snapshot,
+///   creation time, number of changed paths, open shells, whether its
+///   process is running, and where it's mounted via `zones.mount`.
+///
+/// * `snapshots.list` - no params. Yields the snapshots, each with its
+///   version, creation time, git commit, whether it's pinned, the zones which
+///   are of it, and whether any of them are mounted.
+///
+/// * `daemon.info` - no params. Yields what `ping` does, along with the mzr
+///   and work dirs, the zones which are mounted or running, where zones are
+///   mounted via `zones.mount`, and the number of open shells.
+///
+/// * `zones.mount` - `{"zone": NAME, "target": PATH}`. Mounts the zone's
+///   view of the work dir at the target directory, like `mzr mount`. As
+///   with it, this requires the daemon to be started with `--expose-zones`.
+///
+/// * `zones.unmount` - `{"target": PATH}`. Unmounts a zone mounted via
+///   `zones.mount`.
+///
+/// * `zones.stop` - `{"zone": NAME}`. Stops the zone's processes and
+///   unmounts it. It gets started again when next used.
+///
+/// * `zones.remove` - `{"zone": NAME}`. Removes the zone, discarding its
+///   changes.
+///
+/// * `zones.merge` - `{"zone": NAME}`, optionally with `"target_dir"`,
+///   `"paths"`, `"excludes"`, and `"dry_run"`, which behave like the options
+///   of `mzr merge`, except that `paths` are relative to the work dir. Yields
+///   `{"summary": ..., "report": ...}`, where the report is the plan as
+///   printed by `mzr merge --format json`.
+///
+/// * `shells.list` - no params. Yields the shells registered by `mzr
+///   shell`, with their pids, zones, and when they were opened.
+///
+/// * `metrics` - no params. Yields the daemon's metrics, along with the disk
+///   usage of each zone.
+///
+/// * `subscribe` - optionally `{"topics": [TOPIC, ...], "change_threshold":
+///   N}`. Keeps the connection open after the response, and sends a line for
+///   each event, being a JSON-RPC notification with the method `notify` and
+///   params such as `{"event": "zone-changed", "zone": NAME,
+///   "changed_paths": 12}`. The topics are `zone-mounted`, `zone-unmounted`,
+///   `zone-changed`, and `merged`, defaulting to all of them. `zone-changed`
+///   is sent once at least `change_threshold` paths have changed in a zone,
+///   which defaults to 1.
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    pub jsonrpc: &'static str,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    pub fn new(id: Value, result: Result<Value, RpcError>) -> RpcResponse {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Error codes defined by JSON-RPC 2.0, along with `FAILED` for methods
+/// which mzr failed to carry out.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const FAILED: i64 = -32000;
+
+impl RpcError {
+    pub fn new(code: i64, message: String) -> RpcError {
+        RpcError { code, message }
+    }
+}
+
+/// Whether a line received by the daemon is a JSON-RPC request, rather than
+/// one of mzr's own requests, which are never JSON objects with a `jsonrpc`
+/// field.
+pub fn is_rpc_request(line: &[u8]) -> bool {
+    match serde_json::from_slice::<Value>(line) {
+        Ok(Value::Object(fields)) => fields.contains_key("jsonrpc"),
+        _ => false,
+    }
+}
+
+/// Parses the params of a method, which are an object with named fields.
+pub fn parse_params<T>(params: &Value) -> Result<T, RpcError>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params.clone(),
+    };
+    serde_json::from_value(params)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", err)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ZoneParams {
+    pub zone: ZoneName,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MountParams {
+    pub zone: ZoneName,
+    pub target: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnmountParams {
+    pub target: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeParams {
+    #[serde(default)]
+    pub topics: Vec<Topic>,
+    #[serde(default = "default_change_threshold")]
+    pub change_threshold: usize,
+}
+
+fn default_change_threshold() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeParams {
+    pub zone: ZoneName,
+    #[serde(default)]
+    pub target_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub force: bool,
+}
@@ -0,0 +1,136 @@
+use crate::cgroups::Usage;
+use crate::colors::*;
+use crate::json;
+use crate::merge::PlanSummary;
+use crate::paths::*;
+use crate::zone::Zone;
+use chrono::{DateTime, Utc};
+use failure::Error;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// Name used for both the temporary snapshot and zone of a `mzr run`
+/// invocation, based on the PID of the process.
+pub fn tmp_run_name(pid: Pid) -> String {
+    format!("run-{}", pid)
+}
+
+/// Record of a `mzr run` invocation, stored within its temporary zone so that
+/// runs can be inspected after the fact.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub zone: ZoneName,
+    pub snapshot: SnapName,
+    pub start_time: DateTime<Utc>,
+    pub duration_millis: u64,
+    pub exit_code: Option<i32>,
+    pub exit_signal: Option<i32>,
+    pub plan: PlanSummary,
+    /// Peak resource usage, recorded when the run had resource limits.
+    #[serde(default)]
+    pub resource_usage: Option<Usage>,
+    /// Zone that the run was invoked within, which its zone was layered on
+    /// and its changes were merged into.
+    #[serde(default)]
+    pub parent_zone: Option<ZoneName>,
+}
+
+impl RunInfo {
+    pub fn new(
+        cmd: &str,
+        args: &[String],
+        zone: &Zone,
+        start_time: DateTime<Utc>,
+        duration: Duration,
+        status: ExitStatus,
+        plan: PlanSummary,
+    ) -> RunInfo {
+        RunInfo {
+            cmd: cmd.to_string(),
+            args: args.to_vec(),
+            zone: zone.name.clone(),
+            snapshot: zone.info.snapshot.clone(),
+            start_time,
+            duration_millis: duration.as_secs() * 1000 + u64::from(duration.subsec_millis()),
+            exit_code: status.code(),
+            exit_signal: status.signal(),
+            plan,
+            resource_usage: None,
+            parent_zone: None,
+        }
+    }
+
+    pub fn write(&self, zone_dir: &ZoneDir) -> Result<(), Error> {
+        json::write(&RunInfoFile::new(zone_dir), self)
+    }
+
+    /// Loads the record of the run associated with a zone, if there is one.
+    pub fn load(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Option<RunInfo>, Error> {
+        let run_info_file = RunInfoFile::new(&ZoneDir::new(mzr_dir, zone_name));
+        if run_info_file.exists() {
+            Ok(Some(json::read(&run_info_file)?.contents))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Finds the record of the run that was most recently started, if any.
+    pub fn load_last(mzr_dir: &MzrDir) -> Result<Option<RunInfo>, Error> {
+        let mut last: Option<RunInfo> = None;
+        for zone_name in Zone::list_names(mzr_dir)? {
+            let info = match RunInfo::load(mzr_dir, &zone_name)? {
+                None => continue,
+                Some(info) => info,
+            };
+            let is_later = match &last {
+                None => true,
+                Some(other) => info.start_time > other.start_time,
+            };
+            if is_later {
+                last = Some(info);
+            }
+        }
+        Ok(last)
+    }
+}
+
+impl Display for RunInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Run of {} in zone {} (snapshot {}",
+            color_cmd(&self.cmd),
+            self.zone,
+            self.snapshot
+        )?;
+        if let Some(parent_zone) = &self.parent_zone {
+            write!(f, ", layered on zone {}", parent_zone)?;
+        }
+        write!(f, "), started at {}, ", self.start_time)?;
+        match (self.exit_code, self.exit_signal) {
+            (Some(0), _) => write!(f, "{}", color_success(&"succeeded"))?,
+            (Some(code), _) => write!(f, "exited with code {}", color_err(&code))?,
+            (None, Some(signal)) => write!(f, "was killed by signal {}", color_err(&signal))?,
+            (None, None) => write!(f, "exited with unknown status")?,
+        }
+        write!(
+            f,
+            " after {}.{:03}s.\nMerge plan had {} update(s), {} conflict(s), and {} skip(s).",
+            self.duration_millis / 1000,
+            self.duration_millis % 1000,
+            self.plan.updates,
+            self.plan.conflicts,
+            self.plan.skips
+        )?;
+        if let Some(usage) = &self.resource_usage {
+            write!(f, "\nResource usage: {}.", usage)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use crate::colors::*;
+use crate::daemon;
+use crate::merge::{interactive_merge, Mode};
+use crate::paths::*;
+use crate::run_info::{tmp_run_name, RunInfo};
+use crate::top_dirs::TopDirs;
+use crate::zone::Zone;
+use failure::{Error, ResultExt};
+use libc::pid_t;
+use nix::unistd::Pid;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Outcome of running one command of the matrix.
+struct JobResult {
+    cmd: String,
+    zone_name: Option<ZoneName>,
+    outcome: Result<ExitStatus, Error>,
+}
+
+/// Reads the commands listed in a matrix file. Each non-empty line that
+/// doesn't start with `#` is a command, which gets run via `sh -c`.
+pub fn read_matrix_file(path: &PathBuf) -> Result<Vec<String>, Error> {
+    let file = File::open(path).context(format_err!(
+        "Failed to open matrix file {}",
+        color_file(&path.display())
+    ))?;
+    let mut cmds = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            cmds.push(trimmed.to_string());
+        }
+    }
+    Ok(cmds)
+}
+
+/// Runs each command in its own temporary zone, by invoking `mzr run` as a
+/// child process for each. At most `jobs` commands run at once. Output of
+/// each command gets prefixed with its zone name. `run_args` are passed to
+/// each `mzr run` before the command, and are expected to include
+/// `--snapshot` for the snapshot they share, and `--no-merge`.
+///
+/// Once they've all exited, their zones are merged one at a time, in the
+/// order the commands were given, so that the merges don't race. Then a
+/// summary gets printed.
+///
+/// Yields `true` if all of the commands succeeded.
+pub fn run(
+    top_dirs: &TopDirs,
+    cmds: Vec<String>,
+    jobs: usize,
+    run_args: Vec<String>,
+) -> Result<bool, Error> {
+    let mzr_exe = env::current_exe()?;
+    let job_count = cmds.len();
+    let queue = Arc::new(Mutex::new(
+        cmds.into_iter().enumerate().rev().collect::<Vec<_>>(),
+    ));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let mut workers = Vec::new();
+    for _ in 0..jobs.max(1).min(job_count) {
+        let queue = queue.clone();
+        let results = results.clone();
+        let mzr_exe = mzr_exe.clone();
+        let run_args = run_args.clone();
+        let work_dir = top_dirs.user_work_dir.clone();
+        workers.push(thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop();
+            match next {
+                None => break,
+                Some((ix, cmd)) => {
+                    let result = run_job(&mzr_exe, &work_dir, &run_args, cmd);
+                    results.lock().unwrap().push((ix, result));
+                }
+            }
+        }));
+    }
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| format_err!("Thread running matrix commands panicked."))?;
+    }
+    let mut results = Arc::try_unwrap(results)
+        .map_err(|_| format_err!("Unexpected error: matrix results still shared."))?
+        .into_inner()
+        .map_err(|_| format_err!("Unexpected error: matrix results lock poisoned."))?;
+    results.sort_by_key(|(ix, _)| *ix);
+    for (_, result) in results.iter() {
+        if let (Some(zone_name), Ok(_)) = (&result.zone_name, &result.outcome) {
+            merge_job(top_dirs, zone_name)?;
+        }
+    }
+    println!();
+    println!("Summary of {} command(s):", job_count);
+    let mut all_succeeded = true;
+    for (_, result) in results {
+        let succeeded = print_job_summary(&top_dirs.mzr_dir, &result)?;
+        all_succeeded = all_succeeded && succeeded;
+    }
+    Ok(all_succeeded)
+}
+
+fn run_job(
+    mzr_exe: &PathBuf,
+    work_dir: &UserWorkDir,
+    run_args: &[String],
+    cmd: String,
+) -> JobResult {
+    let mut zone_name = None;
+    let outcome: Result<ExitStatus, Error> = try {
+        let mut child = Command::new(mzr_exe)
+            .current_dir(work_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("run")
+            .args(run_args)
+            .arg("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .spawn()
+            .context(format_err!("Failed to start mzr run for {:?}", cmd))?;
+        // The child `mzr run` names its temporary zone after its PID.
+        let name = ZoneName::new(tmp_run_name(Pid::from_raw(child.id() as pid_t)))?;
+        let prefix = format!("[{}] ", name);
+        zone_name = Some(name);
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| format_err!("Unexpected error: child has no stdout."))?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| format_err!("Unexpected error: child has no stderr."))?;
+        let stdout_thread = spawn_prefixer(prefix.clone(), stdout);
+        let stderr_thread = spawn_prefixer(prefix, stderr);
+        let status = child.wait()?;
+        let _ = stdout_thread.join();
+        let _ = stderr_thread.join();
+        status
+    };
+    JobResult {
+        cmd,
+        zone_name,
+        outcome,
+    }
+}
+
+/// Merges the changes of a command's zone into the work dir, and updates its
+/// run's record with the merge plan.
+fn merge_job(top_dirs: &TopDirs, zone_name: &ZoneName) -> Result<(), Error> {
+    let mut run_info = match RunInfo::load(&top_dirs.mzr_dir, zone_name)? {
+        Some(run_info) => run_info,
+        // The run didn't get as far as running the command.
+        None => return Ok(()),
+    };
+    let zone = Zone::load(&top_dirs.mzr_dir, zone_name)?;
+    println!("Merging zone {}", zone_name);
+    run_info.plan = interactive_merge(
+        &zone,
+        top_dirs.user_work_dir.as_ref(),
+        Mode::AutoApplyUpdates,
+    )?;
+    run_info.write(&zone.zone_dir)?;
+    if let Err(err) = daemon::record_merge(&top_dirs.mzr_dir, zone_name, &run_info.plan) {
+        println!(
+            "{} failed to record merge in daemon metrics: {}",
+            color_warn(&"Warning:"),
+            err
+        );
+    }
+    Ok(())
+}
+
+fn spawn_prefixer<R: Read + Send + 'static>(
+    prefix: String,
+    source: R,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            handle.write_all(prefix.as_bytes())?;
+            handle.write_all(&line)?;
+        }
+    })
+}
+
+fn print_job_summary(mzr_dir: &MzrDir, result: &JobResult) -> Result<bool, Error> {
+    let zone_name = match &result.zone_name {
+        Some(zone_name) => zone_name,
+        None => {
+            println!("* {:?} failed to start", result.cmd);
+            return Ok(false);
+        }
+    };
+    match &result.outcome {
+        Err(err) => {
+            println!(
+                "* [{}] {:?} {} {}",
+                zone_name,
+                result.cmd,
+                color_err(&"error:"),
+                err
+            );
+            Ok(false)
+        }
+        Ok(status) => {
+            match RunInfo::load(mzr_dir, zone_name)? {
+                Some(run_info) => println!(
+                    "* [{}] {:?} exited with {}, merge plan had {} update(s), {} conflict(s), and {} skip(s).",
+                    zone_name,
+                    result.cmd,
+                    status,
+                    run_info.plan.updates,
+                    run_info.plan.conflicts,
+                    run_info.plan.skips
+                ),
+                None => println!(
+                    "* [{}] {:?} exited with {}, without recording a merge plan.",
+                    zone_name, result.cmd, status
+                ),
+            }
+            Ok(status.success())
+        }
+    }
+}
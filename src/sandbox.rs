@@ -0,0 +1,383 @@
+use failure::{Error, ResultExt};
+use libc::{c_int, c_long, c_ulong, c_void};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+// Landlock syscall numbers, which are the same on all architectures.
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+
+// Syscall numbers of the mount API added in Linux 5.2, along with clone3 and
+// mount_setattr, which are also the same on all architectures.
+const SYS_OPEN_TREE: c_long = 428;
+const SYS_MOVE_MOUNT: c_long = 429;
+const SYS_FSOPEN: c_long = 430;
+const SYS_FSCONFIG: c_long = 431;
+const SYS_FSMOUNT: c_long = 432;
+const SYS_FSPICK: c_long = 433;
+const SYS_CLONE3: c_long = 435;
+const SYS_MOUNT_SETATTR: c_long = 442;
+
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+const LANDLOCK_CREATE_RULESET_VERSION: c_int = 1;
+
+// Filesystem access rights from <linux/landlock.h>, excluding the ones for
+// reading and executing, which aren't restricted.
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// Requires ABI version 2. Unless handled, renames and links across
+// directories fail with `EXDEV`.
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+// Requires ABI version 3. Unless handled, truncation isn't restricted.
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const WRITE_ACCESS: u64 = LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_REMOVE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_CHAR
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_FIFO
+    | LANDLOCK_ACCESS_FS_MAKE_BLOCK
+    | LANDLOCK_ACCESS_FS_MAKE_SYM;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// Constants for seccomp filters, from <linux/seccomp.h>, <linux/filter.h>,
+// and <linux/audit.h>.
+const PR_SET_SECCOMP: c_int = 22;
+const SECCOMP_MODE_FILTER: c_ulong = 2;
+const SECCOMP_RET_KILL: u32 = 0;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_JMP_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+// Offset of the lower half of the first syscall argument, which is where it
+// is on little-endian architectures.
+const SECCOMP_DATA_ARG0_LOW_OFFSET: u32 = 16;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+// Set in the numbers of x32 syscalls, which have the x86_64 architecture.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Syscalls which fail with `EPERM` under the seccomp filter. None of these
+/// are needed by builds, and they are ways to affect things outside the zone.
+const DENIED_SYSCALLS: &[c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    SYS_OPEN_TREE,
+    SYS_MOVE_MOUNT,
+    SYS_FSOPEN,
+    SYS_FSCONFIG,
+    SYS_FSMOUNT,
+    SYS_FSPICK,
+    SYS_MOUNT_SETATTR,
+];
+
+/// Flags which make `clone` create namespaces, which it's denied with, like
+/// `unshare`. `CLONE_NEWTIME` isn't included, since `clone` doesn't support
+/// it, and uses its bit for the exit signal.
+const CLONE_NEW_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+/// Restrictions for a command run by `mzr run --sandbox`. A Landlock ruleset
+/// only allows filesystem writes within particular directories, and
+/// optionally a seccomp filter denies syscalls that have effects beyond the
+/// filesystem.
+///
+/// The restrictions are prepared in the parent process, and then applied in
+/// the child process just before it execs the command.
+pub struct Sandbox {
+    ruleset_fd: RawFd,
+    write_access: u64,
+    seccomp_filter: Option<Vec<SockFilter>>,
+}
+
+// The filter is only read, and the ruleset fd is only used by the child.
+unsafe impl Send for Sandbox {}
+unsafe impl Sync for Sandbox {}
+
+impl Sandbox {
+    pub fn new(writable_dirs: &[PathBuf], seccomp: bool) -> Result<Sandbox, Error> {
+        let write_access = write_access();
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: write_access,
+        };
+        let ruleset_fd = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const LandlockRulesetAttr,
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset_fd < 0 {
+            Err(io::Error::last_os_error()).context(
+                "Failed to create Landlock ruleset. Sandboxing requires Linux 5.13 or later, \
+                 with Landlock enabled.",
+            )?;
+        }
+        let sandbox = Sandbox {
+            ruleset_fd: ruleset_fd as RawFd,
+            write_access,
+            seccomp_filter: if seccomp {
+                Some(seccomp_filter())
+            } else {
+                None
+            },
+        };
+        for dir in writable_dirs {
+            sandbox
+                .allow_writes_beneath(dir)
+                .context(format_err!("Failed to allow writes within {:?}", dir))?;
+        }
+        Ok(sandbox)
+    }
+
+    fn allow_writes_beneath(&self, dir: &PathBuf) -> Result<(), Error> {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let dir_fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if dir_fd < 0 {
+            Err(io::Error::last_os_error())?;
+        }
+        let attr = LandlockPathBeneathAttr {
+            allowed_access: self.write_access,
+            parent_fd: dir_fd,
+        };
+        let result = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                self.ruleset_fd,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const LandlockPathBeneathAttr,
+                0,
+            )
+        };
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(dir_fd);
+        }
+        if result != 0 {
+            Err(err)?;
+        }
+        Ok(())
+    }
+
+    /// Restricts the current process and its future children. This is called
+    /// between fork and exec, so it only makes syscalls.
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe {
+            // Required for unprivileged processes to restrict themselves.
+            if libc::prctl(
+                libc::PR_SET_NO_NEW_PRIVS,
+                1 as c_ulong,
+                0 as c_ulong,
+                0 as c_ulong,
+                0 as c_ulong,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, self.ruleset_fd, 0 as c_int) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(filter) = &self.seccomp_filter {
+                let program = SockFprog {
+                    len: filter.len() as u16,
+                    filter: filter.as_ptr(),
+                };
+                let program_ptr = &program as *const SockFprog as *const c_void;
+                if libc::prctl(
+                    PR_SET_SECCOMP,
+                    SECCOMP_MODE_FILTER,
+                    program_ptr,
+                    0 as c_ulong,
+                    0 as c_ulong,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.ruleset_fd);
+        }
+    }
+}
+
+/// Version of the Landlock ABI supported by the kernel, or `None` if
+/// Landlock isn't available.
+pub fn landlock_abi_version() -> Option<i64> {
+    let version = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0 as libc::size_t,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if version > 0 {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// The access rights to restrict, which includes the ones added by later
+/// versions of the Landlock ABI when the kernel supports them.
+fn write_access() -> u64 {
+    let version = landlock_abi_version().unwrap_or(1);
+    let mut access = WRITE_ACCESS;
+    if version >= 2 {
+        access |= LANDLOCK_ACCESS_FS_REFER;
+    }
+    if version >= 3 {
+        access |= LANDLOCK_ACCESS_FS_TRUNCATE;
+    }
+    access
+}
+
+/// Builds a BPF program which makes the denied syscalls fail with `EPERM`,
+/// and kills processes which make syscalls using a different architecture's
+/// calling convention, since those would have different numbers. This
+/// includes x32 syscalls, which share the x86_64 architecture but have
+/// `X32_SYSCALL_BIT` set.
+///
+/// `clone` is denied when it's passed any of `CLONE_NEW_FLAGS`. The flags
+/// of `clone3` are in memory, which seccomp filters can't read, so it fails
+/// with `ENOSYS` instead, which makes libc fall back to `clone`.
+fn seccomp_filter() -> Vec<SockFilter> {
+    let statement = |code, k| SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH_OFFSET),
+        SockFilter {
+            code: BPF_JMP_JEQ_K,
+            jt: 1,
+            jf: 0,
+            k: AUDIT_ARCH,
+        },
+        statement(BPF_RET_K, SECCOMP_RET_KILL),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR_OFFSET),
+        SockFilter {
+            code: BPF_JMP_JGE_K,
+            jt: 0,
+            jf: 1,
+            k: X32_SYSCALL_BIT,
+        },
+        statement(BPF_RET_K, SECCOMP_RET_KILL),
+    ];
+    for syscall in DENIED_SYSCALLS {
+        // If the syscall matches, fall through to returning the error,
+        // otherwise skip it.
+        filter.push(SockFilter {
+            code: BPF_JMP_JEQ_K,
+            jt: 0,
+            jf: 1,
+            k: *syscall as u32,
+        });
+        filter.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    filter.push(SockFilter {
+        code: BPF_JMP_JEQ_K,
+        jt: 0,
+        jf: 1,
+        k: SYS_CLONE3 as u32,
+    });
+    filter.push(statement(
+        BPF_RET_K,
+        SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+    ));
+    // If it's clone, check its flags, otherwise skip to allowing it. This
+    // loads over the syscall number, so it comes last.
+    filter.push(SockFilter {
+        code: BPF_JMP_JEQ_K,
+        jt: 0,
+        jf: 3,
+        k: libc::SYS_clone as u32,
+    });
+    filter.push(statement(BPF_LD_W_ABS, SECCOMP_DATA_ARG0_LOW_OFFSET));
+    filter.push(SockFilter {
+        code: BPF_JMP_JSET_K,
+        jt: 0,
+        jf: 1,
+        k: CLONE_NEW_FLAGS,
+    });
+    filter.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter
+}
@@ -0,0 +1,226 @@
+use crate::colors::*;
+use crate::utils::{confirm, run_process, Confirmed};
+use crate::version::{TARGET, VERSION};
+use failure::{Error, ResultExt};
+use semver::Version;
+use serde::Deserialize;
+use std::env;
+use std::fs::{remove_file, rename, set_permissions, File, Permissions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Location of the latest release, which is expected to provide a `version`
+/// file with its semver, and for each target a `mzr-TARGET` binary along
+/// with a `mzr-TARGET.manifest` file, see `ReleaseManifest`, and a
+/// `mzr-TARGET.manifest.sig` detached signature of the manifest.
+pub const DEFAULT_RELEASE_URL: &str = "https://github.com/mgsloan/mzr/releases/latest/download";
+
+/// Environment variable which overrides `DEFAULT_RELEASE_URL`.
+pub const RELEASE_URL_VAR: &str = "MZR_RELEASE_URL";
+
+/// Describes a release binary. Its signature covers the version and target
+/// along with the checksum, so that a validly signed binary can't be passed
+/// off as a different version, such as to downgrade to one with known
+/// vulnerabilities, or as a binary for a different target.
+#[derive(Debug, Deserialize)]
+struct ReleaseManifest {
+    version: String,
+    target: String,
+    sha256: String,
+}
+
+pub struct UpdateOptions {
+    pub release_url: Option<String>,
+    /// Keyring used to verify the signature of the manifest via `gpgv`.
+    pub signing_key: Option<PathBuf>,
+    /// Whether to install the release without a signing key, in which case
+    /// the manifest isn't verified.
+    pub insecure: bool,
+    /// Whether to install the release even if it isn't newer.
+    pub force: bool,
+}
+
+/// Replaces the current executable with the latest release. The new binary
+/// is downloaded next to the current one and checked before being renamed
+/// over it, so the replacement is atomic.
+pub fn self_update(options: &UpdateOptions) -> Result<(), Error> {
+    let release_url = options
+        .release_url
+        .clone()
+        .or_else(|| env::var(RELEASE_URL_VAR).ok())
+        .unwrap_or_else(|| DEFAULT_RELEASE_URL.to_string());
+    let release_url = release_url.trim_end_matches('/');
+    if options.signing_key.is_none() && !options.insecure {
+        bail!(
+            "Releases must have their signature verified, so a keyring is needed. \
+             Pass {} KEYRING, or {} to only verify the checksum.",
+            color_cmd(&"--signing-key"),
+            color_cmd(&"--insecure")
+        );
+    }
+    let current = Version::parse(VERSION)?;
+    let latest_string = download_string(&format!("{}/version", release_url))?;
+    let latest = Version::parse(latest_string.trim()).context(format_err!(
+        "Failed to parse version of latest release: {:?}",
+        latest_string
+    ))?;
+    if latest <= current && !options.force {
+        println!(
+            "mzr {} is up to date (the latest release is {}).",
+            current, latest
+        );
+        return Ok(());
+    }
+    let exe = env::current_exe()?
+        .canonicalize()
+        .context("Failed to find the path of the current mzr executable.")?;
+    let exe_dir = match exe.parent() {
+        Some(dir) => dir,
+        None => bail!("Expected mzr executable {:?} to have a parent.", exe),
+    };
+    let asset_url = format!("{}/mzr-{}", release_url, TARGET);
+    let temp_files = TempFiles::new(exe_dir);
+    println!("Downloading {}", color_file(&asset_url));
+    download_file(&asset_url, &temp_files.binary)?;
+    let manifest_url = format!("{}.manifest", asset_url);
+    let manifest_contents = download_string(&manifest_url)?;
+    if let Some(signing_key) = &options.signing_key {
+        File::create(&temp_files.manifest)?.write_all(manifest_contents.as_bytes())?;
+        download_file(&format!("{}.sig", manifest_url), &temp_files.signature)?;
+        run_process(
+            Command::new("gpgv")
+                .arg("--keyring")
+                .arg(signing_key)
+                .arg(&temp_files.signature)
+                .arg(&temp_files.manifest),
+        )
+        .context("Failed to verify the signature of the release manifest.")?;
+        println!("{}", color_success(&"Verified signature of manifest."));
+    } else {
+        println!(
+            "{} not verifying the signature of the release, since {} was passed.",
+            color_warn(&"Warning:"),
+            color_cmd(&"--insecure")
+        );
+    }
+    let manifest: ReleaseManifest = serde_json::from_str(&manifest_contents).context(
+        format_err!("Failed to parse release manifest {}", manifest_url),
+    )?;
+    // The version file isn't signed, so it's only trusted once it matches
+    // the manifest.
+    let signed_version = Version::parse(&manifest.version).context(format_err!(
+        "Failed to parse version in release manifest: {:?}",
+        manifest.version
+    ))?;
+    if signed_version != latest {
+        bail!(
+            "Release manifest is for version {}, but the latest release is {}.",
+            signed_version,
+            latest
+        );
+    }
+    if manifest.target != TARGET {
+        bail!(
+            "Release manifest is for target {}, but this mzr is for {}.",
+            manifest.target,
+            TARGET
+        );
+    }
+    let expected = manifest.sha256.to_lowercase();
+    let actual = sha256sum(&temp_files.binary)?;
+    if actual != expected {
+        bail!(
+            "Checksum of downloaded binary is {}, but expected {}.",
+            actual,
+            expected
+        );
+    }
+    println!("{}", color_success(&"Verified checksum of binary."));
+    let query = format!("Replace mzr {} at {:?} with mzr {}", current, exe, latest);
+    match confirm(&query)? {
+        Confirmed::Yes => {}
+        Confirmed::No => bail!("Update cancelled."),
+    }
+    set_permissions(&temp_files.binary, Permissions::from_mode(0o755))?;
+    rename(&temp_files.binary, &exe).context(format_err!(
+        "Failed to replace {:?}. Is it writable by your user?",
+        exe
+    ))?;
+    println!("Updated mzr to version {}.", color_success(&latest));
+    Ok(())
+}
+
+/// Files used during the update, which are removed when dropped if they
+/// still exist.
+struct TempFiles {
+    binary: PathBuf,
+    manifest: PathBuf,
+    signature: PathBuf,
+}
+
+impl TempFiles {
+    fn new(dir: &Path) -> TempFiles {
+        let prefix = format!(".mzr-self-update-{}", std::process::id());
+        TempFiles {
+            binary: dir.join(&prefix),
+            manifest: dir.join(format!("{}.manifest", prefix)),
+            signature: dir.join(format!("{}.manifest.sig", prefix)),
+        }
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &[&self.binary, &self.manifest, &self.signature] {
+            if path.exists() {
+                let _ = remove_file(path);
+            }
+        }
+    }
+}
+
+fn curl(url: &str) -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.stdin(Stdio::null())
+        .args(&["--fail", "--silent", "--show-error", "--location"])
+        .arg(url);
+    cmd
+}
+
+fn download_file(url: &str, path: &Path) -> Result<(), Error> {
+    run_process(curl(url).arg("--output").arg(path))
+        .context(format_err!("Failed to download {}", url))?;
+    Ok(())
+}
+
+fn download_string(url: &str) -> Result<String, Error> {
+    let output = curl(url)
+        .stderr(Stdio::inherit())
+        .output()
+        .context(format_err!("Failed to run curl to download {}", url))?;
+    if !output.status.success() {
+        bail!(
+            "Failed to download {}: curl exited with {}",
+            url,
+            output.status
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn sha256sum(path: &Path) -> Result<String, Error> {
+    let output = Command::new("sha256sum")
+        .stdin(Stdio::null())
+        .arg(path)
+        .output()
+        .context("Failed to run sha256sum")?;
+    if !output.status.success() {
+        bail!("sha256sum exited with {}", output.status);
+    }
+    match String::from_utf8(output.stdout)?.split_whitespace().next() {
+        Some(checksum) => Ok(checksum.to_lowercase()),
+        None => bail!("Unexpected empty output from sha256sum."),
+    }
+}
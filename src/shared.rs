@@ -0,0 +1,156 @@
+use crate::json;
+use crate::paths::*;
+use failure::{Error, ResultExt};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::CString;
+use std::fs::{
+    create_dir, create_dir_all, rename, set_permissions, symlink_metadata, DirBuilder, Permissions,
+};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Names of the directories within the mzr dir which have a subdirectory
+/// for each user when it's shared, only accessible to that user.
+const PER_USER_DIRS: &[&str] = &["zone", "daemon", "mnt"];
+
+/// Record that the mzr dir is shared between the users of a unix group,
+/// written by `mzr share`. Snapshots, objects and configuration are shared
+/// by the group, while each user's zones and daemon are kept in their own
+/// subdirectories, see `ZonesDir` and `DaemonDir`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedInfo {
+    pub group: String,
+    pub gid: u32,
+}
+
+impl SharedInfo {
+    /// Loads the record, which only exists if the mzr dir is shared.
+    pub fn load(mzr_dir: &MzrDir) -> Result<Option<SharedInfo>, Error> {
+        let shared_file = SharedFile::new(mzr_dir);
+        if shared_file.exists() {
+            Ok(Some(json::read(&shared_file)?.contents))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Name of the user whose zones and daemon are used when the mzr dir is
+/// shared. This comes from `USER` rather than the uid, since it's the same
+/// within zones and the daemon's user namespace, where the user is root.
+/// Names which aren't usable as a directory name fall back on the uid.
+pub fn current_user() -> String {
+    match env::var("USER") {
+        Ok(ref user) if !user.is_empty() && !user.contains('/') && !user.starts_with('.') => {
+            user.clone()
+        }
+        _ => Uid::current().to_string(),
+    }
+}
+
+/// Creates one of the current user's directories, which is only accessible
+/// to them when the mzr dir is shared.
+pub fn create_user_dir(mzr_dir: &MzrDir, dir: &Path) -> Result<(), Error> {
+    if SharedFile::new(mzr_dir).exists() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    } else {
+        create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Shares the mzr dir with the group. Everything is given to the group and
+/// made group-writable, with directories being setgid so that files created
+/// within them also belong to the group. Existing zones and daemon files
+/// are moved into the current user's subdirectories.
+pub fn share(mzr_dir: &MzrDir, group: &str) -> Result<SharedInfo, Error> {
+    if let Some(info) = SharedInfo::load(mzr_dir)? {
+        bail!(
+            "The mzr directory {} is already shared with group {}.",
+            mzr_dir,
+            info.group
+        );
+    }
+    let info = SharedInfo {
+        group: group.to_string(),
+        gid: lookup_group(group)?,
+    };
+    let user = current_user();
+    for name in PER_USER_DIRS.iter() {
+        let dir = mzr_dir.join(name);
+        if dir.is_dir() {
+            // Moved aside first, since a zone may have the user's name.
+            let moving_dir = mzr_dir.join(format!("{}.moving", name));
+            rename(&dir, &moving_dir)?;
+            create_dir(&dir)?;
+            rename(&moving_dir, dir.join(&user))?;
+        } else {
+            create_dir_all(dir.join(&user))?;
+        }
+    }
+    let per_user_dirs: Vec<PathBuf> = PER_USER_DIRS
+        .iter()
+        .map(|name| mzr_dir.join(name))
+        .collect();
+    let mut entries = WalkDir::new(mzr_dir).same_file_system(true).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let path = entry.path();
+        let is_per_user_parent = per_user_dirs.iter().any(|dir| dir == path);
+        if path.parent().map_or(false, |parent| {
+            per_user_dirs.iter().any(|dir| dir == parent)
+        }) {
+            // Only the user can access their own zones and daemon.
+            set_permissions(path, Permissions::from_mode(0o700))?;
+            entries.skip_current_dir();
+            continue;
+        }
+        share_entry(path, info.gid, is_per_user_parent).context(format_err!(
+            "Failed to share {:?} with group {}",
+            path,
+            group
+        ))?;
+    }
+    json::write(&SharedFile::new(mzr_dir), &info)?;
+    share_entry(&SharedFile::new(mzr_dir), info.gid, false)?;
+    Ok(info)
+}
+
+/// Gives the entry to the group, with the group getting the same access as
+/// the user. Directories holding per-user subdirectories are also sticky,
+/// so that users can't remove each other's.
+fn share_entry(path: &Path, gid: u32, is_per_user_parent: bool) -> Result<(), Error> {
+    let path_cstring = CString::new(path.as_os_str().as_bytes())?;
+    // A uid of -1 leaves the user unchanged.
+    if unsafe { libc::lchown(path_cstring.as_ptr(), !0, gid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let metadata = symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mut mode = metadata.mode() & 0o7777;
+    mode |= (mode & 0o700) >> 3;
+    if metadata.is_dir() {
+        mode |= 0o2000;
+        if is_per_user_parent {
+            mode |= 0o1000;
+        }
+    }
+    set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+fn lookup_group(group: &str) -> Result<u32, Error> {
+    let group_cstring = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(group_cstring.as_ptr()) };
+    if entry.is_null() {
+        bail!("There is no group named {:?}.", group);
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
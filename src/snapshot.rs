@@ -1,21 +1,578 @@
 use crate::colors::*;
+use crate::config::Config;
+use crate::errors::{kind_error, ErrorKind};
+use crate::events::{self, EventKind};
+use crate::git;
+use crate::hg;
+use crate::hooks::{self, Hook};
+use crate::immutable;
+use crate::jj;
+use crate::json;
+use crate::objects;
 use crate::paths::*;
+use crate::project_set::ProjectSet;
+use crate::snapshot_manifest;
 use crate::top_dirs::TopDirs;
-use crate::utils::run_process;
+use crate::utils::{run_process, run_process_output};
+use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
-use std::fs::create_dir_all;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir, create_dir_all, metadata, read_dir, set_permissions, symlink_metadata};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapInfo {
+    /// Starts at 1, and is incremented each time the snapshot is updated.
+    pub version: u32,
+    pub creation_time: DateTime<Utc>,
+    pub update_time: Option<DateTime<Utc>>,
+    /// Git commit that the work dir was at when the snapshot was taken or
+    /// last updated, if it's a git repository.
+    #[serde(default)]
+    pub git_commit: Option<String>,
+    /// Git directories within the snapshot, relative to its root. Their
+    /// shared contents, such as objects and refs, aren't copied into the
+    /// snapshot, since zones share them with the work dir. `None` for
+    /// snapshots taken before this was recorded, which have complete copies.
+    #[serde(default)]
+    pub git_dirs: Option<Vec<PathBuf>>,
+    /// jj directories within the snapshot, relative to its root, whose
+    /// `repo` dirs are shared with the work dir like those of `git_dirs`.
+    /// `None` for snapshots taken before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jj_dirs: Option<Vec<PathBuf>>,
+    /// Mercurial directories within the snapshot, relative to its root,
+    /// whose stores are shared with the work dir. `None` for snapshots taken
+    /// before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hg_dirs: Option<Vec<PathBuf>>,
+    /// Whether the snapshot is protected from being removed by the retention
+    /// policy, set by `mzr snap pin`.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Git commit of each member of the project set which is a git
+    /// repository, when the mzr dir has one. See `ProjectSet`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub member_commits: BTreeMap<PathBuf, String>,
+    /// Subtrees of the work dir which a sparse snapshot, taken by `mzr snap
+    /// --path`, is limited to, relative to it. Zones of sparse snapshots
+    /// only overlay these subtrees, see `Zone::bind_to`. Empty when the
+    /// snapshot is of the whole work dir.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<PathBuf>,
+    /// Whether the snapshot's files have the immutable attribute set, which
+    /// is cleared while mzr modifies them. See `make_immutable`.
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+impl SnapInfo {
+    /// Loads the snapshot's info. Snapshots taken before info files existed
+    /// are treated as being at version 1, created when the directory was
+    /// last modified.
+    pub fn load(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapInfo, Error> {
+        let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+        if info_file.exists() {
+            Ok(json::read(&info_file)?.contents)
+        } else {
+            let snap_dir = SnapDir::new(mzr_dir, snap_name);
+            Ok(SnapInfo {
+                version: 1,
+                creation_time: DateTime::from(metadata(&snap_dir)?.modified()?),
+                update_time: None,
+                git_commit: None,
+                git_dirs: None,
+                jj_dirs: None,
+                hg_dirs: None,
+                pinned: false,
+                member_commits: BTreeMap::new(),
+                paths: Vec::new(),
+                immutable: false,
+            })
+        }
+    }
+
+    pub fn write(&self, mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+        let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+        if let Some(parent) = info_file.parent() {
+            create_dir_all(parent)?;
+        }
+        json::write(&info_file, self)
+    }
+}
+
 pub fn of_workdir(top_dirs: &TopDirs, snap_name: &SnapName) -> Result<SnapDir, Error> {
-    create(&top_dirs.user_work_dir, &top_dirs.mzr_dir, snap_name)
+    of_workdir_paths(top_dirs, snap_name, &[])
+}
+
+/// Takes a snapshot of the work dir, which is sparse if paths are given,
+/// only including those subtrees. The paths are relative to the work dir.
+pub fn of_workdir_paths(
+    top_dirs: &TopDirs,
+    snap_name: &SnapName,
+    paths: &[PathBuf],
+) -> Result<SnapDir, Error> {
+    let project_set = ProjectSet::load(&top_dirs.mzr_dir)?;
+    let git_dirs = find_git_dirs(&top_dirs.user_work_dir, &project_set, paths)?;
+    let jj_dirs = find_jj_dirs(&top_dirs.user_work_dir, &project_set, paths, &git_dirs);
+    let hg_dirs = find_hg_dirs(&top_dirs.user_work_dir, &project_set, paths);
+    let mut excluded = shared_git_paths(&git_dirs);
+    excluded.extend(shared_jj_paths(&jj_dirs));
+    excluded.extend(shared_hg_paths(&hg_dirs));
+    let snap_dir = if paths.is_empty() {
+        if let Some(project_set) = &project_set {
+            excluded.extend(project_set.non_members(&top_dirs.user_work_dir)?);
+        }
+        create(
+            &top_dirs.user_work_dir,
+            &top_dirs.mzr_dir,
+            snap_name,
+            &excluded,
+        )?
+    } else {
+        create_sparse(
+            &top_dirs.user_work_dir,
+            &top_dirs.mzr_dir,
+            snap_name,
+            paths,
+            &excluded,
+        )?
+    };
+    SnapInfo {
+        version: 1,
+        creation_time: Utc::now(),
+        update_time: None,
+        git_commit: git::head_sha(&top_dirs.user_work_dir).ok(),
+        git_dirs: Some(git_dirs),
+        jj_dirs: Some(jj_dirs),
+        hg_dirs: Some(hg_dirs),
+        pinned: false,
+        member_commits: project_set
+            .map(|project_set| project_set.git_commits(&top_dirs.user_work_dir))
+            .unwrap_or_default(),
+        paths: paths.to_vec(),
+        immutable: false,
+    }
+    .write(&top_dirs.mzr_dir, snap_name)?;
+    if Config::load(&top_dirs.mzr_dir)?.immutable_snapshots {
+        make_immutable(&top_dirs.mzr_dir, snap_name)?;
+    }
+    snapshot_manifest::record(&top_dirs.mzr_dir, snap_name)?;
+    events::record(
+        &top_dirs.mzr_dir,
+        EventKind::SnapshotCreated {
+            snapshot: snap_name.clone(),
+        },
+    );
+    hooks::run_or_warn(
+        &top_dirs.mzr_dir,
+        Hook::SnapshotCreate,
+        &[("MZR_HOOK_SNAPSHOT", snap_name.as_str())],
+    );
+    Ok(snap_dir)
+}
+
+/// Brings an existing snapshot up to date with the work dir, copying only the
+/// files which have changed, and deleting files which have been removed.
+///
+/// Note that zones using the snapshot will see its new contents, and overlayfs
+/// doesn't expect lower dirs to be modified while mounted. So `mzr snap
+/// --update` refuses to update snapshots which zones use, unless forced.
+pub fn update_from_workdir(top_dirs: &TopDirs, snap_name: &SnapName) -> Result<SnapInfo, Error> {
+    let snap_dir = SnapDir::new(&top_dirs.mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        return Err(kind_error(
+            ErrorKind::SnapshotNotFound,
+            format!(
+                "Can't update snapshot {}, since it doesn't exist.",
+                snap_name
+            ),
+        ));
+    }
+    let mut info = SnapInfo::load(&top_dirs.mzr_dir, snap_name)?;
+    if info.immutable {
+        immutable::set_tree(&snap_dir, false)?;
+    }
+    let project_set = ProjectSet::load(&top_dirs.mzr_dir)?;
+    let git_dirs = find_git_dirs(&top_dirs.user_work_dir, &project_set, &info.paths)?;
+    let jj_dirs = find_jj_dirs(
+        &top_dirs.user_work_dir,
+        &project_set,
+        &info.paths,
+        &git_dirs,
+    );
+    let mut shared_paths = shared_git_paths(&git_dirs);
+    // Like git dirs, copies of jj repos are left in place in snapshots which
+    // predate recording them.
+    if info.jj_dirs.is_some() {
+        shared_paths.extend(shared_jj_paths(&jj_dirs));
+    }
+    let hg_dirs = find_hg_dirs(&top_dirs.user_work_dir, &project_set, &info.paths);
+    if info.hg_dirs.is_some() {
+        shared_paths.extend(shared_hg_paths(&hg_dirs));
+    }
+    if info.paths.is_empty() {
+        let mut excluded = shared_paths;
+        if let Some(project_set) = &project_set {
+            excluded.extend(project_set.non_members(&top_dirs.user_work_dir)?);
+        }
+        rsync_into(&top_dirs.user_work_dir, &snap_dir, &excluded)?;
+    } else {
+        // Each subtree is synced separately, so that the rest of the work
+        // dir isn't copied.
+        for path in info.paths.iter() {
+            let excluded = shared_paths
+                .iter()
+                .filter_map(|shared_path| shared_path.strip_prefix(path).ok())
+                .map(Path::to_path_buf)
+                .collect();
+            rsync_into(
+                &top_dirs.user_work_dir.join(path),
+                &snap_dir.join(path),
+                &excluded,
+            )?;
+        }
+    }
+    info.version += 1;
+    // Copies of shared git paths taken by older versions of mzr are left in
+    // place, and so the git dirs are only recorded for new snapshots.
+    if info.git_dirs.is_some() {
+        info.git_dirs = Some(git_dirs);
+    }
+    if info.jj_dirs.is_some() {
+        info.jj_dirs = Some(jj_dirs);
+    }
+    if info.hg_dirs.is_some() {
+        info.hg_dirs = Some(hg_dirs);
+    }
+    info.update_time = Some(Utc::now());
+    info.git_commit = git::head_sha(&top_dirs.user_work_dir).ok();
+    if let Some(project_set) = &project_set {
+        info.member_commits = project_set.git_commits(&top_dirs.user_work_dir);
+    }
+    if info.immutable {
+        immutable::set_tree(&snap_dir, true)?;
+    }
+    info.write(&top_dirs.mzr_dir, snap_name)?;
+    snapshot_manifest::record(&top_dirs.mzr_dir, snap_name)?;
+    Ok(info)
+}
+
+/// Sets the immutable attribute on the snapshot's files, so that nothing can
+/// modify them, warning if that isn't permitted. This is in addition to
+/// `snapshot_manifest::check_unmodified`, which detects modifications.
+/// Making the files read-only instead isn't an option, since zones would
+/// see the modes of their snapshot's files.
+pub fn make_immutable(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+    let mut info = SnapInfo::load(mzr_dir, snap_name)?;
+    if immutable::set_tree(&SnapDir::new(mzr_dir, snap_name), true)? {
+        info.immutable = true;
+        info.write(mzr_dir, snap_name)?;
+    } else {
+        // Undoes setting it on some of the files.
+        immutable::set_tree(&SnapDir::new(mzr_dir, snap_name), false)?;
+        println!(
+            "{} not making snapshot {} immutable, since setting the immutable attribute on its \
+             files isn't permitted.",
+            color_warn(&"Warning:"),
+            snap_name
+        );
+    }
+    Ok(())
+}
+
+/// Runs the action with the snapshots' immutable attributes cleared, for
+/// when mzr modifies snapshots without changing their contents, such as
+/// when deduplicating their files. The snapshots are then marked as
+/// unmodified.
+pub fn with_writable<T, F>(mzr_dir: &MzrDir, snap_names: &[SnapName], action: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let mut immutable_dirs = Vec::new();
+    for snap_name in snap_names {
+        if SnapInfo::load(mzr_dir, snap_name)?.immutable {
+            let snap_dir = SnapDir::new(mzr_dir, snap_name);
+            immutable::set_tree(&snap_dir, false)?;
+            immutable_dirs.push(snap_dir);
+        }
+    }
+    let result = action();
+    for snap_dir in immutable_dirs {
+        immutable::set_tree(&snap_dir, true)?;
+    }
+    if result.is_ok() {
+        for snap_name in snap_names {
+            snapshot_manifest::mark_unmodified(mzr_dir, snap_name)?;
+        }
+    }
+    result
+}
+
+/// Syncs the target with the source via rsync, deleting files which are no
+/// longer in the source. The excluded paths are relative to the source.
+///
+/// rsync replaces files whose contents changed with new files, but changes
+/// the metadata of others in place. So first, a dry run finds the latter,
+/// and those which are hardlinked into the objects store are unshared.
+fn rsync_into(source: &Path, target: &Path, excluded: &BTreeSet<PathBuf>) -> Result<(), Error> {
+    let source_is_dir = source.is_dir();
+    let mut dry_run = rsync_cmd(source, target, excluded);
+    dry_run
+        .arg("--dry-run")
+        // Lines like ".f...p..... PATH" for files whose permissions change.
+        .arg("--out-format=%i %n");
+    for line in run_process_output(&mut dry_run)?.lines() {
+        // Only regular files whose contents are unchanged, which are
+        // indicated by "." rather than ">" for being transferred.
+        if !line.starts_with(".f") || line.len() <= 12 {
+            continue;
+        }
+        let path = if source_is_dir {
+            target.join(&line[12..])
+        } else {
+            target.to_path_buf()
+        };
+        // Names with unusual characters are escaped by rsync, so may not be
+        // found, in which case they aren't hardlinks made by mzr.
+        if symlink_metadata(&path).is_ok() {
+            objects::unshare(&path)?;
+        }
+    }
+    run_process(&mut rsync_cmd(source, target, excluded))?;
+    Ok(())
+}
+
+fn rsync_cmd(source: &Path, target: &Path, excluded: &BTreeSet<PathBuf>) -> Command {
+    let mut source_arg = source.as_os_str().to_os_string();
+    let mut target_arg = target.as_os_str().to_os_string();
+    // Trailing slashes cause rsync to sync the contents of the directories,
+    // rather than the directories themselves.
+    if source.is_dir() {
+        source_arg.push("/");
+        target_arg.push("/");
+    }
+    let mut cmd_base = Command::new("rsync");
+    let cmd = cmd_base
+        .stdin(Stdio::null())
+        // Recursive, and preserve symlinks, permissions, timestamps, and
+        // special files. Preserving timestamps is particularly important,
+        // since merging relies on them.
+        .arg("--archive")
+        .arg("--hard-links")
+        // Delete files from the snapshot which are no longer in the work dir.
+        .arg("--delete");
+    for excluded_path in excluded {
+        cmd.arg(format!("--exclude=/{}", excluded_path.display()));
+    }
+    cmd.arg(source_arg).arg(target_arg);
+    cmd_base
+}
+
+/// Sets whether the snapshot is pinned, yielding whether it was pinned
+/// before.
+pub fn set_pinned(mzr_dir: &MzrDir, snap_name: &SnapName, pinned: bool) -> Result<bool, Error> {
+    if !exists(mzr_dir, snap_name) {
+        return Err(kind_error(
+            ErrorKind::SnapshotNotFound,
+            format!("Snapshot {} does not exist.", snap_name),
+        ));
+    }
+    let mut info = SnapInfo::load(mzr_dir, snap_name)?;
+    let was_pinned = info.pinned;
+    info.pinned = pinned;
+    info.write(mzr_dir, snap_name)?;
+    Ok(was_pinned)
 }
 
-fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapDir, Error> {
+pub fn exists(mzr_dir: &MzrDir, snap_name: &SnapName) -> bool {
+    SnapDir::new(mzr_dir, snap_name).exists()
+}
+
+/// Lists the names of all snapshots, in no particular order.
+///
+/// Snapshot names may contain slashes, such as those named after git branches,
+/// so directories are descended into when there are info files within the
+/// corresponding directory of `snap-info`. Snapshots taken before info files
+/// existed are recognized by being top level directories which don't contain
+/// any snapshots.
+pub fn list_names(mzr_dir: &MzrDir) -> Result<Vec<SnapName>, Error> {
+    let snaps_dir = SnapsDir::new(mzr_dir);
+    let mut names = Vec::new();
+    if snaps_dir.is_dir() {
+        list_names_within(mzr_dir, &snaps_dir, &PathBuf::new(), &mut names)?;
+    }
+    Ok(names)
+}
+
+fn list_names_within(
+    mzr_dir: &MzrDir,
+    snaps_dir: &SnapsDir,
+    rel_dir: &PathBuf,
+    names: &mut Vec<SnapName>,
+) -> Result<(), Error> {
+    let dir = snaps_dir.join(rel_dir);
+    for entry in read_dir(&dir).context(format_err!(
+        "Unexpected error while listing snapshots in {}",
+        color_dir(&dir.display())
+    ))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let rel_path = rel_dir.join(entry.file_name());
+        let name = SnapName::new(
+            rel_path
+                .to_str()
+                .ok_or_else(|| format_err!("Snapshot name {:?} is not valid unicode.", rel_path))?
+                .to_string(),
+        )?;
+        let is_top_level = rel_dir.as_os_str().is_empty();
+        if SnapInfoFile::new(mzr_dir, &name).exists() {
+            names.push(name);
+        } else if SnapInfosDir::new(mzr_dir).join(&rel_path).is_dir() {
+            let names_before = names.len();
+            list_names_within(mzr_dir, snaps_dir, &rel_path, names)?;
+            if is_top_level && names.len() == names_before {
+                names.push(name);
+            }
+        } else if is_top_level {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Yields the first of `NAME_v2`, `NAME_v3`, ... which isn't already used by
+/// a snapshot.
+pub fn next_versioned_name(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapName, Error> {
+    let mut version = 2;
+    loop {
+        let candidate = SnapName::new(format!("{}_v{}", snap_name.as_str(), version))?;
+        if !exists(mzr_dir, &candidate) {
+            return Ok(candidate);
+        }
+        version += 1;
+    }
+}
+
+/// Finds the git directories in the work dir, relative to it. Only those
+/// within the paths of a sparse snapshot, or within members of the project
+/// set, are included.
+fn find_git_dirs(
+    work_dir: &UserWorkDir,
+    project_set: &Option<ProjectSet>,
+    paths: &[PathBuf],
+) -> Result<Vec<PathBuf>, Error> {
+    Ok(git::find_git_dirs(work_dir)?
+        .into_iter()
+        .map(|rel_git_dir| rel_git_dir.to_path_buf())
+        .filter(|git_dir| is_snapshotted(git_dir, project_set, paths))
+        .collect())
+}
+
+/// Finds the jj directories in the work dir, relative to it, which are
+/// included like those of `find_git_dirs`.
+fn find_jj_dirs(
+    work_dir: &UserWorkDir,
+    project_set: &Option<ProjectSet>,
+    paths: &[PathBuf],
+    git_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    jj::find_jj_dirs(work_dir, git_dirs)
+        .into_iter()
+        .filter(|jj_dir| is_snapshotted(jj_dir, project_set, paths))
+        .collect()
+}
+
+/// Finds the Mercurial directories at the root of the work dir, or of the
+/// project set's members, which are included like those of `find_git_dirs`.
+fn find_hg_dirs(
+    work_dir: &UserWorkDir,
+    project_set: &Option<ProjectSet>,
+    paths: &[PathBuf],
+) -> Vec<PathBuf> {
+    let candidates = match project_set {
+        Some(project_set) => project_set.members.clone(),
+        None => vec![PathBuf::new()],
+    };
+    hg::find_hg_dirs(work_dir, &candidates)
+        .into_iter()
+        .filter(|hg_dir| is_snapshotted(hg_dir, project_set, paths))
+        .collect()
+}
+
+/// Whether the path, relative to the work dir, is within a snapshot of the
+/// paths, or of the whole work dir or project set if there are none.
+fn is_snapshotted(rel_path: &Path, project_set: &Option<ProjectSet>, paths: &[PathBuf]) -> bool {
+    match project_set {
+        _ if !paths.is_empty() => paths.iter().any(|path| rel_path.starts_with(path)),
+        Some(project_set) => project_set.member_of(rel_path).is_some(),
+        None => true,
+    }
+}
+
+/// Paths within the git directories which zones share with the work dir, and
+/// so don't need to be copied into snapshots.
+fn shared_git_paths(git_dirs: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    for git_dir in git_dirs {
+        for shared_path in git::SHARED_REPO_PATHS.iter() {
+            paths.insert(git_dir.join(shared_path));
+        }
+    }
+    paths
+}
+
+/// Paths of repository contents which are shared with the work dir rather
+/// than copied into the snapshot, relative to it. These are needed to make
+/// the snapshot self-contained, such as when exporting it.
+pub fn shared_repo_paths(info: &SnapInfo) -> BTreeSet<PathBuf> {
+    let no_dirs = Vec::new();
+    let mut paths = shared_git_paths(info.git_dirs.as_ref().unwrap_or(&no_dirs));
+    paths.extend(shared_jj_paths(info.jj_dirs.as_ref().unwrap_or(&no_dirs)));
+    paths.extend(shared_hg_paths(info.hg_dirs.as_ref().unwrap_or(&no_dirs)));
+    paths
+}
+
+/// Paths within the jj directories which zones share with the work dir.
+fn shared_jj_paths(jj_dirs: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    for jj_dir in jj_dirs {
+        for shared_path in jj::SHARED_REPO_PATHS.iter() {
+            paths.insert(jj_dir.join(shared_path));
+        }
+    }
+    paths
+}
+
+/// Paths within the Mercurial directories which zones share with the work
+/// dir.
+fn shared_hg_paths(hg_dirs: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    for hg_dir in hg_dirs {
+        for shared_path in hg::SHARED_REPO_PATHS.iter() {
+            paths.insert(hg_dir.join(shared_path));
+        }
+    }
+    paths
+}
+
+fn create(
+    source_dir: &PathBuf,
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    excluded: &BTreeSet<PathBuf>,
+) -> Result<SnapDir, Error> {
     let snap_dir = &SnapDir::new(mzr_dir, snap_name);
     if snap_dir.exists() {
         // TODO(friendliness): Should suggest "mzr rm" feature once it exists.
-        bail!("A snapshot named {} already exists.", snap_name);
+        return Err(kind_error(
+            ErrorKind::SnapshotExists,
+            format!("A snapshot named {} already exists.", snap_name),
+        ));
     }
     let snap_parent = snap_dir
         .parent()
@@ -24,6 +581,10 @@ fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Resul
         "Unexpected error while creating snapshot parent directory {}",
         color_dir(&snap_parent.display())
     ))?;
+    if !excluded.is_empty() {
+        copy_excluding(source_dir, snap_dir, &PathBuf::new(), excluded)?;
+        return Ok(snap_dir.clone());
+    }
     let mut cmd_base = Command::new("cp");
     let cmd = cmd_base
         .stdin(Stdio::null())
@@ -50,3 +611,84 @@ fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Resul
     // TODO(cleanup): Can this clone be avoided?
     Ok(snap_dir.clone())
 }
+
+/// Creates a sparse snapshot, containing only the paths, along with the
+/// directories leading to them.
+fn create_sparse(
+    source_dir: &PathBuf,
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    paths: &[PathBuf],
+    excluded: &BTreeSet<PathBuf>,
+) -> Result<SnapDir, Error> {
+    let snap_dir = &SnapDir::new(mzr_dir, snap_name);
+    if snap_dir.exists() {
+        return Err(kind_error(
+            ErrorKind::SnapshotExists,
+            format!("A snapshot named {} already exists.", snap_name),
+        ));
+    }
+    create_dir_all(snap_dir).context(format_err!(
+        "Unexpected error while creating snapshot directory {}",
+        color_dir(&snap_dir.display())
+    ))?;
+    for path in paths {
+        // Directories leading to the path get the permissions of the work
+        // dir's, but none of their other entries.
+        let mut ancestors: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            let target = snap_dir.join(ancestor);
+            if !target.exists() {
+                create_dir(&target)?;
+                set_permissions(&target, metadata(source_dir.join(ancestor))?.permissions())?;
+            }
+        }
+        let source = source_dir.join(path);
+        let target = snap_dir.join(path);
+        if source.is_dir() && excluded.iter().any(|excluded| excluded.starts_with(path)) {
+            copy_excluding(&source, &target, path, excluded)?;
+        } else {
+            let mut cmd = Command::new("cp");
+            cmd.stdin(Stdio::null())
+                // See the comments on the cp invocation in `create`.
+                .arg("--archive")
+                .arg("--reflink=auto")
+                .arg("--no-clobber")
+                .arg("--no-target-directory")
+                .arg(&source)
+                .arg(&target);
+            run_process(&mut cmd)?;
+        }
+    }
+    Ok(snap_dir.clone())
+}
+
+/// Copies the source directory to the target, except for the excluded
+/// paths, which are relative to the work dir. `rel_dir` is the source's path
+/// relative to the work dir. A single rsync invocation copies everything, so
+/// that hardlinks within the copy are preserved.
+fn copy_excluding(
+    source_dir: &Path,
+    target_dir: &Path,
+    rel_dir: &PathBuf,
+    excluded: &BTreeSet<PathBuf>,
+) -> Result<(), Error> {
+    // Fails if the target already exists, like `cp --no-target-directory`.
+    create_dir(target_dir).context(format_err!(
+        "Unexpected error while creating snapshot directory {}",
+        color_dir(&target_dir.display())
+    ))?;
+    let excluded = excluded
+        .iter()
+        .filter_map(|path| path.strip_prefix(rel_dir).ok())
+        .filter(|path| !path.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .collect();
+    run_process(&mut rsync_cmd(source_dir, target_dir, &excluded))?;
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use crate::json;
+use crate::paths::*;
+use crate::snapshot::{self, SnapInfo};
+use crate::snapshot_manifest::{hash_regular_files, SnapManifest};
+use crate::top_dirs::TopDirs;
+use crate::utils::run_process;
+use chrono::Utc;
+use failure::{Error, ResultExt};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read_link, remove_dir_all, rename};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use walkdir::WalkDir;
+
+/// Name of the manifest within exported archives.
+const MANIFEST_NAME: &str = "mzr-manifest.json";
+
+/// Name of the directory storing the snapshot contents within exported
+/// archives.
+const CONTENTS_NAME: &str = "snapshot";
+
+/// Describes the contents of an exported snapshot, so that its integrity can
+/// be checked on import.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub snap_name: SnapName,
+    pub info: SnapInfo,
+    /// Maps paths of regular files, relative to the snapshot, to the git hash
+    /// of their contents.
+    pub files: BTreeMap<PathBuf, String>,
+    /// Maps paths of all entries, relative to the snapshot, to their type and
+    /// permissions. Empty for archives exported before this was recorded.
+    #[serde(default)]
+    pub entries: BTreeMap<PathBuf, EntryMeta>,
+}
+
+/// Metadata of an entry that isn't covered by the hashes of file contents.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// `st_mode` of the entry, which includes its type and permission bits.
+    pub mode: u32,
+    /// Target of the entry, if it's a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<PathBuf>,
+}
+
+/// Exports the snapshot as a zstd compressed tarball, which includes a
+/// manifest of file hashes. Repository contents which the snapshot shares
+/// with the work dir, such as git objects and refs, are included from the
+/// work dir, so that the archive is self-contained.
+pub fn export(top_dirs: &TopDirs, snap_name: &SnapName, archive: &PathBuf) -> Result<(), Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        bail!(
+            "Can't export snapshot {}, since it doesn't exist.",
+            snap_name
+        );
+    }
+    let info = SnapInfo::load(mzr_dir, snap_name)?;
+    let shared_paths: Vec<PathBuf> = snapshot::shared_repo_paths(&info)
+        .into_iter()
+        .filter(|path| top_dirs.user_work_dir.join(path).exists())
+        .collect();
+    let mut files = hash_regular_files(&snap_dir)?;
+    let mut entries = BTreeMap::new();
+    add_entries(&snap_dir, Path::new(""), &mut entries)?;
+    for shared_path in shared_paths.iter() {
+        let source = top_dirs.user_work_dir.join(shared_path);
+        for (path, hash) in hash_regular_files(&source)? {
+            files.insert(join_rel(shared_path, &path), hash);
+        }
+        add_entries(&source, shared_path, &mut entries)?;
+    }
+    let manifest = Manifest {
+        snap_name: snap_name.clone(),
+        // The archive has complete copies of the repositories.
+        info: SnapInfo {
+            git_dirs: None,
+            jj_dirs: None,
+            hg_dirs: None,
+            ..info
+        },
+        files,
+        entries,
+    };
+    let tmp_dir = MzrTmpDir::new(mzr_dir, &format!("export-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result: Result<(), Error> = try {
+        json::write(&tmp_dir.join(MANIFEST_NAME), &manifest)?;
+        run_process(
+            Command::new("tar")
+                .stdin(Stdio::null())
+                .arg("--create")
+                .arg("--zstd")
+                .arg("--xattrs")
+                .arg("--file")
+                .arg(archive)
+                // The manifest comes first, so that it can be read without
+                // decompressing the whole archive.
+                .arg("--directory")
+                .arg(tmp_dir.as_os_str())
+                .arg(MANIFEST_NAME)
+                .arg("--directory")
+                .arg(snap_dir.as_os_str())
+                .arg(format!("--transform=s,^\\.,{},", CONTENTS_NAME))
+                .arg(".")
+                .arg("--directory")
+                .arg(top_dirs.user_work_dir.as_os_str())
+                .args(
+                    shared_paths
+                        .iter()
+                        .map(|path| Path::new(".").join(path).into_os_string()),
+                ),
+        )?;
+    };
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Imports a snapshot from an archive created by `export`, checking that its
+/// contents match the manifest. If no name is specified, the name of the
+/// exported snapshot is used.
+pub fn import(
+    mzr_dir: &MzrDir,
+    archive: &PathBuf,
+    snap_name: &Option<SnapName>,
+) -> Result<SnapName, Error> {
+    let tmp_dir = MzrTmpDir::new(mzr_dir, &format!("import-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result = import_impl(mzr_dir, archive, snap_name, &tmp_dir);
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+fn import_impl(
+    mzr_dir: &MzrDir,
+    archive: &PathBuf,
+    snap_name: &Option<SnapName>,
+    tmp_dir: &MzrTmpDir,
+) -> Result<SnapName, Error> {
+    run_process(
+        Command::new("tar")
+            .stdin(Stdio::null())
+            .arg("--extract")
+            .arg("--zstd")
+            // Otherwise the umask applies to the permissions of entries.
+            .arg("--preserve-permissions")
+            .arg("--xattrs")
+            .arg("--file")
+            .arg(archive)
+            .arg("--directory")
+            .arg(tmp_dir.as_os_str()),
+    )?;
+    let manifest: Manifest = json::read(&tmp_dir.join(MANIFEST_NAME))
+        .context(format_err!(
+            "Failed to read manifest from {:?}. Was it created by mzr snap export?",
+            archive
+        ))?
+        .contents;
+    let snap_name = snap_name.clone().unwrap_or(manifest.snap_name);
+    let snap_dir = SnapDir::new(mzr_dir, &snap_name);
+    if snapshot::exists(mzr_dir, &snap_name) {
+        bail!("A snapshot named {} already exists.", snap_name);
+    }
+    let contents_dir = tmp_dir.join(CONTENTS_NAME);
+    let actual_files = hash_regular_files(&contents_dir)?;
+    let mismatches = count_mismatches(&manifest.files, &actual_files);
+    if mismatches > 0 {
+        bail!(
+            "Contents of the archive don't match its manifest. {} file(s) differ.",
+            mismatches
+        );
+    }
+    if !manifest.entries.is_empty() {
+        let mut actual_entries = BTreeMap::new();
+        add_entries(&contents_dir, Path::new(""), &mut actual_entries)?;
+        let mismatches = count_mismatches(&manifest.entries, &actual_entries);
+        if mismatches > 0 {
+            bail!(
+                "Entries of the archive don't match its manifest. {} entries have a \
+                 different type, permissions or symlink target.",
+                mismatches
+            );
+        }
+    }
+    if let Some(parent) = snap_dir.parent() {
+        create_dir_all(parent)?;
+    }
+    rename(&contents_dir, &snap_dir).context(format_err!(
+        "Failed to move imported snapshot into place at {}",
+        snap_dir
+    ))?;
+    SnapInfo {
+        update_time: Some(Utc::now()),
+        ..manifest.info
+    }
+    .write(mzr_dir, &snap_name)?;
+    // The archive's hashes were just checked against the contents.
+    SnapManifest {
+        files: manifest.files,
+        recorded_time: Some(Utc::now()),
+    }
+    .write(mzr_dir, &snap_name)?;
+    Ok(snap_name)
+}
+
+/// Joins a path relative to the shared path onto it. Shared paths which are
+/// files, like `.git/config`, are walked as themselves, with an empty
+/// relative path, which `join` would give a trailing slash.
+fn join_rel(shared_path: &Path, rel_path: &Path) -> PathBuf {
+    if rel_path.as_os_str().is_empty() {
+        shared_path.to_path_buf()
+    } else {
+        shared_path.join(rel_path)
+    }
+}
+
+/// Adds the metadata of the entries within the root to the map, keyed by
+/// their path relative to the root, joined onto the prefix. The root itself
+/// is only included when the prefix is non-empty.
+fn add_entries(
+    root: &Path,
+    prefix: &Path,
+    entries: &mut BTreeMap<PathBuf, EntryMeta>,
+) -> Result<(), Error> {
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        let path = join_rel(prefix, entry.path().strip_prefix(root)?);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let symlink_target = if entry.file_type().is_symlink() {
+            Some(read_link(entry.path())?)
+        } else {
+            None
+        };
+        entries.insert(
+            path,
+            EntryMeta {
+                mode: entry.metadata()?.mode(),
+                symlink_target,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Counts the paths whose values differ between the maps, including those
+/// only in one of them.
+fn count_mismatches<T: PartialEq>(
+    expected: &BTreeMap<PathBuf, T>,
+    actual: &BTreeMap<PathBuf, T>,
+) -> usize {
+    let all_paths: BTreeSet<&PathBuf> = expected.keys().chain(actual.keys()).collect();
+    all_paths
+        .into_iter()
+        .filter(|path| expected.get(*path) != actual.get(*path))
+        .count()
+}
@@ -0,0 +1,189 @@
+use crate::json;
+use crate::objects::hash_files;
+use crate::paths::*;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// Hashes of a snapshot's regular files, recorded when it's taken or updated,
+/// so that `mzr snap verify` can detect files which have since been modified
+/// or removed. This matters since snapshots are the baseline that merges
+/// compare against.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapManifest {
+    /// Maps paths of regular files, relative to the snapshot, to the git hash
+    /// of their contents.
+    pub files: BTreeMap<PathBuf, String>,
+    /// When the manifest was recorded. Files and directories modified since
+    /// then have been written to after the snapshot was taken, see
+    /// `modified_since_recorded`.
+    #[serde(default)]
+    pub recorded_time: Option<DateTime<Utc>>,
+}
+
+impl SnapManifest {
+    /// Loads the snapshot's manifest, yielding `None` for snapshots taken
+    /// before manifests were recorded.
+    pub fn load(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<Option<SnapManifest>, Error> {
+        let manifest_file = SnapManifestFile::new(mzr_dir, snap_name);
+        if manifest_file.exists() {
+            Ok(Some(json::read(&manifest_file)?.contents))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn write(&self, mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+        let manifest_file = SnapManifestFile::new(mzr_dir, snap_name);
+        if let Some(parent) = manifest_file.parent() {
+            create_dir_all(parent)?;
+        }
+        json::write(&manifest_file, self)
+    }
+}
+
+/// Hashes the snapshot's current contents and records them as its manifest.
+pub fn record(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapManifest, Error> {
+    let manifest = SnapManifest {
+        files: hash_regular_files(&SnapDir::new(mzr_dir, snap_name)).context(format_err!(
+            "Failed to hash files of snapshot {}",
+            snap_name
+        ))?,
+        recorded_time: Some(Utc::now()),
+    };
+    manifest.write(mzr_dir, snap_name)?;
+    Ok(manifest)
+}
+
+/// Marks the snapshot's current state as expected, for after mzr itself has
+/// modified the snapshot without changing its contents, such as when
+/// deduplicating its files.
+pub fn mark_unmodified(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+    if let Some(mut manifest) = SnapManifest::load(mzr_dir, snap_name)? {
+        manifest.recorded_time = Some(Utc::now());
+        manifest.write(mzr_dir, snap_name)?;
+    }
+    Ok(())
+}
+
+/// Paths within the snapshot which have been modified since its manifest
+/// was recorded, relative to it. This is much quicker than `verify`, since
+/// it only compares modification times, and so is checked whenever a zone
+/// of the snapshot gets mounted. Snapshots without a recorded time aren't
+/// checked.
+pub fn modified_since_recorded(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+) -> Result<Vec<PathBuf>, Error> {
+    let recorded_time = match SnapManifest::load(mzr_dir, snap_name)? {
+        Some(SnapManifest {
+            recorded_time: Some(recorded_time),
+            ..
+        }) => SystemTime::from(recorded_time),
+        _ => return Ok(Vec::new()),
+    };
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    let mut modified = Vec::new();
+    for entry in WalkDir::new(&snap_dir) {
+        let entry = entry?;
+        if entry.metadata()?.modified()? > recorded_time {
+            modified.push(entry.path().strip_prefix(&snap_dir)?.to_path_buf());
+        }
+    }
+    Ok(modified)
+}
+
+/// Fails if the snapshot has been modified since its manifest was recorded,
+/// since merges of zones of it would then be compared against the wrong
+/// baseline.
+pub fn check_unmodified(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+    let modified = modified_since_recorded(mzr_dir, snap_name)?;
+    if modified.is_empty() {
+        return Ok(());
+    }
+    let examples: Vec<String> = modified
+        .iter()
+        .take(5)
+        .map(|path| format!("{:?}", path))
+        .collect();
+    bail!(
+        "Snapshot {} has been modified since it was taken, such as {}, for {} path(s) in total. \
+         Use mzr snap verify {} to check its files, and mzr snap verify --record {} to accept \
+         its current contents.",
+        snap_name,
+        examples.join(", "),
+        modified.len(),
+        snap_name,
+        snap_name
+    );
+}
+
+/// Differences between a snapshot's contents and its manifest.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Number of files in the manifest.
+    pub checked: usize,
+    /// Files in the manifest which are no longer regular files in the
+    /// snapshot.
+    pub missing: Vec<PathBuf>,
+    /// Files whose contents no longer match their hash.
+    pub corrupted: Vec<PathBuf>,
+    /// Regular files in the snapshot which aren't in the manifest.
+    pub unexpected: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Checks the snapshot's files against its manifest.
+pub fn verify(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    manifest: &SnapManifest,
+) -> Result<Verification, Error> {
+    let actual_files = hash_regular_files(&SnapDir::new(mzr_dir, snap_name))?;
+    let mut verification = Verification {
+        checked: manifest.files.len(),
+        ..Verification::default()
+    };
+    for (path, hash) in manifest.files.iter() {
+        match actual_files.get(path) {
+            None => verification.missing.push(path.clone()),
+            Some(actual_hash) if actual_hash != hash => verification.corrupted.push(path.clone()),
+            Some(_) => {}
+        }
+    }
+    for path in actual_files.keys() {
+        if !manifest.files.contains_key(path) {
+            verification.unexpected.push(path.clone());
+        }
+    }
+    Ok(verification)
+}
+
+/// Hashes the regular files within the directory, keyed by their path
+/// relative to it.
+pub fn hash_regular_files<P: AsRef<Path>>(root: P) -> Result<BTreeMap<PathBuf, String>, Error> {
+    let root = root.as_ref();
+    let mut paths = Vec::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            paths.push(entry.path().to_path_buf());
+        }
+    }
+    let hashes = hash_files(paths.clone())?;
+    let mut files = BTreeMap::new();
+    for (path, hash) in paths.into_iter().zip(hashes.into_iter()) {
+        files.insert(PathBuf::from(path.strip_prefix(root)?), hash);
+    }
+    Ok(files)
+}
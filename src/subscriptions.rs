@@ -0,0 +1,163 @@
+use crate::merge::PlanSummary;
+use crate::paths::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long the daemon waits for a subscriber to accept a notification
+/// before dropping it, so that a client which stops reading can't block the
+/// daemon.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Kinds of notification which clients can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Topic {
+    ZoneMounted,
+    ZoneUnmounted,
+    ZoneChanged,
+    Merged,
+}
+
+/// Something which happened in the daemon, sent to the clients subscribed
+/// to its topic.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Notification {
+    ZoneMounted {
+        zone: ZoneName,
+    },
+    ZoneUnmounted {
+        zone: ZoneName,
+    },
+    /// Sent once at least the subscriber's threshold of paths have changed
+    /// within the zone since the last such notification. Paths which
+    /// changed more than once are counted each time.
+    ZoneChanged {
+        zone: ZoneName,
+        changed_paths: usize,
+    },
+    Merged {
+        zone: ZoneName,
+        summary: PlanSummary,
+    },
+}
+
+impl Notification {
+    fn topic(&self) -> Topic {
+        match self {
+            Notification::ZoneMounted { .. } => Topic::ZoneMounted,
+            Notification::ZoneUnmounted { .. } => Topic::ZoneUnmounted,
+            Notification::ZoneChanged { .. } => Topic::ZoneChanged,
+            Notification::Merged { .. } => Topic::Merged,
+        }
+    }
+}
+
+/// Clients with long-lived connections to the daemon, which are sent
+/// notifications as JSON-RPC 2.0 notifications with the method `notify`, one
+/// per line. Shared with the journaling threads, which report changes.
+#[derive(Clone, Default)]
+pub struct Subscribers(Arc<Mutex<Vec<Subscriber>>>);
+
+struct Subscriber {
+    stream: UnixStream,
+    topics: Vec<Topic>,
+    change_threshold: usize,
+    /// Number of paths changed within each zone since it was last notified
+    /// about the zone.
+    pending_changes: HashMap<ZoneName, usize>,
+}
+
+impl Subscribers {
+    /// Adds a subscriber to the topics, or to all of them if none are given.
+    pub fn add(
+        &self,
+        stream: UnixStream,
+        topics: Vec<Topic>,
+        change_threshold: usize,
+    ) -> io::Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let topics = if topics.is_empty() {
+            vec![
+                Topic::ZoneMounted,
+                Topic::ZoneUnmounted,
+                Topic::ZoneChanged,
+                Topic::Merged,
+            ]
+        } else {
+            topics
+        };
+        if let Ok(mut subscribers) = self.0.lock() {
+            subscribers.push(Subscriber {
+                stream,
+                topics,
+                change_threshold: change_threshold.max(1),
+                pending_changes: HashMap::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Sends the notification to the clients subscribed to its topic,
+    /// dropping those which can't be written to.
+    pub fn notify(&self, notification: &Notification) {
+        let topic = notification.topic();
+        if let Ok(mut subscribers) = self.0.lock() {
+            subscribers.retain(|subscriber| {
+                !subscriber.topics.contains(&topic) || send(&subscriber.stream, notification)
+            });
+        }
+    }
+
+    /// Counts paths changed within the zone, notifying the subscribers whose
+    /// threshold has been reached.
+    pub fn record_changes(&self, zone_name: &ZoneName, count: usize) {
+        if let Ok(mut subscribers) = self.0.lock() {
+            let mut failed = Vec::new();
+            for (ix, subscriber) in subscribers.iter_mut().enumerate() {
+                if !subscriber.topics.contains(&Topic::ZoneChanged) {
+                    continue;
+                }
+                let threshold = subscriber.change_threshold;
+                let pending = subscriber
+                    .pending_changes
+                    .entry(zone_name.clone())
+                    .or_insert(0);
+                *pending += count;
+                if *pending >= threshold {
+                    let notification = Notification::ZoneChanged {
+                        zone: zone_name.clone(),
+                        changed_paths: *pending,
+                    };
+                    *pending = 0;
+                    if !send(&subscriber.stream, &notification) {
+                        failed.push(ix);
+                    }
+                }
+            }
+            for ix in failed.into_iter().rev() {
+                subscribers.remove(ix);
+            }
+        }
+    }
+}
+
+/// Writes the notification as a line, yielding whether it succeeded.
+fn send(mut stream: &UnixStream, notification: &Notification) -> bool {
+    let params = match serde_json::to_value(notification) {
+        Ok(params) => params,
+        Err(_) => return true,
+    };
+    let mut message = serde_json::Map::new();
+    message.insert(String::from("jsonrpc"), Value::from("2.0"));
+    message.insert(String::from("method"), Value::from("notify"));
+    message.insert(String::from("params"), params);
+    let mut line = Value::Object(message).to_string();
+    line.push('\n');
+    stream.write_all(line.as_bytes()).is_ok()
+}
@@ -0,0 +1,109 @@
+use crate::config::Config;
+use crate::copier::Copier;
+use crate::paths::*;
+use crate::utils::run_process;
+use failure::{Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use walkdir::WalkDir;
+
+/// State which every new zone starts out with, such as a `.env` file or
+/// local build configuration. Paths which come from the template are never
+/// merged, since they're specific to zones.
+///
+/// The contents of the template dir, if it exists, are applied first, then
+/// the configured files, and then the configured commands.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Template {
+    /// Files to write into new zones, keyed by their path relative to the
+    /// work dir.
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+    /// Commands to run via `sh -c` in the changes dir of new zones, which is
+    /// relative to the work dir like the files within it. `MZR_TEMPLATE_ZONE`
+    /// and `MZR_TEMPLATE_SNAP_DIR` are set, so that they can refer to the
+    /// snapshot's files.
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+impl Template {
+    fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.commands.is_empty()
+    }
+}
+
+/// Applies the template into the changes dir of a newly created zone.
+/// Yields the paths which came from the template, relative to the work dir,
+/// for `ZoneInfo::template_paths`.
+pub fn apply(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    snap_dir: &SnapDir,
+    changes_dir: &OvfsChangesDir,
+) -> Result<Vec<PathBuf>, Error> {
+    let template = Config::load(mzr_dir)?.template;
+    let template_dir = ZoneTemplateDir::new(mzr_dir);
+    if template.is_empty() && !template_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let result: Result<(), Error> = try {
+        if template_dir.is_dir() {
+            let mut copier = Copier::new().with_owner_of(changes_dir)?;
+            for entry in WalkDir::new(&template_dir).min_depth(1) {
+                let entry = entry?;
+                let rel_path = entry.path().strip_prefix(&template_dir)?;
+                copier.copy(entry.path(), &changes_dir.join(rel_path))?;
+            }
+        }
+        for (rel_path, contents) in template.files.iter() {
+            let target = changes_dir.join(rel_path);
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+            fs::write(&target, contents)?;
+        }
+        for cmd_string in template.commands.iter() {
+            run_process(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd_string)
+                    .current_dir(changes_dir)
+                    .stdin(Stdio::null())
+                    .env("MZR_TEMPLATE_ZONE", zone_name.as_str())
+                    .env("MZR_TEMPLATE_SNAP_DIR", snap_dir.as_os_str()),
+            )?;
+        }
+    };
+    result.context(format_err!(
+        "Failed to apply the zone template to zone {}",
+        zone_name
+    ))?;
+    let mut template_paths = Vec::new();
+    find_template_paths(snap_dir, changes_dir, Path::new(""), &mut template_paths)?;
+    Ok(template_paths)
+}
+
+/// Finds the paths which the template added to the changes dir. Directories
+/// which the snapshot also has are descended into rather than included, so
+/// that other changes within them still get merged.
+fn find_template_paths(
+    snap_dir: &SnapDir,
+    changes_dir: &OvfsChangesDir,
+    rel_dir: &Path,
+    template_paths: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(changes_dir.join(rel_dir))? {
+        let entry = entry?;
+        let rel_path = rel_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() && snap_dir.join(&rel_path).is_dir() {
+            find_template_paths(snap_dir, changes_dir, &rel_path, template_paths)?;
+        } else {
+            template_paths.push(rel_path);
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,22 @@
 use crate::colors::*;
+use crate::config::Config;
+use crate::fs_probe;
 use crate::paths::{MzrDir, UserWorkDir};
-use crate::utils::{confirm, Confirmed};
-use failure::{Error, ResultExt};
+use crate::utils::{assume_yes, confirm, no_input, Confirmed};
+use failure::{Error, Fail, ResultExt};
+use nix::unistd::isatty;
 use std::env;
-use std::fs::create_dir_all;
-use std::path::PathBuf;
+use std::fs::{canonicalize, create_dir_all, metadata, read_dir, remove_dir_all, remove_file};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Environment variable which pins the work dir, rather than finding it from
+/// the current directory. Set by the `--project` flag.
+pub const PROJECT_VAR: &str = "MZR_PROJECT";
+
+/// Environment variable set within zones to the work dir, along with
+/// `MZR_DIR`, so that mzr commands run within them don't need to find it.
+pub const WORK_DIR_VAR: &str = "MZR_WORK_DIR";
 
 #[derive(Debug, Clone)]
 pub struct TopDirs {
@@ -14,54 +26,139 @@ pub struct TopDirs {
 
 impl TopDirs {
     pub fn find(action: &str) -> Result<TopDirs, Error> {
+        if let Some(top_dirs) = TopDirs::from_env()? {
+            if !top_dirs.mzr_dir.is_dir() {
+                Err(MzrDirNotFound.context(format!(
+                    "There's no mzr directory at {}, and can't {} without one. Use {} to \
+                     create one.",
+                    top_dirs.mzr_dir,
+                    action,
+                    color_cmd(&"mzr init")
+                )))?;
+            }
+            return Ok(top_dirs);
+        }
         match TopDirs::find_impl(&current_dir()?) {
             Ok(top_dirs) => Ok(top_dirs),
             Err(err) => match err.downcast() {
-                Ok(MzrDirNotFound) => Err(format_err!(
+                Ok(MzrDirNotFound) => Err(MzrDirNotFound.context(format!(
                     "Couldn't find mzr directory, and can't {} without one.",
                     action
-                ))?,
+                )))?,
                 Err(other_err) => Err(other_err)?,
             },
         }
     }
 
+    /// Searches the directory and its parents for a work dir with a sibling
+    /// mzr dir. When the current directory was reached through symlinks, the
+    /// path it was reached by is searched first, so that mzr dirs beside a
+    /// symlinked work dir are found. See `Config::keep_symlinked_work_dir`.
     fn find_impl(start_dir: &PathBuf) -> Result<TopDirs, Error> {
-        let mut dir = start_dir.clone();
-        loop {
-            let candidate = TopDirs::from_user_work(UserWorkDir::new(&dir));
-            if candidate.mzr_dir.is_dir() {
-                return Ok(candidate);
+        let mut start_dirs = Vec::new();
+        if let Some(logical_dir) = logical_current_dir(start_dir) {
+            start_dirs.push(logical_dir);
+        }
+        start_dirs.push(start_dir.clone());
+        for start_dir in start_dirs.iter() {
+            // Filesystem roots can't be work dirs, since they have no
+            // siblings.
+            for dir in start_dir
+                .ancestors()
+                .filter(|dir| dir.file_name().is_some())
+            {
+                let candidate = TopDirs::from_user_work(UserWorkDir::new(&dir.to_path_buf()))?;
+                if candidate.mzr_dir.is_dir() {
+                    return candidate.resolve_work_dir();
+                }
             }
-            dir.pop();
-            if dir.file_name().is_none() {
-                return Err(MzrDirNotFound.into());
+        }
+        Err(MzrDirNotFound.into())
+    }
+
+    /// Canonicalizes the work dir, unless configured to keep the path it was
+    /// found by. The mzr dir stays where it was found.
+    fn resolve_work_dir(self) -> Result<TopDirs, Error> {
+        if Config::load(&self.mzr_dir)?.keep_symlinked_work_dir {
+            return Ok(self);
+        }
+        let work_dir = canonicalize(&self.user_work_dir).context(format_err!(
+            "Failed to resolve the path of work directory {}",
+            self.user_work_dir
+        ))?;
+        Ok(TopDirs {
+            mzr_dir: self.mzr_dir,
+            user_work_dir: UserWorkDir::new(&work_dir),
+        })
+    }
+
+    /// Top dirs given by the environment, either pinned via `PROJECT_VAR`, or
+    /// those of the zone this process is within. The mzr dir is only known to
+    /// exist for zones.
+    pub fn from_env() -> Result<Option<TopDirs>, Error> {
+        if let Some(project_dir) = env::var_os(PROJECT_VAR) {
+            let project_dir = PathBuf::from(project_dir);
+            if !project_dir.is_dir() {
+                bail!(
+                    "{} is set to {:?}, which isn't a directory.",
+                    PROJECT_VAR,
+                    project_dir
+                );
             }
+            return Ok(Some(TopDirs::from_user_work(UserWorkDir::new(
+                &project_dir,
+            ))?));
         }
+        if let (Some(mzr_dir), Some(work_dir)) = (env::var_os("MZR_DIR"), env::var_os(WORK_DIR_VAR))
+        {
+            // The work dir may have been canonicalized, so isn't necessarily
+            // beside the mzr dir.
+            let top_dirs = TopDirs {
+                mzr_dir: MzrDir::from_path(Path::new(&mzr_dir)),
+                user_work_dir: UserWorkDir::new(&PathBuf::from(work_dir)),
+            };
+            // Ignored if stale, such as when inherited by a process which
+            // has left the zone.
+            if top_dirs.mzr_dir.is_dir() {
+                return Ok(Some(top_dirs));
+            }
+        }
+        Ok(None)
     }
 
     pub fn find_or_prompt_create(action: &str) -> Result<TopDirs, Error> {
-        let start_dir = env::var_os("MZR_DIR")
-            .map(|v| v.into())
-            .unwrap_or(current_dir()?);
-        match TopDirs::find_impl(&start_dir) {
+        // A pinned work dir is used as is, rather than searching its parents.
+        let (start_dir, pinned) = match TopDirs::from_env()? {
+            Some(ref top_dirs) if top_dirs.mzr_dir.is_dir() => return Ok(top_dirs.clone()),
+            Some(top_dirs) => (top_dirs.user_work_dir.to_path_buf(), true),
+            None => (current_dir()?, false),
+        };
+        let found = if pinned {
+            Err(MzrDirNotFound.into())
+        } else {
+            TopDirs::find_impl(&start_dir)
+        };
+        match found {
             Ok(top_dirs) => Ok(top_dirs),
             Err(err) => {
                 match err.downcast() {
                     Ok(MzrDirNotFound) => {
-                        println!("Couldn't find a mzr directory sibling to any parent directory, but one is needed in order to {}.", action);
-                        let dirs = match find_git_repo(&start_dir) {
-                            None => TopDirs::from_user_work(UserWorkDir::new(&start_dir)),
-                            Some(git_dir) => {
-                                println!("There's a git repository at {}", git_dir);
-                                TopDirs::from_user_work(git_dir)
-                            }
-                        };
+                        if pinned {
+                            println!("There's no mzr directory for {:?}, but one is needed in order to {}.", start_dir, action);
+                        } else {
+                            println!("Couldn't find a mzr directory sibling to any parent directory, but one is needed in order to {}.", action);
+                        }
+                        if !assume_yes() && (no_input() || !isatty(0).unwrap_or(false)) {
+                            Err(MzrDirNotFound.context(format!(
+                                "Not prompting to create one, since input isn't from a terminal, or \
+                                 --no-input was specified. Use {} to create one.",
+                                color_cmd(&"mzr init")
+                            )))?;
+                        }
+                        let dirs = TopDirs::new_at(&start_dir, !pinned)?;
                         match confirm(&format!("Init a new mzr directory at {}", dirs.mzr_dir))? {
                             Confirmed::Yes => {
-                                //TODO(cleanup): can this clone be avoided? (same on other
-                                // create_dir_all usages)
-                                create_dir_all(dirs.mzr_dir.clone())?;
+                                dirs.create(None)?;
                                 println!(
                                     "{} mzr directory initialized.",
                                     color_success(&"Success:")
@@ -69,9 +166,9 @@ impl TopDirs {
                                 //TODO(cleanup): can this clone be avoided?
                                 Ok(dirs.clone())
                             }
-                            Confirmed::No => {
-                                Err(format_err!("Can't {} without a mzr directory", action))
-                            }
+                            Confirmed::No => Err(MzrDirNotFound
+                                .context(format!("Can't {} without a mzr directory", action))
+                                .into()),
                         }
                     }
                     Err(other_err) => Err(other_err),
@@ -80,18 +177,85 @@ impl TopDirs {
         }
     }
 
-    fn from_user_work(user_work_dir: UserWorkDir) -> TopDirs {
-        TopDirs {
-            mzr_dir: MzrDir::new(&user_work_dir),
-            user_work_dir,
+    /// Top dirs for a new mzr directory, which has not yet been created. If
+    /// `prefer_git_root` is set and the directory is within a git repository,
+    /// then the root of the repository is the work dir.
+    pub fn new_at(dir: &PathBuf, prefer_git_root: bool) -> Result<TopDirs, Error> {
+        match find_git_repo(dir) {
+            Some(git_dir) if prefer_git_root => {
+                println!("There's a git repository at {}", git_dir);
+                TopDirs::from_user_work(git_dir)
+            }
+            _ => TopDirs::from_user_work(UserWorkDir::new(dir)),
         }
     }
+
+    /// Creates the mzr directory, along with its initial configuration. If
+    /// `store_at` is given, then the mzr directory is created there instead,
+    /// with a symlink to it beside the work dir, for when the work dir's
+    /// filesystem can't hold zones. Fails if the filesystem is unsuitable,
+    /// see `fs_probe::probe`.
+    pub fn create(&self, store_at: Option<&Path>) -> Result<(), Error> {
+        let real_dir = match store_at {
+            Some(store_at) => {
+                if store_at.exists() && read_dir(store_at)?.next().is_some() {
+                    bail!(
+                        "Can't store the mzr directory at {}, since it isn't empty.",
+                        color_dir(&store_at.display())
+                    );
+                }
+                create_dir_all(store_at)?;
+                symlink(canonicalize(store_at)?, &self.mzr_dir)?;
+                store_at.to_path_buf()
+            }
+            //TODO(cleanup): can this clone be avoided? (same on other
+            // create_dir_all usages)
+            None => {
+                create_dir_all(self.mzr_dir.clone())?;
+                self.mzr_dir.to_path_buf()
+            }
+        };
+        if let Err(err) = fs_probe::check_mzr_dir(&real_dir) {
+            let _ = remove_dir_all(&real_dir);
+            if store_at.is_some() {
+                let _ = remove_file(&self.mzr_dir);
+            }
+            return Err(err);
+        }
+        Config::default().write(&self.mzr_dir)
+    }
+
+    fn from_user_work(user_work_dir: UserWorkDir) -> Result<TopDirs, Error> {
+        Ok(TopDirs {
+            mzr_dir: MzrDir::new(&user_work_dir)?,
+            user_work_dir,
+        })
+    }
 }
 
 #[derive(Fail, Debug)]
 #[fail(display = "Did not find mzr directory for any parent directories.")]
 pub struct MzrDirNotFound;
 
+/// The current directory as reached through symlinks, which is `PWD` when
+/// it refers to the same directory as the canonical current directory, and
+/// differs from it.
+fn logical_current_dir(current_dir: &Path) -> Option<PathBuf> {
+    let logical_dir = PathBuf::from(env::var_os("PWD")?);
+    if !logical_dir.is_absolute() || logical_dir == current_dir {
+        return None;
+    }
+    let logical_metadata = metadata(&logical_dir).ok()?;
+    let current_metadata = metadata(current_dir).ok()?;
+    if (logical_metadata.dev(), logical_metadata.ino())
+        == (current_metadata.dev(), current_metadata.ino())
+    {
+        Some(logical_dir)
+    } else {
+        None
+    }
+}
+
 /// Like `env::current_dir`, but gives a decent error.
 fn current_dir() -> Result<PathBuf, Error> {
     Ok(env::current_dir().context("Error getting current directory - does it still exist?")?)
@@ -113,3 +277,67 @@ fn find_git_repo(start_dir: &PathBuf) -> Option<UserWorkDir> {
         cur.pop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Creates an empty directory for the test, canonicalized so that paths
+    /// within it can be compared with resolved ones.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mzr-top-dirs-test-{}-{}", process::id(), name));
+        if dir.exists() {
+            remove_dir_all(&dir).unwrap();
+        }
+        create_dir_all(&dir).unwrap();
+        canonicalize(&dir).unwrap()
+    }
+
+    #[test]
+    fn finds_mzr_dir_beside_an_ancestor() {
+        let dir = test_dir("ancestor");
+        let work_dir = dir.join("project");
+        create_dir_all(work_dir.join("src")).unwrap();
+        create_dir_all(dir.join("project.mzr")).unwrap();
+        let top_dirs = TopDirs::find_impl(&work_dir.join("src")).unwrap();
+        assert_eq!(top_dirs.mzr_dir.to_path_buf(), dir.join("project.mzr"));
+        assert_eq!(top_dirs.user_work_dir.to_path_buf(), work_dir);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_symlinked_work_dir_unless_configured_not_to() {
+        let dir = test_dir("symlink");
+        let real_work_dir = dir.join("real");
+        create_dir_all(&real_work_dir).unwrap();
+        let link = dir.join("link");
+        symlink(&real_work_dir, &link).unwrap();
+        let mzr_dir = dir.join("link.mzr");
+        create_dir_all(&mzr_dir).unwrap();
+
+        let top_dirs = TopDirs::find_impl(&link).unwrap();
+        assert_eq!(top_dirs.mzr_dir.to_path_buf(), mzr_dir);
+        assert_eq!(top_dirs.user_work_dir.to_path_buf(), real_work_dir);
+
+        Config {
+            keep_symlinked_work_dir: true,
+            ..Config::default()
+        }
+        .write(&top_dirs.mzr_dir)
+        .unwrap();
+        let top_dirs = TopDirs::find_impl(&link).unwrap();
+        assert_eq!(top_dirs.mzr_dir.to_path_buf(), mzr_dir);
+        assert_eq!(top_dirs.user_work_dir.to_path_buf(), link);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn filesystem_root_is_not_a_work_dir() {
+        assert!(MzrDir::new(&UserWorkDir::new(&PathBuf::from("/"))).is_err());
+        match TopDirs::find_impl(&PathBuf::from("/")) {
+            Err(err) => assert!(err.downcast::<MzrDirNotFound>().is_ok()),
+            Ok(top_dirs) => panic!("Unexpectedly found {:?}", top_dirs),
+        }
+    }
+}
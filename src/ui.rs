@@ -0,0 +1,281 @@
+use crate::conflicts;
+use crate::daemon;
+use crate::display::Humanize;
+use crate::freezer;
+use crate::top_dirs::TopDirs;
+use crate::utils::{self, Confirmed};
+use crate::zone::Zone;
+use failure::{Error, ResultExt};
+use libc::pid_t;
+use nix::sys::termios::{self, SetArg, SpecialCharacterIndices, Termios};
+use nix::unistd::isatty;
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::Command;
+
+/// How long to wait for a key before refreshing, in tenths of a second.
+const REFRESH_DECISECONDS: u8 = 20;
+
+/// Runs the terminal dashboard of `mzr ui`, which lists the zones along with
+/// their snapshots, numbers of changed paths, and open shells, and shows
+/// whether the daemon is running. It refreshes every couple of seconds.
+///
+/// Actions run the corresponding mzr command in the foreground, with the
+/// terminal restored, so that they can prompt as usual.
+pub fn run(top_dirs: &TopDirs) -> Result<(), Error> {
+    if !isatty(0).unwrap_or(false) || !isatty(1).unwrap_or(false) {
+        bail!("mzr ui needs to be run in an interactive terminal.");
+    }
+    let mut dashboard = Dashboard {
+        top_dirs: top_dirs.clone(),
+        rows: Vec::new(),
+        selected: 0,
+        daemon_status: String::new(),
+        message: None,
+    };
+    loop {
+        dashboard.refresh()?;
+        let key = {
+            let _raw_mode = RawMode::enable()?;
+            dashboard.render()?;
+            read_key()?
+        };
+        match key {
+            None => {}
+            Some(Key::Quit) => return Ok(()),
+            Some(Key::Up) => dashboard.selected = dashboard.selected.saturating_sub(1),
+            Some(Key::Down) => dashboard.selected += 1,
+            Some(Key::Refresh) => dashboard.message = None,
+            Some(Key::Shell) => dashboard.run_on_selected(&["shell"], false)?,
+            Some(Key::Merge) => dashboard.run_on_selected(&["merge"], true)?,
+            Some(Key::Remove) => dashboard.remove_selected()?,
+        }
+    }
+}
+
+struct Dashboard {
+    top_dirs: TopDirs,
+    rows: Vec<ZoneRow>,
+    selected: usize,
+    daemon_status: String,
+    /// Result of the last action.
+    message: Option<String>,
+}
+
+struct ZoneRow {
+    zone: Zone,
+    changed: Result<usize, String>,
+    shell_pids: Vec<pid_t>,
+    frozen: bool,
+}
+
+impl Dashboard {
+    fn refresh(&mut self) -> Result<(), Error> {
+        let mzr_dir = &self.top_dirs.mzr_dir;
+        let running = daemon::socket_exists(mzr_dir);
+        self.daemon_status = if running {
+            match daemon::ping(mzr_dir) {
+                Ok(health) => format!(
+                    "running, PID {}, {} zone process(es)",
+                    health.pid, health.zone_processes
+                ),
+                Err(err) => format!("not responding: {}", err),
+            }
+        } else {
+            String::from("not running")
+        };
+        let shells = if running {
+            daemon::list_shells(mzr_dir).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let mut zone_names = Zone::list_names(mzr_dir)?;
+        zone_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        self.rows.clear();
+        for zone_name in zone_names {
+            let zone = Zone::load(mzr_dir, &zone_name)?;
+            self.rows.push(ZoneRow {
+                changed: conflicts::changed_paths(&zone)
+                    .map(|paths| paths.len())
+                    .map_err(|err| err.to_string()),
+                shell_pids: shells
+                    .iter()
+                    .filter(|shell| shell.zone_name == zone_name)
+                    .map(|shell| shell.pid)
+                    .collect(),
+                frozen: freezer::is_frozen(mzr_dir, &zone_name),
+                zone,
+            });
+        }
+        if self.selected >= self.rows.len() {
+            self.selected = self.rows.len().saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Draws the dashboard. Since the terminal is in raw mode, lines end
+    /// with `\r\n`.
+    fn render(&self) -> Result<(), Error> {
+        let humanize = Humanize::default();
+        let mut out = String::from("\x1b[2J\x1b[H");
+        out.push_str(&format!(
+            "mzr: {}\r\nDaemon: {}\r\n\r\n",
+            self.top_dirs.user_work_dir.display(),
+            self.daemon_status
+        ));
+        if self.rows.is_empty() {
+            out.push_str("There are no zones.\r\n");
+        }
+        for (ix, row) in self.rows.iter().enumerate() {
+            let changed = match &row.changed {
+                Ok(count) => format!("{} changed", count),
+                Err(err) => format!("changes unknown: {}", err),
+            };
+            let mut notes = Vec::new();
+            if !row.shell_pids.is_empty() {
+                let pids: Vec<String> = row.shell_pids.iter().map(pid_t::to_string).collect();
+                notes.push(format!("shells {}", pids.join(" ")));
+            }
+            if row.frozen {
+                notes.push(String::from("frozen"));
+            }
+            if row.zone.info.temporary {
+                notes.push(String::from("temporary"));
+            }
+            let line = format!(
+                "{:<24} snapshot {:<20} {:<12} created {:<16} {}",
+                row.zone.name.as_str(),
+                row.zone.info.snapshot.as_str(),
+                changed,
+                humanize.time(&row.zone.info.creation_time),
+                notes.join(", ")
+            );
+            if ix == self.selected {
+                // Reverse video, to highlight the selection.
+                out.push_str(&format!("\x1b[7m> {}\x1b[0m\r\n", line));
+            } else {
+                out.push_str(&format!("  {}\r\n", line));
+            }
+        }
+        out.push_str("\r\n");
+        if let Some(message) = &self.message {
+            out.push_str(&format!("{}\r\n", message));
+        }
+        out.push_str(
+            "up/down or k/j: select, s: shell, m: merge, d: remove, r: refresh, q: quit\r\n",
+        );
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        handle.write_all(out.as_bytes())?;
+        handle.flush()?;
+        Ok(())
+    }
+
+    /// Runs an mzr command with the selected zone's name as its last
+    /// argument. When `pause` is set, waits for enter afterwards, so that
+    /// its output can be read before the dashboard is redrawn.
+    fn run_on_selected(&mut self, args: &[&str], pause: bool) -> Result<(), Error> {
+        let zone_name = match self.rows.get(self.selected) {
+            None => return Ok(()),
+            Some(row) => row.zone.name.clone(),
+        };
+        print!("\x1b[2J\x1b[H");
+        io::stdout().flush()?;
+        let status = Command::new(env::current_exe()?)
+            .args(args)
+            .arg(zone_name.as_str())
+            .status()
+            .context("Failed to run mzr")?;
+        self.message = Some(format!(
+            "mzr {} {} {}.",
+            args.join(" "),
+            zone_name,
+            if status.success() {
+                "succeeded"
+            } else {
+                "failed"
+            }
+        ));
+        if pause {
+            print!("\nPress enter to return to the dashboard.");
+            io::stdout().flush()?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the selected zone, after asking for confirmation, since any
+    /// changes which weren't merged are lost.
+    fn remove_selected(&mut self) -> Result<(), Error> {
+        let zone_name = match self.rows.get(self.selected) {
+            None => return Ok(()),
+            Some(row) => row.zone.name.clone(),
+        };
+        print!("\x1b[2J\x1b[H");
+        let query = format!(
+            "Remove zone {}, along with any changes which weren't merged",
+            zone_name
+        );
+        match utils::confirm(&query) {
+            Ok(Confirmed::Yes) => self.run_on_selected(&["zone", "remove"], true),
+            Ok(Confirmed::No) | Err(_) => {
+                self.message = Some(format!("Didn't remove zone {}.", zone_name));
+                Ok(())
+            }
+        }
+    }
+}
+
+enum Key {
+    Up,
+    Down,
+    Refresh,
+    Shell,
+    Merge,
+    Remove,
+    Quit,
+}
+
+/// Waits for a key, yielding `None` if none was pressed before it's time to
+/// refresh, or the key isn't bound.
+fn read_key() -> Result<Option<Key>, Error> {
+    let mut buf = [0; 8];
+    let count = io::stdin().read(&mut buf)?;
+    Ok(match &buf[..count] {
+        // A lone escape isn't bound, since it may be the start of an arrow
+        // key's sequence which was split across reads.
+        b"q" | b"\x03" => Some(Key::Quit),
+        b"k" | b"\x1b[A" | b"\x1bOA" => Some(Key::Up),
+        b"j" | b"\x1b[B" | b"\x1bOB" => Some(Key::Down),
+        b"r" => Some(Key::Refresh),
+        b"s" | b"\r" => Some(Key::Shell),
+        b"m" => Some(Key::Merge),
+        b"d" => Some(Key::Remove),
+        _ => None,
+    })
+}
+
+/// Puts the terminal in raw mode, with reads timing out so that the
+/// dashboard refreshes, until dropped.
+struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable() -> Result<RawMode, Error> {
+        let original = termios::tcgetattr(0)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = REFRESH_DECISECONDS;
+        termios::tcsetattr(0, SetArg::TCSANOW, &raw)?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(0, SetArg::TCSANOW, &self.original);
+    }
+}
@@ -1,8 +1,9 @@
+use crate::cleanup;
 use crate::colors::*;
 use failure::{Error, Fail, ResultExt};
 use nix::unistd;
+use std::env;
 use std::ffi::CString;
-use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::{self, Read, Write};
@@ -22,7 +23,37 @@ pub enum Confirmed {
     No,
 }
 
+/// Environment variable which, when set, makes `confirm` answer yes without
+/// prompting. Set by the `--yes` flag.
+pub const ASSUME_YES_VAR: &str = "MZR_ASSUME_YES";
+
+/// Environment variable which, when set, makes `confirm` fail rather than
+/// prompting. Set by the `--no-input` flag.
+pub const NO_INPUT_VAR: &str = "MZR_NO_INPUT";
+
+pub fn assume_yes() -> bool {
+    env_flag(ASSUME_YES_VAR)
+}
+
+pub fn no_input() -> bool {
+    env_flag(NO_INPUT_VAR)
+}
+
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => !value.is_empty() && value != "0",
+        Err(_) => false,
+    }
+}
+
 pub fn confirm(query: &str) -> Result<Confirmed, Error> {
+    if assume_yes() {
+        println!("{} [y/n]? y (assumed, due to --yes)", query);
+        return Ok(Confirmed::Yes);
+    }
+    if no_input() {
+        return Err(InputRequired(query.to_string()).into());
+    }
     print!("{} [y/n]? ", query);
     io::stdout().flush()?;
     let mut input = String::new();
@@ -40,18 +71,32 @@ pub fn confirm(query: &str) -> Result<Confirmed, Error> {
 #[fail(display = "Expected 'y' or 'n' response.")]
 struct UnexpectedConfirmInput(String);
 
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Input is needed to answer {:?}, but --no-input was specified. \
+               Use --yes to answer yes.",
+    _0
+)]
+pub struct InputRequired(String);
+
 /*
  * Path utilities
  */
 
-pub fn add_suffix_to_path(path: &PathBuf, suffix: &str) -> PathBuf {
-    match path.file_name().and_then(|x| x.to_str()) {
+/// Adds the suffix to the path's file name, yielding a sibling path. Fails
+/// for paths which don't end with a name, such as `/` or `..`.
+pub fn add_suffix_to_path(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    match path.file_name() {
         Some(name) => {
-            let mut result = path.clone();
-            result.set_file_name(OsStr::new(&[name, suffix].concat()));
-            result
+            let mut name = name.to_os_string();
+            name.push(suffix);
+            Ok(path.with_file_name(name))
         }
-        None => panic!("Failed to add {} suffix to {}", suffix, path.display()),
+        None => bail!(
+            "Can't add {} suffix to {:?}, since it doesn't end with a name.",
+            suffix,
+            path
+        ),
     }
 }
 
@@ -86,13 +131,50 @@ pub fn strip_prefix(prefix: &str, input: &str) -> Option<String> {
     }
 }
 
+/// Whether the text matches the glob pattern. `*` matches any characters
+/// other than `/`, `**` matches any characters, and `?` matches any one
+/// character other than `/`.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_matches_chars(&pattern, &text)
+}
+
+fn glob_matches_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) if rest.first() == Some(&'*') => {
+            let rest = &rest[1..];
+            // "**/" also matches when there are no directories in between.
+            (0..=text.len()).any(|ix| glob_matches_chars(rest, &text[ix..]))
+                || (rest.first() == Some(&'/') && glob_matches_chars(&rest[1..], text))
+        }
+        Some(('*', rest)) => (0..=text.len())
+            .take_while(|ix| *ix == 0 || text[ix - 1] != '/')
+            .any(|ix| glob_matches_chars(rest, &text[ix..])),
+        Some(('?', rest)) => match text.split_first() {
+            Some((c, text_rest)) if *c != '/' => glob_matches_chars(rest, text_rest),
+            _ => false,
+        },
+        Some((p, rest)) => match text.split_first() {
+            Some((c, text_rest)) if c == p => glob_matches_chars(rest, text_rest),
+            _ => false,
+        },
+    }
+}
+
 /*
  * Process utilities
  */
 
-/// Runs a process and yields an error if encountered.
+/// Runs a process and yields an error if encountered. If mzr is interrupted
+/// meanwhile, the process is stopped before cleanup happens, see
+/// `cleanup::as_helper`.
 pub fn run_process(cmd: &mut Command) -> Result<(), Error> {
-    match cmd.status() {
+    let status = cmd
+        .spawn()
+        .and_then(|mut child| cleanup::as_helper(child.id(), || child.wait()));
+    match status {
         Err(e) => Err(e).context(format_err!(
             "Error encountered while running {:?}",
             color_cmd(cmd)
@@ -110,6 +192,23 @@ pub fn run_process(cmd: &mut Command) -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs a process like `run_process`, yielding its stdout. Its stderr is
+/// passed through.
+pub fn run_process_output(cmd: &mut Command) -> Result<String, Error> {
+    let output = cmd.stderr(Stdio::inherit()).output().context(format_err!(
+        "Error encountered while running {:?}",
+        color_cmd(cmd)
+    ))?;
+    if !output.status.success() {
+        bail!(
+            "{:?} exited with failure status {}",
+            color_cmd(cmd),
+            color_err(&output.status)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
 // TODO: should handle args, will probably need that.
 pub fn execvp(cmd: &str) -> Result<Void, Error> {
     let cmd_cstring = CString::new(cmd).context(format!(
@@ -156,3 +255,35 @@ where
 pub fn parse_pid_file<P: AsRef<Path> + Display>(path: P) -> Result<unistd::Pid, Error> {
     parse_file(path).map(unistd::Pid::from_raw)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_suffix_to_path_appends_to_the_file_name() {
+        assert_eq!(
+            add_suffix_to_path(Path::new("/home/user/project"), ".mzr").unwrap(),
+            PathBuf::from("/home/user/project.mzr")
+        );
+        assert_eq!(
+            add_suffix_to_path(Path::new("project"), ".mzr").unwrap(),
+            PathBuf::from("project.mzr")
+        );
+        assert_eq!(
+            add_suffix_to_path(Path::new("/home/user/project/"), ".mzr").unwrap(),
+            PathBuf::from("/home/user/project.mzr")
+        );
+        assert_eq!(
+            add_suffix_to_path(Path::new("/project"), ".mzr").unwrap(),
+            PathBuf::from("/project.mzr")
+        );
+    }
+
+    #[test]
+    fn add_suffix_to_path_fails_without_a_file_name() {
+        assert!(add_suffix_to_path(Path::new("/"), ".mzr").is_err());
+        assert!(add_suffix_to_path(Path::new("/home/user/.."), ".mzr").is_err());
+        assert!(add_suffix_to_path(Path::new(""), ".mzr").is_err());
+    }
+}
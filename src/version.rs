@@ -0,0 +1,129 @@
+use crate::colors::*;
+use crate::sandbox;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Abbreviated hash of the commit mzr was built from, with a "-dirty" suffix
+/// if there were uncommitted changes. Set by `build.rs`.
+pub const GIT_COMMIT: &str = env!("MZR_GIT_COMMIT");
+
+/// Date mzr was built, in UTC. Set by `build.rs`.
+pub const BUILD_DATE: &str = env!("MZR_BUILD_DATE");
+
+/// Target triple mzr was built for. Set by `build.rs`.
+pub const TARGET: &str = env!("MZR_TARGET");
+
+/// Optional features of mzr that this build includes.
+pub fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "self-update") {
+        features.push("self-update");
+    }
+    features
+}
+
+/// Mechanisms mzr relies on, along with whether they are available on this
+/// system.
+pub fn backends() -> Vec<(&'static str, Result<String, String>)> {
+    vec![
+        ("overlayfs", overlayfs_support()),
+        ("user namespaces", user_namespace_support()),
+        ("cgroups v2", cgroups_support()),
+        ("landlock", landlock_support()),
+        ("seccomp", seccomp_support()),
+        ("git", git_support()),
+    ]
+}
+
+pub fn print_version(verbose: bool) {
+    println!("mzr {}", VERSION);
+    if !verbose {
+        return;
+    }
+    println!("commit: {}", GIT_COMMIT);
+    println!("build date: {}", BUILD_DATE);
+    println!("target: {}", TARGET);
+    let features = enabled_features();
+    println!(
+        "features: {}",
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    );
+    println!("backends:");
+    for (name, support) in backends() {
+        match support {
+            Ok(details) => println!("  {}: {}", name, color_success(&details)),
+            Err(reason) => println!("  {}: {}", name, color_warn(&reason)),
+        }
+    }
+}
+
+fn overlayfs_support() -> Result<String, String> {
+    let filesystems = read_to_string("/proc/filesystems").map_err(|err| err.to_string())?;
+    if filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some("overlay"))
+    {
+        Ok("available".to_string())
+    } else {
+        Err("not loaded (the overlay module may load on first use)".to_string())
+    }
+}
+
+fn user_namespace_support() -> Result<String, String> {
+    // Only present on kernels with Debian's patch for disabling unprivileged
+    // user namespaces.
+    if let Ok(enabled) = read_to_string("/proc/sys/kernel/unprivileged_userns_clone") {
+        if enabled.trim() == "0" {
+            return Err("disabled by kernel.unprivileged_userns_clone".to_string());
+        }
+    }
+    match read_to_string("/proc/sys/user/max_user_namespaces") {
+        Ok(max) if max.trim() == "0" => Err("disabled by user.max_user_namespaces".to_string()),
+        Ok(_) => Ok("available".to_string()),
+        Err(_) => Err("not supported by the kernel".to_string()),
+    }
+}
+
+fn cgroups_support() -> Result<String, String> {
+    let controllers_file = Path::new("/sys/fs/cgroup/cgroup.controllers");
+    match read_to_string(controllers_file) {
+        Ok(controllers) => Ok(format!("controllers: {}", controllers.trim())),
+        Err(_) => Err("not in use, so resource limits are unavailable".to_string()),
+    }
+}
+
+fn landlock_support() -> Result<String, String> {
+    match sandbox::landlock_abi_version() {
+        Some(version) => Ok(format!("ABI version {}", version)),
+        None => Err("not available, so --sandbox can't be used".to_string()),
+    }
+}
+
+fn seccomp_support() -> Result<String, String> {
+    let status = read_to_string("/proc/self/status").map_err(|err| err.to_string())?;
+    if status.lines().any(|line| line.starts_with("Seccomp:")) {
+        Ok("available".to_string())
+    } else {
+        Err("not supported by the kernel, so --seccomp can't be used".to_string())
+    }
+}
+
+fn git_support() -> Result<String, String> {
+    let output = Command::new("git")
+        .stdin(Stdio::null())
+        .arg("--version")
+        .output()
+        .map_err(|err| format!("failed to run git: {}", err))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(format!("git --version exited with {}", output.status))
+    }
+}
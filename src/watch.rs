@@ -0,0 +1,149 @@
+use crate::colors::*;
+use crate::git::get_git_dir;
+use crate::inotify::TreeWatcher;
+use crate::merge::{self, ConflictReason, DirAction};
+use crate::merge_txn;
+use crate::top_dirs::TopDirs;
+use crate::zone::Zone;
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use std::fs::{symlink_metadata, Metadata};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Watches the zone's changes, and applies non-conflicting updates to the
+/// target dir as they appear. Once a change is noticed, updates are applied
+/// after no further changes happen for the debounce duration, so that files
+/// aren't copied while they are still being written. Deletions of files are
+/// applied too. Runs until interrupted.
+pub fn run(
+    top_dirs: &TopDirs,
+    zone: &Zone,
+    target_dir: &PathBuf,
+    debounce: Duration,
+) -> Result<(), Error> {
+    // The zone's git directory is shared with the target, other than a few
+    // files like HEAD and the index, which shouldn't be copied.
+    let excluded_dirs: Vec<PathBuf> = get_git_dir(&top_dirs.user_work_dir)
+        .into_iter()
+        .map(|rel_git_dir| rel_git_dir.to_path_buf())
+        .collect();
+    let mut watcher = TreeWatcher::new(&zone.ovfs_changes_dir)?;
+    println!(
+        "Watching zone {} for changes to apply to {}. Press Ctrl-C to stop.",
+        zone.name,
+        color_dir(&target_dir.display())
+    );
+    let mut reported_conflicts = HashSet::new();
+    let mut applied = HashMap::new();
+    loop {
+        sync(
+            top_dirs,
+            zone,
+            target_dir,
+            &excluded_dirs,
+            &mut reported_conflicts,
+            &mut applied,
+        )?;
+        // Block until there are changes, and then wait for them to settle.
+        watcher.read_changes()?;
+        while watcher.wait(debounce)? {
+            watcher.read_changes()?;
+        }
+    }
+}
+
+/// Applies the zone's current changes. `applied` records the metadata of the
+/// files which were applied, so that they can be told apart from files
+/// modified in the target dir, see `merge::plan_watch`.
+fn sync(
+    top_dirs: &TopDirs,
+    zone: &Zone,
+    target_dir: &PathBuf,
+    excluded_dirs: &[PathBuf],
+    reported_conflicts: &mut HashSet<PathBuf>,
+    applied: &mut HashMap<PathBuf, Metadata>,
+) -> Result<(), Error> {
+    let plan = merge::plan_watch(zone, target_dir, excluded_dirs, applied)?;
+    merge_txn::apply(&top_dirs.mzr_dir, zone, &plan, target_dir)?;
+    for rename in plan.renames.iter() {
+        applied.remove(&rename.from);
+        applied.insert(
+            rename.to.clone(),
+            symlink_metadata(target_dir.join(&rename.to))?,
+        );
+    }
+    for update in plan.updates.iter() {
+        if merge::is_whiteout(&update.source_metadata) {
+            applied.remove(&update.rel_path);
+        } else {
+            applied.insert(
+                update.rel_path.clone(),
+                symlink_metadata(target_dir.join(&update.rel_path))?,
+            );
+        }
+    }
+    for dir_update in plan.dir_updates.iter() {
+        let change = match dir_update.action {
+            DirAction::Create => "Created",
+            DirAction::Remove => "Removed",
+            DirAction::Replace => "Replaced",
+        };
+        println!(
+            "{} {}",
+            color_success(&change),
+            color_dir(&dir_update.rel_path.display())
+        );
+    }
+    for rename in plan.renames.iter() {
+        println!(
+            "{} {} \u{2192} {}",
+            color_success(&"Renamed"),
+            color_file(&rename.from.display()),
+            color_file(&rename.to.display())
+        );
+    }
+    for update in plan.updates.iter() {
+        let change = if merge::is_whiteout(&update.source_metadata) {
+            "Deleted"
+        } else {
+            "Updated"
+        };
+        println!(
+            "{} {}",
+            color_success(&change),
+            color_file(&update.rel_path.display())
+        );
+    }
+    // Conflicts are only reported once, since they are left in place.
+    let mut current_conflicts = HashSet::new();
+    for conflict in plan.conflicts.iter() {
+        if !reported_conflicts.contains(&conflict.rel_path) {
+            let change = match conflict.reason {
+                ConflictReason::NotInSnapshot => "created",
+                ConflictReason::ModifiedInTarget => "modified",
+            };
+            println!(
+                "{} {} was also {} in {}, so it wasn't updated.",
+                color_warn(&"Conflict:"),
+                color_file(&conflict.rel_path.display()),
+                change,
+                color_dir(&target_dir.display())
+            );
+        }
+        current_conflicts.insert(conflict.rel_path.clone());
+    }
+    *reported_conflicts = current_conflicts;
+    for skip in plan.skips.iter() {
+        match &skip.source {
+            None => println!("{} {}", color_warn(&"Skipped:"), skip.reason),
+            Some(path) => println!(
+                "{} {}: {}",
+                color_warn(&"Skipped:"),
+                color_file(&path.display()),
+                skip.reason
+            ),
+        }
+    }
+    Ok(())
+}
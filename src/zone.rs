@@ -1,16 +1,41 @@
-use crate::colors::color_dir;
+use crate::colors::{color_cmd, color_dir};
+use crate::compaction;
+use crate::config::Config;
+use crate::daemon;
+use crate::errors::{kind_error, ErrorKind};
+use crate::events::{self, EventKind};
+use crate::fs_probe;
+use crate::git;
+use crate::hooks::{self, Hook};
 use crate::json;
+use crate::merge;
+use crate::mount;
+use crate::mountinfo;
+use crate::overlay;
 use crate::paths::*;
+use crate::shared;
+use crate::snapshot::SnapInfo;
+use crate::template;
+use crate::top_dirs::TopDirs;
 use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
-use libmount::{BindMount, Overlay};
+use nix::mount::MsFlags;
 use serde::{Deserialize, Serialize};
-use std::fs::{create_dir, create_dir_all};
-use std::iter;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{
+    create_dir, create_dir_all, read_dir, remove_dir_all, set_permissions, symlink_metadata, File,
+    Permissions,
+};
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct Zone {
     pub name: ZoneName,
+    pub mzr_dir: MzrDir,
     pub zone_dir: ZoneDir,
     pub snap_dir: SnapDir,
     pub ovfs_changes_dir: OvfsChangesDir,
@@ -23,6 +48,47 @@ pub struct Zone {
 pub struct ZoneInfo {
     pub snapshot: SnapName,
     pub creation_time: DateTime<Utc>,
+    /// Whether the zone's git repositories are registered as worktrees of
+    /// the user's repositories, rather than symlinking into them.
+    #[serde(default)]
+    pub git_worktree: bool,
+    /// Git branch created for the zone by `mzr shell --branch`.
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// Paths which get a tmpfs mounted over them when the zone is entered,
+    /// so that files written there, such as build artifacts, don't go to the
+    /// changes dir. Relative paths are relative to the work dir.
+    #[serde(default)]
+    pub scratch_dirs: Vec<PathBuf>,
+    /// Zone which this zone is layered on, for zones created by `mzr run`
+    /// within another zone. The parent's changes dir is used as a lower
+    /// layer above the snapshot, see `lower_dirs`.
+    #[serde(default)]
+    pub parent: Option<ZoneName>,
+    /// Whether the zone was created by `mzr shell --tmp`, to be deleted when
+    /// the shell exits.
+    #[serde(default)]
+    pub temporary: bool,
+    /// Paths which came from the zone template when the zone was created,
+    /// relative to the work dir. These are never merged.
+    #[serde(default)]
+    pub template_paths: Vec<PathBuf>,
+    /// Environment variables set when entering the zone, such as a
+    /// `CARGO_TARGET_DIR` or `DATABASE_URL` specific to the zone, so that
+    /// concurrent zones don't share external state. See `Zone::env_vars`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directories within the user's home directory, relative to it, which
+    /// the zone gets its own version of. Within the zone, a directory in the
+    /// zone's home dir is bound over each, so that tools which write to
+    /// user-level caches, such as the cargo registry or ccache, don't share
+    /// them with other zones.
+    #[serde(default)]
+    pub isolated_home_dirs: Vec<PathBuf>,
+    /// Note about what the zone is for, set by `mzr zone create --desc`, and
+    /// shown by `mzr list`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 impl Zone {
@@ -30,13 +96,33 @@ impl Zone {
         mzr_dir: &MzrDir,
         zone_name: &ZoneName,
         snap_name: &SnapName,
+        git_worktree: bool,
     ) -> Result<Zone, Error> {
         let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
-        Zone::create_impl(mzr_dir, &zone_dir, zone_name, snap_name)
+        Zone::create_impl(mzr_dir, &zone_dir, zone_name, snap_name, git_worktree)
+    }
+
+    /// Creates a zone layered on another zone, so that it starts out with the
+    /// parent's changes to their shared snapshot.
+    pub fn create_layered(
+        mzr_dir: &MzrDir,
+        zone_name: &ZoneName,
+        parent: &Zone,
+    ) -> Result<Zone, Error> {
+        let mut zone = Zone::create(mzr_dir, zone_name, &parent.info.snapshot, false)?;
+        zone.info.parent = Some(parent.name.clone());
+        zone.write_info()?;
+        Ok(zone)
     }
 
     pub fn load(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Zone, Error> {
         let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
+        if !zone_dir.is_dir() {
+            return Err(kind_error(
+                ErrorKind::ZoneNotFound,
+                format!("Zone {} does not exist.", zone_name),
+            ));
+        }
         Zone::load_impl(mzr_dir, &zone_dir, &zone_name)
     }
 
@@ -53,6 +139,40 @@ impl Zone {
         ZoneDir::new(mzr_dir, &zone_name).is_dir()
     }
 
+    /// Lists the names of all zones, in no particular order.
+    pub fn list_names(mzr_dir: &MzrDir) -> Result<Vec<ZoneName>, Error> {
+        let zones_dir = ZonesDir::new(mzr_dir);
+        let mut names = Vec::new();
+        if !zones_dir.is_dir() {
+            return Ok(names);
+        }
+        for entry in read_dir(&zones_dir).context(format_err!(
+            "Unexpected error while listing zones in {}",
+            zones_dir
+        ))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|name| format_err!("Zone name {:?} is not valid unicode.", name))?;
+                names.push(ZoneName::new(name)?);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Lists the names of the zones which are directly layered on the zone.
+    pub fn list_layered_on(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Vec<ZoneName>, Error> {
+        let mut names = Vec::new();
+        for name in Zone::list_names(mzr_dir)? {
+            if Zone::load(mzr_dir, &name)?.info.parent.as_ref() == Some(zone_name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
     pub fn load_or_create<F>(
         mzr_dir: &MzrDir,
         zone_name: &ZoneName,
@@ -66,7 +186,7 @@ impl Zone {
             Zone::load_impl(mzr_dir, &zone_dir, &zone_name)
         } else {
             let snap_name = get_snap_name()?;
-            Zone::create_impl(mzr_dir, &zone_dir, zone_name, &snap_name)
+            Zone::create_impl(mzr_dir, &zone_dir, zone_name, &snap_name, false)
         }
     }
 
@@ -75,19 +195,22 @@ impl Zone {
         zone_dir: &ZoneDir,
         zone_name: &ZoneName,
         snap_name: &SnapName,
+        git_worktree: bool,
     ) -> Result<Zone, Error> {
         let snap_dir = SnapDir::new(mzr_dir, &snap_name);
         if !snap_dir.is_dir() {
-            bail!(
-                "Expected that the {} snapshot would exist at {}",
-                snap_name,
-                snap_dir
-            );
+            return Err(kind_error(
+                ErrorKind::SnapshotNotFound,
+                format!(
+                    "Expected that the {} snapshot would exist at {}",
+                    snap_name, snap_dir
+                ),
+            ));
         }
         let zone_parent = zone_dir
             .parent()
             .ok_or_else(|| format_err!("Unexpected error: zone directory must have a parent."))?;
-        create_dir_all(zone_parent).context(format_err!(
+        shared::create_user_dir(mzr_dir, zone_parent).context(format_err!(
             "Unexpected error while creating zone parent directory {}",
             color_dir(&zone_parent.display())
         ))?;
@@ -122,13 +245,52 @@ impl Zone {
                     "Unexpected error while creating zone mount directory for overlayfs: {}",
                     ovfs_mount_dir
                 ))?;
+                // Otherwise mounting the zone fails with an unhelpful error.
+                if let Err(err) = fs_probe::check_zone_dirs(&ovfs_changes_dir, &ovfs_work_dir) {
+                    let _ = remove_dir_all(&zone_dir);
+                    return Err(err);
+                }
+                let template_paths =
+                    match template::apply(mzr_dir, zone_name, &snap_dir, &ovfs_changes_dir) {
+                        Ok(template_paths) => template_paths,
+                        Err(err) => {
+                            // Otherwise the zone would be left without info.
+                            let _ = remove_dir_all(&zone_dir);
+                            return Err(err);
+                        }
+                    };
                 let info = ZoneInfo {
                     snapshot: snap_name.clone(),
                     creation_time: Utc::now(),
+                    git_worktree,
+                    branch: None,
+                    scratch_dirs: Vec::new(),
+                    parent: None,
+                    temporary: false,
+                    template_paths,
+                    env: BTreeMap::new(),
+                    isolated_home_dirs: Vec::new(),
+                    description: None,
                 };
                 json::write(&ZoneInfoFile::new(&zone_dir), &info)?;
+                events::record(
+                    mzr_dir,
+                    EventKind::ZoneCreated {
+                        zone: zone_name.clone(),
+                        snapshot: snap_name.clone(),
+                    },
+                );
+                hooks::run_or_warn(
+                    mzr_dir,
+                    Hook::ZoneCreate,
+                    &[
+                        ("MZR_HOOK_ZONE", zone_name.as_str()),
+                        ("MZR_HOOK_SNAPSHOT", snap_name.as_str()),
+                    ],
+                );
                 Ok(Zone {
                     name: zone_name.clone(),
+                    mzr_dir: mzr_dir.clone(),
                     zone_dir: zone_dir.clone(),
                     snap_dir,
                     ovfs_changes_dir,
@@ -152,6 +314,7 @@ impl Zone {
         let ovfs_mount_dir = OvfsMountDir::new(zone_dir);
         Ok(Zone {
             name: zone_name.clone(),
+            mzr_dir: mzr_dir.clone(),
             zone_dir: zone_dir.clone(),
             snap_dir,
             ovfs_changes_dir,
@@ -161,23 +324,371 @@ impl Zone {
         })
     }
 
+    pub fn write_info(&self) -> Result<(), Error> {
+        json::write(&ZoneInfoFile::new(&self.zone_dir), &self.info)
+    }
+
+    /// Environment variables to set when entering the zone, from the config's
+    /// `env` followed by the zone's own. Within their values, `${MZR_ZONE}`,
+    /// `${MZR_DIR}`, and `${MZR_ZONE_DIR}` are replaced with the zone's name,
+    /// the mzr directory, and the zone's directory, so that the config can
+    /// give each zone its own paths.
+    pub fn env_vars(&self) -> Result<BTreeMap<String, String>, Error> {
+        let mut vars = Config::load(&self.mzr_dir)?.env;
+        vars.extend(self.info.env.clone());
+        let mzr_dir = self.mzr_dir.to_string_lossy();
+        let zone_dir = self.zone_dir.to_string_lossy();
+        Ok(vars
+            .into_iter()
+            .map(|(name, value)| {
+                let value = value
+                    .replace("${MZR_ZONE}", self.name.as_str())
+                    .replace("${MZR_DIR}", &mzr_dir)
+                    .replace("${MZR_ZONE_DIR}", &zone_dir);
+                (name, value)
+            })
+            .collect())
+    }
+
+    /// Removes the zone's directory, along with the metadata of any git
+    /// worktrees registered for it in the user's repositories.
+    pub fn remove(&self, user_work_dir: &UserWorkDir) -> Result<(), Error> {
+        if self.info.git_worktree {
+            for rel_git_dir in git::find_git_dirs(&self.snap_dir)? {
+                if rel_git_dir.file_name() == Some(OsStr::new(".git")) {
+                    git::remove_worktree(&user_work_dir.join(&rel_git_dir), &self.name)?;
+                }
+            }
+        }
+        remove_dir_all(&self.zone_dir).context(format_err!(
+            "Unexpected error while removing zone directory {}",
+            self.zone_dir
+        ))?;
+        events::record(
+            &self.mzr_dir,
+            EventKind::ZoneRemoved {
+                zone: self.name.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Discards the zone's changes to the paths, which are relative to the
+    /// work dir, or all of its changes if no paths are given. This removes
+    /// the copied-up files and whiteouts from the changes dir, so that the
+    /// contents of the lower dirs show through again. Yields the paths which
+    /// had changes to discard.
+    ///
+    /// Overlayfs doesn't support modifying the changes dir while it's
+    /// mounted, so the zone must not be mounted.
+    pub fn revert(&self, rel_paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+        compaction::check_not_compacted(self)?;
+        let changes_dir = &self.ovfs_changes_dir;
+        // The work dir itself stands for all of the changes.
+        let revert_all = rel_paths.is_empty() || rel_paths.iter().any(|p| p.as_os_str().is_empty());
+        let rel_paths = if revert_all {
+            let mut entries = Vec::new();
+            for entry in read_dir(changes_dir)? {
+                entries.push(PathBuf::from(entry?.file_name()));
+            }
+            entries
+        } else {
+            rel_paths.to_vec()
+        };
+        let mut reverted = Vec::new();
+        for rel_path in rel_paths {
+            // Opaque directories hide everything beneath them in the lower
+            // dirs, so removing a path within one wouldn't revert it.
+            for ancestor in rel_path.ancestors().skip(1) {
+                if ancestor.as_os_str().is_empty() {
+                    continue;
+                }
+                if merge::is_opaque_dir(&changes_dir.join(ancestor)) {
+                    bail!(
+                        "{} was replaced within zone {}, so {} can only be reverted along with it.",
+                        color_dir(&ancestor.display()),
+                        self.name,
+                        color_dir(&rel_path.display())
+                    );
+                }
+            }
+            let path = changes_dir.join(&rel_path);
+            if symlink_metadata(&path).is_ok() {
+                merge::remove_all(&path)?;
+                reverted.push(rel_path);
+            }
+        }
+        Ok(reverted)
+    }
+
+    /// Fixes problems which stop the zone's overlay from mounting, such as
+    /// stale contents of the overlayfs work dir left by a crash, yielding
+    /// descriptions of the repairs. Problems which can't be fixed
+    /// automatically are errors which say what to do about them.
+    ///
+    /// Like `revert`, this requires that the zone isn't mounted.
+    pub fn repair(&self) -> Result<Vec<String>, Error> {
+        let mut repairs = Vec::new();
+        // Missing parent zones are reported by `lower_dirs`.
+        for lower_dir in self.lower_dirs()? {
+            if !lower_dir.is_dir() {
+                bail!(
+                    "Zone {} is layered on {}, which is missing. Use {} to move the zone onto \
+                     another snapshot.",
+                    self.name,
+                    color_dir(&lower_dir.display()),
+                    color_cmd(&format!("mzr zone rebase {} --onto SNAP_NAME", self.name))
+                );
+            }
+        }
+        match symlink_metadata(&self.ovfs_changes_dir) {
+            Ok(ref metadata) if metadata.is_dir() => {}
+            Ok(_) => bail!(
+                "The changes dir of zone {}, {}, isn't a directory. Move it elsewhere to keep \
+                 its contents, and then repair the zone again to start it with no changes.",
+                self.name,
+                color_dir(&self.ovfs_changes_dir.display())
+            ),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                create_dir_all(&self.ovfs_changes_dir)?;
+                repairs.push(format!(
+                    "Created missing changes dir {}, so the zone has no changes.",
+                    color_dir(&self.ovfs_changes_dir.display())
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        // Overlayfs refuses to mount when its work dir has leftovers from an
+        // earlier mount which wasn't cleanly unmounted. It creates
+        // directories within the work dir without any permissions, so those
+        // are made removable first.
+        if symlink_metadata(&self.ovfs_work_dir).is_ok() {
+            if read_dir(&self.ovfs_work_dir)?.next().is_some() {
+                make_removable(&self.ovfs_work_dir)?;
+                remove_dir_all(&self.ovfs_work_dir)?;
+                repairs.push(format!(
+                    "Cleared overlayfs work dir {}.",
+                    color_dir(&self.ovfs_work_dir.display())
+                ));
+            }
+        }
+        if !self.ovfs_work_dir.is_dir() {
+            create_dir_all(&self.ovfs_work_dir)?;
+            repairs.push(format!(
+                "Created overlayfs work dir {}.",
+                color_dir(&self.ovfs_work_dir.display())
+            ));
+        }
+        if symlink_metadata(&self.ovfs_changes_dir)?.dev()
+            != symlink_metadata(&self.ovfs_work_dir)?.dev()
+        {
+            bail!(
+                "The changes dir and work dir of zone {} are on different filesystems, which \
+                 overlayfs doesn't allow. Move {} onto the same filesystem as {}.",
+                self.name,
+                color_dir(&self.ovfs_work_dir.display()),
+                color_dir(&self.ovfs_changes_dir.display())
+            );
+        }
+        if !self.ovfs_mount_dir.is_dir() {
+            create_dir_all(&self.ovfs_mount_dir)?;
+            repairs.push(format!(
+                "Created mount dir {}.",
+                color_dir(&self.ovfs_mount_dir.display())
+            ));
+        }
+        Ok(repairs)
+    }
+
+    /// Directories underneath the zone's changes, from topmost to bottommost:
+    /// the changes dirs of the zones it's layered on, followed by the
+    /// snapshot. Changes made within a parent zone are visible within this
+    /// zone, though overlayfs doesn't guarantee how promptly, since it
+    /// expects lower layers to be unchanging.
+    pub fn lower_dirs(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut lower_dirs = Vec::new();
+        let mut parent = self.info.parent.clone();
+        let mut child = self.name.clone();
+        while let Some(parent_name) = parent {
+            let parent_zone = match Zone::load_if_exists(&self.mzr_dir, &parent_name)? {
+                Some(parent_zone) => parent_zone,
+                None => {
+                    return Err(kind_error(
+                        ErrorKind::ZoneNotFound,
+                        format!(
+                            "Zone {} is layered on zone {}, which no longer exists.",
+                            child, parent_name
+                        ),
+                    ))
+                }
+            };
+            lower_dirs.push(parent_zone.ovfs_changes_dir.to_path_buf());
+            parent = parent_zone.info.parent;
+            child = parent_name;
+        }
+        lower_dirs.push(self.snap_dir.to_path_buf());
+        Ok(lower_dirs)
+    }
+
     pub fn mount(&self) -> Result<(), Error> {
-        Overlay::writable(
-            iter::once(self.snap_dir.as_ref()),
-            &self.ovfs_changes_dir,
-            &self.ovfs_work_dir,
+        // These directories aren't transferred when zones are copied between
+        // mzr directories, so create them if necessary.
+        create_dir_all(&self.ovfs_work_dir)?;
+        create_dir_all(&self.ovfs_mount_dir)?;
+        let lower_dirs = self.lower_dirs()?;
+        overlay::mount(
+            &lower_dirs,
+            Some((&self.ovfs_changes_dir, &self.ovfs_work_dir)),
             &self.ovfs_mount_dir,
+            &self.overlay_features()?,
         )
-        .mount()
-        // TODO(cleanup): Should make it so that '?' can be used,
-        // by making libmount Error implement Sync. Same pattern
-        // repeated below for bind mount.
-        .map_err(|e| format_err!("{}", e))
     }
 
+    /// The overlayfs features enabled in the config which can be used,
+    /// warning about those which can't.
+    fn overlay_features(&self) -> Result<Vec<&'static str>, Error> {
+        let options = Config::load(&self.mzr_dir)?.overlay;
+        let (usable, unusable) = overlay::usable_features(&options);
+        for (feature, reason) in unusable {
+            println!(
+                "Warning: not enabling overlayfs {} for zone {}, since {}.",
+                feature, self.name, reason
+            );
+        }
+        Ok(usable)
+    }
+
+    /// Binds the zone over the work dir. For zones of sparse snapshots, only
+    /// the snapshot's subtrees are bound, each over the same path within the
+    /// work dir, so the rest of the work dir is seen as it is outside the
+    /// zone, and writes to it aren't isolated.
     pub fn bind_to(&self, user_work_dir: &UserWorkDir) -> Result<(), Error> {
-        BindMount::new(&self.ovfs_mount_dir, &user_work_dir)
-            .mount()
-            .map_err(|e| format_err!("{}", e))
+        let paths = SnapInfo::load(&self.mzr_dir, &self.info.snapshot)?.paths;
+        if paths.is_empty() {
+            return Ok(mount::bind(&self.ovfs_mount_dir, user_work_dir)?);
+        }
+        for path in paths {
+            let source = self.ovfs_mount_dir.join(&path);
+            let target = user_work_dir.join(&path);
+            // Bind mounts need a target of the same type, which may have been
+            // removed from the work dir since the snapshot was taken.
+            if source.is_dir() {
+                create_dir_all(&target)?;
+            } else if symlink_metadata(&target).is_err() {
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
+                File::create(&target)?;
+            }
+            mount::bind(&source, &target)?;
+        }
+        Ok(())
+    }
+
+    /// Mounts a tmpfs over each of the scratch dirs. This is done by the zone
+    /// process after binding the zone to the work dir, so the mounts are only
+    /// visible within the zone. Scratch dirs within the work dir are created
+    /// if they don't exist yet.
+    pub fn mount_scratch_dirs(
+        &self,
+        user_work_dir: &UserWorkDir,
+        scratch_dirs: &[PathBuf],
+    ) -> Result<(), Error> {
+        for scratch_dir in scratch_dirs {
+            let target = user_work_dir.join(scratch_dir);
+            if target.starts_with(user_work_dir) {
+                create_dir_all(&target)?;
+            }
+            mount::tmpfs(&target, MsFlags::MS_NOSUID | MsFlags::MS_NODEV)?;
+        }
+        Ok(())
+    }
+
+    /// Binds the zone's own version of each of the home dirs over them. Like
+    /// scratch dirs, this is done by the zone process, so the mounts are only
+    /// visible within the zone. The zone's versions start out empty.
+    pub fn bind_home_dirs(&self, home: &Path, home_dirs: &[PathBuf]) -> Result<(), Error> {
+        let zone_home_dir = ZoneHomeDir::new(&self.zone_dir);
+        for home_dir in home_dirs {
+            if home_dir.is_absolute() {
+                bail!(
+                    "Isolated home dir {:?} should be relative to the home directory.",
+                    home_dir
+                );
+            }
+            let source = zone_home_dir.join(home_dir);
+            let target = home.join(home_dir);
+            create_dir_all(&source)?;
+            create_dir_all(&target)?;
+            mount::bind(&source, &target)?;
+        }
+        Ok(())
+    }
+
+    /// Binds the common version of each of the shared cache dirs over it, from
+    /// the shared cache dir of the mzr directory. This is done after binding
+    /// isolated home dirs, so that directories within them can be shared.
+    pub fn bind_shared_cache_dirs(
+        &self,
+        user_work_dir: &UserWorkDir,
+        cache_dirs: &[PathBuf],
+    ) -> Result<(), Error> {
+        let shared_cache_dir = SharedCacheDir::new(&self.mzr_dir);
+        for cache_dir in cache_dirs {
+            let target = user_work_dir.join(cache_dir);
+            // Absolute paths are stored under their path from the root.
+            let source = match target.strip_prefix(user_work_dir) {
+                Ok(rel_path) => shared_cache_dir.join("work").join(rel_path),
+                Err(_) => shared_cache_dir
+                    .join("root")
+                    .join(target.strip_prefix("/").unwrap_or(&target)),
+            };
+            create_dir_all(&source)?;
+            create_dir_all(&target)?;
+            mount::bind(&source, &target)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives the user full permissions on the directory and the directories
+/// within it, so that their contents can be removed.
+fn make_removable(dir: &Path) -> Result<(), Error> {
+    set_permissions(dir, Permissions::from_mode(0o700))?;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_removable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the current process is, relative to zones.
+pub enum Location {
+    /// The work dir is the user's actual work dir.
+    Outside,
+    /// The work dir is a zone's view of it. The zone's name is known if it was
+    /// entered via mzr, or its overlayfs is mounted at the work dir.
+    Within(Option<ZoneName>),
+}
+
+/// Determines whether the current process is within a zone. Zones entered via
+/// mzr set `MZR_ZONE`, but in case that isn't inherited, the work dir is also
+/// checked for being an overlayfs mount. If the mount doesn't identify the
+/// zone, the daemon is asked which `mzr shell` the process was started from.
+pub fn current_location(top_dirs: &TopDirs) -> Result<Location, Error> {
+    if let Ok(zone_name) = env::var("MZR_ZONE") {
+        return Ok(Location::Within(Some(ZoneName::new(zone_name)?)));
+    }
+    match mountinfo::mount_at(&top_dirs.user_work_dir)? {
+        Some(ref mount) if mount.is_overlay() => match mount.zone_name(&top_dirs.mzr_dir) {
+            Some(zone_name) => Ok(Location::Within(Some(zone_name))),
+            None if daemon::socket_exists(&top_dirs.mzr_dir) => Ok(Location::Within(
+                daemon::zone_of_current_process(&top_dirs.mzr_dir)?,
+            )),
+            None => Ok(Location::Within(None)),
+        },
+        _ => Ok(Location::Outside),
     }
 }
@@ -0,0 +1,154 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::git::{get_git_dir, SHARED_REPO_PATHS};
+use crate::json;
+use crate::paths::*;
+use crate::snapshot::{self, SnapInfo};
+use crate::top_dirs::TopDirs;
+use crate::utils::run_process;
+use crate::zone::Zone;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, remove_dir, remove_dir_all, rename};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Name of the manifest within zone bundles.
+const MANIFEST_NAME: &str = "mzr-zone-manifest.json";
+
+/// Name of the directory storing the zone's changes within zone bundles.
+const CHANGES_NAME: &str = "changes";
+
+/// Describes an exported zone, in particular which snapshot its changes are
+/// based on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneManifest {
+    pub zone_name: ZoneName,
+    pub snapshot: SnapName,
+    pub snapshot_version: u32,
+    pub export_time: DateTime<Utc>,
+}
+
+/// Exports the zone's changes directory as a tarball, along with a manifest
+/// describing the snapshot it is based on.
+///
+/// Overlayfs whiteouts and opaque directory xattrs are included, both the
+/// `trusted.` ones and the `user.` ones of overlays mounted in a user
+/// namespace, so that deletions are preserved. The symlinks into the shared
+/// git repository are excluded, since the daemon creates them when the zone
+/// is mounted.
+pub fn export(top_dirs: &TopDirs, zone_name: &ZoneName, bundle: &PathBuf) -> Result<(), Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    compaction::check_not_compacted(&zone)?;
+    let snap_info = SnapInfo::load(mzr_dir, &zone.info.snapshot)?;
+    let manifest = ZoneManifest {
+        zone_name: zone_name.clone(),
+        snapshot: zone.info.snapshot.clone(),
+        snapshot_version: snap_info.version,
+        export_time: Utc::now(),
+    };
+    let tmp_dir = MzrTmpDir::new(mzr_dir, &format!("zone-export-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result: Result<(), Error> = try {
+        json::write(&tmp_dir.join(MANIFEST_NAME), &manifest)?;
+        let mut cmd_base = Command::new("tar");
+        let cmd = cmd_base
+            .stdin(Stdio::null())
+            .arg("--create")
+            .arg("--gzip")
+            .arg("--xattrs")
+            .arg("--xattrs-include=trusted.overlay.*")
+            .arg("--xattrs-include=user.overlay.*")
+            .arg("--file")
+            .arg(bundle);
+        if let Ok(rel_git_dir) = get_git_dir(&top_dirs.user_work_dir) {
+            for shared_path in SHARED_REPO_PATHS.iter() {
+                cmd.arg(format!(
+                    "--exclude=./{}",
+                    rel_git_dir.join(shared_path).display()
+                ));
+            }
+        }
+        cmd.arg("--directory")
+            .arg(tmp_dir.as_os_str())
+            .arg(MANIFEST_NAME)
+            .arg("--directory")
+            .arg(zone.ovfs_changes_dir.as_os_str())
+            .arg(format!("--transform=s,^\\.,{},", CHANGES_NAME))
+            .arg(".");
+        run_process(cmd)?;
+    };
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Creates a zone from a bundle created by `export`. The snapshot that the
+/// zone was based on must already exist. If no name is specified, the name of
+/// the exported zone is used.
+pub fn import(
+    mzr_dir: &MzrDir,
+    bundle: &PathBuf,
+    zone_name: &Option<ZoneName>,
+) -> Result<ZoneName, Error> {
+    let tmp_dir = MzrTmpDir::new(mzr_dir, &format!("zone-import-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let result = import_impl(mzr_dir, bundle, zone_name, &tmp_dir);
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+fn import_impl(
+    mzr_dir: &MzrDir,
+    bundle: &PathBuf,
+    zone_name: &Option<ZoneName>,
+    tmp_dir: &MzrTmpDir,
+) -> Result<ZoneName, Error> {
+    run_process(
+        Command::new("tar")
+            .stdin(Stdio::null())
+            .arg("--extract")
+            .arg("--gzip")
+            .arg("--xattrs")
+            .arg("--xattrs-include=trusted.overlay.*")
+            .arg("--xattrs-include=user.overlay.*")
+            .arg("--file")
+            .arg(bundle)
+            .arg("--directory")
+            .arg(tmp_dir.as_os_str()),
+    )?;
+    let manifest: ZoneManifest = json::read(&tmp_dir.join(MANIFEST_NAME))
+        .context(format_err!(
+            "Failed to read manifest from {:?}. Was it created by mzr zone export?",
+            bundle
+        ))?
+        .contents;
+    let zone_name = zone_name.clone().unwrap_or(manifest.zone_name);
+    if !snapshot::exists(mzr_dir, &manifest.snapshot) {
+        bail!(
+            "Zone is based on snapshot {}, which doesn't exist here. \
+             It can be transferred via mzr snap export and mzr snap import.",
+            manifest.snapshot
+        );
+    }
+    let snap_info = SnapInfo::load(mzr_dir, &manifest.snapshot)?;
+    if snap_info.version != manifest.snapshot_version {
+        println!(
+            "{} zone was exported with version {} of snapshot {}, but version {} is here.",
+            color_warn(&"Warning:"),
+            manifest.snapshot_version,
+            manifest.snapshot,
+            snap_info.version
+        );
+    }
+    let zone = Zone::create(mzr_dir, &zone_name, &manifest.snapshot, false)?;
+    // Replace the new zone's empty changes dir with the imported one.
+    remove_dir(&zone.ovfs_changes_dir)?;
+    rename(tmp_dir.join(CHANGES_NAME), &zone.ovfs_changes_dir).context(format_err!(
+        "Failed to move imported changes into place at {}",
+        zone.ovfs_changes_dir
+    ))?;
+    Ok(zone_name)
+}
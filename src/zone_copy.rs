@@ -0,0 +1,178 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::copier::Copier;
+use crate::merge;
+use crate::zone::Zone;
+use failure::Error;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{create_dir, read_dir, remove_file, symlink_metadata};
+use std::path::{Path, PathBuf};
+
+/// Copies a path as it appears within the source zone into the destination
+/// zone's changes, yielding the number of entries copied. The source's view
+/// is resolved through its layers, without needing it to be mounted, and
+/// file contents are reflinked when the filesystem supports it.
+///
+/// Directories are merged with those the destination already has, like
+/// `cp -r`. Overlayfs doesn't support modifying the changes dir while it's
+/// mounted, so the destination zone must not be mounted.
+pub fn copy(source_zone: &Zone, dest_zone: &Zone, rel_path: &Path) -> Result<usize, Error> {
+    if source_zone.name == dest_zone.name {
+        bail!("Can't copy from zone {} into itself.", source_zone.name);
+    }
+    compaction::check_not_compacted(source_zone)?;
+    compaction::check_not_compacted(dest_zone)?;
+    let source_layers = layers(source_zone)?;
+    if view_layers(&source_layers, rel_path).is_empty() {
+        bail!(
+            "Zone {} doesn't have {}.",
+            source_zone.name,
+            color_file(&rel_path.display())
+        );
+    }
+    let dest_layers = layers(dest_zone)?;
+    let mut copier = Copier::for_layers(&source_zone.ovfs_changes_dir, source_layers[1..].to_vec())
+        .with_owner_of(&dest_zone.ovfs_changes_dir)?;
+    create_parent_dirs(&mut copier, dest_zone, &dest_layers, rel_path)?;
+    copy_view(
+        &mut copier,
+        &source_layers,
+        rel_path,
+        &dest_zone.ovfs_changes_dir.join(rel_path),
+    )
+}
+
+/// The zone's changes dir followed by its lower dirs, from topmost to
+/// bottommost.
+fn layers(zone: &Zone) -> Result<Vec<PathBuf>, Error> {
+    let mut layers = vec![zone.ovfs_changes_dir.to_path_buf()];
+    layers.extend(zone.lower_dirs()?);
+    Ok(layers)
+}
+
+/// The layers which contribute to the zone's view of the path, from topmost
+/// to bottommost. This is empty if the path isn't visible, has multiple
+/// entries if it's a directory merged from several layers, and otherwise
+/// has the one layer whose entry is visible.
+fn view_layers(layers: &[PathBuf], rel_path: &Path) -> Vec<PathBuf> {
+    let mut current = layers.to_vec();
+    let mut prefix = PathBuf::new();
+    for component in rel_path.components() {
+        prefix.push(component);
+        current = step_layers(&current, &prefix);
+    }
+    current
+}
+
+/// Narrows down the layers which contribute to a directory's view to those
+/// which contribute to the view of an entry within it. Whiteouts and opaque
+/// directories hide the layers below them, as does anything other than a
+/// directory, and directories hide non-directories below them.
+fn step_layers(layers: &[PathBuf], rel_path: &Path) -> Vec<PathBuf> {
+    let mut contributing: Vec<PathBuf> = Vec::new();
+    for layer in layers {
+        let path = layer.join(rel_path);
+        match symlink_metadata(&path) {
+            Err(_) => continue,
+            Ok(ref metadata) if merge::is_whiteout(metadata) => break,
+            Ok(ref metadata) if metadata.is_dir() => {
+                contributing.push(layer.clone());
+                if merge::is_opaque_dir(&path) {
+                    break;
+                }
+            }
+            Ok(_) => {
+                if contributing.is_empty() {
+                    contributing.push(layer.clone());
+                }
+                break;
+            }
+        }
+    }
+    contributing
+}
+
+/// Copies the view of the path, and the views of the entries within it if
+/// it's a directory, to the target.
+fn copy_view(
+    copier: &mut Copier,
+    layers: &[PathBuf],
+    rel_path: &Path,
+    target: &Path,
+) -> Result<usize, Error> {
+    let top_layer = match layers.first() {
+        Some(top_layer) => top_layer,
+        None => return Ok(0),
+    };
+    let source = top_layer.join(rel_path);
+    let is_dir = symlink_metadata(&source)?.is_dir();
+    // Whiteouts and other non-directories are replaced by copying, but a
+    // directory can't be created over them.
+    match symlink_metadata(target) {
+        Ok(ref metadata) if is_dir && !metadata.is_dir() => remove_file(target)?,
+        Ok(ref metadata) if !is_dir && metadata.is_dir() => bail!(
+            "Can't copy {} over the directory {}.",
+            color_file(&source.display()),
+            color_dir(&target.display())
+        ),
+        _ => {}
+    }
+    copier.copy(&source, target)?;
+    let mut count = 1;
+    if is_dir {
+        let mut names: BTreeSet<OsString> = BTreeSet::new();
+        for layer in layers {
+            for entry in read_dir(layer.join(rel_path))? {
+                names.insert(entry?.file_name());
+            }
+        }
+        for name in names {
+            let entry_rel_path = rel_path.join(&name);
+            let entry_layers = step_layers(layers, &entry_rel_path);
+            count += copy_view(copier, &entry_layers, &entry_rel_path, &target.join(&name))?;
+        }
+    }
+    Ok(count)
+}
+
+/// Creates the directories containing the path within the destination
+/// zone's changes dir, copying their metadata from the destination's view
+/// when it has them.
+fn create_parent_dirs(
+    copier: &mut Copier,
+    dest_zone: &Zone,
+    dest_layers: &[PathBuf],
+    rel_path: &Path,
+) -> Result<(), Error> {
+    let mut ancestors: Vec<&Path> = rel_path
+        .ancestors()
+        .skip(1)
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .collect();
+    ancestors.reverse();
+    for ancestor in ancestors {
+        let target = dest_zone.ovfs_changes_dir.join(ancestor);
+        match symlink_metadata(&target) {
+            Ok(ref metadata) if metadata.is_dir() => continue,
+            Ok(_) => bail!(
+                "{} isn't a directory within zone {}.",
+                color_file(&ancestor.display()),
+                dest_zone.name
+            ),
+            Err(_) => {}
+        }
+        match view_layers(dest_layers, ancestor).first() {
+            Some(layer) if symlink_metadata(layer.join(ancestor))?.is_dir() => {
+                copier.copy(&layer.join(ancestor), &target)?;
+            }
+            Some(_) => bail!(
+                "{} isn't a directory within zone {}.",
+                color_file(&ancestor.display()),
+                dest_zone.name
+            ),
+            None => create_dir(&target)?,
+        }
+    }
+    Ok(())
+}
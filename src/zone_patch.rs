@@ -0,0 +1,141 @@
+use crate::colors::*;
+use crate::compaction;
+use crate::daemon;
+use crate::git;
+use crate::ipc::Channel;
+use crate::paths::*;
+use crate::project_set::ProjectSet;
+use crate::snapshot::SnapInfo;
+use crate::top_dirs::TopDirs;
+use crate::zone::Zone;
+use failure::Error;
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult, Pid};
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::Path;
+use std::process::exit;
+
+/// A commit of the zone's files, whose parent is the commit the zone's
+/// snapshot was taken of.
+pub struct ZoneCommit {
+    pub base: String,
+    pub commit: String,
+}
+
+/// Commits the zone's view of the work dir on top of the commit its snapshot
+/// was taken of, without updating any refs, for `mzr zone format-patch`.
+/// Unless a message is given, it's derived from the zone, see
+/// `default_message`. Yields `None` when the zone's files match that commit.
+///
+/// This compares files, so commits made within the zone aren't preserved,
+/// and uncommitted changes in the work dir at the time of the snapshot are
+/// included. Ignored files are left out, as with `git add`.
+pub fn commit_zone(
+    top_dirs: &TopDirs,
+    zone_name: &ZoneName,
+    message: Option<&str>,
+) -> Result<Option<ZoneCommit>, Error> {
+    let mzr_dir = &top_dirs.mzr_dir;
+    if ProjectSet::load(mzr_dir)?.is_some() {
+        bail!("mzr zone format-patch doesn't yet support project sets.");
+    }
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    compaction::check_not_compacted(&zone)?;
+    let base = match SnapInfo::load(mzr_dir, &zone.info.snapshot)?.git_commit {
+        Some(commit) => commit,
+        None => bail!(
+            "Snapshot {} of zone {} wasn't taken of a git commit, so there's nothing to diff \
+             its files against.",
+            zone.info.snapshot,
+            zone_name
+        ),
+    };
+    let tree = write_zone_tree(top_dirs, &zone, &base)?;
+    if tree == git::commit_tree_sha(&top_dirs.user_work_dir, &base)? {
+        return Ok(None);
+    }
+    let message = match message {
+        Some(message) => message.to_string(),
+        None => default_message(&zone),
+    };
+    let commit = git::commit_tree(&top_dirs.user_work_dir, &tree, &base, &message)?;
+    Ok(Some(ZoneCommit { base, commit }))
+}
+
+/// Writes a git tree of the zone's files. This happens in a child process
+/// which enters the zone's mount namespace, since the zone's files are only
+/// visible there. The tree's objects are written to the repository that the
+/// zone shares with the work dir, so the parent can use them.
+fn write_zone_tree(top_dirs: &TopDirs, zone: &Zone, base: &str) -> Result<String, Error> {
+    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, &zone.name)?;
+    let tmp_dir = MzrTmpDir::new(&top_dirs.mzr_dir, &format!("format-patch-{}", Pid::this()));
+    create_dir_all(&tmp_dir)?;
+    let (mut parent_channel, mut child_channel) = Channel::pair()?;
+    let result: Result<String, Error> = match fork()? {
+        ForkResult::Child => {
+            drop(parent_channel);
+            let result: Result<String, Error> = try {
+                daemon::enter_zone_process_user_and_mount(&zone_pid)?;
+                git::write_work_tree(&top_dirs.user_work_dir, base, &tmp_dir.join("index"))?
+            };
+            let status = result.map_err(|err| err.to_string());
+            match child_channel.send(&status) {
+                Ok(()) => exit(0),
+                Err(_) => exit(1),
+            }
+        }
+        ForkResult::Parent { child } => {
+            drop(child_channel);
+            let result: Result<String, Error> = try {
+                match parent_channel.recv::<Result<String, String>>()? {
+                    Ok(tree) => tree,
+                    Err(err) => Err(format_err!(
+                        "Failed to write a git tree of the files in zone {}: {}",
+                        zone.name,
+                        err
+                    ))?,
+                }
+            };
+            waitpid(child, None)?;
+            result
+        }
+    };
+    remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Describes the zone for the commit message of its patch. The subject is
+/// the first line of the zone's description, if it has one.
+fn default_message(zone: &Zone) -> String {
+    let subject = zone
+        .info
+        .description
+        .as_ref()
+        .and_then(|description| description.lines().next())
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.to_string())
+        .unwrap_or_else(|| format!("Changes from mzr zone {}", zone.name));
+    format!(
+        "{}\n\nExported from mzr zone {}, which is based on snapshot {}.",
+        subject, zone.name, zone.info.snapshot
+    )
+}
+
+/// Name of the ref which `git::create_bundle` temporarily creates for the
+/// zone, and which the bundle lists. Recipients fetch it by this name.
+pub fn bundle_ref_name(zone_name: &ZoneName) -> String {
+    format!("refs/mzr/format-patch/{}", zone_name)
+}
+
+/// Suggests how to use a bundle created for the zone.
+pub fn bundle_hint(bundle: &Path, zone_name: &ZoneName) -> String {
+    format!(
+        "To apply it, run {} in a clone with the base commit.",
+        color_cmd(&format!(
+            "git fetch {} {}:{}",
+            bundle.display(),
+            bundle_ref_name(zone_name),
+            zone_name
+        ))
+    )
+}
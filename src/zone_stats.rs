@@ -0,0 +1,152 @@
+use crate::display::Humanize;
+use crate::json;
+use crate::paths::*;
+use crate::zone::Zone;
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use walkdir::WalkDir;
+
+/// How long cached statistics are used before being recomputed.
+const MAX_CACHE_AGE_SECS: i64 = 60;
+
+/// Minimum time between the sizes kept in `ZoneStats::history`.
+const HISTORY_INTERVAL_SECS: i64 = 60 * 60;
+
+/// Number of sizes kept in `ZoneStats::history`.
+const HISTORY_LEN: usize = 24;
+
+/// Size of changes dir beyond which `mzr status` points out the zone, since
+/// it likely has build outputs which should be in a scratch dir.
+const LARGE_CHANGES_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Statistics about a zone's changes dir, which help with noticing zones
+/// whose changes have ballooned, such as due to a build dir which should be
+/// a scratch dir. Computing them requires walking the changes dir, so
+/// they're cached in the zone dir for a minute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneStats {
+    /// Files in the changes dir, whether copied up from the snapshot when
+    /// modified, or created within the zone.
+    pub files: u64,
+    /// Paths deleted within the zone, which overlayfs records as whiteouts.
+    pub deletions: u64,
+    /// Space allocated for the changes dir.
+    pub changes_bytes: u64,
+    /// Latest modification time within the changes dir, if it has anything.
+    pub last_activity: Option<DateTime<Utc>>,
+    pub computed_time: DateTime<Utc>,
+    /// Earlier sizes of the changes dir, oldest first, for showing how
+    /// quickly it's growing.
+    #[serde(default)]
+    pub history: Vec<(DateTime<Utc>, u64)>,
+}
+
+impl ZoneStats {
+    /// Loads the zone's cached statistics, recomputing them if they're
+    /// missing or stale.
+    pub fn get(zone: &Zone) -> Result<ZoneStats, Error> {
+        let stats_file = ZoneStatsFile::new(&zone.zone_dir);
+        let cached: Option<ZoneStats> = if stats_file.exists() {
+            json::read(&stats_file).ok().map(|file| file.contents)
+        } else {
+            None
+        };
+        if let Some(cached) = &cached {
+            let age = Utc::now().signed_duration_since(cached.computed_time);
+            if age < Duration::seconds(MAX_CACHE_AGE_SECS) && age >= Duration::zero() {
+                return Ok(cached.clone());
+            }
+        }
+        let mut stats = ZoneStats::compute(zone)?;
+        let mut history = cached.map(|cached| cached.history).unwrap_or_default();
+        let due = match history.last() {
+            Some((time, _)) => {
+                stats.computed_time.signed_duration_since(*time)
+                    >= Duration::seconds(HISTORY_INTERVAL_SECS)
+            }
+            None => true,
+        };
+        if due {
+            history.push((stats.computed_time, stats.changes_bytes));
+            let excess = history.len().saturating_sub(HISTORY_LEN);
+            history.drain(..excess);
+        }
+        stats.history = history;
+        json::write(&stats_file, &stats)?;
+        Ok(stats)
+    }
+
+    fn compute(zone: &Zone) -> Result<ZoneStats, Error> {
+        let mut stats = ZoneStats {
+            files: 0,
+            deletions: 0,
+            changes_bytes: 0,
+            last_activity: None,
+            computed_time: Utc::now(),
+            history: Vec::new(),
+        };
+        for entry in WalkDir::new(&zone.ovfs_changes_dir)
+            .same_file_system(true)
+            .min_depth(1)
+        {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let file_type = metadata.file_type();
+            if file_type.is_char_device() && metadata.rdev() == 0 {
+                stats.deletions += 1;
+            } else if !file_type.is_dir() {
+                stats.files += 1;
+            }
+            stats.changes_bytes += metadata.blocks() * 512;
+            let modified = DateTime::<Utc>::from(metadata.modified()?);
+            if stats.last_activity.map_or(true, |latest| modified > latest) {
+                stats.last_activity = Some(modified);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// How much the changes dir has grown since the oldest recorded size,
+    /// along with when that was.
+    pub fn growth(&self) -> Option<(i64, DateTime<Utc>)> {
+        let (time, bytes) = self.history.first()?;
+        if *time == self.computed_time {
+            return None;
+        }
+        Some((self.changes_bytes as i64 - *bytes as i64, *time))
+    }
+
+    pub fn is_large(&self) -> bool {
+        self.changes_bytes >= LARGE_CHANGES_BYTES
+    }
+
+    /// Summary for listings, such as `12 changed file(s), 1.4 GiB of changes
+    /// (+300 MiB since 2 hours ago), last active 5 minutes ago`.
+    pub fn describe(&self, humanize: &Humanize) -> String {
+        let mut result = format!("{} changed file(s)", self.files);
+        if self.deletions > 0 {
+            result.push_str(&format!(", {} deletion(s)", self.deletions));
+        }
+        result.push_str(&format!(
+            ", {} of changes",
+            humanize.size(self.changes_bytes)
+        ));
+        if let Some((growth, since)) = self.growth() {
+            if growth != 0 {
+                let sign = if growth > 0 { "+" } else { "-" };
+                result.push_str(&format!(
+                    " ({}{} since {})",
+                    sign,
+                    humanize.size(growth.abs() as u64),
+                    humanize.time(&since)
+                ));
+            }
+        }
+        if let Some(last_activity) = &self.last_activity {
+            result.push_str(&format!(", last active {}", humanize.time(last_activity)));
+        }
+        result
+    }
+}